@@ -1,8 +1,11 @@
 /// Represents the smallest unit of BitcoinZ: Satoshis.
 pub type Amount = i64;
 
+/// Number of satoshis in one BTCZ.
+pub const COIN: Amount = 100_000_000;
+
 /// Maximum allowed amount in the BitcoinZ network.
-pub const MAX_MONEY: Amount = 21_000_000_000_000_000; // 21 million BTCZ in satoshis.
+pub const MAX_MONEY: Amount = 21_000_000_000 * COIN; // 21 billion BTCZ in satoshis.
 
 /// Checks if the given amount is within the valid monetary range.
 pub fn is_valid_amount(value: Amount) -> bool {
@@ -31,6 +34,13 @@ mod tests {
         assert!(!is_valid_amount(-1));
     }
 
+    #[test]
+    fn test_max_money_is_supply_cap() {
+        // BitcoinZ issues 21 billion BTCZ in total
+        assert_eq!(MAX_MONEY, 2_100_000_000_000_000_000);
+        assert_eq!(satoshis_to_btcz(MAX_MONEY), 21_000_000_000.0);
+    }
+
     #[test]
     fn test_conversion() {
         let satoshis = 100_000_000; // 1 BTCZ
@@ -1,15 +1,35 @@
 use num_bigint::BigUint;
-use num_traits::{One, Zero};
-use std::ops::{Add, Div, Mul, Sub, Shl, Shr};
+use num_traits::{One, ToPrimitive, Zero};
+use std::ops::{Add, Div, Mul, Not, Shl, Shr, Sub};
 
 /// Represents a 256-bit unsigned integer for arithmetic operations.
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Arithmetic wraps modulo 2^256, as with `arith_uint256` in Bitcoin Core.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ArithUint256(BigUint);
 
+/// Result of decoding a compact target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactTarget {
+    pub value: ArithUint256,
+    /// The sign bit was set on a non-zero mantissa.
+    pub negative: bool,
+    /// The value does not fit in 256 bits.
+    pub overflow: bool,
+}
+
+fn modulus() -> BigUint {
+    BigUint::one() << 256u32
+}
+
 impl ArithUint256 {
-    /// Creates a new `ArithUint256` from a `BigUint`.
+    /// Creates a new `ArithUint256` from a `BigUint`, truncated to 256 bits.
     pub fn new(value: BigUint) -> Self {
-        ArithUint256(value)
+        if value.bits() > 256 {
+            ArithUint256(value % modulus())
+        } else {
+            ArithUint256(value)
+        }
     }
 
     /// Creates an `ArithUint256` initialized to zero.
@@ -22,34 +42,121 @@ impl ArithUint256 {
         ArithUint256(BigUint::one())
     }
 
-    /// Converts the number to its compact representation.
-    pub fn to_compact(&self) -> u32 {
-        let mut size = (self.0.bits() + 7) / 8;
-        let mut compact: u32 = 0;
+    /// Creates an `ArithUint256` holding 2^256 - 1.
+    pub fn max_value() -> Self {
+        ArithUint256(modulus() - BigUint::one())
+    }
 
-        if size > 3 {
-            let bytes = self.0.to_bytes_be();
-            compact = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | (bytes[2] as u32);
-            compact |= size << 24;
-        } else {
-            compact = self.0.to_u32_digits().1[0];
-            compact |= size << 24;
+    /// Creates an `ArithUint256` from a 64-bit value.
+    pub fn from_u64(value: u64) -> Self {
+        ArithUint256(BigUint::from(value))
+    }
+
+    /// Parses a big-endian hex string, as hashes and targets are displayed.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.trim_start_matches("0x");
+        if hex.len() > 64 {
+            return None;
         }
+        BigUint::parse_bytes(hex.as_bytes(), 16).map(ArithUint256)
+    }
 
-        compact
+    /// Formats the number as 64 big-endian hex digits.
+    pub fn to_hex(&self) -> String {
+        format!("{:064x}", self.0)
+    }
+
+    /// Interprets a 32-byte hash, stored little-endian, as a number.
+    pub fn from_le_bytes(bytes: &[u8; 32]) -> Self {
+        ArithUint256(BigUint::from_bytes_le(bytes))
     }
 
-    /// Converts from a compact representation.
+    /// Converts the number to the 32-byte little-endian hash layout.
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        let le = self.0.to_bytes_le();
+        bytes[..le.len()].copy_from_slice(&le);
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Returns the position of the highest set bit plus one, or zero.
+    pub fn bits(&self) -> u32 {
+        self.0.bits() as u32
+    }
+
+    /// Returns the lowest 64 bits.
+    pub fn low_u64(&self) -> u64 {
+        (&self.0 & BigUint::from(u64::MAX)).to_u64().unwrap_or(0)
+    }
+
+    /// Decodes the compact representation used for nBits: an exponent byte
+    /// giving the length in bytes, a sign bit and a 23-bit mantissa.
+    pub fn set_compact(compact: u32) -> CompactTarget {
+        let size = compact >> 24;
+        let mut word = compact & 0x007fffff;
+        let value = if size <= 3 {
+            word >>= 8 * (3 - size);
+            ArithUint256::from_u64(word as u64)
+        } else {
+            ArithUint256::from_u64(word as u64) << (8 * (size - 3))
+        };
+
+        CompactTarget {
+            value,
+            negative: word != 0 && (compact & 0x00800000) != 0,
+            overflow: word != 0
+                && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)),
+        }
+    }
+
+    /// Decodes a compact target, ignoring the sign and overflow flags.
     pub fn from_compact(compact: u32) -> Self {
-        let size = (compact >> 24) & 0xff;
-        let mut value = compact & 0x007fffff;
+        Self::set_compact(compact).value
+    }
 
-        if size > 3 {
-            value <<= 8 * (size - 3);
+    /// Encodes the number in compact form, optionally with the sign bit.
+    pub fn get_compact(&self, negative: bool) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (self.clone() >> (8 * (size - 3))).low_u64() as u32
+        };
+
+        // The 0x00800000 bit denotes the sign. Thus, if it is already set,
+        // divide the mantissa by 256 and increase the exponent.
+        if compact & 0x00800000 != 0 {
+            compact >>= 8;
+            size += 1;
         }
+        compact |= size << 24;
+        if negative && (compact & 0x007fffff) != 0 {
+            compact |= 0x00800000;
+        }
+        compact
+    }
 
-        ArithUint256(BigUint::from(value))
+    /// Converts the number to its compact representation.
+    pub fn to_compact(&self) -> u32 {
+        self.get_compact(false)
+    }
+}
+
+/// Returns the expected number of hashes needed to meet the target encoded
+/// in `bits`, 2^256 / (target + 1); zero for an invalid target.
+pub fn get_block_proof(bits: u32) -> ArithUint256 {
+    let target = ArithUint256::set_compact(bits);
+    if target.negative || target.overflow || target.value.is_zero() {
+        return ArithUint256::zero();
     }
+
+    // 2^256 doesn't fit, but it equals (~target / (target + 1)) + 1
+    let target = target.value;
+    (!target.clone() / (target + ArithUint256::one())) + ArithUint256::one()
 }
 
 /// Implement arithmetic operators for `ArithUint256`.
@@ -57,7 +164,7 @@ impl Add for ArithUint256 {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        ArithUint256(self.0 + other.0)
+        ArithUint256::new(self.0 + other.0)
     }
 }
 
@@ -65,7 +172,8 @@ impl Sub for ArithUint256 {
     type Output = Self;
 
     fn sub(self, other: Self) -> Self {
-        ArithUint256(self.0 - other.0)
+        // Borrow from 2^256 so that underflow wraps around
+        ArithUint256::new(self.0 + modulus() - other.0)
     }
 }
 
@@ -73,7 +181,7 @@ impl Mul for ArithUint256 {
     type Output = Self;
 
     fn mul(self, other: Self) -> Self {
-        ArithUint256(self.0 * other.0)
+        ArithUint256::new(self.0 * other.0)
     }
 }
 
@@ -81,15 +189,24 @@ impl Div for ArithUint256 {
     type Output = Self;
 
     fn div(self, other: Self) -> Self {
+        assert!(!other.is_zero(), "Division by zero");
         ArithUint256(self.0 / other.0)
     }
 }
 
+impl Not for ArithUint256 {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        ArithUint256::max_value() - self
+    }
+}
+
 impl Shl<u32> for ArithUint256 {
     type Output = Self;
 
     fn shl(self, shift: u32) -> Self {
-        ArithUint256(self.0 << shift)
+        ArithUint256::new(self.0 << shift)
     }
 }
 
@@ -116,9 +233,120 @@ mod tests {
 
     #[test]
     fn test_arith_uint256_compact_conversion() {
-        let number = ArithUint256::new(BigUint::from(0x1d00ffffu32));
+        let number = ArithUint256::from_compact(0x1d00ffff);
         let compact = number.to_compact();
         let restored = ArithUint256::from_compact(compact);
         assert_eq!(number, restored);
+        assert_eq!(compact, 0x1d00ffff);
+    }
+
+    #[test]
+    fn test_wrapping() {
+        let max = ArithUint256::max_value();
+        assert_eq!(ArithUint256::zero() - ArithUint256::one(), max);
+        assert_eq!(max.clone() + ArithUint256::one(), ArithUint256::zero());
+        assert_eq!(!ArithUint256::zero(), max);
+        assert_eq!(ArithUint256::one() << 256, ArithUint256::zero());
+        assert_eq!((ArithUint256::one() << 255).bits(), 256);
+    }
+
+    #[test]
+    fn test_le_bytes() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0x01;
+        bytes[31] = 0x80;
+        let number = ArithUint256::from_le_bytes(&bytes);
+        assert_eq!(number.bits(), 256);
+        assert_eq!(number.to_le_bytes(), bytes);
+        assert_eq!(
+            number.to_hex(),
+            "8000000000000000000000000000000000000000000000000000000000000001"
+        );
+        assert_eq!(ArithUint256::from_hex(&number.to_hex()), Some(number));
+        assert_eq!(ArithUint256::zero().to_le_bytes(), [0u8; 32]);
+    }
+
+    /// (compact, value as hex, negative, overflow, re-encoded compact)
+    const SET_COMPACT_VECTORS: &[(u32, u64, bool, bool, u32)] = &[
+        (0x00000000, 0, false, false, 0),
+        (0x00123456, 0, false, false, 0),
+        (0x01003456, 0, false, false, 0),
+        (0x02000056, 0, false, false, 0),
+        (0x03000000, 0, false, false, 0),
+        (0x04000000, 0, false, false, 0),
+        (0x00923456, 0, false, false, 0),
+        (0x01803456, 0, false, false, 0),
+        (0x02800056, 0, false, false, 0),
+        (0x03800000, 0, false, false, 0),
+        (0x04800000, 0, false, false, 0),
+        (0x01123456, 0x12, false, false, 0x01120000),
+        (0x01fedcba, 0x7e, true, false, 0x01fe0000),
+        (0x02123456, 0x1234, false, false, 0x02123400),
+        (0x03123456, 0x123456, false, false, 0x03123456),
+        (0x04123456, 0x12345600, false, false, 0x04123456),
+        (0x04923456, 0x12345600, true, false, 0x04923456),
+        (0x05009234, 0x92340000, false, false, 0x05009234),
+    ];
+
+    #[test]
+    fn test_set_compact_upstream_vectors() {
+        for &(compact, value, negative, overflow, encoded) in SET_COMPACT_VECTORS {
+            let target = ArithUint256::set_compact(compact);
+            assert_eq!(target.value, ArithUint256::from_u64(value), "{:08x}", compact);
+            assert_eq!(target.negative, negative, "{:08x}", compact);
+            assert_eq!(target.overflow, overflow, "{:08x}", compact);
+            assert_eq!(target.value.get_compact(target.negative), encoded, "{:08x}", compact);
+        }
+
+        let target = ArithUint256::set_compact(0x20123456);
+        assert_eq!(
+            target.value.to_hex(),
+            "1234560000000000000000000000000000000000000000000000000000000000"
+        );
+        assert_eq!(target.value.to_compact(), 0x20123456);
+        assert!(!target.negative && !target.overflow);
+
+        let target = ArithUint256::set_compact(0xff123456);
+        assert!(!target.negative);
+        assert!(target.overflow);
+
+        // Compacts are never generated with the 0x00800000 bit set
+        assert_eq!(ArithUint256::from_u64(0x80).to_compact(), 0x02008000);
+    }
+
+    #[test]
+    fn test_overflow_boundaries() {
+        assert!(!ArithUint256::set_compact(0x22000001).overflow);
+        assert!(ArithUint256::set_compact(0x23000001).overflow);
+        assert!(!ArithUint256::set_compact(0x210000ff).overflow);
+        assert!(ArithUint256::set_compact(0x22000100).overflow);
+        assert!(!ArithUint256::set_compact(0x2000ffff).overflow);
+        assert!(ArithUint256::set_compact(0x21010000).overflow);
+    }
+
+    #[test]
+    fn test_compact_round_trip() {
+        let mantissas = [0x000001, 0x00007f, 0x000080, 0x00ffff, 0x010000, 0x123456, 0x7fffff];
+        for size in 0..=32u32 {
+            for &mantissa in &mantissas {
+                let target = ArithUint256::set_compact(size << 24 | mantissa);
+                assert!(!target.negative && !target.overflow);
+                let compact = target.value.to_compact();
+                assert_eq!(compact & 0x00800000, 0);
+                assert_eq!(ArithUint256::from_compact(compact), target.value);
+                // A canonical encoding survives the round trip unchanged
+                assert_eq!(ArithUint256::set_compact(compact).value.to_compact(), compact);
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_block_proof() {
+        assert_eq!(get_block_proof(0x1d00ffff), ArithUint256::from_u64(0x100010001));
+        assert_eq!(get_block_proof(0x207fffff), ArithUint256::from_u64(2));
+        assert_eq!(get_block_proof(0x03000001), ArithUint256::one() << 255);
+        assert_eq!(get_block_proof(0), ArithUint256::zero());
+        assert_eq!(get_block_proof(0x04923456), ArithUint256::zero());
+        assert_eq!(get_block_proof(0xff123456), ArithUint256::zero());
     }
 }
@@ -1,54 +1,171 @@
-use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
-use bitcoin::consensus::encode::{deserialize, serialize};
-use bitcoin::network::constants::Network;
-use bitcoin::util::address::Address;
-use bitcoin::util::psbt::PartiallySignedTransaction;
-use bitcoin::util::key::PrivateKey;
-use bitcoin::Script;
+use bitcoinz::base58::decode_base58_with_checksum;
+use bitcoinz::consensus::upgrades::{is_consensus_branch_id, NetworkUpgrade};
+use bitcoinz::hash::hash160;
+use bitcoinz::primitives::transaction::{
+    OutPoint, Transaction, TxInput, TxOutput, SAPLING_TX_VERSION, SAPLING_VERSION_GROUP_ID,
+};
+use bitcoinz::script::Script;
+use bitcoinz::serialize::{Deserializable, Serializable};
+use bitcoinz::sighash::{signature_hash, PrecomputedTransactionData, SIGHASH_ALL};
 use clap::{App, Arg};
-use std::str::FromStr;
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use serde::Deserialize;
 
-/// Creates a raw Bitcoin transaction.
-fn create_raw_transaction(inputs: Vec<(String, u32)>, outputs: Vec<(String, u64)>) -> String {
-    let txins: Vec<TxIn> = inputs
-        .into_iter()
-        .map(|(txid, vout)| TxIn {
-            previous_output: bitcoin::OutPoint::new(txid.parse().unwrap(), vout),
-            script_sig: Script::new(),
-            sequence: 0xFFFFFFFF,
-            witness: vec![],
-        })
-        .collect();
+/// Base58 prefix of transparent P2PKH addresses ("t1...")
+const PUBKEY_ADDRESS_PREFIX: [u8; 2] = [0x1C, 0xB8];
+/// Base58 prefix of transparent P2SH addresses ("t3...")
+const SCRIPT_ADDRESS_PREFIX: [u8; 2] = [0x1C, 0xBD];
+/// Base58 prefix of WIF-encoded private keys
+const SECRET_KEY_PREFIX: u8 = 0x80;
 
-    let txouts: Vec<TxOut> = outputs
-        .into_iter()
-        .map(|(address, amount)| TxOut {
-            value: amount,
-            script_pubkey: Address::from_str(&address).unwrap().script_pubkey(),
-        })
-        .collect();
+/// A previous output being spent, as passed to `signrawtransaction`
+#[derive(Debug, Deserialize)]
+struct PrevTx {
+    txid: String,
+    vout: u32,
+    #[serde(rename = "scriptPubKey")]
+    script_pub_key: String,
+    amount: i64,
+}
+
+/// Parses a display-order (byte-reversed) hex txid.
+fn parse_txid(txid: &str) -> [u8; 32] {
+    let mut bytes = hex::decode(txid).expect("Invalid txid");
+    assert_eq!(bytes.len(), 32, "Invalid txid length");
+    bytes.reverse();
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&bytes);
+    out
+}
+
+/// Builds the scriptPubKey paying to a transparent address.
+fn address_to_script(address: &str) -> Script {
+    let payload = decode_base58_with_checksum(address).expect("Invalid address");
+    assert_eq!(payload.len(), 22, "Invalid address length");
+    let hash = &payload[2..];
+    let mut script = Vec::with_capacity(25);
+    if payload[..2] == PUBKEY_ADDRESS_PREFIX {
+        // OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+        script.extend_from_slice(&[0x76, 0xa9, 0x14]);
+        script.extend_from_slice(hash);
+        script.extend_from_slice(&[0x88, 0xac]);
+    } else if payload[..2] == SCRIPT_ADDRESS_PREFIX {
+        // OP_HASH160 <hash> OP_EQUAL
+        script.extend_from_slice(&[0xa9, 0x14]);
+        script.extend_from_slice(hash);
+        script.push(0x87);
+    } else {
+        panic!("Unsupported address type");
+    }
+    Script::new(script)
+}
 
+/// Decodes a WIF private key, returning the key and whether its public key
+/// is used in compressed form.
+fn decode_wif(wif: &str) -> (SecretKey, bool) {
+    let payload = decode_base58_with_checksum(wif).expect("Invalid private key");
+    assert_eq!(payload[0], SECRET_KEY_PREFIX, "Invalid private key prefix");
+    let compressed = payload.len() == 34 && payload[33] == 0x01;
+    let key = SecretKey::from_slice(&payload[1..33]).expect("Invalid private key");
+    (key, compressed)
+}
+
+/// Creates a raw Sapling transaction.
+fn create_raw_transaction(inputs: Vec<(String, u32)>, outputs: Vec<(String, i64)>) -> String {
     let tx = Transaction {
-        version: 2,
-        lock_time: 0,
-        input: txins,
-        output: txouts,
+        overwintered: true,
+        version: SAPLING_TX_VERSION,
+        version_group_id: SAPLING_VERSION_GROUP_ID,
+        inputs: inputs
+            .into_iter()
+            .map(|(txid, vout)| TxInput {
+                prev_out: OutPoint {
+                    txid: parse_txid(&txid),
+                    index: vout,
+                },
+                script_sig: Script::default(),
+                sequence: 0xFFFFFFFF,
+            })
+            .collect(),
+        outputs: outputs
+            .into_iter()
+            .map(|(address, amount)| TxOutput {
+                value: amount,
+                script_pubkey: address_to_script(&address),
+            })
+            .collect(),
+        ..Default::default()
     };
 
-    hex::encode(serialize(&tx))
+    let mut bytes = Vec::new();
+    tx.serialize(&mut bytes).expect("Transaction serialization failed");
+    hex::encode(bytes)
 }
 
-/// Signs a raw Bitcoin transaction with provided private keys.
-fn sign_raw_transaction(raw_tx: &str, keys: Vec<&str>) -> String {
-    let tx: Transaction = deserialize(&hex::decode(raw_tx).unwrap()).unwrap();
-    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+/// Signs the P2PKH inputs of a raw transaction with the provided private keys.
+fn sign_raw_transaction(raw_tx: &str, prev_txs: Vec<PrevTx>, keys: Vec<&str>, branch_id: u32) -> String {
+    let bytes = hex::decode(raw_tx).expect("Invalid transaction hex");
+    let mut tx = Transaction::deserialize(&mut bytes.as_slice()).expect("Invalid transaction");
+
+    let secp = Secp256k1::new();
+    let keys: Vec<(SecretKey, Vec<u8>)> = keys
+        .into_iter()
+        .map(|wif| {
+            let (key, compressed) = decode_wif(wif);
+            let public_key = PublicKey::from_secret_key(&secp, &key);
+            let encoded = if compressed {
+                public_key.serialize().to_vec()
+            } else {
+                public_key.serialize_uncompressed().to_vec()
+            };
+            (key, encoded)
+        })
+        .collect();
+
+    let cache = PrecomputedTransactionData::new(&tx);
+    let mut script_sigs = Vec::with_capacity(tx.inputs.len());
+    for (n_in, input) in tx.inputs.iter().enumerate() {
+        let prev = prev_txs
+            .iter()
+            .find(|p| parse_txid(&p.txid) == input.prev_out.txid && p.vout == input.prev_out.index)
+            .expect("Missing previous output for input");
+        let script_code = Script::new(hex::decode(&prev.script_pub_key).expect("Invalid scriptPubKey"));
+        let code = script_code.as_bytes();
+        let is_p2pkh = code.len() == 25 && code[..3] == [0x76, 0xa9, 0x14] && code[23..] == [0x88, 0xac];
+        if !is_p2pkh {
+            script_sigs.push(input.script_sig.clone());
+            continue;
+        }
 
-    for key in keys {
-        let privkey = PrivateKey::from_str(key).unwrap();
-        psbt.sign(&privkey.key, Network::Bitcoin).unwrap();
+        let (key, public_key) = match keys.iter().find(|(_, pk)| hash160(pk) == code[3..23]) {
+            Some(found) => found,
+            None => {
+                script_sigs.push(input.script_sig.clone());
+                continue;
+            }
+        };
+
+        let hash = signature_hash(&script_code, &tx, n_in, SIGHASH_ALL, prev.amount, branch_id, Some(&cache))
+            .expect("Signature hash failed");
+        let message = Message::from_slice(&hash).expect("32-byte hash");
+        let mut signature = secp.sign_ecdsa(&message, key).serialize_der().to_vec();
+        signature.push(SIGHASH_ALL as u8);
+
+        let mut script_sig = Vec::with_capacity(2 + signature.len() + public_key.len());
+        script_sig.push(signature.len() as u8);
+        script_sig.extend_from_slice(&signature);
+        script_sig.push(public_key.len() as u8);
+        script_sig.extend_from_slice(public_key);
+        script_sigs.push(Script::new(script_sig));
+    }
+
+    for (input, script_sig) in tx.inputs.iter_mut().zip(script_sigs) {
+        input.script_sig = script_sig;
     }
 
-    hex::encode(serialize(&psbt.extract_tx()))
+    let mut bytes = Vec::new();
+    tx.serialize(&mut bytes).expect("Transaction serialization failed");
+    hex::encode(bytes)
 }
 
 fn main() {
@@ -58,18 +175,29 @@ fn main() {
         .about("Command Line Interface for BitcoinZ Transaction Management")
         .subcommand(
             App::new("createrawtransaction")
-                .about("Creates a raw Bitcoin transaction")
+                .about("Creates a raw BitcoinZ transaction")
                 .arg(Arg::new("inputs").about("Transaction inputs").required(true))
                 .arg(Arg::new("outputs").about("Transaction outputs").required(true)),
         )
         .subcommand(
             App::new("signrawtransaction")
-                .about("Signs a raw Bitcoin transaction")
+                .about("Signs a raw BitcoinZ transaction")
                 .arg(Arg::new("rawtx").about("Raw transaction in hex").required(true))
+                .arg(
+                    Arg::new("prevtxs")
+                        .about("Previous outputs: [{\"txid\",\"vout\",\"scriptPubKey\",\"amount\"}]")
+                        .required(true),
+                )
                 .arg(
                     Arg::new("keys")
                         .about("Private keys for signing")
                         .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("branchid")
+                        .long("branchid")
+                        .takes_value(true)
+                        .about("Consensus branch ID to sign for, in hex"),
                 ),
         )
         .get_matches();
@@ -77,17 +205,26 @@ fn main() {
     if let Some(matches) = matches.subcommand_matches("createrawtransaction") {
         let inputs: Vec<(String, u32)> = serde_json::from_str(matches.value_of("inputs").unwrap())
             .expect("Invalid input format");
-        let outputs: Vec<(String, u64)> = serde_json::from_str(matches.value_of("outputs").unwrap())
+        let outputs: Vec<(String, i64)> = serde_json::from_str(matches.value_of("outputs").unwrap())
             .expect("Invalid output format");
         let raw_tx = create_raw_transaction(inputs, outputs);
         println!("{}", raw_tx);
     } else if let Some(matches) = matches.subcommand_matches("signrawtransaction") {
         let raw_tx = matches.value_of("rawtx").unwrap();
+        let prev_txs: Vec<PrevTx> = serde_json::from_str(matches.value_of("prevtxs").unwrap())
+            .expect("Invalid prevtxs format");
         let keys: Vec<&str> = matches
             .values_of("keys")
             .unwrap_or_default()
             .collect();
-        let signed_tx = sign_raw_transaction(raw_tx, keys);
+        let branch_id = matches
+            .value_of("branchid")
+            .map(|id| u32::from_str_radix(id, 16).expect("Invalid branch ID"))
+            .unwrap_or_else(|| NetworkUpgrade::Sapling.branch_id());
+        if !is_consensus_branch_id(branch_id) {
+            panic!("Unknown consensus branch ID {:08x}", branch_id);
+        }
+        let signed_tx = sign_raw_transaction(raw_tx, prev_txs, keys, branch_id);
         println!("{}", signed_tx);
     } else {
         eprintln!("Error: Unknown command");
@@ -0,0 +1,709 @@
+use crate::chain::BlockIndex;
+use crate::consensus::consensus::MAX_BLOCK_SIZE;
+use crate::hash::hash256;
+use crate::primitives::block::{Block, HEADER_SIZE};
+use crate::serialize::{Deserializable, Serializable, SerializationError, VarInt};
+use crate::undo::BlockUndo;
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Largest size of a blk file
+pub const MAX_BLOCKFILE_SIZE: u32 = 0x8000000; // 128 MiB
+/// Blk files are grown in steps of this size
+pub const BLOCKFILE_CHUNK_SIZE: u32 = 0x1000000; // 16 MiB
+/// Rev files are grown in steps of this size
+pub const UNDOFILE_CHUNK_SIZE: u32 = 0x100000; // 1 MiB
+/// Size of the network magic and length that precede every record
+pub const STORAGE_HEADER_BYTES: u32 = 8;
+
+#[derive(Debug, Error)]
+pub enum BlockStoreError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("serialization error: {0}")]
+    Serialization(#[from] SerializationError),
+    #[error("record doesn't start with the network magic")]
+    BadMagic,
+    #[error("record size doesn't match its header")]
+    BadSize,
+    #[error("block hash doesn't match the block index")]
+    HashMismatch,
+    #[error("undo data checksum mismatch")]
+    ChecksumMismatch,
+    #[error("block data not available")]
+    NotAvailable,
+}
+
+/// Position of a record in a blk or rev file, just after its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct DiskBlockPos {
+    pub file: u32,
+    pub pos: u32,
+}
+
+/// Statistics of a blk file and its rev file
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BlockFileInfo {
+    /// Number of blocks stored in the file
+    pub blocks: u32,
+    /// Used bytes of the blk file
+    pub size: u32,
+    /// Used bytes of the rev file
+    pub undo_size: u32,
+    pub height_first: u32,
+    pub height_last: u32,
+    pub time_first: u64,
+    pub time_last: u64,
+}
+
+impl BlockFileInfo {
+    /// Widens the height and time ranges to cover a new block.
+    pub fn add_block(&mut self, height: u32, time: u64) {
+        if self.blocks == 0 || self.height_first > height {
+            self.height_first = height;
+        }
+        if self.blocks == 0 || self.time_first > time {
+            self.time_first = time;
+        }
+        self.blocks += 1;
+        self.height_last = self.height_last.max(height);
+        self.time_last = self.time_last.max(time);
+    }
+}
+
+impl Serializable for BlockFileInfo {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        VarInt(self.blocks as u64).serialize(writer)?;
+        VarInt(self.size as u64).serialize(writer)?;
+        VarInt(self.undo_size as u64).serialize(writer)?;
+        VarInt(self.height_first as u64).serialize(writer)?;
+        VarInt(self.height_last as u64).serialize(writer)?;
+        VarInt(self.time_first).serialize(writer)?;
+        VarInt(self.time_last).serialize(writer)
+    }
+}
+
+impl Deserializable for BlockFileInfo {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        fn read_u32<R: Read>(reader: &mut R) -> Result<u32, SerializationError> {
+            let value = VarInt::deserialize(reader)?.0;
+            u32::try_from(value).map_err(|_| SerializationError::InvalidData)
+        }
+        Ok(BlockFileInfo {
+            blocks: read_u32(reader)?,
+            size: read_u32(reader)?,
+            undo_size: read_u32(reader)?,
+            height_first: read_u32(reader)?,
+            height_last: read_u32(reader)?,
+            time_first: VarInt::deserialize(reader)?.0,
+            time_last: VarInt::deserialize(reader)?.0,
+        })
+    }
+}
+
+/// Path of blk or rev file `file` in the blocks directory.
+pub fn block_file_path(dir: &Path, prefix: &str, file: u32) -> PathBuf {
+    dir.join(format!("{}{:05}.dat", prefix, file))
+}
+
+/// Grows `file` in whole chunks so that it can hold `size` bytes, which
+/// keeps blocks mostly contiguous on disk.
+fn allocate(file: &File, old_size: u32, size: u32, chunk: u32) -> io::Result<()> {
+    let old_chunks = old_size.div_ceil(chunk);
+    let new_chunks = size.div_ceil(chunk);
+    if new_chunks > old_chunks {
+        let len = new_chunks as u64 * chunk as u64;
+        if file.metadata()?.len() < len {
+            file.set_len(len)?;
+        }
+    }
+    Ok(())
+}
+
+/// Blocks and undo data on disk, in the layout of bitcoinzd: blocks are
+/// appended to `blkNNNNN.dat` and their undo data to the matching
+/// `revNNNNN.dat`, each record framed by the network magic and its size.
+/// Undo records are followed by a checksum committing to the parent block.
+pub struct BlockStore {
+    dir: PathBuf,
+    magic: [u8; 4],
+    files: Vec<BlockFileInfo>,
+    last_file: u32,
+    /// Files whose statistics changed since `take_dirty_files`
+    dirty_files: BTreeSet<u32>,
+    max_file_size: u32,
+}
+
+impl BlockStore {
+    /// Opens an empty store in `dir`, creating the directory if needed.
+    pub fn new(dir: &Path, magic: [u8; 4]) -> io::Result<Self> {
+        Self::with_files(dir, magic, Vec::new(), 0)
+    }
+
+    /// Opens a store whose file statistics were saved with the block index.
+    pub fn with_files(
+        dir: &Path,
+        magic: [u8; 4],
+        mut files: Vec<BlockFileInfo>,
+        last_file: u32,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        if files.len() <= last_file as usize {
+            files.resize(last_file as usize + 1, BlockFileInfo::default());
+        }
+        Ok(BlockStore {
+            dir: dir.to_path_buf(),
+            magic,
+            files,
+            last_file,
+            dirty_files: BTreeSet::new(),
+            max_file_size: MAX_BLOCKFILE_SIZE,
+        })
+    }
+
+    /// Caps blk files at `size` bytes instead of `MAX_BLOCKFILE_SIZE`.
+    pub fn set_max_file_size(&mut self, size: u32) {
+        self.max_file_size = size;
+    }
+
+    /// The blocks directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Network magic framing the records.
+    pub fn magic(&self) -> [u8; 4] {
+        self.magic
+    }
+
+    /// Number of the blk file new blocks are appended to.
+    pub fn last_file(&self) -> u32 {
+        self.last_file
+    }
+
+    /// Statistics of blk file `file`.
+    pub fn file_info(&self, file: u32) -> Option<&BlockFileInfo> {
+        self.files.get(file as usize)
+    }
+
+    /// Returns the files whose statistics changed since the last call, so
+    /// that they can be saved.
+    pub fn take_dirty_files(&mut self) -> Vec<(u32, BlockFileInfo)> {
+        let dirty = std::mem::take(&mut self.dirty_files);
+        dirty.into_iter().map(|file| (file, self.files[file as usize].clone())).collect()
+    }
+
+    /// Bytes used by the blk and rev files.
+    pub fn current_usage(&self) -> u64 {
+        self.files.iter().map(|info| info.size as u64 + info.undo_size as u64).sum()
+    }
+
+    /// Resets the statistics of blk file `file`, whose blocks are being
+    /// pruned. The files are deleted by `unlink_pruned_files`.
+    pub fn prune_file(&mut self, file: u32) {
+        self.files[file as usize] = BlockFileInfo::default();
+        self.dirty_files.insert(file);
+    }
+
+    /// Deletes the blk and rev files of pruned `files`.
+    pub fn unlink_pruned_files(&self, files: &[u32]) -> io::Result<()> {
+        for &file in files {
+            for prefix in ["blk", "rev"] {
+                match fs::remove_file(block_file_path(&self.dir, prefix, file)) {
+                    Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&self, prefix: &str, file: u32) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(block_file_path(&self.dir, prefix, file))
+    }
+
+    /// Chooses where a block record of `add_size` bytes goes, moving on to a
+    /// new blk file when the current one is full. With `known`, the block
+    /// is already on disk there, as when reindexing.
+    fn find_block_pos(
+        &mut self,
+        add_size: u32,
+        height: u32,
+        time: u64,
+        known: Option<DiskBlockPos>,
+    ) -> Result<DiskBlockPos, BlockStoreError> {
+        let pos = match known {
+            Some(pos) => pos,
+            None => {
+                let mut file = self.last_file;
+                while self.files[file as usize].size != 0
+                    && self.files[file as usize].size + add_size >= self.max_file_size
+                {
+                    file += 1;
+                    if self.files.len() <= file as usize {
+                        self.files.push(BlockFileInfo::default());
+                    }
+                }
+                DiskBlockPos {
+                    file,
+                    pos: self.files[file as usize].size,
+                }
+            }
+        };
+        if self.files.len() <= pos.file as usize {
+            self.files.resize(pos.file as usize + 1, BlockFileInfo::default());
+        }
+
+        if pos.file != self.last_file {
+            // Only files being written to are pre-allocated and need trimming
+            self.flush(known.is_none())?;
+            self.last_file = pos.file;
+        }
+
+        let info = &mut self.files[pos.file as usize];
+        info.add_block(height, time);
+        if known.is_some() {
+            info.size = info.size.max(pos.pos + add_size);
+        } else {
+            info.size += add_size;
+            let size = info.size;
+            let file = self.open("blk", pos.file)?;
+            allocate(&file, pos.pos, size, BLOCKFILE_CHUNK_SIZE)?;
+        }
+        self.dirty_files.insert(pos.file);
+        Ok(pos)
+    }
+
+    /// Reserves `add_size` bytes at the end of rev file `file`.
+    fn find_undo_pos(&mut self, file: u32, add_size: u32) -> Result<DiskBlockPos, BlockStoreError> {
+        let info = &mut self.files[file as usize];
+        let pos = DiskBlockPos {
+            file,
+            pos: info.undo_size,
+        };
+        info.undo_size += add_size;
+        let undo_size = info.undo_size;
+        self.dirty_files.insert(file);
+
+        let rev = self.open("rev", file)?;
+        allocate(&rev, pos.pos, undo_size, UNDOFILE_CHUNK_SIZE)?;
+        Ok(pos)
+    }
+
+    /// Writes the magic and size header, then `data`, at `pos`.
+    fn write_record(&self, prefix: &str, pos: DiskBlockPos, data: &[u8]) -> io::Result<()> {
+        let mut file = self.open(prefix, pos.file)?;
+        file.seek(SeekFrom::Start(pos.pos as u64))?;
+        file.write_all(&self.magic)?;
+        file.write_all(&(data.len() as u32).to_le_bytes())?;
+        file.write_all(data)
+    }
+
+    /// Opens a record, checking its header, and returns the file positioned
+    /// at its data together with the data size.
+    fn open_record(&self, prefix: &str, pos: DiskBlockPos) -> Result<(File, u32), BlockStoreError> {
+        if pos.pos < STORAGE_HEADER_BYTES {
+            return Err(BlockStoreError::BadMagic);
+        }
+        let path = block_file_path(&self.dir, prefix, pos.file);
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start((pos.pos - STORAGE_HEADER_BYTES) as u64))?;
+        let mut header = [0u8; STORAGE_HEADER_BYTES as usize];
+        file.read_exact(&mut header)?;
+        if header[..4] != self.magic {
+            return Err(BlockStoreError::BadMagic);
+        }
+        let size = u32::from_le_bytes(header[4..].try_into().expect("four bytes"));
+        Ok((file, size))
+    }
+
+    /// Appends `block` at `height` to the current blk file, or only records
+    /// it if its data is already at `known`. Returns the position of its
+    /// data.
+    pub fn write_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+        known: Option<DiskBlockPos>,
+    ) -> Result<DiskBlockPos, BlockStoreError> {
+        let mut data = Vec::new();
+        block.serialize(&mut data)?;
+        let add_size = data.len() as u32 + STORAGE_HEADER_BYTES;
+        let time = block.header.timestamp as u64;
+        let record = self.find_block_pos(add_size, height, time, known)?;
+        if known.is_some() {
+            return Ok(record);
+        }
+        self.write_record("blk", record, &data)?;
+        Ok(DiskBlockPos {
+            file: record.file,
+            pos: record.pos + STORAGE_HEADER_BYTES,
+        })
+    }
+
+    /// Reads the block whose data starts at `pos`.
+    pub fn read_block(&self, pos: DiskBlockPos) -> Result<Block, BlockStoreError> {
+        let (file, size) = self.open_record("blk", pos)?;
+        let mut data = Vec::new();
+        file.take(size as u64).read_to_end(&mut data)?;
+        if data.len() != size as usize {
+            return Err(BlockStoreError::BadSize);
+        }
+        let mut reader = data.as_slice();
+        let block = Block::deserialize(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(BlockStoreError::BadSize);
+        }
+        Ok(block)
+    }
+
+    /// Reads the block of an index entry, checking that it is the block the
+    /// entry describes.
+    pub fn read_block_at(&self, index: &BlockIndex) -> Result<Block, BlockStoreError> {
+        if !index.have_data() {
+            return Err(BlockStoreError::NotAvailable);
+        }
+        let pos = DiskBlockPos {
+            file: index.file,
+            pos: index.data_pos,
+        };
+        let block = self.read_block(pos)?;
+        if block.hash() != index.hash {
+            return Err(BlockStoreError::HashMismatch);
+        }
+        Ok(block)
+    }
+
+    /// Appends the undo data of a block stored in blk file `file` to the
+    /// matching rev file, followed by a checksum over the parent block hash
+    /// and the data. Returns the position of the data.
+    pub fn write_undo(
+        &mut self,
+        undo: &BlockUndo,
+        file: u32,
+        prev_hash: &[u8; 32],
+    ) -> Result<DiskBlockPos, BlockStoreError> {
+        let mut data = Vec::new();
+        undo.serialize(&mut data)?;
+        let add_size = data.len() as u32 + STORAGE_HEADER_BYTES + 32;
+        let record = self.find_undo_pos(file, add_size)?;
+
+        let mut checked = prev_hash.to_vec();
+        checked.extend_from_slice(&data);
+        data.extend_from_slice(&hash256(&checked));
+        // The checksum isn't counted in the size of the record
+        let mut rev = self.open("rev", record.file)?;
+        rev.seek(SeekFrom::Start(record.pos as u64))?;
+        rev.write_all(&self.magic)?;
+        rev.write_all(&(data.len() as u32 - 32).to_le_bytes())?;
+        rev.write_all(&data)?;
+        Ok(DiskBlockPos {
+            file: record.file,
+            pos: record.pos + STORAGE_HEADER_BYTES,
+        })
+    }
+
+    /// Reads the undo data at `pos` of the block whose parent is
+    /// `prev_hash`, verifying its checksum.
+    pub fn read_undo(
+        &self,
+        pos: DiskBlockPos,
+        prev_hash: &[u8; 32],
+    ) -> Result<BlockUndo, BlockStoreError> {
+        let (file, size) = self.open_record("rev", pos)?;
+        let mut data = Vec::new();
+        file.take(size as u64 + 32).read_to_end(&mut data)?;
+        if data.len() != size as usize + 32 {
+            return Err(BlockStoreError::BadSize);
+        }
+        let checksum = data.split_off(size as usize);
+
+        let mut checked = prev_hash.to_vec();
+        checked.extend_from_slice(&data);
+        if hash256(&checked)[..] != checksum[..] {
+            return Err(BlockStoreError::ChecksumMismatch);
+        }
+        let mut reader = data.as_slice();
+        let undo = BlockUndo::deserialize(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(BlockStoreError::BadSize);
+        }
+        Ok(undo)
+    }
+
+    /// Reads the undo data of an index entry.
+    pub fn read_undo_at(&self, index: &BlockIndex) -> Result<BlockUndo, BlockStoreError> {
+        if !index.have_undo() {
+            return Err(BlockStoreError::NotAvailable);
+        }
+        let pos = DiskBlockPos {
+            file: index.file,
+            pos: index.undo_pos,
+        };
+        self.read_undo(pos, &index.header.prev_block_hash)
+    }
+
+    /// Syncs the current blk and rev files to disk. With `finalize`, the
+    /// unused pre-allocated space at their ends is also released.
+    pub fn flush(&self, finalize: bool) -> io::Result<()> {
+        let info = &self.files[self.last_file as usize];
+        let files = [("blk", info.size), ("rev", info.undo_size)];
+        for (prefix, size) in files {
+            let path = block_file_path(&self.dir, prefix, self.last_file);
+            if !path.exists() {
+                continue;
+            }
+            let file = self.open(prefix, self.last_file)?;
+            if finalize {
+                file.set_len(size as u64)?;
+            }
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the blocks of a file of magic-framed records, such as a blk file
+/// or the bootstrap file written by `contrib/linearize`. Bytes between
+/// records and records that don't hold a block are skipped.
+pub struct BlockFileReader<R> {
+    reader: R,
+    magic: [u8; 4],
+}
+
+impl<R: Read + Seek> BlockFileReader<R> {
+    pub fn new(reader: R, magic: [u8; 4]) -> Self {
+        BlockFileReader { reader, magic }
+    }
+
+    /// Offset of the reader in the file.
+    pub fn position(&mut self) -> io::Result<u64> {
+        self.reader.stream_position()
+    }
+
+    /// Reads the next block, returning it with the offset of its data, or
+    /// None at the end of the file.
+    pub fn next_block(&mut self) -> io::Result<Option<(u64, Block)>> {
+        loop {
+            if !self.find_magic()? {
+                return Ok(None);
+            }
+            // On failure, scanning resumes just after this magic
+            let resume = self.reader.stream_position()?;
+            let mut size = [0u8; 4];
+            if !read_or_eof(&mut self.reader, &mut size)? {
+                return Ok(None);
+            }
+            let size = u32::from_le_bytes(size) as usize;
+            if !(HEADER_SIZE..=MAX_BLOCK_SIZE).contains(&size) {
+                self.reader.seek(SeekFrom::Start(resume))?;
+                continue;
+            }
+
+            let mut data = vec![0u8; size];
+            if !read_or_eof(&mut self.reader, &mut data)? {
+                return Ok(None);
+            }
+            match Block::deserialize(&mut data.as_slice()) {
+                Ok(block) => return Ok(Some((resume + 4, block))),
+                Err(_) => {
+                    self.reader.seek(SeekFrom::Start(resume))?;
+                }
+            }
+        }
+    }
+
+    /// Advances past the next occurrence of the magic, returning false if
+    /// the file ends first
+    fn find_magic(&mut self) -> io::Result<bool> {
+        let mut window = [0u8; 4];
+        let mut seen = 0;
+        let mut byte = [0u8];
+        while seen < 4 || window != self.magic {
+            if !read_or_eof(&mut self.reader, &mut byte)? {
+                return Ok(false);
+            }
+            window.rotate_left(1);
+            window[3] = byte[0];
+            seen += 1;
+        }
+        Ok(true)
+    }
+}
+
+/// Fills `buf`, returning false if the reader ends first
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coins::Coin;
+    use crate::primitives::block::BlockHeader;
+    use crate::primitives::transaction::{Transaction, TxOutput};
+    use crate::script::Script;
+    use crate::undo::TxUndo;
+    use std::env;
+
+    const MAGIC: [u8; 4] = [0x24, 0xe9, 0x27, 0x64];
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn block(n: u32) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 4,
+                prev_block_hash: [n as u8; 32],
+                timestamp: 1_500_000_000 + n,
+                ..Default::default()
+            },
+            transactions: vec![Transaction {
+                outputs: vec![TxOutput {
+                    value: n as i64,
+                    script_pubkey: Script::new(vec![0x51; 100]),
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn test_blocks_round_trip() {
+        let dir = temp_dir("blockstore_blocks");
+        let mut store = BlockStore::new(&dir, MAGIC).unwrap();
+        let positions: Vec<_> =
+            (0..5).map(|n| store.write_block(&block(n), n, None).unwrap()).collect();
+        assert_eq!(positions[0], DiskBlockPos { file: 0, pos: 8 });
+        for (n, pos) in positions.iter().enumerate() {
+            assert_eq!(store.read_block(*pos).unwrap(), block(n as u32));
+        }
+
+        let info = store.file_info(0).unwrap();
+        assert_eq!(info.blocks, 5);
+        assert_eq!((info.height_first, info.height_last), (0, 4));
+        assert_eq!((info.time_first, info.time_last), (1_500_000_000, 1_500_000_004));
+        assert_eq!(info.size, positions[4].pos + block(4).get_size() as u32);
+        assert_eq!(store.take_dirty_files().len(), 1);
+        assert!(store.take_dirty_files().is_empty());
+
+        // Records are framed like bitcoinzd's, in a pre-allocated file
+        let raw = fs::read(block_file_path(&dir, "blk", 0)).unwrap();
+        assert_eq!(raw.len(), BLOCKFILE_CHUNK_SIZE as usize);
+        assert_eq!(raw[..4], MAGIC);
+        assert_eq!(raw[4..8], (block(0).get_size() as u32).to_le_bytes());
+
+        let other_network = BlockStore::new(&dir, [0; 4]).unwrap();
+        assert!(matches!(other_network.read_block(positions[0]), Err(BlockStoreError::BadMagic)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_roll_over() {
+        let dir = temp_dir("blockstore_rollover");
+        let mut store = BlockStore::new(&dir, MAGIC).unwrap();
+        let record = block(0).get_size() as u32 + STORAGE_HEADER_BYTES;
+        store.set_max_file_size(record * 2 + 1);
+
+        let positions: Vec<_> =
+            (0..5).map(|n| store.write_block(&block(n), n, None).unwrap()).collect();
+        let files: Vec<_> = positions.iter().map(|pos| pos.file).collect();
+        assert_eq!(files, [0, 0, 1, 1, 2]);
+        assert_eq!(store.last_file(), 2);
+        assert_eq!(store.read_block(positions[3]).unwrap(), block(3));
+        assert_eq!(store.file_info(1).unwrap().height_first, 2);
+
+        // Full files are trimmed to their contents
+        let len = fs::metadata(block_file_path(&dir, "blk", 0)).unwrap().len();
+        assert_eq!(len, record as u64 * 2);
+
+        store.take_dirty_files();
+        let usage = store.current_usage();
+        store.prune_file(0);
+        store.unlink_pruned_files(&[0]).unwrap();
+        assert_eq!(store.current_usage(), usage - record as u64 * 2);
+        assert_eq!(store.take_dirty_files(), vec![(0, BlockFileInfo::default())]);
+        assert!(!block_file_path(&dir, "blk", 0).exists());
+        assert!(store.read_block(positions[0]).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_undo_checksum() {
+        let dir = temp_dir("blockstore_undo");
+        let mut store = BlockStore::new(&dir, MAGIC).unwrap();
+        let pos = store.write_block(&block(1), 1, None).unwrap();
+
+        let mut undo = BlockUndo::new([3; 32]);
+        let mut tx_undo = TxUndo::new();
+        let output = TxOutput {
+            value: 5000,
+            script_pubkey: Script::new(vec![0x51]),
+        };
+        tx_undo.add_input(Coin::new(output.clone(), 0, true));
+        tx_undo.add_input(Coin::new(output, 7, false));
+        undo.add_tx_undo(tx_undo);
+
+        let prev_hash = [1; 32];
+        let undo_pos = store.write_undo(&undo, pos.file, &prev_hash).unwrap();
+        assert_eq!(store.read_undo(undo_pos, &prev_hash).unwrap(), undo);
+        assert!(matches!(
+            store.read_undo(undo_pos, &[2; 32]),
+            Err(BlockStoreError::ChecksumMismatch)
+        ));
+
+        let path = block_file_path(&dir, "rev", 0);
+        let mut raw = fs::read(&path).unwrap();
+        raw[undo_pos.pos as usize + 2] ^= 1;
+        fs::write(&path, raw).unwrap();
+        assert!(matches!(
+            store.read_undo(undo_pos, &prev_hash),
+            Err(BlockStoreError::ChecksumMismatch)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_block_file_reader() {
+        let frame = |block: &Block| {
+            let mut data = MAGIC.to_vec();
+            data.extend_from_slice(&(block.get_size() as u32).to_le_bytes());
+            block.serialize(&mut data).unwrap();
+            data
+        };
+        let mut file = vec![0u8; 3];
+        file.extend(frame(&block(1)));
+        // A record too small to hold a block, then one that doesn't parse
+        file.extend_from_slice(&MAGIC);
+        file.extend_from_slice(&[4, 0, 0, 0]);
+        let mut truncated = frame(&block(2));
+        truncated[4..8].copy_from_slice(&(block(2).get_size() as u32 - 2).to_le_bytes());
+        file.extend(truncated);
+        let second = file.len();
+        file.extend(frame(&block(3)));
+        file.extend_from_slice(&MAGIC[..2]);
+
+        let mut reader = BlockFileReader::new(io::Cursor::new(file), MAGIC);
+        let (pos, first) = reader.next_block().unwrap().unwrap();
+        assert_eq!((pos, first), (3 + STORAGE_HEADER_BYTES as u64, block(1)));
+        let (pos, third) = reader.next_block().unwrap().unwrap();
+        assert_eq!((pos, third), (second as u64 + STORAGE_HEADER_BYTES as u64, block(3)));
+        assert!(reader.next_block().unwrap().is_none());
+    }
+}
@@ -1,66 +1,900 @@
-use std::collections::HashMap;
+use crate::arith_uint256::{get_block_proof, ArithUint256};
+use crate::pow::BlockIndexView;
+use crate::primitives::block::BlockHeader;
+use crate::serialize::{Deserializable, Serializable, SerializationError, VarInt};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::{Read, Write};
+use thiserror::Error;
 
-/// Represents a block header in the blockchain.
+/// Double SHA-256 hash identifying a block
+pub type BlockHash = [u8; 32];
+
+/// Position of an entry in the block index
+pub type BlockId = usize;
+
+/// Unused
+pub const BLOCK_VALID_UNKNOWN: u32 = 0;
+/// Parsed, version ok, hash satisfies claimed PoW, timestamp not in future
+pub const BLOCK_VALID_HEADER: u32 = 1;
+/// All parent headers found, difficulty matches, timestamp >= median previous
+pub const BLOCK_VALID_TREE: u32 = 2;
+/// Only first tx is coinbase, transactions valid, no duplicate txids
+pub const BLOCK_VALID_TRANSACTIONS: u32 = 3;
+/// Outputs do not overspend inputs, no double spends, coinbase output ok
+pub const BLOCK_VALID_CHAIN: u32 = 4;
+/// Scripts and signatures ok
+pub const BLOCK_VALID_SCRIPTS: u32 = 5;
+/// All validity bits
+pub const BLOCK_VALID_MASK: u32 = 7;
+
+/// Full block available in a blk file
+pub const BLOCK_HAVE_DATA: u32 = 8;
+/// Undo data available in a rev file
+pub const BLOCK_HAVE_UNDO: u32 = 16;
+pub const BLOCK_HAVE_MASK: u32 = BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO;
+
+/// Stage after last reached validness failed
+pub const BLOCK_FAILED_VALID: u32 = 32;
+/// Descends from a failed block
+pub const BLOCK_FAILED_CHILD: u32 = 64;
+pub const BLOCK_FAILED_MASK: u32 = BLOCK_FAILED_VALID | BLOCK_FAILED_CHILD;
+
+/// Reasons a header can't be added to the block index
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum ChainError {
+    #[error("prev-blk-not-found")]
+    PrevBlockNotFound,
+    #[error("bad-prevblk")]
+    PrevBlockInvalid,
+    #[error("duplicate")]
+    Duplicate,
+}
+
+/// State of the branch ending at a chain tip, as reported by `getchaintips`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainTipStatus {
+    /// Tip of the active chain
+    Active,
+    /// The branch contains an invalid block
+    Invalid,
+    /// Some blocks of the branch haven't been received
+    HeadersOnly,
+    /// Fully validated, but not part of the active chain
+    ValidFork,
+    /// All blocks received, but the branch was never connected
+    ValidHeaders,
+    Unknown,
+}
+
+impl ChainTipStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChainTipStatus::Active => "active",
+            ChainTipStatus::Invalid => "invalid",
+            ChainTipStatus::HeadersOnly => "headers-only",
+            ChainTipStatus::ValidFork => "valid-fork",
+            ChainTipStatus::ValidHeaders => "valid-headers",
+            ChainTipStatus::Unknown => "unknown",
+        }
+    }
+}
+
+/// An entry in the block index: a header together with its position in the
+/// block tree and what is known about its validity and storage.
+#[derive(Debug, Clone)]
+pub struct BlockIndex {
+    pub hash: BlockHash,
+    pub header: BlockHeader,
+    /// Parent entry, None for genesis
+    pub prev: Option<BlockId>,
+    /// Some further ancestor, used to find ancestors in O(log n)
+    pub skip: Option<BlockId>,
+    pub height: u32,
+    /// Total work of the chain up to and including this block
+    pub chain_work: ArithUint256,
+    /// Combination of the `BLOCK_*` flags
+    pub status: u32,
+    /// blk/rev file number, valid with BLOCK_HAVE_DATA or BLOCK_HAVE_UNDO
+    pub file: u32,
+    /// Offset of the block in its blk file, valid with BLOCK_HAVE_DATA
+    pub data_pos: u32,
+    /// Offset of the undo data in its rev file, valid with BLOCK_HAVE_UNDO
+    pub undo_pos: u32,
+    /// Number of transactions in this block, zero until its data is received
+    pub tx_count: u32,
+    /// Number of transactions in the chain up to and including this block,
+    /// non-zero only if data is available for all of them
+    pub chain_tx: u64,
+    /// Order in which the block data was received, to break chainwork ties
+    pub sequence_id: u64,
+}
+
+impl BlockIndex {
+    pub fn time(&self) -> i64 {
+        self.header.timestamp as i64
+    }
+
+    pub fn have_data(&self) -> bool {
+        self.status & BLOCK_HAVE_DATA != 0
+    }
+
+    pub fn have_undo(&self) -> bool {
+        self.status & BLOCK_HAVE_UNDO != 0
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.status & BLOCK_FAILED_MASK != 0
+    }
+
+    /// Checks whether this block has been validated up to `up_to` and has
+    /// not failed since
+    pub fn is_valid(&self, up_to: u32) -> bool {
+        debug_assert!(up_to & !BLOCK_VALID_MASK == 0);
+        !self.is_failed() && self.status & BLOCK_VALID_MASK >= up_to
+    }
+
+    /// Raises the validity level, returning true if it changed
+    pub fn raise_validity(&mut self, up_to: u32) -> bool {
+        debug_assert!(up_to & !BLOCK_VALID_MASK == 0);
+        if self.is_failed() || self.status & BLOCK_VALID_MASK >= up_to {
+            return false;
+        }
+        self.status = (self.status & !BLOCK_VALID_MASK) | up_to;
+        true
+    }
+}
+
+/// What the block tree database keeps of a block index entry. The links and
+/// chain statistics are rebuilt when the index is loaded.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct BlockHeader {
-    pub hash: String,       // Block hash
-    pub prev_hash: String,  // Previous block hash
-    pub height: u32,        // Block height
+pub struct DiskBlockIndex {
+    pub header: BlockHeader,
+    pub height: u32,
+    pub status: u32,
+    pub file: u32,
+    pub data_pos: u32,
+    pub undo_pos: u32,
+    pub tx_count: u32,
 }
 
-/// Represents the active blockchain.
+impl From<&BlockIndex> for DiskBlockIndex {
+    fn from(index: &BlockIndex) -> Self {
+        DiskBlockIndex {
+            header: index.header.clone(),
+            height: index.height,
+            status: index.status,
+            file: index.file,
+            data_pos: index.data_pos,
+            undo_pos: index.undo_pos,
+            tx_count: index.tx_count,
+        }
+    }
+}
+
+impl Serializable for DiskBlockIndex {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        VarInt(self.height as u64).serialize(writer)?;
+        VarInt(self.status as u64).serialize(writer)?;
+        VarInt(self.tx_count as u64).serialize(writer)?;
+        if self.status & BLOCK_HAVE_MASK != 0 {
+            VarInt(self.file as u64).serialize(writer)?;
+        }
+        if self.status & BLOCK_HAVE_DATA != 0 {
+            VarInt(self.data_pos as u64).serialize(writer)?;
+        }
+        if self.status & BLOCK_HAVE_UNDO != 0 {
+            VarInt(self.undo_pos as u64).serialize(writer)?;
+        }
+        self.header.serialize(writer)
+    }
+}
+
+impl Deserializable for DiskBlockIndex {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        fn read_u32<R: Read>(reader: &mut R) -> Result<u32, SerializationError> {
+            let value = VarInt::deserialize(reader)?.0;
+            u32::try_from(value).map_err(|_| SerializationError::InvalidData)
+        }
+        let height = read_u32(reader)?;
+        let status = read_u32(reader)?;
+        let tx_count = read_u32(reader)?;
+        let file = if status & BLOCK_HAVE_MASK != 0 { read_u32(reader)? } else { 0 };
+        let data_pos = if status & BLOCK_HAVE_DATA != 0 { read_u32(reader)? } else { 0 };
+        let undo_pos = if status & BLOCK_HAVE_UNDO != 0 { read_u32(reader)? } else { 0 };
+        Ok(DiskBlockIndex {
+            header: BlockHeader::deserialize(reader)?,
+            height,
+            status,
+            file,
+            data_pos,
+            undo_pos,
+            tx_count,
+        })
+    }
+}
+
+/// Turns the lowest set bit of `n` off
+fn invert_lowest_one(n: u32) -> u32 {
+    n & n.wrapping_sub(1)
+}
+
+/// Height the skip pointer of a block at `height` points to. Any number
+/// strictly lower than `height` works, but this choice lets `get_ancestor`
+/// reach any height in O(log n) steps.
+fn get_skip_height(height: u32) -> u32 {
+    if height < 2 {
+        return 0;
+    }
+    // Jumps are taken to the height with the lowest set bits cleared, with
+    // odd heights jumping a bit less far so that walks stay short
+    if height & 1 == 1 {
+        invert_lowest_one(invert_lowest_one(height - 1)) + 1
+    } else {
+        invert_lowest_one(height)
+    }
+}
+
+/// Key ordering tip candidates by most work first, then earliest received
+type CandidateKey = (ArithUint256, Reverse<u64>, BlockId);
+
+/// The block tree, with the active chain following the valid tip with the
+/// most work.
+#[derive(Debug, Default)]
 pub struct Blockchain {
-    blocks: HashMap<String, BlockHeader>, // Map of block hash to BlockHeader
-    active_chain: Vec<BlockHeader>,       // Active chain of blocks
+    entries: Vec<BlockIndex>,
+    by_hash: HashMap<BlockHash, BlockId>,
+    /// Active chain, indexed by height
+    active: Vec<BlockId>,
+    /// Blocks with data for their whole chain, at least as good as the tip
+    candidates: BTreeSet<CandidateKey>,
+    /// Blocks with data whose ancestors don't all have data yet, by parent
+    unlinked: HashMap<BlockId, Vec<BlockId>>,
+    best_header: Option<BlockId>,
+    next_sequence_id: u64,
+    /// Entries changed since `take_dirty_entries`
+    dirty: BTreeSet<BlockId>,
 }
 
 impl Blockchain {
-    /// Creates a new blockchain.
+    /// Creates an empty blockchain.
     pub fn new() -> Self {
-        Blockchain {
-            blocks: HashMap::new(),
-            active_chain: Vec::new(),
+        Self::default()
+    }
+
+    /// Number of entries in the block index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Looks up a block by hash.
+    pub fn lookup(&self, hash: &BlockHash) -> Option<BlockId> {
+        self.by_hash.get(hash).copied()
+    }
+
+    /// Returns the index entry of `id`.
+    pub fn get(&self, id: BlockId) -> &BlockIndex {
+        &self.entries[id]
+    }
+
+    pub(crate) fn get_mut(&mut self, id: BlockId) -> &mut BlockIndex {
+        self.dirty.insert(id);
+        &mut self.entries[id]
+    }
+
+    /// Returns the entries changed since the last call, so that they can be
+    /// saved.
+    pub fn take_dirty_entries(&mut self) -> Vec<DiskBlockIndex> {
+        let dirty = std::mem::take(&mut self.dirty);
+        dirty.into_iter().map(|id| DiskBlockIndex::from(&self.entries[id])).collect()
+    }
+
+    /// Iterates over all entries in the block index.
+    pub fn iter(&self) -> impl Iterator<Item = (BlockId, &BlockIndex)> {
+        self.entries.iter().enumerate()
+    }
+
+    /// The header with the most work, whether or not its block is known.
+    pub fn best_header(&self) -> Option<BlockId> {
+        self.best_header
+    }
+
+    /// Adds a header whose parent is already known, or the genesis header to
+    /// an empty index. Returns the existing entry for a known header.
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<BlockId, ChainError> {
+        let hash = header.hash();
+        if let Some(id) = self.lookup(&hash) {
+            if self.entries[id].is_failed() {
+                return Err(ChainError::Duplicate);
+            }
+            return Ok(id);
+        }
+
+        let prev = if self.entries.is_empty() && header.prev_block_hash == [0; 32] {
+            None
+        } else {
+            let prev = self
+                .lookup(&header.prev_block_hash)
+                .ok_or(ChainError::PrevBlockNotFound)?;
+            if self.entries[prev].is_failed() {
+                return Err(ChainError::PrevBlockInvalid);
+            }
+            Some(prev)
+        };
+        Ok(self.insert_header(header, prev))
+    }
+
+    /// Adds `header` as a child of `prev` without checking either
+    fn insert_header(&mut self, header: BlockHeader, prev: Option<BlockId>) -> BlockId {
+        let hash = header.hash();
+        let proof = get_block_proof(header.bits);
+        let (height, chain_work, skip) = match prev {
+            Some(prev) => {
+                let height = self.entries[prev].height + 1;
+                let skip = self.get_ancestor(prev, get_skip_height(height));
+                (height, self.entries[prev].chain_work.clone() + proof, skip)
+            }
+            None => (0, proof, None),
+        };
+
+        let id = self.entries.len();
+        self.entries.push(BlockIndex {
+            hash,
+            header,
+            prev,
+            skip,
+            height,
+            chain_work,
+            status: BLOCK_VALID_TREE,
+            file: 0,
+            data_pos: 0,
+            undo_pos: 0,
+            tx_count: 0,
+            chain_tx: 0,
+            sequence_id: 0,
+        });
+        self.by_hash.insert(hash, id);
+        self.dirty.insert(id);
+
+        let better = match self.best_header {
+            Some(best) => self.entries[id].chain_work > self.entries[best].chain_work,
+            None => true,
+        };
+        if better {
+            self.best_header = Some(id);
+        }
+        id
+    }
+
+    /// Rebuilds an empty index from the entries saved in the block tree
+    /// database. Blocks with data for their whole chain become candidate
+    /// tips, as with `received_block`; the tip is left for the caller to set.
+    pub fn load(&mut self, mut stored: Vec<DiskBlockIndex>) -> Result<(), ChainError> {
+        debug_assert!(self.is_empty());
+        stored.sort_by_key(|entry| entry.height);
+        for entry in stored {
+            let prev = if entry.height == 0 {
+                None
+            } else {
+                let prev = self
+                    .lookup(&entry.header.prev_block_hash)
+                    .ok_or(ChainError::PrevBlockNotFound)?;
+                Some(prev)
+            };
+            let id = self.insert_header(entry.header, prev);
+            let index = &mut self.entries[id];
+            index.status = entry.status;
+            index.file = entry.file;
+            index.data_pos = entry.data_pos;
+            index.undo_pos = entry.undo_pos;
+            index.tx_count = entry.tx_count;
+
+            if entry.tx_count != 0 {
+                match prev {
+                    Some(prev) if self.entries[prev].chain_tx == 0 => {
+                        self.unlinked.entry(prev).or_default().push(id);
+                    }
+                    _ => self.link(id),
+                }
+            }
+        }
+        self.update_best_header();
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// Records that the full block of `id`, holding `tx_count` transactions,
+    /// was stored at `data_pos` in blk file `file`. Once data is available for
+    /// a block and all its ancestors it becomes a candidate tip.
+    pub fn received_block(&mut self, id: BlockId, tx_count: u32, file: u32, data_pos: u32) {
+        self.dirty.insert(id);
+        let entry = &mut self.entries[id];
+        entry.tx_count = tx_count;
+        entry.chain_tx = 0;
+        entry.file = file;
+        entry.data_pos = data_pos;
+        entry.undo_pos = 0;
+        entry.status |= BLOCK_HAVE_DATA;
+        entry.raise_validity(BLOCK_VALID_TRANSACTIONS);
+
+        let prev = entry.prev;
+        let linked = prev.is_none_or(|prev| self.entries[prev].chain_tx != 0);
+        if !linked {
+            self.unlinked.entry(prev.unwrap()).or_default().push(id);
+            return;
+        }
+
+        self.link(id);
+    }
+
+    /// Links `id`, whose parent is linked, and any descendants that were
+    /// waiting on it, making them candidate tips.
+    fn link(&mut self, id: BlockId) {
+        let mut queue = VecDeque::from(vec![id]);
+        while let Some(id) = queue.pop_front() {
+            let prev_chain_tx =
+                self.entries[id].prev.map_or(0, |prev| self.entries[prev].chain_tx);
+            let entry = &mut self.entries[id];
+            entry.chain_tx = prev_chain_tx + entry.tx_count as u64;
+            entry.sequence_id = self.next_sequence_id;
+            self.next_sequence_id += 1;
+
+            let good_enough = match self.tip() {
+                Some(tip) => self.candidate_key(id) >= self.candidate_key(tip),
+                None => true,
+            };
+            if good_enough && !self.entries[id].is_failed() {
+                self.candidates.insert(self.candidate_key(id));
+            }
+            if let Some(children) = self.unlinked.remove(&id) {
+                queue.extend(children);
+            }
+        }
+    }
+
+    /// Treats `id` and its ancestors as validated without their data and
+    /// makes `id` the tip, for the base block of a UTXO snapshot. Blocks
+    /// without data get a placeholder transaction count of one, as
+    /// upstream, and `id` gets `chain_tx` from the snapshot parameters.
+    pub fn assume_valid(&mut self, id: BlockId, chain_tx: u64) {
+        let mut path = Vec::new();
+        let mut walk = Some(id);
+        while let Some(block) = walk {
+            path.push(block);
+            walk = self.entries[block].prev;
+        }
+        let mut prev_chain_tx = 0;
+        for &block in path.iter().rev() {
+            self.dirty.insert(block);
+            let entry = &mut self.entries[block];
+            if entry.tx_count == 0 {
+                entry.tx_count = 1;
+            }
+            if entry.chain_tx == 0 {
+                entry.chain_tx = prev_chain_tx + entry.tx_count as u64;
+            }
+            entry.raise_validity(BLOCK_VALID_SCRIPTS);
+            prev_chain_tx = entry.chain_tx;
+        }
+
+        let entry = &mut self.entries[id];
+        entry.chain_tx = chain_tx;
+        entry.sequence_id = self.next_sequence_id;
+        self.next_sequence_id += 1;
+        self.set_tip(Some(id));
+        self.candidates.insert(self.candidate_key(id));
+        self.prune_candidates();
+        for child in self.unlinked.remove(&id).unwrap_or_default() {
+            self.link(child);
+        }
+    }
+
+    /// Records that the undo data of `id` was stored at `undo_pos` in the rev
+    /// file matching its blk file.
+    pub fn received_undo(&mut self, id: BlockId, undo_pos: u32) {
+        self.dirty.insert(id);
+        let entry = &mut self.entries[id];
+        entry.undo_pos = undo_pos;
+        entry.status |= BLOCK_HAVE_UNDO;
+    }
+
+    /// Forgets the data and undo data of the blocks stored in blk file
+    /// `file`, which is being pruned. Their headers stay in the index.
+    pub fn prune_file(&mut self, file: u32) {
+        for (id, entry) in self.entries.iter_mut().enumerate() {
+            if entry.status & BLOCK_HAVE_MASK != 0 && entry.file == file {
+                self.dirty.insert(id);
+                entry.status &= !BLOCK_HAVE_MASK;
+                entry.file = 0;
+                entry.data_pos = 0;
+                entry.undo_pos = 0;
+            }
+        }
+        // Pruned blocks can't be linked in once their parent arrives
+        let entries = &self.entries;
+        self.unlinked.retain(|_, children| {
+            children.retain(|&child| entries[child].have_data());
+            !children.is_empty()
+        });
+    }
+
+    fn candidate_key(&self, id: BlockId) -> CandidateKey {
+        let entry = &self.entries[id];
+        (entry.chain_work.clone(), Reverse(entry.sequence_id), id)
+    }
+
+    /// Marks `id` as failing validation so it and its descendants are no
+    /// longer considered as tips.
+    pub fn mark_block_failed(&mut self, id: BlockId) {
+        self.entries[id].status |= BLOCK_FAILED_VALID;
+        self.dirty.insert(id);
+        let key = self.candidate_key(id);
+        self.candidates.remove(&key);
+    }
+
+    /// Marks `id` as invalid and all its descendants as descending from an
+    /// invalid block, as `invalidateblock` does. Moving the active chain off
+    /// them is up to the caller.
+    pub fn invalidate_block(&mut self, id: BlockId) {
+        self.mark_block_failed(id);
+        let height = self.entries[id].height;
+        for other in 0..self.entries.len() {
+            if other != id && self.get_ancestor(other, height) == Some(id) {
+                self.entries[other].status |= BLOCK_FAILED_CHILD;
+                self.dirty.insert(other);
+                let key = self.candidate_key(other);
+                self.candidates.remove(&key);
+            }
+        }
+        self.update_best_header();
+    }
+
+    /// Clears the failure flags of `id`, its descendants and its ancestors,
+    /// as `reconsiderblock` does, so that they can become the tip again.
+    pub fn reconsider_block(&mut self, id: BlockId) {
+        let height = self.entries[id].height;
+        for other in 0..self.entries.len() {
+            if self.entries[other].is_failed() && self.get_ancestor(other, height) == Some(id) {
+                self.entries[other].status &= !BLOCK_FAILED_MASK;
+                self.dirty.insert(other);
+            }
+        }
+        let mut walk = self.entries[id].prev;
+        while let Some(id) = walk {
+            if self.entries[id].is_failed() {
+                self.entries[id].status &= !BLOCK_FAILED_MASK;
+                self.dirty.insert(id);
+            }
+            walk = self.entries[id].prev;
+        }
+        self.restore_candidates();
+        self.update_best_header();
+    }
+
+    /// Makes every linked, valid block at least as good as the tip a
+    /// candidate again, after the tip moved back or failures were cleared.
+    pub fn restore_candidates(&mut self) {
+        for id in 0..self.entries.len() {
+            let entry = &self.entries[id];
+            if !entry.is_valid(BLOCK_VALID_TRANSACTIONS) || entry.chain_tx == 0 {
+                continue;
+            }
+            let good_enough = match self.tip() {
+                Some(tip) => self.candidate_key(id) >= self.candidate_key(tip),
+                None => true,
+            };
+            if good_enough {
+                self.candidates.insert(self.candidate_key(id));
+            }
+        }
+    }
+
+    /// Points the best header at the valid header with the most work, the
+    /// first one added on ties
+    fn update_best_header(&mut self) {
+        let entries = &self.entries;
+        self.best_header = (0..entries.len())
+            .filter(|&id| !entries[id].is_failed())
+            .max_by(|&a, &b| entries[a].chain_work.cmp(&entries[b].chain_work).then(b.cmp(&a)));
+    }
+
+    /// Returns the candidate tip with the most work whose blocks back to the
+    /// active chain all have data and none has failed. Candidates found to be
+    /// unusable are dropped.
+    pub fn find_most_work_chain(&mut self) -> Option<BlockId> {
+        loop {
+            let candidate = self.candidates.iter().next_back()?.2;
+
+            let mut usable = true;
+            let mut walk = Some(candidate);
+            while let Some(id) = walk {
+                if self.contains(id) {
+                    break;
+                }
+                let entry = &self.entries[id];
+                if entry.is_failed() || !entry.have_data() {
+                    usable = false;
+                    break;
+                }
+                walk = entry.prev;
+            }
+            if usable {
+                return Some(candidate);
+            }
+
+            // Descendants of a failed block can never become the tip
+            let failed_ancestor = walk.is_some_and(|id| self.entries[id].is_failed());
+            if failed_ancestor && walk != Some(candidate) {
+                self.entries[candidate].status |= BLOCK_FAILED_CHILD;
+                self.dirty.insert(candidate);
+            }
+            let key = self.candidate_key(candidate);
+            self.candidates.remove(&key);
+        }
+    }
+
+    /// Moves the active chain to the candidate with the most work, if it has
+    /// more work than the current tip. Returns the resulting tip.
+    pub fn activate_best_chain(&mut self) -> Option<BlockId> {
+        if let Some(best) = self.find_most_work_chain() {
+            let better = match self.tip() {
+                Some(tip) => self.entries[best].chain_work > self.entries[tip].chain_work,
+                None => true,
+            };
+            if better {
+                self.set_tip(Some(best));
+            }
         }
+        self.prune_candidates();
+        self.tip()
     }
 
-    /// Adds a block header to the blockchain.
-    pub fn add_block(&mut self, header: BlockHeader) {
-        self.blocks.insert(header.hash.clone(), header.clone());
-        self.update_active_chain();
+    /// Drops candidates that are worse than the tip; they can't become
+    /// active unless the tip is invalidated.
+    pub(crate) fn prune_candidates(&mut self) {
+        if let Some(tip) = self.tip() {
+            let tip_key = self.candidate_key(tip);
+            self.candidates = self.candidates.split_off(&tip_key);
+        }
     }
 
-    /// Updates the active chain by recalculating the longest chain.
-    fn update_active_chain(&mut self) {
-        let mut chain = Vec::new();
-        if let Some(mut tip) = self.blocks.values().find(|b| self.is_tip(&b.hash)) {
-            while let Some(prev) = self.blocks.get(&tip.prev_hash) {
-                chain.push(tip.clone());
-                tip = prev;
+    /// Makes `tip` and its ancestors the active chain.
+    pub fn set_tip(&mut self, tip: Option<BlockId>) {
+        let tip = match tip {
+            Some(tip) => tip,
+            None => {
+                self.active.clear();
+                return;
+            }
+        };
+
+        let height = self.entries[tip].height as usize;
+        self.active.resize(height + 1, tip);
+        let mut walk = Some(tip);
+        while let Some(id) = walk {
+            let height = self.entries[id].height as usize;
+            if self.active[height] == id && id != tip {
+                break;
             }
-            chain.push(tip.clone());
+            self.active[height] = id;
+            walk = self.entries[id].prev;
         }
-        chain.reverse();
-        self.active_chain = chain;
     }
 
-    /// Checks if a block is a tip of the chain.
-    fn is_tip(&self, hash: &String) -> bool {
-        !self.blocks.values().any(|b| b.prev_hash == *hash)
+    /// Tip of the active chain.
+    pub fn tip(&self) -> Option<BlockId> {
+        self.active.last().copied()
+    }
+
+    /// Genesis block of the active chain.
+    pub fn genesis(&self) -> Option<BlockId> {
+        self.active.first().copied()
+    }
+
+    /// Height of the active chain tip.
+    pub fn height(&self) -> Option<u32> {
+        self.tip().map(|tip| self.entries[tip].height)
     }
 
-    /// Retrieves the block header at the given height in the active chain.
-    pub fn get_ancestor(&self, height: u32) -> Option<&BlockHeader> {
-        self.active_chain.get(height as usize)
+    /// Block at `height` in the active chain.
+    pub fn at_height(&self, height: u32) -> Option<BlockId> {
+        self.active.get(height as usize).copied()
     }
 
     /// Checks if a block is part of the active chain.
-    pub fn contains(&self, hash: &str) -> bool {
-        self.active_chain.iter().any(|b| b.hash == hash)
+    pub fn contains(&self, id: BlockId) -> bool {
+        self.at_height(self.entries[id].height) == Some(id)
+    }
+
+    /// Successor of `id` in the active chain.
+    pub fn next(&self, id: BlockId) -> Option<BlockId> {
+        if self.contains(id) {
+            self.at_height(self.entries[id].height + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Ancestor of `id` at `height`, found in O(log n) via skip pointers.
+    pub fn get_ancestor(&self, id: BlockId, height: u32) -> Option<BlockId> {
+        let mut walk = id;
+        let mut height_walk = self.entries[walk].height;
+        if height > height_walk {
+            return None;
+        }
+
+        while height_walk > height {
+            let height_skip = get_skip_height(height_walk);
+            let height_skip_prev = get_skip_height(height_walk - 1);
+            let entry = &self.entries[walk];
+            // Only follow the skip pointer if prev->skip isn't better
+            let take_skip = height_skip == height
+                || (height_skip > height
+                    && !(height_skip_prev + 2 < height_skip && height_skip_prev >= height));
+            match entry.skip {
+                Some(skip) if take_skip => {
+                    walk = skip;
+                    height_walk = height_skip;
+                }
+                _ => {
+                    walk = entry.prev?;
+                    height_walk -= 1;
+                }
+            }
+        }
+        Some(walk)
+    }
+
+    /// Last block that `a` and `b` have in common, if they share a genesis.
+    pub fn last_common_ancestor(&self, a: BlockId, b: BlockId) -> Option<BlockId> {
+        let height = self.entries[a].height.min(self.entries[b].height);
+        let mut a = self.get_ancestor(a, height);
+        let mut b = self.get_ancestor(b, height);
+        while let (Some(x), Some(y)) = (a, b) {
+            if x == y {
+                return Some(x);
+            }
+            a = self.entries[x].prev;
+            b = self.entries[y].prev;
+        }
+        None
+    }
+
+    /// Last block of the active chain that is an ancestor of `id`.
+    pub fn find_fork(&self, id: BlockId) -> Option<BlockId> {
+        let tip_height = self.height()?;
+        let mut walk = self.get_ancestor(id, tip_height.min(self.entries[id].height));
+        while let Some(id) = walk {
+            if self.contains(id) {
+                return Some(id);
+            }
+            walk = self.entries[id].prev;
+        }
+        None
+    }
+
+    /// Block locator for `id`, or for the tip if None: the ten most recent
+    /// hashes, then exponentially further back, ending with genesis.
+    pub fn get_locator(&self, id: Option<BlockId>) -> Vec<BlockHash> {
+        let mut locator = Vec::with_capacity(32);
+        let mut step = 1;
+        let mut walk = id.or_else(|| self.tip());
+        while let Some(id) = walk {
+            let entry = &self.entries[id];
+            locator.push(entry.hash);
+            if entry.height == 0 {
+                break;
+            }
+            // Exponentially larger steps back, plus the genesis block
+            let height = entry.height.saturating_sub(step);
+            walk = if self.contains(id) {
+                self.at_height(height)
+            } else {
+                self.get_ancestor(id, height)
+            };
+            if locator.len() > 10 {
+                step *= 2;
+            }
+        }
+        locator
+    }
+
+    /// Finds the latest block of `locator` in the active chain, falling back
+    /// to genesis.
+    pub fn find_fork_in_locator(&self, locator: &[BlockHash]) -> Option<BlockId> {
+        locator
+            .iter()
+            .filter_map(|hash| self.lookup(hash))
+            .find(|&id| self.contains(id))
+            .or_else(|| self.genesis())
+    }
+
+    /// Blocks without children, and the tip of the active chain, highest
+    /// first.
+    pub fn chain_tips(&self) -> Vec<BlockId> {
+        let mut is_tip = vec![true; self.entries.len()];
+        for entry in &self.entries {
+            if let Some(prev) = entry.prev {
+                is_tip[prev] = false;
+            }
+        }
+        if let Some(tip) = self.tip() {
+            is_tip[tip] = true;
+        }
+        let mut tips: Vec<_> = (0..self.entries.len()).filter(|&id| is_tip[id]).collect();
+        tips.sort_by_key(|&id| Reverse(self.entries[id].height));
+        tips
+    }
+
+    /// State of the branch ending at `id`.
+    pub fn tip_status(&self, id: BlockId) -> ChainTipStatus {
+        let entry = &self.entries[id];
+        if self.contains(id) {
+            ChainTipStatus::Active
+        } else if entry.is_failed() {
+            ChainTipStatus::Invalid
+        } else if entry.chain_tx == 0 {
+            ChainTipStatus::HeadersOnly
+        } else if entry.is_valid(BLOCK_VALID_SCRIPTS) {
+            ChainTipStatus::ValidFork
+        } else if entry.is_valid(BLOCK_VALID_TREE) {
+            ChainTipStatus::ValidHeaders
+        } else {
+            ChainTipStatus::Unknown
+        }
+    }
+
+    /// The chain ending at `tip`, which need not be the active chain.
+    pub fn view_at(&self, tip: BlockId) -> ChainView<'_> {
+        ChainView { chain: self, tip }
+    }
+}
+
+/// A chain of the block tree given by its tip, such as a header chain being
+/// validated before it becomes active
+pub struct ChainView<'a> {
+    chain: &'a Blockchain,
+    tip: BlockId,
+}
+
+impl ChainView<'_> {
+    fn at(&self, height: u32) -> &BlockIndex {
+        let id =
+            self.chain.get_ancestor(self.tip, height).expect("height is at most the tip height");
+        self.chain.get(id)
+    }
+}
+
+impl BlockIndexView for ChainView<'_> {
+    fn tip_height(&self) -> Option<u32> {
+        Some(self.chain.get(self.tip).height)
+    }
+
+    fn bits(&self, height: u32) -> u32 {
+        self.at(height).header.bits
+    }
+
+    fn time(&self, height: u32) -> i64 {
+        self.at(height).time()
+    }
+}
+
+/// The active chain
+impl BlockIndexView for Blockchain {
+    fn tip_height(&self) -> Option<u32> {
+        self.height()
+    }
+
+    fn bits(&self, height: u32) -> u32 {
+        self.entries[self.active[height as usize]].header.bits
     }
 
-    /// Retrieves the current chain tip.
-    pub fn chain_tip(&self) -> Option<&BlockHeader> {
-        self.active_chain.last()
+    fn time(&self, height: u32) -> i64 {
+        self.entries[self.active[height as usize]].time()
     }
 }
 
@@ -68,33 +902,255 @@ impl Blockchain {
 mod tests {
     use super::*;
 
+    /// Easy target worth 2 units of work
+    const EASY_BITS: u32 = 0x207fffff;
+    /// Harder target worth 8192 units of work
+    const HARD_BITS: u32 = 0x1f07ffff;
+
+    fn header(prev: BlockHash, tag: u32, bits: u32) -> BlockHeader {
+        BlockHeader {
+            version: 4,
+            prev_block_hash: prev,
+            timestamp: 1_500_000_000 + tag,
+            bits,
+            ..Default::default()
+        }
+    }
+
+    /// Extends `prev` (or starts a chain) with `count` headers, all received
+    fn extend(
+        chain: &mut Blockchain,
+        prev: Option<BlockId>,
+        count: u32,
+        tag: u32,
+        bits: u32,
+    ) -> Vec<BlockId> {
+        let mut prev_hash = prev.map_or([0; 32], |prev| chain.get(prev).hash);
+        (0..count)
+            .map(|i| {
+                let id = chain.add_header(header(prev_hash, tag + i, bits)).unwrap();
+                chain.received_block(id, 1, 0, 0);
+                prev_hash = chain.get(id).hash;
+                id
+            })
+            .collect()
+    }
+
     #[test]
     fn test_blockchain_operations() {
         let mut blockchain = Blockchain::new();
-
-        // Add blocks
-        blockchain.add_block(BlockHeader {
-            hash: "block1".to_string(),
-            prev_hash: "".to_string(),
-            height: 0,
-        });
-        blockchain.add_block(BlockHeader {
-            hash: "block2".to_string(),
-            prev_hash: "block1".to_string(),
-            height: 1,
-        });
-        blockchain.add_block(BlockHeader {
-            hash: "block3".to_string(),
-            prev_hash: "block2".to_string(),
-            height: 2,
-        });
+        let blocks = extend(&mut blockchain, None, 3, 0, EASY_BITS);
+        assert_eq!(blockchain.activate_best_chain(), Some(blocks[2]));
 
         // Test chain navigation
-        assert_eq!(blockchain.chain_tip().unwrap().hash, "block3");
-        assert!(blockchain.contains("block2"));
-        assert_eq!(
-            blockchain.get_ancestor(1).unwrap().hash,
-            "block2".to_string()
-        );
+        assert_eq!(blockchain.height(), Some(2));
+        assert!(blockchain.contains(blocks[1]));
+        assert_eq!(blockchain.at_height(1), Some(blocks[1]));
+        assert_eq!(blockchain.next(blocks[0]), Some(blocks[1]));
+        assert_eq!(blockchain.get(blocks[2]).chain_tx, 3);
+        assert_eq!(blockchain.get(blocks[2]).chain_work, ArithUint256::from_u64(6));
+        assert_eq!(blockchain.lookup(&blockchain.get(blocks[1]).hash), Some(blocks[1]));
+        assert_eq!(blockchain.tip_height(), Some(2));
+    }
+
+    #[test]
+    fn test_most_work_beats_longest() {
+        let mut chain = Blockchain::new();
+        let genesis = extend(&mut chain, None, 1, 0, EASY_BITS)[0];
+        let long = extend(&mut chain, Some(genesis), 5, 100, EASY_BITS);
+        assert_eq!(chain.activate_best_chain(), Some(long[4]));
+
+        let heavy = extend(&mut chain, Some(genesis), 1, 200, HARD_BITS);
+        assert_eq!(chain.activate_best_chain(), Some(heavy[0]));
+        assert_eq!(chain.height(), Some(1));
+        assert!(!chain.contains(long[0]));
+        assert_eq!(chain.find_fork(long[4]), Some(genesis));
+        assert_eq!(chain.last_common_ancestor(long[4], heavy[0]), Some(genesis));
+
+        // Side chains can still be walked by height
+        let view = chain.view_at(long[4]);
+        assert_eq!(view.tip_height(), Some(5));
+        assert_eq!(view.time(2), chain.get(long[1]).time());
+        assert_eq!(view.bits(0), EASY_BITS);
+    }
+
+    #[test]
+    fn test_first_received_wins_ties() {
+        let mut chain = Blockchain::new();
+        let genesis = extend(&mut chain, None, 1, 0, EASY_BITS)[0];
+        let first = extend(&mut chain, Some(genesis), 2, 100, EASY_BITS);
+        let second = extend(&mut chain, Some(genesis), 2, 200, EASY_BITS);
+        assert_eq!(chain.activate_best_chain(), Some(first[1]));
+
+        // Extending the other branch makes it the most-work chain
+        let longer = extend(&mut chain, Some(second[1]), 1, 300, EASY_BITS);
+        assert_eq!(chain.activate_best_chain(), Some(longer[0]));
+    }
+
+    #[test]
+    fn test_blocks_need_data() {
+        let mut chain = Blockchain::new();
+        let genesis = extend(&mut chain, None, 1, 0, EASY_BITS)[0];
+        let a = chain.add_header(header(chain.get(genesis).hash, 1, EASY_BITS)).unwrap();
+        let b = chain.add_header(header(chain.get(a).hash, 2, EASY_BITS)).unwrap();
+        assert_eq!(chain.best_header(), Some(b));
+        assert_eq!(chain.activate_best_chain(), Some(genesis));
+
+        // The child arrives first and waits for its parent
+        chain.received_block(b, 4, 0, 100);
+        assert_eq!(chain.get(b).chain_tx, 0);
+        assert_eq!(chain.activate_best_chain(), Some(genesis));
+
+        chain.received_block(a, 2, 0, 50);
+        assert_eq!(chain.get(b).chain_tx, 7);
+        assert!(chain.get(b).is_valid(BLOCK_VALID_TRANSACTIONS));
+        assert_eq!(chain.activate_best_chain(), Some(b));
+
+        // Pruning keeps the headers but not the data
+        chain.received_undo(a, 20);
+        chain.prune_file(0);
+        assert!(!chain.get(a).have_data() && !chain.get(a).have_undo());
+        assert_eq!(chain.get(a).data_pos, 0);
+        assert_eq!(chain.tip(), Some(b));
+        assert_eq!(chain.lookup(&chain.get(a).hash), Some(a));
+    }
+
+    #[test]
+    fn test_failed_blocks() {
+        let mut chain = Blockchain::new();
+        let genesis = extend(&mut chain, None, 1, 0, EASY_BITS)[0];
+        let good = extend(&mut chain, Some(genesis), 1, 100, EASY_BITS);
+        let bad = extend(&mut chain, Some(genesis), 2, 200, EASY_BITS);
+        chain.mark_block_failed(bad[0]);
+        assert_eq!(chain.activate_best_chain(), Some(good[0]));
+        assert!(chain.get(bad[1]).is_failed());
+        assert!(!chain.get_mut(bad[0]).raise_validity(BLOCK_VALID_SCRIPTS));
+
+        let child = header(chain.get(bad[0]).hash, 300, EASY_BITS);
+        assert_eq!(chain.add_header(child), Err(ChainError::PrevBlockInvalid));
+        let duplicate = chain.get(bad[0]).header.clone();
+        assert_eq!(chain.add_header(duplicate), Err(ChainError::Duplicate));
+        let orphan = header([9; 32], 400, EASY_BITS);
+        assert_eq!(chain.add_header(orphan), Err(ChainError::PrevBlockNotFound));
+    }
+
+    #[test]
+    fn test_invalidate_and_reconsider() {
+        let mut chain = Blockchain::new();
+        let genesis = extend(&mut chain, None, 1, 0, EASY_BITS)[0];
+        let main = extend(&mut chain, Some(genesis), 3, 100, EASY_BITS);
+        let fork = extend(&mut chain, Some(genesis), 2, 200, EASY_BITS);
+        let headers_only = chain.add_header(header(chain.get(main[2]).hash, 300, EASY_BITS));
+        let headers_only = headers_only.unwrap();
+        assert_eq!(chain.activate_best_chain(), Some(main[2]));
+        assert_eq!(chain.chain_tips(), [headers_only, main[2], fork[1]]);
+        assert_eq!(chain.tip_status(main[2]), ChainTipStatus::Active);
+        assert_eq!(chain.tip_status(headers_only), ChainTipStatus::HeadersOnly);
+        assert_eq!(chain.tip_status(fork[1]), ChainTipStatus::ValidHeaders);
+
+        chain.invalidate_block(main[1]);
+        assert!(chain.get(headers_only).is_failed());
+        assert_eq!(chain.best_header(), Some(fork[1]));
+        chain.set_tip(Some(main[0]));
+        chain.restore_candidates();
+        assert_eq!(chain.activate_best_chain(), Some(fork[1]));
+        assert_eq!(chain.tip_status(main[2]), ChainTipStatus::Invalid);
+
+        chain.reconsider_block(main[1]);
+        assert!(!chain.get(main[2]).is_failed());
+        assert_eq!(chain.best_header(), Some(headers_only));
+        assert_eq!(chain.activate_best_chain(), Some(main[2]));
+    }
+
+    #[test]
+    fn test_get_ancestor() {
+        let mut chain = Blockchain::new();
+        let blocks = extend(&mut chain, None, 1000, 0, EASY_BITS);
+        let fork = extend(&mut chain, Some(blocks[500]), 300, 5000, EASY_BITS);
+        chain.activate_best_chain();
+
+        for &tip in &[blocks[999], fork[299]] {
+            let mut walk = Some(tip);
+            while let Some(id) = walk {
+                let height = chain.get(id).height;
+                assert_eq!(chain.get_ancestor(tip, height), Some(id));
+                if let Some(skip) = chain.get(id).skip {
+                    assert_eq!(chain.get(skip).height, get_skip_height(height));
+                }
+                walk = chain.get(id).prev;
+            }
+        }
+        assert_eq!(chain.get_ancestor(blocks[10], 11), None);
+        assert_eq!(chain.find_fork(fork[299]), Some(blocks[500]));
+        assert_eq!(chain.last_common_ancestor(fork[10], blocks[600]), Some(blocks[500]));
+    }
+
+    #[test]
+    fn test_locator() {
+        let mut chain = Blockchain::new();
+        let blocks = extend(&mut chain, None, 100, 0, EASY_BITS);
+        let fork = extend(&mut chain, Some(blocks[49]), 20, 1000, EASY_BITS);
+        chain.activate_best_chain();
+
+        for &tip in &[blocks[99], fork[19]] {
+            let locator = chain.get_locator(Some(tip));
+            let heights: Vec<u32> = locator
+                .iter()
+                .map(|hash| chain.get(chain.lookup(hash).unwrap()).height)
+                .collect();
+            let top = chain.get(tip).height;
+            assert_eq!(&heights[..11], &(top - 10..=top).rev().collect::<Vec<_>>()[..]);
+            assert_eq!(heights.last(), Some(&0));
+            assert!(heights.windows(2).all(|pair| pair[0] > pair[1]));
+            assert!(locator.len() < 20);
+            for hash in &locator {
+                let id = chain.lookup(hash).unwrap();
+                assert_eq!(chain.get_ancestor(tip, chain.get(id).height), Some(id));
+            }
+        }
+
+        let locator = chain.get_locator(Some(fork[19]));
+        // The locator holds heights 69 to 59, 58, 56, 52, 44, 28 and 0, and
+        // the fork branches off after height 49
+        assert_eq!(chain.find_fork_in_locator(&locator), Some(blocks[44]));
+        assert_eq!(chain.find_fork_in_locator(&[[7; 32]]), Some(blocks[0]));
+    }
+
+    #[test]
+    fn test_load_saved_index() {
+        let mut chain = Blockchain::new();
+        let genesis = extend(&mut chain, None, 1, 0, EASY_BITS)[0];
+        let main = extend(&mut chain, Some(genesis), 3, 100, EASY_BITS);
+        let side = extend(&mut chain, Some(genesis), 1, 200, EASY_BITS);
+        let header_only =
+            chain.add_header(header(chain.get(main[2]).hash, 300, EASY_BITS)).unwrap();
+        chain.mark_block_failed(side[0]);
+        assert_eq!(chain.activate_best_chain(), Some(main[2]));
+
+        let saved: Vec<DiskBlockIndex> = chain
+            .take_dirty_entries()
+            .into_iter()
+            .rev()
+            .map(|entry| {
+                let mut bytes = Vec::new();
+                entry.serialize(&mut bytes).unwrap();
+                DiskBlockIndex::deserialize(&mut &bytes[..]).unwrap()
+            })
+            .collect();
+        assert_eq!(saved.len(), 6);
+        assert!(chain.take_dirty_entries().is_empty());
+
+        let mut loaded = Blockchain::new();
+        loaded.load(saved).unwrap();
+        assert!(loaded.take_dirty_entries().is_empty());
+        let hash = |chain: &Blockchain, id| chain.get(id).hash;
+        let tip = loaded.activate_best_chain().unwrap();
+        assert_eq!(hash(&loaded, tip), hash(&chain, main[2]));
+        assert_eq!(loaded.get(tip).chain_tx, 4);
+        let failed = loaded.lookup(&hash(&chain, side[0])).unwrap();
+        assert!(loaded.get(failed).is_failed());
+        let pending = loaded.lookup(&hash(&chain, header_only)).unwrap();
+        assert_eq!(loaded.get(pending).chain_tx, 0);
+        assert_eq!(loaded.best_header(), Some(pending));
     }
 }
@@ -1,3 +1,4 @@
+use crate::consensus::params::ConsensusParams;
 use std::net::SocketAddr;
 
 /// Represents network parameters for BitcoinZ.
@@ -9,6 +10,25 @@ pub struct ChainParams {
     pub address_prefix: u8,
     pub genesis_block: Block,
     pub seed_nodes: Vec<SocketAddr>,
+    /// Automatic pruning doesn't start before the chain reaches this height
+    pub prune_after_height: u32,
+    /// UTXO snapshots trusted by `loadtxoutset`
+    pub assume_utxo: Vec<AssumeUtxo>,
+    /// Consensus rules, including the network upgrade schedule
+    pub consensus: ConsensusParams,
+}
+
+/// A UTXO set snapshot that may be loaded instead of validating the chain up
+/// to its base block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssumeUtxo {
+    pub height: u32,
+    pub block_hash: [u8; 32],
+    /// `hash_serialized` of the UTXO set after the block, as reported by
+    /// `gettxoutsetinfo`
+    pub hash_serialized: [u8; 32],
+    /// Number of transactions up to and including the block
+    pub chain_tx: u64,
 }
 
 /// Represents a block in the blockchain.
@@ -40,6 +60,13 @@ pub fn mainnet_params() -> ChainParams {
             "127.0.0.1:8233".parse().unwrap(),
             "192.168.1.1:8233".parse().unwrap(),
         ],
+        prune_after_height: 100_000,
+        // Empty until snapshots are published: an entry needs the base block
+        // hash and the `hash_serialized` and `chain_tx` reported for it by a
+        // fully validated node. Until then `loadtxoutset` refuses every
+        // snapshot, as doc/assumeutxo.md explains.
+        assume_utxo: Vec::new(),
+        consensus: ConsensusParams::main(),
     }
 }
 
@@ -58,6 +85,10 @@ pub fn testnet_params() -> ChainParams {
             414098458,
         ),
         seed_nodes: vec!["127.0.0.1:18233".parse().unwrap()],
+        prune_after_height: 1_000,
+        // Empty for the same reason as on mainnet
+        assume_utxo: Vec::new(),
+        consensus: ConsensusParams::testnet(),
     }
 }
 
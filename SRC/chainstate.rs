@@ -0,0 +1,1409 @@
+use crate::blockstore::{
+    block_file_path, BlockFileReader, BlockStore, BlockStoreError, DiskBlockPos,
+    BLOCKFILE_CHUNK_SIZE, UNDOFILE_CHUNK_SIZE,
+};
+use crate::chain::{BlockHash, BlockId, Blockchain, ChainError, BLOCK_VALID_SCRIPTS};
+use crate::chainparams::AssumeUtxo;
+use crate::coins::{CoinsStats, CoinsView, CoinsViewCache, CoinsViewError};
+use crate::consensus::params::ConsensusParams;
+use crate::consensus::validation::{
+    check_block, check_block_header, check_header_against_chain, connect_block,
+    contextual_check_block, contextual_check_block_header, disconnect_block,
+    BlockValidationError, DisconnectResult,
+};
+use crate::primitives::block::{Block, BlockHeader};
+use crate::serialize::Deserializable;
+use crate::timedata::TimeData;
+use crate::txdb::{BlockTreeDb, CoinsViewDb};
+use crate::ui_interface::UiInterface;
+use crate::utxo_snapshot::{
+    display_hash, read_snapshot, write_snapshot, SnapshotError, SnapshotMetadata,
+};
+use log::warn;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use thiserror::Error;
+
+/// The node leaves initial block download once its tip is at most this
+/// old, in seconds
+pub const DEFAULT_MAX_TIP_AGE: i64 = 24 * 60 * 60;
+/// Blocks at the tip whose data is never pruned, so that reorganizations
+/// can still disconnect them
+pub const MIN_BLOCKS_TO_KEEP: u32 = 288;
+/// Smallest automatic prune target, in bytes: room for the blocks kept at
+/// the tip, their undo data and some orphans
+pub const MIN_DISK_SPACE_FOR_BLOCK_FILES: u64 = 550 * 1024 * 1024;
+/// Blocks at the tip checked at startup (`-checkblocks`), 0 for all
+pub const DEFAULT_CHECKBLOCKS: u32 = 288;
+/// How thoroughly blocks are checked at startup (`-checklevel`), 0 to 4
+pub const DEFAULT_CHECKLEVEL: u32 = 3;
+
+/// Whether blk and rev files of old blocks are deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneMode {
+    Disabled,
+    /// Files are only pruned by `pruneblockchain`
+    Manual,
+    /// The oldest files are pruned once all files use more than this many
+    /// bytes
+    Automatic(u64),
+}
+
+#[derive(Debug, Error)]
+pub enum ChainstateError {
+    #[error("{0}")]
+    Chain(#[from] ChainError),
+    #[error("{0}")]
+    Invalid(#[from] BlockValidationError),
+    #[error("block storage error: {0}")]
+    Store(#[from] BlockStoreError),
+    #[error("{0}")]
+    Coins(#[from] CoinsViewError),
+    #[error("failed to disconnect block at height {0}")]
+    DisconnectFailed(u32),
+    #[error("the genesis block can't be invalidated")]
+    InvalidateGenesis,
+    #[error("{0}")]
+    Snapshot(#[from] SnapshotError),
+    #[error("verification failed at height {height}: {reason}")]
+    VerifyFailed { height: u32, reason: String },
+    #[error("the coins are at block {0}, which isn't in the block index")]
+    UnknownBestBlock(String),
+    #[error("inconclusive-not-best-prevblk")]
+    NotBestPrevBlock,
+}
+
+impl From<std::io::Error> for ChainstateError {
+    fn from(error: std::io::Error) -> Self {
+        ChainstateError::Store(error.into())
+    }
+}
+
+impl ChainstateError {
+    /// Misbehaviour score assigned to the peer that relayed the header or
+    /// block
+    pub fn dos_score(&self) -> u32 {
+        match self {
+            ChainstateError::Chain(ChainError::PrevBlockInvalid)
+            | ChainstateError::Chain(ChainError::Duplicate) => 100,
+            ChainstateError::Invalid(error) => error.dos_score(),
+            _ => 0,
+        }
+    }
+
+    /// True if the error is about the header or block itself rather than
+    /// a failure of the node's storage
+    pub fn is_invalid(&self) -> bool {
+        matches!(self, ChainstateError::Chain(_) | ChainstateError::Invalid(_))
+    }
+}
+
+/// The block index, the blocks on disk and the UTXO set of the active
+/// chain, kept consistent with each other: the coins always match the tip.
+pub struct Chainstate {
+    chain: Blockchain,
+    blocks: BlockStore,
+    /// Where the block index and the file statistics are saved
+    block_tree: BlockTreeDb,
+    coins: CoinsViewCache<'static>,
+    /// The database under `coins`, for walking the whole UTXO set
+    db: CoinsViewDb,
+    params: ConsensusParams,
+    /// Bytes the coins cache may use before it is flushed
+    cache_budget: usize,
+    /// Latched to false once the tip is recent and has enough work
+    initial_download: Cell<bool>,
+    /// Imported blocks waiting for their parent, by parent hash, with their
+    /// position if they are already in a blk file
+    unknown_parent: HashMap<BlockHash, Vec<(Block, Option<DiskBlockPos>)>>,
+    prune_mode: PruneMode,
+    /// Automatic pruning waits until the chain is past this height
+    prune_after_height: u32,
+    /// Set once any block file has been pruned
+    have_pruned: bool,
+    /// Set when blocks were written since automatic pruning last ran
+    check_for_pruning: bool,
+    /// UTXO snapshots that may be loaded, from the chain parameters
+    assume_utxo: Vec<AssumeUtxo>,
+    /// A loaded snapshot whose base header isn't in the index yet. No block
+    /// is connected until it is.
+    snapshot_base: Option<AssumeUtxo>,
+}
+
+impl Chainstate {
+    /// Creates a chainstate over the blocks in `blocks`, the block index in
+    /// `block_tree` and the UTXO set in `coins`, with a coins cache of
+    /// `cache_budget` bytes. The index starts out empty until
+    /// `load_block_index` is called.
+    pub fn new(
+        blocks: BlockStore,
+        block_tree: BlockTreeDb,
+        coins: CoinsViewDb,
+        params: ConsensusParams,
+        cache_budget: usize,
+    ) -> Self {
+        Chainstate {
+            chain: Blockchain::new(),
+            blocks,
+            block_tree,
+            coins: CoinsViewCache::new(coins.clone()),
+            db: coins,
+            params,
+            cache_budget,
+            initial_download: Cell::new(true),
+            unknown_parent: HashMap::new(),
+            prune_mode: PruneMode::Disabled,
+            prune_after_height: 0,
+            have_pruned: false,
+            check_for_pruning: false,
+            assume_utxo: Vec::new(),
+            snapshot_base: None,
+        }
+    }
+
+    /// Enables pruning of the block files. Automatic pruning doesn't start
+    /// before the chain is past `prune_after_height`.
+    pub fn set_prune_mode(&mut self, mode: PruneMode, prune_after_height: u32) {
+        self.prune_mode = mode;
+        self.prune_after_height = prune_after_height;
+    }
+
+    /// Sets the UTXO snapshots `load_snapshot` accepts.
+    pub fn set_assume_utxo(&mut self, assume_utxo: Vec<AssumeUtxo>) {
+        self.assume_utxo = assume_utxo;
+    }
+
+    pub fn chain(&self) -> &Blockchain {
+        &self.chain
+    }
+
+    pub fn blocks(&self) -> &BlockStore {
+        &self.blocks
+    }
+
+    pub fn coins(&self) -> &CoinsViewCache<'static> {
+        &self.coins
+    }
+
+    pub fn params(&self) -> &ConsensusParams {
+        &self.params
+    }
+
+    pub fn prune_mode(&self) -> PruneMode {
+        self.prune_mode
+    }
+
+    pub fn prune_after_height(&self) -> u32 {
+        self.prune_after_height
+    }
+
+    /// True if some blocks in the index no longer have their data on disk
+    pub fn have_pruned(&self) -> bool {
+        self.have_pruned
+    }
+
+    /// Loads the block index saved by `flush` and makes the block the coins
+    /// are at the tip. If the coins database is empty, as with
+    /// `-reindex-chainstate`, genesis becomes the tip and
+    /// `activate_best_chain` connects the stored blocks again.
+    pub fn load_block_index(&mut self) -> Result<(), ChainstateError> {
+        let entries = self.block_tree.read_block_index()?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.chain.load(entries)?;
+        let best_block = self.coins.best_block();
+        let tip = if best_block == [0; 32] {
+            let genesis = self.chain.iter().find(|(_, index)| index.height == 0);
+            let (id, index) = genesis.ok_or(ChainError::PrevBlockNotFound)?;
+            self.coins.set_best_block(index.hash);
+            id
+        } else {
+            self.chain
+                .lookup(&best_block)
+                .ok_or_else(|| ChainstateError::UnknownBestBlock(display_hash(&best_block)))?
+        };
+        self.chain.set_tip(Some(tip));
+        self.chain.prune_candidates();
+        self.have_pruned =
+            self.chain.iter().any(|(_, index)| index.tx_count != 0 && !index.have_data());
+        Ok(())
+    }
+
+    /// Stores the genesis block and makes it the tip of an empty index. Its
+    /// coinbase is not added to the UTXO set, as upstream.
+    pub fn load_genesis(&mut self, genesis: &Block) -> Result<(), ChainstateError> {
+        self.store_genesis(genesis, None)
+    }
+
+    fn store_genesis(
+        &mut self,
+        genesis: &Block,
+        known: Option<DiskBlockPos>,
+    ) -> Result<(), ChainstateError> {
+        if !self.chain.is_empty() {
+            return Ok(());
+        }
+        let id = self.chain.add_header(genesis.header.clone())?;
+        let pos = self.blocks.write_block(genesis, 0, known)?;
+        self.chain.received_block(id, genesis.transactions.len() as u32, pos.file, pos.pos);
+        self.chain.get_mut(id).raise_validity(BLOCK_VALID_SCRIPTS);
+        self.chain.activate_best_chain();
+        if self.coins.best_block() == [0; 32] {
+            self.coins.set_best_block(genesis.hash());
+        }
+        self.flush()
+    }
+
+    /// Validates `header` and adds it to the block index. The parent must
+    /// already be in the index.
+    pub fn accept_block_header(
+        &mut self,
+        header: &BlockHeader,
+        time_data: &TimeData,
+    ) -> Result<BlockId, ChainstateError> {
+        if let Some(id) = self.chain.lookup(&header.hash()) {
+            if self.chain.get(id).is_failed() {
+                return Err(ChainError::Duplicate.into());
+            }
+            return Ok(id);
+        }
+
+        check_block_header(header, &self.params, time_data, true)?;
+        let prev = self
+            .chain
+            .lookup(&header.prev_block_hash)
+            .ok_or(ChainError::PrevBlockNotFound)?;
+        let height = self.chain.get(prev).height + 1;
+        contextual_check_block_header(header, height, &self.params)?;
+        check_header_against_chain(header, &self.chain.view_at(prev), &self.params)?;
+        let id = self.chain.add_header(header.clone())?;
+        self.apply_snapshot_base();
+        Ok(id)
+    }
+
+    /// Validates a batch of headers, each extending the previous one, and
+    /// adds them to the block index. Returns the last header's entry.
+    pub fn process_new_block_headers(
+        &mut self,
+        headers: &[BlockHeader],
+        time_data: &TimeData,
+    ) -> Result<Option<BlockId>, ChainstateError> {
+        let mut last = None;
+        for header in headers {
+            last = Some(self.accept_block_header(header, time_data)?);
+        }
+        Ok(last)
+    }
+
+    /// Validates `block` as far as possible without its inputs and stores
+    /// it, or only records it if it is already on disk at `known`. Blocks
+    /// breaking the consensus rules are marked failed in the index.
+    pub fn accept_block(
+        &mut self,
+        block: &Block,
+        time_data: &TimeData,
+        known: Option<DiskBlockPos>,
+    ) -> Result<BlockId, ChainstateError> {
+        let id = self.accept_block_header(&block.header, time_data)?;
+        // A block with a transaction count but no data was pruned after
+        // being processed, and isn't stored again
+        let index = self.chain.get(id);
+        if index.have_data() || index.tx_count != 0 {
+            return Ok(id);
+        }
+
+        let height = self.chain.get(id).height;
+        let checked = check_block(block, &self.params, time_data, true, true)
+            .and_then(|()| contextual_check_block(block, height, &self.params));
+        if let Err(error) = checked {
+            if error.is_consensus_failure() {
+                self.chain.mark_block_failed(id);
+            }
+            return Err(error.into());
+        }
+
+        let pos = self.blocks.write_block(block, height, known)?;
+        self.chain.received_block(id, block.transactions.len() as u32, pos.file, pos.pos);
+        // Blocks already on disk, as when reindexing, don't grow the files
+        self.check_for_pruning |= known.is_none();
+        Ok(id)
+    }
+
+    /// Stores a block received from a peer and moves the tip as far as the
+    /// blocks on disk allow. Returns the entry of the block.
+    pub fn process_new_block(
+        &mut self,
+        block: &Block,
+        time_data: &TimeData,
+    ) -> Result<BlockId, ChainstateError> {
+        let id = self.accept_block(block, time_data, None)?;
+        self.activate_best_chain()?;
+        Ok(id)
+    }
+
+    /// Checks that `block` can be connected on top of the tip, running the
+    /// header, block and transaction checks and connecting it to the UTXO
+    /// set, without storing anything. Used to vet blocks from the miner.
+    pub fn test_block_validity(
+        &mut self,
+        block: &Block,
+        time_data: &TimeData,
+    ) -> Result<(), ChainstateError> {
+        let prev_hash = &block.header.prev_block_hash;
+        let prev = self.chain.lookup(prev_hash).ok_or(ChainError::PrevBlockNotFound)?;
+        if self.chain.tip() != Some(prev) || self.coins.best_block() != *prev_hash {
+            return Err(ChainstateError::NotBestPrevBlock);
+        }
+
+        let height = self.chain.get(prev).height + 1;
+        check_block(block, &self.params, time_data, true, true)?;
+        contextual_check_block_header(&block.header, height, &self.params)?;
+        check_header_against_chain(&block.header, &self.chain.view_at(prev), &self.params)?;
+        contextual_check_block(block, height, &self.params)?;
+
+        // The changes are dropped with the cache
+        let mut view = CoinsViewCache::new(&mut self.coins);
+        connect_block(block, height, &mut view, &self.params)?;
+        Ok(())
+    }
+
+    /// Reorganizes to the valid chain with the most work whose blocks are
+    /// all on disk, connecting one block at a time. Blocks breaking the
+    /// consensus rules are marked failed and the next best chain is tried;
+    /// any other error is returned. Returns the new tip.
+    pub fn activate_best_chain(&mut self) -> Result<Option<BlockId>, ChainstateError> {
+        // The coins are at the snapshot base, which can't be connected to
+        if self.snapshot_base.is_some() {
+            return Ok(self.chain.tip());
+        }
+        while let Some(best) = self.chain.find_most_work_chain() {
+            let tip = match self.chain.tip() {
+                Some(tip) => tip,
+                None => break,
+            };
+            if self.chain.get(best).chain_work <= self.chain.get(tip).chain_work {
+                break;
+            }
+
+            let fork = self.chain.find_fork(best).expect("chains share the genesis block");
+            while self.chain.tip() != Some(fork) {
+                self.disconnect_tip()?;
+            }
+
+            let mut path = Vec::new();
+            let mut walk = best;
+            while walk != fork {
+                path.push(walk);
+                walk = self.chain.get(walk).prev.expect("fork is an ancestor");
+            }
+            for &id in path.iter().rev() {
+                match self.connect_tip(id) {
+                    Ok(()) => {}
+                    Err(ChainstateError::Invalid(error)) if error.is_consensus_failure() => {
+                        self.chain.mark_block_failed(id);
+                        break;
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+        self.chain.prune_candidates();
+        // Flush everything, in order, so the coins never get ahead of the
+        // blocks and index on disk
+        if self.coins.dynamic_memory_usage() > self.cache_budget {
+            self.flush()?;
+        }
+        if std::mem::take(&mut self.check_for_pruning) {
+            let files = self.find_files_to_prune();
+            self.prune_files(&files)?;
+        }
+        Ok(self.chain.tip())
+    }
+
+    /// Marks `id` and its descendants invalid, disconnects them from the
+    /// active chain and activates the best remaining chain. Used by
+    /// `invalidateblock` to leave a bad fork by hand.
+    pub fn invalidate_block(&mut self, id: BlockId) -> Result<(), ChainstateError> {
+        if self.chain.get(id).prev.is_none() {
+            return Err(ChainstateError::InvalidateGenesis);
+        }
+        self.chain.invalidate_block(id);
+        while self.chain.contains(id) {
+            self.disconnect_tip()?;
+        }
+        // Chains that were worse than the old tip may now be the best
+        self.chain.restore_candidates();
+        self.activate_best_chain()?;
+        Ok(())
+    }
+
+    /// Clears the failure flags of `id`, its descendants and ancestors, then
+    /// activates the best chain again. Undoes `invalidate_block`.
+    pub fn reconsider_block(&mut self, id: BlockId) -> Result<(), ChainstateError> {
+        self.chain.reconsider_block(id);
+        self.activate_best_chain()?;
+        Ok(())
+    }
+
+    /// Connects `id`, a child of the tip, writing its undo data
+    fn connect_tip(&mut self, id: BlockId) -> Result<(), ChainstateError> {
+        let block = self.blocks.read_block_at(self.chain.get(id))?;
+        let height = self.chain.get(id).height;
+        let mut view = CoinsViewCache::new(&mut self.coins);
+        let undo = connect_block(&block, height, &mut view, &self.params)?;
+
+        let file = self.chain.get(id).file;
+        let pos = self.blocks.write_undo(&undo, file, &block.header.prev_block_hash)?;
+        self.chain.received_undo(id, pos.pos);
+        view.flush()?;
+        self.chain.get_mut(id).raise_validity(BLOCK_VALID_SCRIPTS);
+        self.chain.set_tip(Some(id));
+        Ok(())
+    }
+
+    /// Disconnects the tip using its undo data
+    fn disconnect_tip(&mut self) -> Result<(), ChainstateError> {
+        let tip = self.chain.tip().expect("chain has a tip");
+        let index = self.chain.get(tip);
+        let prev = index.prev.expect("genesis can't be disconnected");
+        let block = self.blocks.read_block_at(index)?;
+        let undo = self.blocks.read_undo_at(index)?;
+        let prev_sapling_root = self.chain.get(prev).header.final_sapling_root;
+
+        let mut view = CoinsViewCache::new(&mut self.coins);
+        let height = index.height;
+        let result =
+            disconnect_block(&block, height, &undo, prev_sapling_root, &mut view, &self.params);
+        if result != DisconnectResult::Ok {
+            return Err(ChainstateError::DisconnectFailed(height));
+        }
+        view.flush()?;
+        self.chain.set_tip(Some(prev));
+        Ok(())
+    }
+
+    /// Imports the blocks of a file of magic-framed blocks, such as the
+    /// bootstrap file written by `contrib/linearize`. If the file is blk
+    /// file `file` of the store, as when reindexing, the blocks are indexed
+    /// where they are instead of being copied. Blocks whose parent hasn't
+    /// been seen are held until it is, and the first block of an empty
+    /// index is taken as genesis. Returns the number of blocks added.
+    pub fn load_external_block_file<R: Read + Seek>(
+        &mut self,
+        mut reader: R,
+        file: Option<u32>,
+        time_data: &TimeData,
+        ui: &UiInterface,
+        title: &str,
+    ) -> Result<usize, ChainstateError> {
+        let len = reader.seek(SeekFrom::End(0))?.max(1);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut reader = BlockFileReader::new(reader, self.blocks.magic());
+        let mut loaded = 0;
+        let mut percent = 0;
+        ui.show_progress(title, 0);
+
+        while let Some((pos, block)) = reader.next_block()? {
+            let known = file.map(|file| DiskBlockPos {
+                file,
+                pos: pos as u32,
+            });
+            let prev = block.header.prev_block_hash;
+            if prev == [0; 32] {
+                if self.chain.is_empty() {
+                    self.store_genesis(&block, known)?;
+                    loaded += 1;
+                }
+            } else if self.chain.lookup(&prev).is_none() {
+                self.unknown_parent.entry(prev).or_default().push((block, known));
+                continue;
+            } else if self.import_block(&block, known, time_data)? {
+                loaded += 1;
+            }
+
+            // Blocks that were waiting on this one can go in now
+            let mut queue = vec![block.hash()];
+            while let Some(parent) = queue.pop() {
+                let children = self.unknown_parent.remove(&parent).unwrap_or_default();
+                for (child, known) in children {
+                    if self.import_block(&child, known, time_data)? {
+                        loaded += 1;
+                    }
+                    queue.push(child.hash());
+                }
+            }
+
+            let done = (reader.position()? * 100 / len) as u32;
+            if done > percent {
+                percent = done;
+                ui.show_progress(title, percent);
+            }
+        }
+        ui.show_progress(title, 100);
+
+        let orphans: usize = self.unknown_parent.values().map(Vec::len).sum();
+        if orphans > 0 {
+            warn!("{} imported blocks are still waiting for their parent", orphans);
+        }
+        Ok(loaded)
+    }
+
+    /// Accepts an imported block and connects what it allows. Invalid
+    /// blocks are skipped; returns whether the block was added.
+    fn import_block(
+        &mut self,
+        block: &Block,
+        known: Option<DiskBlockPos>,
+        time_data: &TimeData,
+    ) -> Result<bool, ChainstateError> {
+        if let Some(id) = self.chain.lookup(&block.hash()) {
+            let index = self.chain.get(id);
+            if index.have_data() || index.tx_count != 0 {
+                return Ok(false);
+            }
+        }
+        match self.accept_block(block, time_data, known) {
+            Ok(_) => {}
+            Err(error) if error.is_invalid() => {
+                warn!("Skipping imported block {}: {}", display_hash(&block.hash()), error);
+                return Ok(false);
+            }
+            Err(error) => return Err(error),
+        }
+        self.activate_best_chain()?;
+        Ok(true)
+    }
+
+    /// Rebuilds the block index and the UTXO set from the blk files, which
+    /// are scanned in order. The chainstate must start out empty.
+    pub fn reindex(
+        &mut self,
+        time_data: &TimeData,
+        ui: &UiInterface,
+    ) -> Result<usize, ChainstateError> {
+        ui.init_message("Reindexing blocks...");
+        let mut loaded = 0;
+        let mut file = 0;
+        loop {
+            let path = block_file_path(self.blocks.dir(), "blk", file);
+            if !path.exists() {
+                break;
+            }
+            let title = format!("Reindexing block file blk{:05}.dat...", file);
+            let reader = BufReader::new(File::open(path)?);
+            loaded += self.load_external_block_file(reader, Some(file), time_data, ui, &title)?;
+            file += 1;
+        }
+        self.flush()?;
+        Ok(loaded)
+    }
+
+    /// Imports the blocks of the files given with `-loadblock`.
+    pub fn import_files<P: AsRef<Path>>(
+        &mut self,
+        paths: &[P],
+        time_data: &TimeData,
+        ui: &UiInterface,
+    ) -> Result<usize, ChainstateError> {
+        let mut loaded = 0;
+        for path in paths {
+            let path = path.as_ref();
+            ui.init_message(&format!("Importing blocks from {}...", path.display()));
+            let title = format!("Importing {}...", path.display());
+            let reader = BufReader::new(File::open(path)?);
+            loaded += self.load_external_block_file(reader, None, time_data, ui, &title)?;
+        }
+        self.flush()?;
+        Ok(loaded)
+    }
+
+    /// Syncs the block files, saves the changed block index entries and
+    /// file statistics, then writes the coins cache to the database. In
+    /// that order the index on disk always has the blocks the coins are at.
+    pub fn flush(&mut self) -> Result<(), ChainstateError> {
+        self.blocks.flush(false).map_err(BlockStoreError::from)?;
+        let files = self.blocks.take_dirty_files();
+        let entries = self.chain.take_dirty_entries();
+        self.block_tree.write(&files, self.blocks.last_file(), &entries)?;
+        self.block_tree.flush()?;
+        self.coins.flush()?;
+        Ok(())
+    }
+
+    /// Checks the last `check_depth` blocks of the active chain, or all of
+    /// them if it is zero, as after an unclean shutdown. Each level adds to
+    /// the previous ones: 0 reads the blocks, 1 validates them, 2 reads
+    /// their undo data, 3 disconnects them in memory to check the undo data
+    /// against the UTXO set while the coins fit the cache budget, and 4
+    /// connects them again. Nothing is written. Blocks without data, such
+    /// as pruned ones, end the check. Fails with the height of the first
+    /// bad block found walking down from the tip.
+    pub fn verify_db(
+        &mut self,
+        check_level: u32,
+        check_depth: u32,
+        time_data: &TimeData,
+        ui: &UiInterface,
+    ) -> Result<(), ChainstateError> {
+        let tip = match self.chain.tip() {
+            Some(tip) if self.chain.get(tip).prev.is_some() => tip,
+            _ => return Ok(()),
+        };
+        let tip_height = self.chain.get(tip).height;
+        let check_level = check_level.min(4);
+        let check_depth = match check_depth {
+            0 => tip_height,
+            depth => depth.min(tip_height),
+        };
+        let failed = |height, reason: String| ChainstateError::VerifyFailed { height, reason };
+        let title = "Verifying blocks...";
+        ui.init_message(title);
+        ui.show_progress(title, 0);
+
+        // Blocks are disconnected from a throwaway cache over the coins, so
+        // it ends up at `state`
+        let budget = self.cache_budget.saturating_sub(self.coins.dynamic_memory_usage());
+        let mut view = CoinsViewCache::new(&mut self.coins);
+        let mut state = tip;
+        let mut unclean = None;
+        let mut walk = tip;
+        while let Some(prev) = self.chain.get(walk).prev {
+            let index = self.chain.get(walk);
+            let height = index.height;
+            if tip_height - height >= check_depth || !index.have_data() {
+                break;
+            }
+            let mut percent = (tip_height - height) * 100 / check_depth;
+            if check_level >= 4 {
+                percent /= 2;
+            }
+            ui.show_progress(title, percent);
+
+            let block = self
+                .blocks
+                .read_block_at(index)
+                .map_err(|e| failed(height, format!("can't read block: {}", e)))?;
+            if check_level >= 1 {
+                check_block(&block, &self.params, time_data, true, true)
+                    .map_err(|e| failed(height, format!("bad block: {}", e)))?;
+            }
+            if check_level >= 2 {
+                let undo = self
+                    .blocks
+                    .read_undo_at(index)
+                    .map_err(|e| failed(height, format!("can't read undo data: {}", e)))?;
+                if undo.tx_undos.len() + 1 != block.transactions.len() {
+                    return Err(failed(height, "undo data doesn't match the block".into()));
+                }
+                if check_level >= 3 && walk == state && view.dynamic_memory_usage() <= budget {
+                    let prev_sapling_root = self.chain.get(prev).header.final_sapling_root;
+                    let result = disconnect_block(
+                        &block,
+                        height,
+                        &undo,
+                        prev_sapling_root,
+                        &mut view,
+                        &self.params,
+                    );
+                    match result {
+                        DisconnectResult::Ok => {}
+                        // Keep going down to report the lowest such block
+                        DisconnectResult::Unclean => unclean = Some(height),
+                        DisconnectResult::Failed => {
+                            return Err(failed(height, "can't disconnect block".into()));
+                        }
+                    }
+                    state = prev;
+                }
+            }
+            walk = prev;
+        }
+        if let Some(height) = unclean {
+            return Err(failed(height, "coin database inconsistencies found".into()));
+        }
+
+        if check_level >= 4 {
+            let checked = tip_height - self.chain.get(state).height;
+            while state != tip {
+                state = self.chain.next(state).expect("the state is on the active chain");
+                let index = self.chain.get(state);
+                let height = index.height;
+                ui.show_progress(title, 100 - (tip_height - height) * 50 / checked.max(1));
+                let block = self
+                    .blocks
+                    .read_block_at(index)
+                    .map_err(|e| failed(height, format!("can't read block: {}", e)))?;
+                connect_block(&block, height, &mut view, &self.params)
+                    .map_err(|e| failed(height, format!("can't reconnect block: {}", e)))?;
+            }
+        }
+        ui.show_progress(title, 100);
+        Ok(())
+    }
+
+    /// Statistics of the UTXO set at the tip, as reported by
+    /// `gettxoutsetinfo`. The coins cache is flushed first.
+    pub fn utxo_stats(&mut self) -> Result<CoinsStats, ChainstateError> {
+        self.flush()?;
+        let mut stats = self.db.get_stats()?;
+        if let Some(id) = self.chain.lookup(&stats.best_block) {
+            stats.height = self.chain.get(id).height;
+        }
+        Ok(stats)
+    }
+
+    /// Writes the UTXO set at the tip to `writer` as a snapshot that
+    /// `load_snapshot` can bootstrap another node from.
+    pub fn dump_snapshot<W: Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> Result<SnapshotMetadata, ChainstateError> {
+        self.flush()?;
+        Ok(write_snapshot(&self.db, self.blocks.magic(), writer)?)
+    }
+
+    /// Loads the UTXO set from a snapshot into an empty chainstate. The
+    /// base block must be in the allowlist of the chain parameters and the
+    /// loaded set must hash to the allowlisted hash, or it is erased again.
+    /// Once the base header is in the index it becomes the tip, and blocks
+    /// are connected on top of it.
+    pub fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<SnapshotMetadata, ChainstateError> {
+        if self.chain.height().unwrap_or(0) > 0 || self.db.coins().next().is_some() {
+            return Err(SnapshotError::NotEmpty.into());
+        }
+        let metadata = SnapshotMetadata::deserialize(reader).map_err(SnapshotError::from)?;
+        if metadata.network_magic != self.blocks.magic() {
+            return Err(SnapshotError::WrongNetwork.into());
+        }
+        let assumed = self
+            .assume_utxo
+            .iter()
+            .find(|assumed| assumed.block_hash == metadata.base_block)
+            .cloned()
+            .ok_or_else(|| SnapshotError::NotAllowed(display_hash(&metadata.base_block)))?;
+
+        let best_block = self.coins.best_block();
+        let loaded = read_snapshot(reader, &metadata, &mut self.db).and_then(|()| {
+            let stats = self.db.get_stats()?;
+            if stats.hash_serialized != assumed.hash_serialized {
+                return Err(SnapshotError::HashMismatch {
+                    expected: display_hash(&assumed.hash_serialized),
+                    actual: display_hash(&stats.hash_serialized),
+                });
+            }
+            if stats.transaction_outputs != metadata.coins_count {
+                return Err(SnapshotError::CoinsCount {
+                    expected: metadata.coins_count,
+                    actual: stats.transaction_outputs,
+                });
+            }
+            Ok(())
+        });
+        // The cache holds the best block and anchors read before the load
+        self.coins = CoinsViewCache::new(self.db.clone());
+        if let Err(error) = loaded {
+            // Nothing of an unverified snapshot is kept
+            self.db.clear()?;
+            self.coins.set_best_block(best_block);
+            self.flush()?;
+            return Err(error.into());
+        }
+
+        self.snapshot_base = Some(assumed);
+        self.apply_snapshot_base();
+        self.db.flush()?;
+        Ok(metadata)
+    }
+
+    /// Makes the base block of a loaded snapshot the tip once its header is
+    /// in the index.
+    fn apply_snapshot_base(&mut self) {
+        let base = match &self.snapshot_base {
+            Some(base) => base,
+            None => return,
+        };
+        if let Some(id) = self.chain.lookup(&base.block_hash) {
+            self.chain.assume_valid(id, base.chain_tx);
+            self.snapshot_base = None;
+        }
+    }
+
+    /// Picks the oldest block files to prune so that the files fit the
+    /// automatic prune target again, with room for new chunks. Files
+    /// holding any of the last `MIN_BLOCKS_TO_KEEP` blocks are kept.
+    fn find_files_to_prune(&self) -> Vec<u32> {
+        let target = match self.prune_mode {
+            PruneMode::Automatic(target) => target,
+            _ => return Vec::new(),
+        };
+        let height = self.chain.height().unwrap_or(0);
+        if height <= self.prune_after_height {
+            return Vec::new();
+        }
+        let last_prunable = match height.checked_sub(MIN_BLOCKS_TO_KEEP) {
+            Some(height) => height,
+            None => return Vec::new(),
+        };
+
+        let buffer = (BLOCKFILE_CHUNK_SIZE + UNDOFILE_CHUNK_SIZE) as u64;
+        let mut usage = self.blocks.current_usage();
+        let mut files = Vec::new();
+        // The file being written to is never pruned
+        for file in 0..self.blocks.last_file() {
+            if usage + buffer < target {
+                break;
+            }
+            let info = self.blocks.file_info(file).expect("files up to the last one exist");
+            if info.size == 0 || info.height_last > last_prunable {
+                continue;
+            }
+            usage -= info.size as u64 + info.undo_size as u64;
+            files.push(file);
+        }
+        files
+    }
+
+    /// Prunes the block files holding only blocks up to `height`, keeping
+    /// the last `MIN_BLOCKS_TO_KEEP` blocks whatever `height` is. Used by
+    /// `pruneblockchain`.
+    pub fn prune_block_files_manual(&mut self, height: u32) -> Result<(), ChainstateError> {
+        let tip_height = self.chain.height().unwrap_or(0);
+        let last_prunable = match tip_height.checked_sub(MIN_BLOCKS_TO_KEEP) {
+            Some(limit) => height.min(limit),
+            None => return Ok(()),
+        };
+        let files: Vec<_> = (0..self.blocks.last_file())
+            .filter(|&file| {
+                let info = self.blocks.file_info(file).expect("files up to the last one exist");
+                info.size != 0 && info.height_last <= last_prunable
+            })
+            .collect();
+        self.prune_files(&files)
+    }
+
+    /// Drops `files` from the block index and deletes them. The coins are
+    /// flushed first, so that they never depend on a deleted block.
+    fn prune_files(&mut self, files: &[u32]) -> Result<(), ChainstateError> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        for &file in files {
+            self.chain.prune_file(file);
+            self.blocks.prune_file(file);
+        }
+        self.have_pruned = true;
+        self.flush()?;
+        self.blocks.unlink_pruned_files(files)?;
+        Ok(())
+    }
+
+    /// Height from which the active chain has all its blocks on disk, up
+    /// to the tip.
+    pub fn prune_height(&self) -> Option<u32> {
+        let mut walk = self.chain.tip()?;
+        while let Some(prev) = self.chain.get(walk).prev {
+            if !self.chain.get(prev).have_data() {
+                break;
+            }
+            walk = prev;
+        }
+        Some(self.chain.get(walk).height)
+    }
+
+    /// Checks if the node is still catching up with the network: the tip
+    /// lacks the minimum chainwork or is older than `DEFAULT_MAX_TIP_AGE`.
+    /// Once caught up this stays false.
+    pub fn is_initial_block_download(&self) -> bool {
+        if !self.initial_download.get() {
+            return false;
+        }
+        let tip = match self.chain.tip() {
+            Some(tip) => self.chain.get(tip),
+            None => return true,
+        };
+        if tip.chain_work < self.params.minimum_chain_work
+            || tip.time() < TimeData::get_system_time() - DEFAULT_MAX_TIP_AGE
+        {
+            return true;
+        }
+        self.initial_download.set(false);
+        false
+    }
+
+    /// Estimates the share of all transactions up to now that the chain
+    /// ending at `id` contains, from the transaction rate in the chain
+    /// parameters.
+    pub fn guess_verification_progress(&self, id: Option<BlockId>) -> f64 {
+        let index = match id {
+            Some(id) => self.chain.get(id),
+            None => return 0.0,
+        };
+        let data = &self.params.chain_tx_data;
+        let now = TimeData::get_system_time();
+
+        let total = if index.chain_tx <= data.tx_count {
+            data.tx_count as f64 + (now - data.time) as f64 * data.tx_rate
+        } else {
+            index.chain_tx as f64 + (now - index.time()) as f64 * data.tx_rate
+        };
+        if total <= 0.0 {
+            return 0.0;
+        }
+        (index.chain_tx as f64 / total).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::{BlockHash, ChainTipStatus};
+    use crate::coins::UTXOSet;
+    use crate::consensus::merkle::block_merkle_root;
+    use crate::miner::Miner;
+    use crate::pow::BlockIndexView;
+    use crate::primitives::transaction::OutPoint;
+    use crate::script::Script;
+    use crate::serialize::Serializable;
+    use crate::txmempool::Mempool;
+    use crate::ui_interface::SHOW_PROGRESS;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+
+    const MAGIC: [u8; 4] = [0xaa, 0xe8, 0x3f, 0x5f];
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn miner() -> Miner {
+        Miner::new(Arc::new(Mempool::new()), ConsensusParams::regtest())
+    }
+
+    /// Mines a block at `height` on `prev`, paying `tag` as the output script
+    fn mine<V: BlockIndexView + ?Sized>(
+        prev: &V,
+        prev_hash: BlockHash,
+        height: u32,
+        tag: u8,
+    ) -> Block {
+        let miner = miner();
+        let time = 1_500_000_000 + height * 150;
+        let script = Script::new(vec![tag]);
+        // Blocks may extend any chain, so the template spends no coins
+        let view = UTXOSet::new();
+        let mut block = miner.create_block_template(prev, &view, prev_hash, time, &script);
+        assert!(miner.mine_block(&mut block, height, 1_000, &AtomicBool::new(false)));
+        block
+    }
+
+    /// Opens the chainstate in `dir` with its coins in `coins_dir`, as at
+    /// startup
+    fn open_with_coins(dir: &Path, coins_dir: &str) -> Chainstate {
+        let block_tree = BlockTreeDb::new(&dir.join("blocks/index")).unwrap();
+        let (files, last_file) = block_tree.read_block_files().unwrap();
+        let blocks = BlockStore::with_files(&dir.join("blocks"), MAGIC, files, last_file).unwrap();
+        let coins = CoinsViewDb::new(&dir.join(coins_dir)).unwrap();
+        let params = ConsensusParams::regtest();
+        let mut chainstate = Chainstate::new(blocks, block_tree, coins, params, 1 << 20);
+        chainstate.load_block_index().unwrap();
+        chainstate
+    }
+
+    fn open(dir: &Path) -> Chainstate {
+        let mut chainstate = open_with_coins(dir, "chainstate");
+        let empty: &[BlockHeader] = &[];
+        chainstate.load_genesis(&mine(empty, [0; 32], 0, 0x51)).unwrap();
+        chainstate
+    }
+
+    /// Mines `count` blocks on `prev`, adding their headers to the index
+    fn extend(chainstate: &mut Chainstate, prev: BlockId, count: u32, tag: u8) -> Vec<Block> {
+        let time_data = TimeData::new();
+        let mut prev = prev;
+        (0..count)
+            .map(|_| {
+                let chain = chainstate.chain();
+                let height = chain.get(prev).height + 1;
+                let block = mine(&chain.view_at(prev), chain.get(prev).hash, height, tag);
+                prev = chainstate.accept_block_header(&block.header, &time_data).unwrap();
+                block
+            })
+            .collect()
+    }
+
+    fn coinbase_outpoint(block: &Block) -> OutPoint {
+        OutPoint {
+            txid: block.transactions[0].hash(),
+            index: 0,
+        }
+    }
+
+    #[test]
+    fn test_headers_first() {
+        let dir = temp_dir("chainstate_headers_first");
+        let mut chainstate = open(&dir);
+        let time_data = TimeData::new();
+        let genesis = chainstate.chain().tip().unwrap();
+        let blocks = extend(&mut chainstate, genesis, 3, 0x51);
+        let best_header = chainstate.chain().best_header().unwrap();
+        assert_eq!(chainstate.chain().get(best_header).height, 3);
+        assert_eq!(chainstate.chain().tip(), Some(genesis));
+
+        // Blocks arriving out of order wait for their parents
+        chainstate.process_new_block(&blocks[1], &time_data).unwrap();
+        assert_eq!(chainstate.chain().height(), Some(0));
+        chainstate.process_new_block(&blocks[0], &time_data).unwrap();
+        assert_eq!(chainstate.chain().height(), Some(2));
+        chainstate.process_new_block(&blocks[2], &time_data).unwrap();
+        assert_eq!(chainstate.chain().tip(), Some(best_header));
+        assert_eq!(chainstate.coins().best_block(), blocks[2].hash());
+        assert!(chainstate.coins().have_coin(&coinbase_outpoint(&blocks[1])));
+        assert!(chainstate.chain().get(best_header).have_undo());
+
+        // The tip is years old
+        assert!(chainstate.is_initial_block_download());
+        assert_eq!(chainstate.guess_verification_progress(chainstate.chain().tip()), 1.0);
+
+        // Headers must follow the difficulty adjustment, here a fixed target
+        let miner = miner();
+        let view = chainstate.chain().view_at(best_header);
+        let script = Script::new(vec![0x51]);
+        let time = 1_500_000_600;
+        let coins = UTXOSet::new();
+        let mut block =
+            miner.create_block_template(&view, &coins, blocks[2].hash(), time, &script);
+        block.header.bits = 0x200f0f0e;
+        assert!(miner.mine_block(&mut block, 4, 1_000, &AtomicBool::new(false)));
+        assert!(matches!(
+            chainstate.accept_block_header(&block.header, &time_data),
+            Err(ChainstateError::Invalid(BlockValidationError::BadDiffBits))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reorganize_to_most_work() {
+        let dir = temp_dir("chainstate_reorg");
+        let mut chainstate = open(&dir);
+        let time_data = TimeData::new();
+        let genesis = chainstate.chain().tip().unwrap();
+        let short = extend(&mut chainstate, genesis, 2, 0x51);
+        for block in &short {
+            chainstate.process_new_block(block, &time_data).unwrap();
+        }
+        assert!(chainstate.coins().have_coin(&coinbase_outpoint(&short[0])));
+
+        let long = extend(&mut chainstate, genesis, 3, 0x52);
+        for block in &long {
+            chainstate.process_new_block(block, &time_data).unwrap();
+        }
+        assert_eq!(chainstate.chain().height(), Some(3));
+        assert_eq!(chainstate.coins().best_block(), long[2].hash());
+        assert!(!chainstate.coins().have_coin(&coinbase_outpoint(&short[0])));
+        assert!(chainstate.coins().have_coin(&coinbase_outpoint(&long[0])));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_submit_block() {
+        let dir = temp_dir("chainstate_submit");
+        let mut chainstate = open(&dir);
+        let time_data = TimeData::new();
+        let miner = miner();
+        let script = Script::new(vec![0x51]);
+        let template = |chainstate: &Chainstate| {
+            let chain = chainstate.chain();
+            let tip = chain.tip().unwrap();
+            let time = 1_500_000_150 + chain.get(tip).height * 150;
+            let hash = chain.get(tip).hash;
+            miner.create_block_template(chain, chainstate.coins(), hash, time, &script)
+        };
+
+        // A coinbase claiming too much only fails when connected
+        let mut greedy = template(&chainstate);
+        greedy.transactions[0].outputs[0].value += 1;
+        greedy.header.merkle_root = block_merkle_root(&greedy).0;
+        assert!(miner.mine_block(&mut greedy, 1, 1_000, &AtomicBool::new(false)));
+        assert!(matches!(
+            miner.submit_block(&mut chainstate, &greedy, &time_data),
+            Err(ChainstateError::Invalid(BlockValidationError::CoinbaseAmount))
+        ));
+        assert!(chainstate.chain().lookup(&greedy.hash()).is_none());
+
+        let mut block = template(&chainstate);
+        assert!(miner.mine_block(&mut block, 1, 1_000, &AtomicBool::new(false)));
+        let id = miner.submit_block(&mut chainstate, &block, &time_data).unwrap();
+        assert_eq!(chainstate.chain().tip(), Some(id));
+        assert_eq!(chainstate.coins().best_block(), block.hash());
+
+        // Blocks that no longer extend the tip are stale
+        assert!(matches!(
+            chainstate.test_block_validity(&block, &time_data),
+            Err(ChainstateError::NotBestPrevBlock)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalidate_and_reconsider() {
+        let dir = temp_dir("chainstate_invalidate");
+        let mut chainstate = open(&dir);
+        let time_data = TimeData::new();
+        let genesis = chainstate.chain().tip().unwrap();
+        let main = extend(&mut chainstate, genesis, 3, 0x51);
+        let fork = extend(&mut chainstate, genesis, 2, 0x52);
+        for block in main.iter().chain(&fork) {
+            chainstate.process_new_block(block, &time_data).unwrap();
+        }
+        assert_eq!(chainstate.coins().best_block(), main[2].hash());
+
+        // Invalidating the second block moves the tip to the fork
+        let second = chainstate.chain().lookup(&main[1].hash()).unwrap();
+        chainstate.invalidate_block(second).unwrap();
+        assert_eq!(chainstate.coins().best_block(), fork[1].hash());
+        assert!(!chainstate.coins().have_coin(&coinbase_outpoint(&main[0])));
+        assert!(chainstate.coins().have_coin(&coinbase_outpoint(&fork[0])));
+        let old_tip = chainstate.chain().lookup(&main[2].hash()).unwrap();
+        assert_eq!(chainstate.chain().tip_status(old_tip), ChainTipStatus::Invalid);
+        assert!(matches!(
+            chainstate.invalidate_block(genesis),
+            Err(ChainstateError::InvalidateGenesis)
+        ));
+
+        chainstate.reconsider_block(second).unwrap();
+        assert_eq!(chainstate.chain().tip(), Some(old_tip));
+        assert_eq!(chainstate.coins().best_block(), main[2].hash());
+        let fork_tip = chainstate.chain().lookup(&fork[1].hash()).unwrap();
+        assert_eq!(chainstate.chain().tip_status(fork_tip), ChainTipStatus::ValidFork);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reindex_and_import() {
+        let dir = temp_dir("chainstate_reindex");
+        let mut chainstate = open(&dir);
+        let time_data = TimeData::new();
+        let genesis = chainstate.chain().tip().unwrap();
+        let genesis = chainstate.blocks().read_block_at(chainstate.chain().get(genesis)).unwrap();
+        let blocks = extend(&mut chainstate, 0, 3, 0x51);
+        for block in &blocks {
+            chainstate.process_new_block(block, &time_data).unwrap();
+        }
+        let side = extend(&mut chainstate, 1, 1, 0x52);
+        chainstate.process_new_block(&side[0], &time_data).unwrap();
+        chainstate.flush().unwrap();
+        drop(chainstate);
+
+        // The saved index is loaded at startup
+        let mut restarted = open(&dir);
+        assert_eq!(restarted.chain().len(), 5);
+        assert_eq!(restarted.chain().height(), Some(3));
+        assert_eq!(restarted.activate_best_chain().unwrap(), restarted.chain().tip());
+        assert!(!restarted.have_pruned());
+        drop(restarted);
+
+        // With -reindex-chainstate the coins are rebuilt from the saved index
+        let mut rebuilt = open_with_coins(&dir, "rebuilt");
+        assert_eq!(rebuilt.chain().height(), Some(0));
+        rebuilt.activate_best_chain().unwrap();
+        assert_eq!(rebuilt.chain().height(), Some(3));
+        assert_eq!(rebuilt.coins().best_block(), blocks[2].hash());
+        drop(rebuilt);
+
+        // The blk files alone are enough to rebuild the chainstate
+        let ui = UiInterface::new();
+        let store = BlockStore::new(&dir.join("blocks"), MAGIC).unwrap();
+        let block_tree = BlockTreeDb::new(&dir.join("reindexed_index")).unwrap();
+        let coins = CoinsViewDb::new(&dir.join("reindexed")).unwrap();
+        let params = ConsensusParams::regtest();
+        let mut reindexed = Chainstate::new(store, block_tree, coins, params, 1 << 20);
+        assert_eq!(reindexed.reindex(&time_data, &ui).unwrap(), 5);
+        assert_eq!(reindexed.chain().height(), Some(3));
+        assert_eq!(reindexed.coins().best_block(), blocks[2].hash());
+
+        // Blocks out of order in a bootstrap file wait for their parents
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let seen = progress.clone();
+        ui.register_callback(SHOW_PROGRESS, move |message| {
+            seen.lock().unwrap().push(message.to_string())
+        });
+        let mut bootstrap = Vec::new();
+        for block in [&genesis, &blocks[1], &blocks[2], &blocks[0]] {
+            bootstrap.extend_from_slice(&MAGIC);
+            bootstrap.extend_from_slice(&(block.get_size() as u32).to_le_bytes());
+            block.serialize(&mut bootstrap).unwrap();
+        }
+        let store = BlockStore::new(&dir.join("imported_blocks"), MAGIC).unwrap();
+        let block_tree = BlockTreeDb::new(&dir.join("imported_index")).unwrap();
+        let coins = CoinsViewDb::new(&dir.join("imported")).unwrap();
+        let params = ConsensusParams::regtest();
+        let mut imported = Chainstate::new(store, block_tree, coins, params, 1 << 20);
+        let file = Cursor::new(bootstrap);
+        let loaded = imported.load_external_block_file(file, None, &time_data, &ui, "Importing");
+        assert_eq!(loaded.unwrap(), 4);
+        assert_eq!(imported.coins().best_block(), blocks[2].hash());
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.first().map(String::as_str), Some("Importing 0%"));
+        assert_eq!(progress.last().map(String::as_str), Some("Importing 100%"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = temp_dir("chainstate_prune");
+        let mut chainstate = open(&dir);
+        let time_data = TimeData::new();
+        // A few blocks per file
+        chainstate.blocks.set_max_file_size(1_000);
+        chainstate.set_prune_mode(PruneMode::Manual, 0);
+        let genesis = chainstate.chain().tip().unwrap();
+        let blocks = extend(&mut chainstate, genesis, MIN_BLOCKS_TO_KEEP + 10, 0x51);
+        for block in &blocks {
+            chainstate.process_new_block(block, &time_data).unwrap();
+        }
+        assert!(!chainstate.have_pruned());
+        assert_eq!(chainstate.prune_height(), Some(0));
+
+        chainstate.prune_block_files_manual(5).unwrap();
+        let pruned = chainstate.prune_height().unwrap();
+        assert!(pruned > 0 && pruned <= 6);
+        assert!(chainstate.have_pruned());
+        assert!(!chainstate.chain().get(genesis).have_data());
+        assert!(!block_file_path(chainstate.blocks().dir(), "blk", 0).exists());
+
+        // A pruned block sent again isn't stored
+        chainstate.process_new_block(&blocks[0], &time_data).unwrap();
+        assert!(!chainstate.chain().get(chainstate.chain().at_height(1).unwrap()).have_data());
+
+        // The last MIN_BLOCKS_TO_KEEP blocks are always kept
+        chainstate.prune_block_files_manual(u32::MAX).unwrap();
+        let kept = chainstate.prune_height().unwrap();
+        assert!(kept > pruned && kept <= 11);
+
+        // Automatic pruning runs as new blocks are written
+        chainstate.set_prune_mode(PruneMode::Automatic(1), 0);
+        let tip = chainstate.chain().tip().unwrap();
+        for block in extend(&mut chainstate, tip, 5, 0x51) {
+            chainstate.process_new_block(&block, &time_data).unwrap();
+        }
+        let automatic = chainstate.prune_height().unwrap();
+        assert!(automatic > kept && automatic <= 16);
+        assert_eq!(chainstate.chain().height(), Some(MIN_BLOCKS_TO_KEEP + 15));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_db() {
+        let dir = temp_dir("chainstate_verify");
+        let mut chainstate = open(&dir);
+        let time_data = TimeData::new();
+        let ui = UiInterface::new();
+        let genesis = chainstate.chain().tip().unwrap();
+        let blocks = extend(&mut chainstate, genesis, 5, 0x51);
+        for block in &blocks {
+            chainstate.process_new_block(block, &time_data).unwrap();
+        }
+        chainstate.flush().unwrap();
+        for level in 0..=4 {
+            chainstate.verify_db(level, 0, &time_data, &ui).unwrap();
+        }
+        // Blocks are only disconnected in memory
+        assert_eq!(chainstate.coins().best_block(), blocks[4].hash());
+        assert_eq!(chainstate.chain().height(), Some(5));
+
+        // Lost undo data is found from level 2
+        let rev = block_file_path(chainstate.blocks().dir(), "rev", 0);
+        fs::OpenOptions::new().write(true).open(rev).unwrap().set_len(0).unwrap();
+        chainstate.verify_db(1, 0, &time_data, &ui).unwrap();
+        assert!(matches!(
+            chainstate.verify_db(DEFAULT_CHECKLEVEL, 2, &time_data, &ui),
+            Err(ChainstateError::VerifyFailed { height: 5, .. })
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_utxo_snapshot() {
+        let source_dir = temp_dir("chainstate_snapshot_source");
+        let mut source = open(&source_dir);
+        let time_data = TimeData::new();
+        let genesis = source.chain().tip().unwrap();
+        let blocks = extend(&mut source, genesis, 5, 0x51);
+        for block in &blocks {
+            source.process_new_block(block, &time_data).unwrap();
+        }
+        let stats = source.utxo_stats().unwrap();
+        assert_eq!(stats.height, 5);
+        assert_eq!(stats.best_block, blocks[4].hash());
+        assert_eq!(stats.transactions, 5);
+
+        let mut snapshot = Vec::new();
+        let metadata = source.dump_snapshot(&mut snapshot).unwrap();
+        assert_eq!(metadata.base_block, blocks[4].hash());
+        assert_eq!(metadata.coins_count, stats.transaction_outputs);
+
+        let dir = temp_dir("chainstate_snapshot");
+        let mut chainstate = open(&dir);
+        let genesis_hash = chainstate.coins().best_block();
+        assert!(matches!(
+            chainstate.load_snapshot(&mut &snapshot[..]),
+            Err(ChainstateError::Snapshot(SnapshotError::NotAllowed(_)))
+        ));
+
+        // A snapshot not matching the allowlisted hash is erased again
+        let mut assumed = AssumeUtxo {
+            height: 5,
+            block_hash: blocks[4].hash(),
+            hash_serialized: [0; 32],
+            chain_tx: 6,
+        };
+        chainstate.set_assume_utxo(vec![assumed.clone()]);
+        assert!(matches!(
+            chainstate.load_snapshot(&mut &snapshot[..]),
+            Err(ChainstateError::Snapshot(SnapshotError::HashMismatch { .. }))
+        ));
+        let empty = chainstate.utxo_stats().unwrap();
+        assert_eq!((empty.transactions, empty.best_block), (0, genesis_hash));
+
+        assumed.hash_serialized = stats.hash_serialized;
+        chainstate.set_assume_utxo(vec![assumed]);
+        chainstate.load_snapshot(&mut &snapshot[..]).unwrap();
+        assert_eq!(chainstate.utxo_stats().unwrap().hash_serialized, stats.hash_serialized);
+        assert!(matches!(
+            chainstate.load_snapshot(&mut &snapshot[..]),
+            Err(ChainstateError::Snapshot(SnapshotError::NotEmpty))
+        ));
+
+        // The base becomes the tip once its header is known, and blocks are
+        // connected on top of it
+        assert_eq!(chainstate.chain().height(), Some(0));
+        for block in &blocks {
+            chainstate.accept_block_header(&block.header, &time_data).unwrap();
+        }
+        assert_eq!(chainstate.chain().height(), Some(5));
+        let tip = chainstate.chain().tip().unwrap();
+        assert_eq!(chainstate.chain().get(tip).chain_tx, 6);
+        let source_tip = source.chain().tip().unwrap();
+        let next = extend(&mut source, source_tip, 1, 0x52);
+        source.process_new_block(&next[0], &time_data).unwrap();
+        chainstate.process_new_block(&next[0], &time_data).unwrap();
+        assert_eq!(chainstate.chain().height(), Some(6));
+        assert_eq!(chainstate.utxo_stats().unwrap(), source.utxo_stats().unwrap());
+
+        fs::remove_dir_all(source_dir).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
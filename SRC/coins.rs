@@ -1,39 +1,251 @@
-use std::collections::HashMap;
+use crate::compressor::TxOutCompressor;
+use crate::incremental_merkle_tree::{SaplingMerkleTree, SproutMerkleTree};
+use crate::memusage::{mem_usage_hashmap, mem_usage_slice};
+use crate::primitives::transaction::{OutPoint, Transaction, TxOutput};
+use crate::serialize::{Deserializable, Serializable, SerializationError, VarInt};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::mem;
+use thiserror::Error;
 
-/// Represents a transaction output.
-#[derive(Debug, Clone, PartialEq)]
+/// Root of the empty Sapling note commitment tree, displayed upstream as
+/// 3e49b5f954aa9d3545bc6c37744661eea48d7c34e3000d82b7f0010c30f4c2fb
+pub const SAPLING_EMPTY_ROOT: [u8; 32] = [
+    0xfb, 0xc2, 0xf4, 0x30, 0x0c, 0x01, 0xf0, 0xb7, 0x82, 0x0d, 0x00, 0xe3, 0x34, 0x7c, 0x8d, 0xa4,
+    0xee, 0x61, 0x46, 0x74, 0x37, 0x6c, 0xbc, 0x45, 0x35, 0x9d, 0xaa, 0x54, 0xf9, 0xb5, 0x49, 0x3e,
+];
+
+/// The entry differs from the base view and must be written on flush
+pub const DIRTY: u8 = 1 << 0;
+/// The base view doesn't have the coin, so it can be dropped once spent
+pub const FRESH: u8 = 1 << 1;
+
+#[derive(Debug, Error)]
+pub enum CoinsViewError {
+    #[error("coins database error: {0}")]
+    Database(#[from] sled::Error),
+}
+
+/// An unspent transaction output.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Coin {
-    pub value: u64,         // Value in satoshis
-    pub script_pubkey: Vec<u8>, // Output script
-    pub height: u32,        // Block height at which the output was created
-    pub spent: bool,        // Whether the coin has been spent
+    pub output: TxOutput,
+    pub height: u32,       // Block height at which the output was created
+    pub is_coinbase: bool, // Whether it was created by a coinbase transaction
 }
 
-/// Represents a transaction input point.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct OutPoint {
-    pub txid: String, // Transaction ID
-    pub index: u32,   // Output index
+impl Coin {
+    pub fn new(output: TxOutput, height: u32, is_coinbase: bool) -> Self {
+        Coin {
+            output,
+            height,
+            is_coinbase,
+        }
+    }
+}
+
+/// Coins are stored as `VARINT(height * 2 + is_coinbase)` followed by the
+/// compressed output.
+impl Serializable for Coin {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        let code = ((self.height as u64) << 1) | self.is_coinbase as u64;
+        VarInt(code).serialize(writer)?;
+        TxOutCompressor(self.output.clone()).serialize(writer)
+    }
 }
 
-/// UTXO database.
+impl Deserializable for Coin {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        let code = VarInt::deserialize(reader)?.0;
+        let height = u32::try_from(code >> 1).map_err(|_| SerializationError::InvalidData)?;
+        let output = TxOutCompressor::deserialize(reader)?.0;
+        Ok(Coin::new(output, height, code & 1 == 1))
+    }
+}
+
+/// Statistics of a UTXO set, as reported by `gettxoutsetinfo`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CoinsStats {
+    pub height: u32,
+    pub best_block: [u8; 32],
+    /// Number of transactions with unspent outputs
+    pub transactions: u64,
+    pub transaction_outputs: u64,
+    /// Size of the coin records in the database
+    pub serialized_size: u64,
+    /// Hash of the coins and the shielded state, the same on every node at
+    /// the same block
+    pub hash_serialized: [u8; 32],
+    pub total_amount: i64,
+}
+
+/// The shielded pools, each with its own nullifier set and note commitment
+/// tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShieldedType {
+    Sprout,
+    Sapling,
+}
+
+/// A cached value with its `DIRTY` and `FRESH` flags. Spent coins, unspent
+/// nullifiers and forgotten anchors are kept as `None` or `false` so that
+/// flushing can erase them from the base view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry<T> {
+    pub value: T,
+    pub flags: u8,
+}
+
+impl<T> CacheEntry<T> {
+    pub fn new(value: T, flags: u8) -> Self {
+        CacheEntry { value, flags }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.flags & DIRTY != 0
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.flags & FRESH != 0
+    }
+}
+
+/// The cached state of a `CoinsViewCache`, handed to its base view as a
+/// single batch when flushing.
+#[derive(Debug, Default)]
+pub struct CoinsBatch {
+    pub coins: HashMap<OutPoint, CacheEntry<Option<Coin>>>,
+    pub sprout_nullifiers: HashMap<[u8; 32], CacheEntry<bool>>,
+    pub sapling_nullifiers: HashMap<[u8; 32], CacheEntry<bool>>,
+    pub sprout_anchors: HashMap<[u8; 32], CacheEntry<Option<SproutMerkleTree>>>,
+    pub sapling_anchors: HashMap<[u8; 32], CacheEntry<Option<SaplingMerkleTree>>>,
+    pub best_sprout_anchor: [u8; 32],
+    pub best_sapling_anchor: [u8; 32],
+    pub best_block: [u8; 32],
+}
+
+impl CoinsBatch {
+    fn nullifiers_mut(&mut self, pool: ShieldedType) -> &mut HashMap<[u8; 32], CacheEntry<bool>> {
+        match pool {
+            ShieldedType::Sprout => &mut self.sprout_nullifiers,
+            ShieldedType::Sapling => &mut self.sapling_nullifiers,
+        }
+    }
+}
+
+/// Read access to a UTXO set and its shielded state, and a way to write
+/// back the changes made by a cache on top of it.
+pub trait CoinsView {
+    /// Fetches an unspent coin.
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin>;
+
+    /// Checks if a coin exists and is unspent.
+    fn have_coin(&self, outpoint: &OutPoint) -> bool {
+        self.get_coin(outpoint).is_some()
+    }
+
+    /// Hash of the block this state corresponds to.
+    fn best_block(&self) -> [u8; 32];
+
+    /// Checks if a nullifier has been revealed by a spend.
+    fn get_nullifier(&self, nullifier: &[u8; 32], pool: ShieldedType) -> bool;
+
+    /// Returns the Sprout tree with root `anchor`, if it is a known anchor.
+    fn get_sprout_anchor_at(&self, anchor: &[u8; 32]) -> Option<SproutMerkleTree>;
+
+    /// Returns the Sapling tree with root `anchor`, if it is a known anchor.
+    fn get_sapling_anchor_at(&self, anchor: &[u8; 32]) -> Option<SaplingMerkleTree>;
+
+    /// Checks if `anchor` is a known Sapling tree root.
+    fn have_sapling_anchor(&self, anchor: &[u8; 32]) -> bool {
+        self.get_sapling_anchor_at(anchor).is_some()
+    }
+
+    /// Root of the tree after the best block.
+    fn get_best_anchor(&self, pool: ShieldedType) -> [u8; 32];
+
+    /// Applies the dirty entries of `batch` and its best block and anchors.
+    fn batch_write(&mut self, batch: CoinsBatch) -> Result<(), CoinsViewError>;
+}
+
+impl<T: CoinsView + ?Sized> CoinsView for &mut T {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        (**self).get_coin(outpoint)
+    }
+
+    fn have_coin(&self, outpoint: &OutPoint) -> bool {
+        (**self).have_coin(outpoint)
+    }
+
+    fn best_block(&self) -> [u8; 32] {
+        (**self).best_block()
+    }
+
+    fn get_nullifier(&self, nullifier: &[u8; 32], pool: ShieldedType) -> bool {
+        (**self).get_nullifier(nullifier, pool)
+    }
+
+    fn get_sprout_anchor_at(&self, anchor: &[u8; 32]) -> Option<SproutMerkleTree> {
+        (**self).get_sprout_anchor_at(anchor)
+    }
+
+    fn get_sapling_anchor_at(&self, anchor: &[u8; 32]) -> Option<SaplingMerkleTree> {
+        (**self).get_sapling_anchor_at(anchor)
+    }
+
+    fn have_sapling_anchor(&self, anchor: &[u8; 32]) -> bool {
+        (**self).have_sapling_anchor(anchor)
+    }
+
+    fn get_best_anchor(&self, pool: ShieldedType) -> [u8; 32] {
+        (**self).get_best_anchor(pool)
+    }
+
+    fn batch_write(&mut self, batch: CoinsBatch) -> Result<(), CoinsViewError> {
+        (**self).batch_write(batch)
+    }
+}
+
+/// In-memory UTXO set, together with the shielded state: spent nullifiers
+/// and the anchors (note commitment tree roots) that spends may refer to.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UTXOSet {
     map_coins: HashMap<OutPoint, Coin>,
+    sprout_nullifiers: HashSet<[u8; 32]>,
+    sapling_nullifiers: HashSet<[u8; 32]>,
+    sprout_anchors: HashMap<[u8; 32], SproutMerkleTree>,
+    sapling_anchors: HashMap<[u8; 32], SaplingMerkleTree>,
+    best_sprout_anchor: [u8; 32],
+    best_sapling_anchor: [u8; 32],
+    best_block: [u8; 32],
 }
 
-impl UTXOSet {
-    /// Creates a new, empty UTXO set.
-    pub fn new() -> Self {
+impl Default for UTXOSet {
+    fn default() -> Self {
         UTXOSet {
             map_coins: HashMap::new(),
+            sprout_nullifiers: HashSet::new(),
+            sapling_nullifiers: HashSet::new(),
+            sprout_anchors: HashMap::new(),
+            sapling_anchors: HashMap::new(),
+            best_sprout_anchor: SproutMerkleTree::empty_root(),
+            best_sapling_anchor: SAPLING_EMPTY_ROOT,
+            best_block: [0; 32],
         }
     }
+}
+
+impl UTXOSet {
+    /// Creates a new, empty UTXO set.
+    pub fn new() -> Self {
+        Self::default()
+    }
 
     /// Checks if a coin exists and is unspent.
     pub fn have_coin(&self, outpoint: &OutPoint) -> bool {
-        self.map_coins
-            .get(outpoint)
-            .map_or(false, |coin| !coin.spent)
+        self.map_coins.contains_key(outpoint)
     }
 
     /// Adds a coin to the UTXO set.
@@ -41,54 +253,696 @@ impl UTXOSet {
         self.map_coins.insert(outpoint, coin);
     }
 
-    /// Spends a coin by marking it as spent.
-    pub fn spend_coin(&mut self, outpoint: &OutPoint) -> bool {
-        if let Some(coin) = self.map_coins.get_mut(outpoint) {
-            if !coin.spent {
-                coin.spent = true;
-                return true;
+    /// Adds the spendable outputs of `tx` as coins created at `height`.
+    pub fn add_coins(&mut self, tx: &Transaction, height: u32) {
+        let txid = tx.hash();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            if output.script_pubkey.is_unspendable() {
+                continue;
             }
+            let outpoint = OutPoint {
+                txid,
+                index: index as u32,
+            };
+            self.add_coin(outpoint, Coin::new(output.clone(), height, tx.is_coinbase()));
         }
-        false
+    }
+
+    /// Removes a coin, returning it if it was unspent.
+    pub fn spend_coin(&mut self, outpoint: &OutPoint) -> Option<Coin> {
+        self.map_coins.remove(outpoint)
     }
 
     /// Fetches a coin from the UTXO set.
     pub fn access_coin(&self, outpoint: &OutPoint) -> Option<&Coin> {
         self.map_coins.get(outpoint)
     }
+
+    /// Iterates over the unspent coins.
+    pub fn coins(&self) -> impl Iterator<Item = (&OutPoint, &Coin)> {
+        self.map_coins.iter()
+    }
+
+    /// Checks if a nullifier has been revealed by a spend.
+    pub fn get_nullifier(&self, nullifier: &[u8; 32], pool: ShieldedType) -> bool {
+        match pool {
+            ShieldedType::Sprout => self.sprout_nullifiers.contains(nullifier),
+            ShieldedType::Sapling => self.sapling_nullifiers.contains(nullifier),
+        }
+    }
+
+    /// Marks a nullifier as spent or, when undoing a spend, unspent.
+    pub fn set_nullifier(&mut self, nullifier: [u8; 32], pool: ShieldedType, spent: bool) {
+        let set = match pool {
+            ShieldedType::Sprout => &mut self.sprout_nullifiers,
+            ShieldedType::Sapling => &mut self.sapling_nullifiers,
+        };
+        if spent {
+            set.insert(nullifier);
+        } else {
+            set.remove(&nullifier);
+        }
+    }
+
+    /// Marks or unmarks all the nullifiers revealed by `tx`.
+    pub fn set_nullifiers(&mut self, tx: &Transaction, spent: bool) {
+        for join_split in &tx.join_splits {
+            for nullifier in &join_split.nullifiers {
+                self.set_nullifier(*nullifier, ShieldedType::Sprout, spent);
+            }
+        }
+        for spend in &tx.shielded_spends {
+            self.set_nullifier(spend.nullifier, ShieldedType::Sapling, spent);
+        }
+    }
+
+    /// Returns the Sprout tree with root `anchor`, if it is a known anchor.
+    pub fn get_sprout_anchor_at(&self, anchor: &[u8; 32]) -> Option<SproutMerkleTree> {
+        if *anchor == SproutMerkleTree::empty_root() {
+            return Some(SproutMerkleTree::new());
+        }
+        self.sprout_anchors.get(anchor).cloned()
+    }
+
+    /// Returns the Sapling tree with root `anchor`, if it is a known anchor.
+    pub fn get_sapling_anchor_at(&self, anchor: &[u8; 32]) -> Option<SaplingMerkleTree> {
+        if *anchor == SAPLING_EMPTY_ROOT {
+            return Some(SaplingMerkleTree::new());
+        }
+        self.sapling_anchors.get(anchor).cloned()
+    }
+
+    /// Checks if `anchor` is a known Sapling tree root.
+    pub fn have_sapling_anchor(&self, anchor: &[u8; 32]) -> bool {
+        *anchor == SAPLING_EMPTY_ROOT || self.sapling_anchors.contains_key(anchor)
+    }
+
+    /// Root of the tree after the best block.
+    pub fn get_best_anchor(&self, pool: ShieldedType) -> [u8; 32] {
+        match pool {
+            ShieldedType::Sprout => self.best_sprout_anchor,
+            ShieldedType::Sapling => self.best_sapling_anchor,
+        }
+    }
+
+    /// Records the Sprout tree after a block as the best anchor.
+    pub fn push_sprout_anchor(&mut self, tree: SproutMerkleTree) {
+        let root = tree.root();
+        if root != self.best_sprout_anchor {
+            self.sprout_anchors.insert(root, tree);
+            self.best_sprout_anchor = root;
+        }
+    }
+
+    /// Records the Sapling tree after a block as the best anchor.
+    pub fn push_sapling_anchor(&mut self, tree: SaplingMerkleTree) {
+        let root = tree.root();
+        if root != self.best_sapling_anchor {
+            self.sapling_anchors.insert(root, tree);
+            self.best_sapling_anchor = root;
+        }
+    }
+
+    /// Forgets the best anchor when disconnecting a block, making
+    /// `new_best` the best anchor again.
+    pub fn pop_anchor(&mut self, new_best: [u8; 32], pool: ShieldedType) {
+        match pool {
+            ShieldedType::Sprout => {
+                if self.best_sprout_anchor != new_best {
+                    self.sprout_anchors.remove(&self.best_sprout_anchor);
+                    self.best_sprout_anchor = new_best;
+                }
+            }
+            ShieldedType::Sapling => {
+                if self.best_sapling_anchor != new_best {
+                    self.sapling_anchors.remove(&self.best_sapling_anchor);
+                    self.best_sapling_anchor = new_best;
+                }
+            }
+        }
+    }
+
+    /// Hash of the block this state corresponds to.
+    pub fn best_block(&self) -> [u8; 32] {
+        self.best_block
+    }
+
+    pub fn set_best_block(&mut self, hash: [u8; 32]) {
+        self.best_block = hash;
+    }
+}
+
+impl CoinsView for UTXOSet {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.access_coin(outpoint).cloned()
+    }
+
+    fn have_coin(&self, outpoint: &OutPoint) -> bool {
+        UTXOSet::have_coin(self, outpoint)
+    }
+
+    fn best_block(&self) -> [u8; 32] {
+        self.best_block
+    }
+
+    fn get_nullifier(&self, nullifier: &[u8; 32], pool: ShieldedType) -> bool {
+        UTXOSet::get_nullifier(self, nullifier, pool)
+    }
+
+    fn get_sprout_anchor_at(&self, anchor: &[u8; 32]) -> Option<SproutMerkleTree> {
+        UTXOSet::get_sprout_anchor_at(self, anchor)
+    }
+
+    fn get_sapling_anchor_at(&self, anchor: &[u8; 32]) -> Option<SaplingMerkleTree> {
+        UTXOSet::get_sapling_anchor_at(self, anchor)
+    }
+
+    fn have_sapling_anchor(&self, anchor: &[u8; 32]) -> bool {
+        UTXOSet::have_sapling_anchor(self, anchor)
+    }
+
+    fn get_best_anchor(&self, pool: ShieldedType) -> [u8; 32] {
+        UTXOSet::get_best_anchor(self, pool)
+    }
+
+    fn batch_write(&mut self, batch: CoinsBatch) -> Result<(), CoinsViewError> {
+        for (outpoint, entry) in batch.coins.into_iter().filter(|(_, entry)| entry.is_dirty()) {
+            match entry.value {
+                Some(coin) => self.add_coin(outpoint, coin),
+                None => {
+                    self.map_coins.remove(&outpoint);
+                }
+            }
+        }
+        let nullifiers = [
+            (batch.sprout_nullifiers, ShieldedType::Sprout),
+            (batch.sapling_nullifiers, ShieldedType::Sapling),
+        ];
+        for (entries, pool) in nullifiers {
+            for (nullifier, entry) in entries.into_iter().filter(|(_, entry)| entry.is_dirty()) {
+                self.set_nullifier(nullifier, pool, entry.value);
+            }
+        }
+        for (root, entry) in batch.sprout_anchors.into_iter().filter(|(_, e)| e.is_dirty()) {
+            match entry.value {
+                Some(tree) => self.sprout_anchors.insert(root, tree),
+                None => self.sprout_anchors.remove(&root),
+            };
+        }
+        for (root, entry) in batch.sapling_anchors.into_iter().filter(|(_, e)| e.is_dirty()) {
+            match entry.value {
+                Some(tree) => self.sapling_anchors.insert(root, tree),
+                None => self.sapling_anchors.remove(&root),
+            };
+        }
+        self.best_sprout_anchor = batch.best_sprout_anchor;
+        self.best_sapling_anchor = batch.best_sapling_anchor;
+        self.best_block = batch.best_block;
+        Ok(())
+    }
+}
+
+/// A cache on top of another view that tracks which entries changed, so
+/// that only those are written back when it is flushed. Validation applies
+/// each block to a cache of its own and flushes it into the long-lived
+/// cache of the chainstate, which is flushed to the database when it
+/// outgrows its memory budget.
+pub struct CoinsViewCache<'a> {
+    base: Box<dyn CoinsView + 'a>,
+    // Lookups that miss are cached too, so reads need interior mutability
+    cache: RefCell<CoinsBatch>,
+}
+
+impl<'a> CoinsViewCache<'a> {
+    /// Creates an empty cache on top of `base`.
+    pub fn new(base: impl CoinsView + 'a) -> Self {
+        let cache = CoinsBatch {
+            best_sprout_anchor: base.get_best_anchor(ShieldedType::Sprout),
+            best_sapling_anchor: base.get_best_anchor(ShieldedType::Sapling),
+            best_block: base.best_block(),
+            ..Default::default()
+        };
+        CoinsViewCache {
+            base: Box::new(base),
+            cache: RefCell::new(cache),
+        }
+    }
+
+    /// The view this cache reads from and flushes to.
+    pub fn base(&self) -> &dyn CoinsView {
+        self.base.as_ref()
+    }
+
+    fn fetch_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        if let Some(entry) = self.cache.borrow().coins.get(outpoint) {
+            return entry.value.clone();
+        }
+        let coin = self.base.get_coin(outpoint);
+        // The base doesn't have the coin, so nothing needs flushing if it
+        // is added and spent again here
+        let flags = if coin.is_none() { FRESH } else { 0 };
+        let entry = CacheEntry::new(coin.clone(), flags);
+        self.cache.borrow_mut().coins.insert(outpoint.clone(), entry);
+        coin
+    }
+
+    /// Adds a coin. Unless `possible_overwrite` is set, the caller must know
+    /// that the coin is not already unspent.
+    pub fn add_coin(&mut self, outpoint: OutPoint, coin: Coin, possible_overwrite: bool) {
+        if coin.output.script_pubkey.is_unspendable() {
+            return;
+        }
+        let entry = self.cache.get_mut().coins.entry(outpoint).or_insert(CacheEntry::new(None, 0));
+        let mut fresh = false;
+        if !possible_overwrite {
+            assert!(entry.value.is_none(), "attempted to overwrite an unspent coin");
+            // A dirty spent entry still has to tell the base about the spend,
+            // so it can't be dropped if the coin is spent again
+            fresh = !entry.is_dirty();
+        }
+        entry.value = Some(coin);
+        entry.flags |= DIRTY | if fresh { FRESH } else { 0 };
+    }
+
+    /// Adds the spendable outputs of `tx` as coins created at `height`.
+    pub fn add_coins(&mut self, tx: &Transaction, height: u32) {
+        let txid = tx.hash();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint {
+                txid,
+                index: index as u32,
+            };
+            // Coinbases of early blocks could share a txid, so they may
+            // overwrite an unspent coin
+            let coin = Coin::new(output.clone(), height, tx.is_coinbase());
+            self.add_coin(outpoint, coin, tx.is_coinbase());
+        }
+    }
+
+    /// Spends a coin, returning it if it was unspent.
+    pub fn spend_coin(&mut self, outpoint: &OutPoint) -> Option<Coin> {
+        let coin = self.fetch_coin(outpoint)?;
+        let coins = &mut self.cache.get_mut().coins;
+        let entry = coins.get_mut(outpoint).expect("fetched coins are cached");
+        if entry.is_fresh() {
+            coins.remove(outpoint);
+        } else {
+            entry.value = None;
+            entry.flags |= DIRTY;
+        }
+        Some(coin)
+    }
+
+    /// Drops a coin from the cache if it has no changes to flush, e.g. after
+    /// looking it up for a transaction that was rejected.
+    pub fn uncache(&self, outpoint: &OutPoint) {
+        let mut cache = self.cache.borrow_mut();
+        if cache.coins.get(outpoint).is_some_and(|entry| entry.flags == 0) {
+            cache.coins.remove(outpoint);
+        }
+    }
+
+    /// Marks a nullifier as spent or, when undoing a spend, unspent.
+    pub fn set_nullifier(&mut self, nullifier: [u8; 32], pool: ShieldedType, spent: bool) {
+        let entry = CacheEntry::new(spent, DIRTY);
+        self.cache.get_mut().nullifiers_mut(pool).insert(nullifier, entry);
+    }
+
+    /// Marks or unmarks all the nullifiers revealed by `tx`.
+    pub fn set_nullifiers(&mut self, tx: &Transaction, spent: bool) {
+        for join_split in &tx.join_splits {
+            for nullifier in &join_split.nullifiers {
+                self.set_nullifier(*nullifier, ShieldedType::Sprout, spent);
+            }
+        }
+        for spend in &tx.shielded_spends {
+            self.set_nullifier(spend.nullifier, ShieldedType::Sapling, spent);
+        }
+    }
+
+    /// Records the Sprout tree after a block as the best anchor.
+    pub fn push_sprout_anchor(&mut self, tree: SproutMerkleTree) {
+        let cache = self.cache.get_mut();
+        let root = tree.root();
+        if root != cache.best_sprout_anchor {
+            cache.sprout_anchors.insert(root, CacheEntry::new(Some(tree), DIRTY));
+            cache.best_sprout_anchor = root;
+        }
+    }
+
+    /// Records the Sapling tree after a block as the best anchor.
+    pub fn push_sapling_anchor(&mut self, tree: SaplingMerkleTree) {
+        let cache = self.cache.get_mut();
+        let root = tree.root();
+        if root != cache.best_sapling_anchor {
+            cache.sapling_anchors.insert(root, CacheEntry::new(Some(tree), DIRTY));
+            cache.best_sapling_anchor = root;
+        }
+    }
+
+    /// Forgets the best anchor when disconnecting a block, making
+    /// `new_best` the best anchor again.
+    pub fn pop_anchor(&mut self, new_best: [u8; 32], pool: ShieldedType) {
+        let cache = self.cache.get_mut();
+        match pool {
+            ShieldedType::Sprout => {
+                if cache.best_sprout_anchor != new_best {
+                    let entry = CacheEntry::new(None, DIRTY);
+                    cache.sprout_anchors.insert(cache.best_sprout_anchor, entry);
+                    cache.best_sprout_anchor = new_best;
+                }
+            }
+            ShieldedType::Sapling => {
+                if cache.best_sapling_anchor != new_best {
+                    let entry = CacheEntry::new(None, DIRTY);
+                    cache.sapling_anchors.insert(cache.best_sapling_anchor, entry);
+                    cache.best_sapling_anchor = new_best;
+                }
+            }
+        }
+    }
+
+    pub fn set_best_block(&mut self, hash: [u8; 32]) {
+        self.cache.get_mut().best_block = hash;
+    }
+
+    /// Number of coins held by the cache, including spent ones.
+    pub fn cache_size(&self) -> usize {
+        self.cache.borrow().coins.len()
+    }
+
+    /// Estimates the memory used by the cached entries.
+    pub fn dynamic_memory_usage(&self) -> usize {
+        let cache = self.cache.borrow();
+        let scripts: usize = cache
+            .coins
+            .values()
+            .filter_map(|entry| entry.value.as_ref())
+            .map(|coin| mem_usage_slice(coin.output.script_pubkey.as_bytes()))
+            .sum();
+        let sprout_trees: usize = cache
+            .sprout_anchors
+            .values()
+            .filter_map(|entry| entry.value.as_ref())
+            .map(SproutMerkleTree::dynamic_memory_usage)
+            .sum();
+        let sapling_trees: usize = cache
+            .sapling_anchors
+            .values()
+            .filter_map(|entry| entry.value.as_ref())
+            .map(SaplingMerkleTree::dynamic_memory_usage)
+            .sum();
+        mem_usage_hashmap(&cache.coins)
+            + scripts
+            + mem_usage_hashmap(&cache.sprout_nullifiers)
+            + mem_usage_hashmap(&cache.sapling_nullifiers)
+            + mem_usage_hashmap(&cache.sprout_anchors)
+            + sprout_trees
+            + mem_usage_hashmap(&cache.sapling_anchors)
+            + sapling_trees
+    }
+
+    /// Writes all changes to the base view in one batch, together with the
+    /// best block, and empties the cache. If this fails the changes are
+    /// lost and the base view no longer matches the chain.
+    pub fn flush(&mut self) -> Result<(), CoinsViewError> {
+        let cache = self.cache.get_mut();
+        let batch = mem::take(cache);
+        cache.best_sprout_anchor = batch.best_sprout_anchor;
+        cache.best_sapling_anchor = batch.best_sapling_anchor;
+        cache.best_block = batch.best_block;
+        self.base.batch_write(batch)
+    }
+}
+
+impl CoinsView for CoinsViewCache<'_> {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.fetch_coin(outpoint)
+    }
+
+    fn best_block(&self) -> [u8; 32] {
+        self.cache.borrow().best_block
+    }
+
+    fn get_nullifier(&self, nullifier: &[u8; 32], pool: ShieldedType) -> bool {
+        if let Some(entry) = self.cache.borrow_mut().nullifiers_mut(pool).get(nullifier) {
+            return entry.value;
+        }
+        let spent = self.base.get_nullifier(nullifier, pool);
+        let entry = CacheEntry::new(spent, 0);
+        self.cache.borrow_mut().nullifiers_mut(pool).insert(*nullifier, entry);
+        spent
+    }
+
+    fn get_sprout_anchor_at(&self, anchor: &[u8; 32]) -> Option<SproutMerkleTree> {
+        if let Some(entry) = self.cache.borrow().sprout_anchors.get(anchor) {
+            return entry.value.clone();
+        }
+        let tree = self.base.get_sprout_anchor_at(anchor);
+        let entry = CacheEntry::new(tree.clone(), 0);
+        self.cache.borrow_mut().sprout_anchors.insert(*anchor, entry);
+        tree
+    }
+
+    fn get_sapling_anchor_at(&self, anchor: &[u8; 32]) -> Option<SaplingMerkleTree> {
+        if let Some(entry) = self.cache.borrow().sapling_anchors.get(anchor) {
+            return entry.value.clone();
+        }
+        let tree = self.base.get_sapling_anchor_at(anchor);
+        let entry = CacheEntry::new(tree.clone(), 0);
+        self.cache.borrow_mut().sapling_anchors.insert(*anchor, entry);
+        tree
+    }
+
+    fn get_best_anchor(&self, pool: ShieldedType) -> [u8; 32] {
+        let cache = self.cache.borrow();
+        match pool {
+            ShieldedType::Sprout => cache.best_sprout_anchor,
+            ShieldedType::Sapling => cache.best_sapling_anchor,
+        }
+    }
+
+    fn batch_write(&mut self, batch: CoinsBatch) -> Result<(), CoinsViewError> {
+        let cache = self.cache.get_mut();
+        for (outpoint, child) in batch.coins.into_iter().filter(|(_, entry)| entry.is_dirty()) {
+            match cache.coins.get_mut(&outpoint) {
+                None => {
+                    // A fresh coin spent in the child never existed here
+                    if !(child.is_fresh() && child.value.is_none()) {
+                        // It can only stay fresh if it was fresh in the child,
+                        // otherwise the base of this cache may have it
+                        let entry = CacheEntry::new(child.value, DIRTY | (child.flags & FRESH));
+                        cache.coins.insert(outpoint, entry);
+                    }
+                }
+                Some(parent) => {
+                    assert!(
+                        !(child.is_fresh() && parent.value.is_some()),
+                        "FRESH flag misapplied to a coin that exists in the parent cache"
+                    );
+                    if parent.is_fresh() && child.value.is_none() {
+                        cache.coins.remove(&outpoint);
+                    } else {
+                        parent.value = child.value;
+                        parent.flags |= DIRTY;
+                    }
+                }
+            }
+        }
+        merge_dirty(&mut cache.sprout_nullifiers, batch.sprout_nullifiers);
+        merge_dirty(&mut cache.sapling_nullifiers, batch.sapling_nullifiers);
+        merge_dirty(&mut cache.sprout_anchors, batch.sprout_anchors);
+        merge_dirty(&mut cache.sapling_anchors, batch.sapling_anchors);
+        cache.best_sprout_anchor = batch.best_sprout_anchor;
+        cache.best_sapling_anchor = batch.best_sapling_anchor;
+        cache.best_block = batch.best_block;
+        Ok(())
+    }
+}
+
+/// Copies the dirty entries of a child cache into its parent
+fn merge_dirty<K: Eq + Hash, V>(
+    parent: &mut HashMap<K, CacheEntry<V>>,
+    child: HashMap<K, CacheEntry<V>>,
+) {
+    for (key, entry) in child.into_iter().filter(|(_, entry)| entry.is_dirty()) {
+        parent.insert(key, CacheEntry::new(entry.value, DIRTY));
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::script::Script;
 
     #[test]
     fn test_utxo_set_operations() {
         let mut utxo_set = UTXOSet::new();
 
         let outpoint = OutPoint {
-            txid: "abcd1234".to_string(),
+            txid: [0xab; 32],
             index: 0,
         };
 
-        let coin = Coin {
-            value: 5000,
-            script_pubkey: vec![0x76, 0xa9, 0x14], // Example P2PKH script
-            height: 100,
-            spent: false,
-        };
+        let coin = Coin::new(
+            TxOutput {
+                value: 5000,
+                script_pubkey: Script::new(vec![0x76, 0xa9, 0x14]), // Example P2PKH script
+            },
+            100,
+            false,
+        );
 
         // Add coin
         utxo_set.add_coin(outpoint.clone(), coin.clone());
         assert!(utxo_set.have_coin(&outpoint));
 
+        // Access coin
+        let fetched_coin = utxo_set.access_coin(&outpoint).unwrap();
+        assert_eq!(fetched_coin.output.value, 5000);
+
         // Spend coin
-        assert!(utxo_set.spend_coin(&outpoint));
+        assert_eq!(utxo_set.spend_coin(&outpoint), Some(coin));
         assert!(!utxo_set.have_coin(&outpoint));
+        assert_eq!(utxo_set.spend_coin(&outpoint), None);
+    }
 
-        // Access coin
-        let fetched_coin = utxo_set.access_coin(&outpoint).unwrap();
-        assert_eq!(fetched_coin.value, 5000);
-        assert!(fetched_coin.spent);
+    #[test]
+    fn test_anchors() {
+        let mut utxo_set = UTXOSet::new();
+        let empty = SproutMerkleTree::empty_root();
+        assert_eq!(utxo_set.get_best_anchor(ShieldedType::Sprout), empty);
+        assert_eq!(utxo_set.get_sprout_anchor_at(&empty), Some(SproutMerkleTree::new()));
+
+        let mut tree = SproutMerkleTree::new();
+        tree.append([1; 32]).unwrap();
+        let root = tree.root();
+        utxo_set.push_sprout_anchor(tree.clone());
+        assert_eq!(utxo_set.get_sprout_anchor_at(&root), Some(tree));
+
+        utxo_set.pop_anchor(empty, ShieldedType::Sprout);
+        assert_eq!(utxo_set.get_sprout_anchor_at(&root), None);
+        assert_eq!(utxo_set, UTXOSet::new());
+
+        let empty = SaplingMerkleTree::new();
+        assert_eq!(utxo_set.get_sapling_anchor_at(&SAPLING_EMPTY_ROOT), Some(empty));
+        let mut tree = SaplingMerkleTree::new();
+        tree.append([2; 32]).unwrap();
+        let root = tree.root();
+        utxo_set.push_sapling_anchor(tree.clone());
+        assert_eq!(utxo_set.get_sapling_anchor_at(&root), Some(tree));
+        utxo_set.pop_anchor(SAPLING_EMPTY_ROOT, ShieldedType::Sapling);
+        assert!(!utxo_set.have_sapling_anchor(&root));
+        assert_eq!(utxo_set, UTXOSet::new());
+    }
+
+    #[test]
+    fn test_sapling_empty_root() {
+        assert_eq!(SaplingMerkleTree::empty_root(), SAPLING_EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_nullifiers() {
+        let mut utxo_set = UTXOSet::new();
+        utxo_set.set_nullifier([3; 32], ShieldedType::Sapling, true);
+        assert!(utxo_set.get_nullifier(&[3; 32], ShieldedType::Sapling));
+        assert!(!utxo_set.get_nullifier(&[3; 32], ShieldedType::Sprout));
+        utxo_set.set_nullifier([3; 32], ShieldedType::Sapling, false);
+        assert_eq!(utxo_set, UTXOSet::new());
+    }
+
+    fn coin(value: i64) -> Coin {
+        let output = TxOutput {
+            value,
+            script_pubkey: Script::new(vec![0x51]),
+        };
+        Coin::new(output, 1, false)
+    }
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint {
+            txid: [n; 32],
+            index: 0,
+        }
+    }
+
+    #[test]
+    fn test_coin_serialization() {
+        // A P2PKH output of 600 coins at height 203998, from the upstream
+        // coins tests
+        let bytes = hex::decode("97f23c835800816115944e077fe7c803cfa57f29b36bf87c1d35").unwrap();
+        let coin = Coin::deserialize(&mut bytes.as_slice()).unwrap();
+        assert_eq!(coin.height, 203998);
+        assert!(!coin.is_coinbase);
+        assert_eq!(coin.output.value, 60000000000);
+        let script = hex::decode("76a914816115944e077fe7c803cfa57f29b36bf87c1d3588ac").unwrap();
+        assert_eq!(coin.output.script_pubkey.as_bytes(), &script[..]);
+
+        let mut written = Vec::new();
+        coin.serialize(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn test_cache_fresh_coins_skip_base() {
+        let mut base = UTXOSet::new();
+        let mut cache = CoinsViewCache::new(&mut base);
+        cache.add_coin(outpoint(1), coin(10), false);
+        assert!(cache.have_coin(&outpoint(1)));
+        assert_eq!(cache.spend_coin(&outpoint(1)), Some(coin(10)));
+        assert_eq!(cache.cache_size(), 0);
+
+        cache.set_best_block([7; 32]);
+        cache.flush().unwrap();
+        drop(cache);
+        let mut expected = UTXOSet::new();
+        expected.set_best_block([7; 32]);
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn test_nested_caches_flush_spends() {
+        let mut base = UTXOSet::new();
+        base.add_coin(outpoint(1), coin(10));
+        base.add_coin(outpoint(2), coin(20));
+
+        let mut chainstate = CoinsViewCache::new(&mut base);
+        let mut block_view = CoinsViewCache::new(&mut chainstate);
+        assert_eq!(block_view.spend_coin(&outpoint(1)), Some(coin(10)));
+        assert_eq!(block_view.spend_coin(&outpoint(1)), None);
+        block_view.add_coin(outpoint(3), coin(5), false);
+        block_view.set_nullifier([4; 32], ShieldedType::Sprout, true);
+        block_view.flush().unwrap();
+        drop(block_view);
+
+        assert!(!chainstate.have_coin(&outpoint(1)));
+        assert!(chainstate.have_coin(&outpoint(3)));
+        assert!(chainstate.get_nullifier(&[4; 32], ShieldedType::Sprout));
+        // Spending the coin added above makes it vanish without a trace
+        assert_eq!(chainstate.spend_coin(&outpoint(3)), Some(coin(5)));
+        chainstate.flush().unwrap();
+        drop(chainstate);
+
+        assert!(!base.have_coin(&outpoint(1)));
+        assert!(base.have_coin(&outpoint(2)));
+        assert!(!base.have_coin(&outpoint(3)));
+        assert!(base.get_nullifier(&[4; 32], ShieldedType::Sprout));
+    }
+
+    #[test]
+    fn test_cache_memory_usage() {
+        let mut base = UTXOSet::new();
+        let mut cache = CoinsViewCache::new(&mut base);
+        cache.add_coin(outpoint(0), coin(1), false);
+        let usage = cache.dynamic_memory_usage();
+        assert!(usage > 0);
+        for n in 1..100 {
+            cache.add_coin(outpoint(n), coin(n as i64 + 1), false);
+        }
+        assert!(cache.dynamic_memory_usage() > usage);
+        cache.flush().unwrap();
+        assert_eq!(cache.cache_size(), 0);
+        drop(cache);
+        assert_eq!(base.coins().count(), 100);
     }
 }
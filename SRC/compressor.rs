@@ -1,80 +1,250 @@
+use crate::primitives::transaction::TxOutput;
+use crate::script::opcodes::{OP_CHECKSIG, OP_DUP, OP_EQUAL, OP_EQUALVERIFY, OP_HASH160, OP_RETURN};
+use crate::script::{Script, MAX_SCRIPT_SIZE};
+use crate::serialize::{Deserializable, Serializable, SerializationError, VarInt};
+use secp256k1::PublicKey;
+use std::io::{self, Read, Write};
+
+/// Number of special script types, encoded in place of the script size
+pub const SPECIAL_SCRIPTS: u64 = 6;
+
 /// Compresses a transaction output amount.
-pub fn compress_amount(amount: u64) -> u64 {
+///
+/// Amounts are usually round numbers, so the trailing zeros are stored as
+/// an exponent `e` (at most 9) and the last non-zero digit `d` separately:
+/// the result is `1 + 10 * (9 * n + d - 1) + e` if `e < 9`, and
+/// `1 + 10 * (n - 1) + 9` otherwise, where `n` is what's left.
+pub fn compress_amount(mut amount: u64) -> u64 {
     if amount == 0 {
-        0
-    } else if amount <= 10 {
-        amount + 1
-    } else if amount < 1_000_000 {
-        let e = (amount as f64).log10().floor() as u64;
-        let base = 10u64.pow(e as u32);
-        let mantissa = amount / base - 1;
-        let exponent = e + 9;
-        mantissa * 10 + exponent
+        return 0;
+    }
+    let mut e = 0;
+    while amount.is_multiple_of(10) && e < 9 {
+        amount /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = amount % 10;
+        assert!((1..=9).contains(&d));
+        amount /= 10;
+        1 + (amount * 9 + d - 1) * 10 + e
     } else {
-        amount / 100_000_000 + 100
+        1 + (amount - 1) * 10 + 9
     }
 }
 
 /// Decompresses a compressed transaction output amount.
-pub fn decompress_amount(x: u64) -> u64 {
+pub fn decompress_amount(mut x: u64) -> u64 {
     if x == 0 {
-        0
-    } else if x <= 10 {
-        x - 1
-    } else if x < 111 {
-        let e = x % 10;
-        let mantissa = x / 10 - 1;
-        let base = 10u64.pow((e - 9) as u32);
-        (mantissa + 1) * base
+        return 0;
+    }
+    x -= 1;
+    // x = 10 * (9 * n + d - 1) + e
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        // x = 9 * n + d - 1
+        let d = (x % 9) + 1;
+        x /= 9;
+        // x = n
+        x.wrapping_mul(10).wrapping_add(d)
     } else {
-        (x - 100) * 100_000_000
+        x.wrapping_add(1)
+    };
+    while e > 0 {
+        n = n.wrapping_mul(10);
+        e -= 1;
     }
+    n
 }
 
-/// Compresses a scriptPubKey into a compressed format.
+/// Compresses the common script templates: P2PKH and P2SH become a type
+/// byte (0 or 1) and the 20-byte hash, pay-to-pubkey a type byte (2 to 5)
+/// and the x coordinate. Uncompressed keys are stored with the parity of
+/// y in the type, so they must be valid points to be recovered.
 pub fn compress_script(script: &[u8]) -> Option<Vec<u8>> {
-    if script.len() == 25 && script[0] == 0x76 && script[1] == 0xa9 && script[2] == 0x14 && script[23] == 0x88 && script[24] == 0xac {
-        // P2PKH
-        Some(script[3..23].to_vec())
-    } else if script.len() == 23 && script[0] == 0xa9 && script[1] == 0x14 && script[22] == 0x87 {
-        // P2SH
-        Some(script[2..22].to_vec())
-    } else {
-        None
+    match script.len() {
+        25 if script[..3] == [OP_DUP, OP_HASH160, 20]
+            && script[23..] == [OP_EQUALVERIFY, OP_CHECKSIG] =>
+        {
+            let mut out = vec![0x00];
+            out.extend_from_slice(&script[3..23]);
+            Some(out)
+        }
+        23 if script[..2] == [OP_HASH160, 20] && script[22] == OP_EQUAL => {
+            let mut out = vec![0x01];
+            out.extend_from_slice(&script[2..22]);
+            Some(out)
+        }
+        35 if script[0] == 33 && script[34] == OP_CHECKSIG && matches!(script[1], 0x02 | 0x03) => {
+            Some(script[1..34].to_vec())
+        }
+        67 if script[0] == 65 && script[66] == OP_CHECKSIG && script[1] == 0x04 => {
+            // Not fully valid keys couldn't be decompressed again
+            PublicKey::from_slice(&script[1..66]).ok()?;
+            let mut out = vec![0x04 | (script[65] & 0x01)];
+            out.extend_from_slice(&script[2..34]);
+            Some(out)
+        }
+        _ => None,
+    }
+}
+
+/// Number of bytes that follow the type of a compressed script.
+pub fn special_script_size(script_type: u64) -> usize {
+    match script_type {
+        0 | 1 => 20,
+        2..=5 => 32,
+        _ => 0,
     }
 }
 
-/// Decompresses a scriptPubKey from its compressed format.
-pub fn decompress_script(data: &[u8], script_type: u8) -> Option<Vec<u8>> {
+/// Rebuilds a script from its type and the bytes that followed it.
+pub fn decompress_script(script_type: u64, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() != special_script_size(script_type) {
+        return None;
+    }
     match script_type {
         0 => {
-            // P2PKH
-            let mut script = vec![0x76, 0xa9, 0x14];
+            let mut script = vec![OP_DUP, OP_HASH160, 20];
             script.extend_from_slice(data);
-            script.extend_from_slice(&[0x88, 0xac]);
+            script.extend_from_slice(&[OP_EQUALVERIFY, OP_CHECKSIG]);
             Some(script)
         }
         1 => {
-            // P2SH
-            let mut script = vec![0xa9, 0x14];
+            let mut script = vec![OP_HASH160, 20];
             script.extend_from_slice(data);
-            script.push(0x87);
+            script.push(OP_EQUAL);
+            Some(script)
+        }
+        2 | 3 => {
+            let mut script = vec![33, script_type as u8];
+            script.extend_from_slice(data);
+            script.push(OP_CHECKSIG);
+            Some(script)
+        }
+        4 | 5 => {
+            let mut compressed = vec![script_type as u8 - 2];
+            compressed.extend_from_slice(data);
+            let pubkey = PublicKey::from_slice(&compressed).ok()?;
+            let mut script = vec![65];
+            script.extend_from_slice(&pubkey.serialize_uncompressed());
+            script.push(OP_CHECKSIG);
             Some(script)
         }
         _ => None,
     }
 }
 
+/// Serializes a script as its compressed form if it has one, and as the
+/// raw script after its size plus `SPECIAL_SCRIPTS` otherwise.
+pub struct ScriptCompressor(pub Script);
+
+impl Serializable for ScriptCompressor {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        // The type byte doubles as a one-byte VarInt
+        if let Some(compressed) = compress_script(self.0.as_bytes()) {
+            writer.write_all(&compressed)?;
+            return Ok(());
+        }
+        VarInt(self.0.len() as u64 + SPECIAL_SCRIPTS).serialize(writer)?;
+        writer.write_all(self.0.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Deserializable for ScriptCompressor {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        let size = VarInt::deserialize(reader)?.0;
+        if size < SPECIAL_SCRIPTS {
+            let mut data = vec![0; special_script_size(size)];
+            reader.read_exact(&mut data)?;
+            let script = decompress_script(size, &data).ok_or(SerializationError::InvalidData)?;
+            return Ok(ScriptCompressor(Script::new(script)));
+        }
+
+        let size = size - SPECIAL_SCRIPTS;
+        if size > MAX_SCRIPT_SIZE as u64 {
+            // Overly long scripts are unspendable, so keep a short
+            // unspendable script instead
+            io::copy(&mut reader.by_ref().take(size), &mut io::sink())?;
+            return Ok(ScriptCompressor(Script::new(vec![OP_RETURN])));
+        }
+        let mut script = vec![0; size as usize];
+        reader.read_exact(&mut script)?;
+        Ok(ScriptCompressor(Script::new(script)))
+    }
+}
+
+/// Serializes a transaction output as its compressed amount followed by
+/// its compressed script.
+pub struct TxOutCompressor(pub TxOutput);
+
+impl Serializable for TxOutCompressor {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        VarInt(compress_amount(self.0.value as u64)).serialize(writer)?;
+        ScriptCompressor(self.0.script_pubkey.clone()).serialize(writer)
+    }
+}
+
+impl Deserializable for TxOutCompressor {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        let value = decompress_amount(VarInt::deserialize(reader)?.0) as i64;
+        let script_pubkey = ScriptCompressor::deserialize(reader)?.0;
+        Ok(TxOutCompressor(TxOutput {
+            value,
+            script_pubkey,
+        }))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::amount::COIN;
+
+    const CENT: u64 = COIN as u64 / 100;
+
+    fn round_trip(script: Vec<u8>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ScriptCompressor(Script::new(script)).serialize(&mut bytes).unwrap();
+        let script = ScriptCompressor::deserialize(&mut bytes.as_slice()).unwrap().0;
+        script.as_bytes().to_vec()
+    }
+
+    fn compressed_len(script: &[u8]) -> usize {
+        let mut bytes = Vec::new();
+        ScriptCompressor(Script::new(script.to_vec())).serialize(&mut bytes).unwrap();
+        bytes.len()
+    }
+
+    #[test]
+    fn test_amount_reference_vectors() {
+        let vectors = [
+            (0, 0x0),
+            (1, 0x1),
+            (CENT, 0x7),
+            (COIN as u64, 0x9),
+            (50 * COIN as u64, 0x32),
+            (21_000_000 * COIN as u64, 0x1406f40),
+        ];
+        for (amount, compressed) in vectors {
+            assert_eq!(compress_amount(amount), compressed);
+            assert_eq!(decompress_amount(compressed), amount);
+        }
+    }
 
     #[test]
     fn test_compress_decompress_amount() {
-        let original = 12345;
-        let compressed = compress_amount(original);
-        let decompressed = decompress_amount(compressed);
-        assert_eq!(original, decompressed);
+        for amount in (0..100_000).chain((0..100_000).map(|n| n * CENT)) {
+            assert_eq!(decompress_amount(compress_amount(amount)), amount);
+        }
+        for amount in (0..10_000).map(|n| n * COIN as u64) {
+            assert_eq!(decompress_amount(compress_amount(amount)), amount);
+        }
+        for compressed in 0..100_000 {
+            assert_eq!(compress_amount(decompress_amount(compressed)), compressed);
+        }
     }
 
     #[test]
@@ -84,7 +254,68 @@ mod tests {
             0xd9, 0xe8, 0xf7, 0x06, 0x15, 0x24, 0x33, 0x88, 0xac,
         ];
         let compressed = compress_script(&script).unwrap();
-        let decompressed = decompress_script(&compressed, 0).unwrap();
+        assert_eq!(compressed[0], 0);
+        let decompressed = decompress_script(0, &compressed[1..]).unwrap();
         assert_eq!(script, decompressed);
+        assert_eq!(compressed_len(&script), 21);
+        assert_eq!(round_trip(script.clone()), script);
+
+        let mut p2sh = vec![OP_HASH160, 20];
+        p2sh.extend_from_slice(&[0x42; 20]);
+        p2sh.push(OP_EQUAL);
+        assert_eq!(compressed_len(&p2sh), 21);
+        assert_eq!(round_trip(p2sh.clone()), p2sh);
+    }
+
+    #[test]
+    fn test_compress_pubkey_scripts() {
+        let secp = secp256k1::Secp256k1::new();
+        let key = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &key);
+
+        let mut compressed = vec![33];
+        compressed.extend_from_slice(&pubkey.serialize());
+        compressed.push(OP_CHECKSIG);
+        assert_eq!(compressed_len(&compressed), 33);
+        assert_eq!(round_trip(compressed.clone()), compressed);
+
+        let mut uncompressed = vec![65];
+        uncompressed.extend_from_slice(&pubkey.serialize_uncompressed());
+        uncompressed.push(OP_CHECKSIG);
+        assert_eq!(compress_script(&uncompressed).unwrap()[0], 4 | (uncompressed[65] & 1));
+        assert_eq!(compressed_len(&uncompressed), 33);
+        assert_eq!(round_trip(uncompressed.clone()), uncompressed);
+
+        // An uncompressed key that isn't on the curve is kept as is
+        let mut invalid = uncompressed.clone();
+        invalid[40] ^= 1;
+        assert!(compress_script(&invalid).is_none());
+        assert_eq!(compressed_len(&invalid), 1 + invalid.len());
+        assert_eq!(round_trip(invalid.clone()), invalid);
+    }
+
+    #[test]
+    fn test_other_scripts() {
+        let script = vec![OP_RETURN, 4, 1, 2, 3, 4];
+        let mut bytes = Vec::new();
+        ScriptCompressor(Script::new(script.clone())).serialize(&mut bytes).unwrap();
+        assert_eq!(bytes[0] as usize, script.len() + SPECIAL_SCRIPTS as usize);
+        assert_eq!(round_trip(script.clone()), script);
+        assert_eq!(round_trip(Vec::new()), Vec::<u8>::new());
+
+        let long = vec![OP_DUP; MAX_SCRIPT_SIZE + 1];
+        assert_eq!(round_trip(long), vec![OP_RETURN]);
+    }
+
+    #[test]
+    fn test_txout_round_trip() {
+        let output = TxOutput {
+            value: 50 * COIN,
+            script_pubkey: Script::new(vec![OP_DUP; 3]),
+        };
+        let mut bytes = Vec::new();
+        TxOutCompressor(output.clone()).serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, [0x32, 3 + SPECIAL_SCRIPTS as u8, OP_DUP, OP_DUP, OP_DUP]);
+        assert_eq!(TxOutCompressor::deserialize(&mut bytes.as_slice()).unwrap().0, output);
     }
 }
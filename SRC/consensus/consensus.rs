@@ -0,0 +1,12 @@
+/// The maximum allowed size for a serialized block, in bytes
+pub const MAX_BLOCK_SIZE: usize = 2_000_000;
+/// The maximum allowed number of signature check operations in a block
+pub const MAX_BLOCK_SIGOPS: usize = 20_000;
+/// The maximum size of a transaction before Sapling, in bytes
+pub const MAX_TX_SIZE_BEFORE_SAPLING: usize = 100_000;
+/// The maximum size of a transaction after Sapling, in bytes
+pub const MAX_TX_SIZE_AFTER_SAPLING: usize = MAX_BLOCK_SIZE;
+/// Coinbase outputs can only be spent after this number of new blocks
+pub const COINBASE_MATURITY: u32 = 100;
+/// Expiry heights at or above this value are invalid
+pub const TX_EXPIRY_HEIGHT_THRESHOLD: u32 = 500_000_000;
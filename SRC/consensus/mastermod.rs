@@ -1,4 +1,6 @@
+pub mod consensus;
 pub mod params;
+pub mod subsidy;
 pub mod merkle;
 pub mod upgrades;
 pub mod validation;
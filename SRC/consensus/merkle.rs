@@ -1,32 +1,83 @@
-use crate::uint256::Uint256;
+use crate::hash::hash256;
+use crate::primitives::block::Block;
+
+/// Computes the Merkle root of `leaves`, duplicating the last hash of odd
+/// levels.
+///
+/// The second value reports whether two identical hashes were paired at any
+/// level. Such a tree has the same root as one with the duplicated
+/// transactions removed (CVE-2012-2459), so a block with a mutated tree must
+/// be rejected without marking its hash as permanently invalid.
+pub fn compute_merkle_root(leaves: &[[u8; 32]]) -> ([u8; 32], bool) {
+    if leaves.is_empty() {
+        return ([0; 32], false);
+    }
 
-pub fn compute_merkle_root(leaves: &[Uint256]) -> (Uint256, bool) {
     let mut mutated = false;
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        for pair in level.chunks_exact(2) {
+            if pair[0] == pair[1] {
+                mutated = true;
+            }
+        }
+        if level.len() % 2 == 1 {
+            level.push(level[level.len() - 1]);
+        }
 
-    if leaves.is_empty() {
-        return (Uint256::zero(), false);
+        level = level
+            .chunks_exact(2)
+            .map(|pair| {
+                let mut buffer = [0u8; 64];
+                buffer[..32].copy_from_slice(&pair[0]);
+                buffer[32..].copy_from_slice(&pair[1]);
+                hash256(&buffer)
+            })
+            .collect();
     }
 
-    let mut tree = leaves.to_vec();
-    while tree.len() > 1 {
-        let mut next_level = Vec::new();
+    (level[0], mutated)
+}
+
+/// Computes the Merkle root of a block's transaction ids
+pub fn block_merkle_root(block: &Block) -> ([u8; 32], bool) {
+    let leaves: Vec<[u8; 32]> = block.transactions.iter().map(|tx| tx.hash()).collect();
+    compute_merkle_root(&leaves)
+}
 
-        for i in (0..tree.len()).step_by(2) {
-            let left = &tree[i];
-            let right = if i + 1 < tree.len() {
-                &tree[i + 1]
-            } else {
-                left
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            if left == right {
-                mutated = true;
-            }
+    fn leaf(tag: u8) -> [u8; 32] {
+        [tag; 32]
+    }
 
-            next_level.push(Uint256::hash(&[left.as_bytes(), right.as_bytes()].concat()));
-        }
-        tree = next_level;
+    fn hash_pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        let mut buffer = left.to_vec();
+        buffer.extend_from_slice(&right);
+        hash256(&buffer)
     }
 
-    (tree[0].clone(), mutated)
+    #[test]
+    fn test_single_leaf_is_root() {
+        assert_eq!(compute_merkle_root(&[leaf(1)]), (leaf(1), false));
+        assert_eq!(compute_merkle_root(&[]), ([0; 32], false));
+    }
+
+    #[test]
+    fn test_odd_level_duplicates_last() {
+        let (root, mutated) = compute_merkle_root(&[leaf(1), leaf(2), leaf(3)]);
+        let expected = hash_pair(hash_pair(leaf(1), leaf(2)), hash_pair(leaf(3), leaf(3)));
+        assert_eq!(root, expected);
+        assert!(!mutated);
+    }
+
+    #[test]
+    fn test_duplicate_transactions_are_detected() {
+        // [1, 2, 3] and [1, 2, 3, 3] share a root; only the latter is mutated
+        let (root, mutated) = compute_merkle_root(&[leaf(1), leaf(2), leaf(3), leaf(3)]);
+        assert_eq!(root, compute_merkle_root(&[leaf(1), leaf(2), leaf(3)]).0);
+        assert!(mutated);
+    }
 }
@@ -1,16 +1,189 @@
-use crate::uint256::Uint256;
+use crate::arith_uint256::ArithUint256;
+use crate::consensus::subsidy::FundingStream;
+use crate::consensus::upgrades::{NetworkUpgrade, ALWAYS_ACTIVE, MAX_NETWORK_UPGRADES};
+use crate::crypto::equihash::{EquihashParams, BITCOINZ_PERSONALIZATION, ZCASH_PERSONALIZATION};
 
+/// Transaction counts at a recent block, used to estimate how far the
+/// initial block download has got
+#[derive(Debug, Clone, Default)]
+pub struct ChainTxData {
+    /// Timestamp of the block
+    pub time: i64,
+    /// Number of transactions up to and including the block
+    pub tx_count: u64,
+    /// Estimated number of transactions per second after it
+    pub tx_rate: f64,
+}
+
+/// Consensus rules that differ between networks
+#[derive(Debug, Clone)]
 pub struct ConsensusParams {
-    pub pow_limit: Uint256,
+    /// Easiest allowed proof-of-work target
+    pub pow_limit: ArithUint256,
     pub pow_target_spacing: i64,
-    // Additional fields as needed
+    /// Number of blocks whose targets are averaged when retargeting
+    pub pow_averaging_window: i64,
+    /// Largest decrease in difficulty per block, in percent
+    pub pow_max_adjust_down: i64,
+    /// Largest increase in difficulty per block, in percent
+    pub pow_max_adjust_up: i64,
+    /// Keep the difficulty of the previous block instead of retargeting
+    pub pow_no_retargeting: bool,
+    /// Height after which a block may use the minimum difficulty if it
+    /// follows its parent by more than six block intervals
+    pub pow_allow_min_difficulty_blocks_after_height: Option<u32>,
+    /// Equihash parameters and the height each set takes effect, in
+    /// ascending order starting at genesis
+    pub equihash_schedule: Vec<(u32, EquihashParams)>,
+    /// Blocks after a set takes effect during which the previous set is
+    /// still accepted, like upstream's `validEHparameterList`
+    pub equihash_overlap: u32,
+    /// Activation height of each network upgrade, indexed by
+    /// `NetworkUpgrade`; None if the upgrade is not scheduled
+    pub activation_heights: [Option<u32>; MAX_NETWORK_UPGRADES],
+    /// Blocks between subsidy halvings
+    pub subsidy_halving_interval: u32,
+    /// Blocks over which the subsidy ramps up after genesis; 0 disables it
+    pub subsidy_slow_start_interval: u32,
+    /// Shares of the subsidy the coinbase must pay out
+    pub funding_streams: Vec<FundingStream>,
+    /// The node stays in initial block download until its tip has at least
+    /// this much chainwork
+    pub minimum_chain_work: ArithUint256,
+    pub chain_tx_data: ChainTxData,
 }
 
 impl ConsensusParams {
-    pub fn new(pow_limit: Uint256, pow_target_spacing: i64) -> Self {
+    pub fn new(pow_limit: ArithUint256, pow_target_spacing: i64, equihash_n: u32, equihash_k: u32) -> Self {
         ConsensusParams {
             pow_limit,
             pow_target_spacing,
+            pow_averaging_window: 17,
+            pow_max_adjust_down: 32,
+            pow_max_adjust_up: 16,
+            pow_no_retargeting: false,
+            pow_allow_min_difficulty_blocks_after_height: None,
+            equihash_schedule: vec![(
+                0,
+                EquihashParams::new(equihash_n, equihash_k, ZCASH_PERSONALIZATION),
+            )],
+            equihash_overlap: 0,
+            activation_heights: Self::base_activation_heights(),
+            subsidy_halving_interval: 840_000,
+            subsidy_slow_start_interval: 0,
+            funding_streams: Vec::new(),
+            minimum_chain_work: ArithUint256::zero(),
+            chain_tx_data: ChainTxData::default(),
+        }
+    }
+
+    /// Expected time to mine a full averaging window
+    pub fn averaging_window_timespan(&self) -> i64 {
+        self.pow_averaging_window * self.pow_target_spacing
+    }
+
+    /// Shortest window timespan considered when retargeting
+    pub fn min_actual_timespan(&self) -> i64 {
+        (self.averaging_window_timespan() * (100 - self.pow_max_adjust_up)) / 100
+    }
+
+    /// Longest window timespan considered when retargeting
+    pub fn max_actual_timespan(&self) -> i64 {
+        (self.averaging_window_timespan() * (100 + self.pow_max_adjust_down)) / 100
+    }
+
+    /// Equihash parameters in force at `height`, which new blocks are mined
+    /// with
+    pub fn equihash_params(&self, height: u32) -> EquihashParams {
+        self.valid_equihash_params(height)[0]
+    }
+
+    /// Equihash parameters a block at `height` may use: the set in force
+    /// there, then during the overlap after a switch the previous one
+    pub fn valid_equihash_params(&self, height: u32) -> Vec<EquihashParams> {
+        let current = self
+            .equihash_schedule
+            .iter()
+            .rposition(|(start_height, _)| height >= *start_height)
+            .expect("Equihash schedule starts at genesis");
+        let (start_height, params) = self.equihash_schedule[current];
+        let mut valid = vec![params];
+        if current > 0 && height - start_height <= self.equihash_overlap {
+            valid.push(self.equihash_schedule[current - 1].1);
         }
+        valid
+    }
+
+    /// Equihash parameters whose solutions are `solution_size` bytes long.
+    /// Solution sizes differ between the scheduled sets, so a header can be
+    /// verified before its height is known.
+    pub fn equihash_params_for_solution_size(&self, solution_size: usize) -> Option<EquihashParams> {
+        self.equihash_schedule
+            .iter()
+            .map(|(_, params)| *params)
+            .find(|params| params.solution_size() == solution_size)
     }
+
+    fn equihash_144_5() -> EquihashParams {
+        EquihashParams::new(144, 5, BITCOINZ_PERSONALIZATION)
+    }
+
+    fn base_activation_heights() -> [Option<u32>; MAX_NETWORK_UPGRADES] {
+        let mut heights = [None; MAX_NETWORK_UPGRADES];
+        heights[NetworkUpgrade::Base as usize] = Some(ALWAYS_ACTIVE);
+        heights
+    }
+
+    /// Returns the activation height of `upgrade`, if scheduled
+    pub fn activation_height(&self, upgrade: NetworkUpgrade) -> Option<u32> {
+        self.activation_heights[upgrade as usize]
+    }
+
+    /// Schedules `upgrade` at `height`, or disables it with None. Used by
+    /// regtest to exercise upgrade boundaries.
+    pub fn set_activation_height(&mut self, upgrade: NetworkUpgrade, height: Option<u32>) {
+        assert!(upgrade != NetworkUpgrade::Base, "Base is always active");
+        self.activation_heights[upgrade as usize] = height;
+    }
+
+    /// Mainnet parameters
+    pub fn main() -> Self {
+        let mut params =
+            Self::new(pow_limit_from_hex("0007ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"), 150, 200, 9);
+        params.set_activation_height(NetworkUpgrade::Overwinter, Some(328_500));
+        params.set_activation_height(NetworkUpgrade::Sapling, Some(328_500));
+        params.equihash_schedule.push((160_000, Self::equihash_144_5()));
+        params.equihash_overlap = 10;
+        // BitcoinZ launched without a founders' reward, so the whole
+        // subsidy goes to the miner
+        params
+    }
+
+    /// Testnet parameters
+    pub fn testnet() -> Self {
+        let mut params =
+            Self::new(pow_limit_from_hex("07ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"), 150, 200, 9);
+        params.set_activation_height(NetworkUpgrade::Overwinter, Some(207_500));
+        params.set_activation_height(NetworkUpgrade::Sapling, Some(280_000));
+        params.equihash_schedule.push((7_590, Self::equihash_144_5()));
+        params.equihash_overlap = 10;
+        params.pow_allow_min_difficulty_blocks_after_height = Some(299_187);
+        params
+    }
+
+    /// Regression test parameters, with an easy target and small Equihash.
+    /// No upgrades are scheduled; tests enable them as needed.
+    pub fn regtest() -> Self {
+        let mut params =
+            Self::new(pow_limit_from_hex("0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f"), 150, 48, 5);
+        params.subsidy_halving_interval = 150;
+        params.pow_max_adjust_down = 0;
+        params.pow_max_adjust_up = 0;
+        params.pow_no_retargeting = true;
+        params
+    }
+}
+
+fn pow_limit_from_hex(hex: &str) -> ArithUint256 {
+    ArithUint256::from_hex(hex).expect("valid pow limit")
 }
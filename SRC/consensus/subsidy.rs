@@ -0,0 +1,127 @@
+use crate::amount::{Amount, COIN};
+use crate::consensus::params::ConsensusParams;
+use crate::primitives::transaction::Transaction;
+use crate::script::Script;
+
+/// Block subsidy before the first halving
+pub const INITIAL_BLOCK_SUBSIDY: Amount = 12_500 * COIN;
+
+/// A share of the block subsidy that the coinbase must pay to a fixed script
+/// over a range of heights
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FundingStream {
+    pub name: &'static str,
+    /// Share of the subsidy, as numerator / denominator
+    pub numerator: Amount,
+    pub denominator: Amount,
+    /// First height paying into the stream
+    pub start_height: u32,
+    /// First height no longer paying into the stream
+    pub end_height: u32,
+    pub script_pubkey: Script,
+}
+
+impl FundingStream {
+    /// Returns true if blocks at `height` pay into the stream
+    pub fn is_active(&self, height: u32) -> bool {
+        height >= self.start_height && height < self.end_height
+    }
+
+    /// Returns the amount the stream receives out of `subsidy`
+    pub fn value(&self, subsidy: Amount) -> Amount {
+        subsidy * self.numerator / self.denominator
+    }
+}
+
+/// Returns the newly minted coins a block at `height` may claim, excluding
+/// fees
+pub fn get_block_subsidy(height: u32, params: &ConsensusParams) -> Amount {
+    let slow_start_interval = params.subsidy_slow_start_interval;
+    let slow_start_shift = slow_start_interval / 2;
+
+    // Mining slow start: the subsidy ramps up linearly, in two halves so the
+    // total issued matches the schedule without a slow start
+    if height < slow_start_shift {
+        return INITIAL_BLOCK_SUBSIDY / slow_start_interval as Amount * height as Amount;
+    }
+    if height < slow_start_interval {
+        return INITIAL_BLOCK_SUBSIDY / slow_start_interval as Amount * (height + 1) as Amount;
+    }
+
+    let halvings = (height - slow_start_shift) / params.subsidy_halving_interval;
+    // Force the subsidy to zero when the shift would be undefined
+    if halvings >= 64 {
+        return 0;
+    }
+    INITIAL_BLOCK_SUBSIDY >> halvings
+}
+
+/// Returns the outputs the coinbase at `height` must contain, as
+/// (script, value) pairs
+pub fn get_funding_stream_outputs(height: u32, params: &ConsensusParams) -> Vec<(Script, Amount)> {
+    let subsidy = get_block_subsidy(height, params);
+    params
+        .funding_streams
+        .iter()
+        .filter(|stream| stream.is_active(height))
+        .map(|stream| (stream.script_pubkey.clone(), stream.value(subsidy)))
+        .collect()
+}
+
+/// Returns the part of the subsidy left to the miner once funding streams
+/// are paid
+pub fn get_miner_subsidy(height: u32, params: &ConsensusParams) -> Amount {
+    let funding: Amount = get_funding_stream_outputs(height, params)
+        .iter()
+        .map(|(_, value)| value)
+        .sum();
+    get_block_subsidy(height, params) - funding
+}
+
+/// Returns the first required funding stream output missing from `coinbase`
+pub fn find_missing_funding_output(
+    coinbase: &Transaction,
+    height: u32,
+    params: &ConsensusParams,
+) -> Option<(Script, Amount)> {
+    get_funding_stream_outputs(height, params).into_iter().find(|(script, value)| {
+        !coinbase
+            .outputs
+            .iter()
+            .any(|output| output.script_pubkey == *script && output.value == *value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_halvings() {
+        let params = ConsensusParams::main();
+        assert_eq!(get_block_subsidy(0, &params), 12_500 * COIN);
+        assert_eq!(get_block_subsidy(839_999, &params), 12_500 * COIN);
+        assert_eq!(get_block_subsidy(840_000, &params), 6_250 * COIN);
+        assert_eq!(get_block_subsidy(64 * 840_000, &params), 0);
+    }
+
+    #[test]
+    fn test_slow_start() {
+        let mut params = ConsensusParams::regtest();
+        params.subsidy_slow_start_interval = 10;
+        let step = INITIAL_BLOCK_SUBSIDY / 10;
+        assert_eq!(get_block_subsidy(0, &params), 0);
+        assert_eq!(get_block_subsidy(4, &params), 4 * step);
+        assert_eq!(get_block_subsidy(5, &params), 6 * step);
+        assert_eq!(get_block_subsidy(9, &params), 10 * step);
+        assert_eq!(get_block_subsidy(10, &params), INITIAL_BLOCK_SUBSIDY);
+    }
+
+    #[test]
+    fn test_total_supply_within_max_money() {
+        let params = ConsensusParams::main();
+        let interval = params.subsidy_halving_interval as Amount;
+        let total: Amount = (0..64).map(|halving| (INITIAL_BLOCK_SUBSIDY >> halving) * interval).sum();
+        assert!(total <= crate::amount::MAX_MONEY);
+    }
+}
@@ -1,3 +1,222 @@
-pub fn is_activation_height(height: i32, threshold: i32) -> bool {
-    height == threshold
+use crate::consensus::params::ConsensusParams;
+
+/// Network upgrades, in activation order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NetworkUpgrade {
+    Base,
+    Overwinter,
+    Sapling,
+    Blossom,
+    Heartwood,
+    Canopy,
+}
+
+/// Number of entries in `NetworkUpgrade`
+pub const MAX_NETWORK_UPGRADES: usize = 6;
+
+/// Every network upgrade, in activation order
+pub const ALL_NETWORK_UPGRADES: [NetworkUpgrade; MAX_NETWORK_UPGRADES] = [
+    NetworkUpgrade::Base,
+    NetworkUpgrade::Overwinter,
+    NetworkUpgrade::Sapling,
+    NetworkUpgrade::Blossom,
+    NetworkUpgrade::Heartwood,
+    NetworkUpgrade::Canopy,
+];
+
+/// Activation height of upgrades that apply from genesis
+pub const ALWAYS_ACTIVE: u32 = 0;
+
+/// Activation state of a network upgrade at some height
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeState {
+    Disabled,
+    Pending,
+    Active,
+}
+
+impl UpgradeState {
+    /// Returns the name used by `getblockchaininfo`
+    pub fn name(&self) -> &'static str {
+        match self {
+            UpgradeState::Disabled => "disabled",
+            UpgradeState::Pending => "pending",
+            UpgradeState::Active => "active",
+        }
+    }
+}
+
+impl NetworkUpgrade {
+    /// Returns the consensus branch ID committed to by signatures made under
+    /// this upgrade's rules
+    pub const fn branch_id(&self) -> u32 {
+        match self {
+            NetworkUpgrade::Base => 0,
+            NetworkUpgrade::Overwinter => 0x5ba81b19,
+            NetworkUpgrade::Sapling => 0x76b809bb,
+            NetworkUpgrade::Blossom => 0x2bb40e60,
+            NetworkUpgrade::Heartwood => 0xf5b9230b,
+            NetworkUpgrade::Canopy => 0xe9ff75a6,
+        }
+    }
+
+    /// User-facing name of the upgrade
+    pub fn name(&self) -> &'static str {
+        match self {
+            NetworkUpgrade::Base => "Sprout",
+            NetworkUpgrade::Overwinter => "Overwinter",
+            NetworkUpgrade::Sapling => "Sapling",
+            NetworkUpgrade::Blossom => "Blossom",
+            NetworkUpgrade::Heartwood => "Heartwood",
+            NetworkUpgrade::Canopy => "Canopy",
+        }
+    }
+
+    /// User-facing information string about the upgrade
+    pub fn info(&self) -> &'static str {
+        match self {
+            NetworkUpgrade::Base => "The BitcoinZ network at launch",
+            NetworkUpgrade::Overwinter => "Network upgrade for replay protection and transaction expiry",
+            NetworkUpgrade::Sapling => "Network upgrade for efficient shielded transactions",
+            NetworkUpgrade::Blossom => "Network upgrade for shorter block times",
+            NetworkUpgrade::Heartwood => "Network upgrade for shielded coinbase and FlyClient",
+            NetworkUpgrade::Canopy => "Network upgrade for ZIP 211, 212 and 215",
+        }
+    }
+
+    /// Returns the upgrade that precedes this one
+    pub fn previous(&self) -> Option<NetworkUpgrade> {
+        match *self as usize {
+            0 => None,
+            index => Some(ALL_NETWORK_UPGRADES[index - 1]),
+        }
+    }
+}
+
+/// Returns the state of `upgrade` at `height`
+pub fn network_upgrade_state(
+    height: u32,
+    params: &ConsensusParams,
+    upgrade: NetworkUpgrade,
+) -> UpgradeState {
+    match params.activation_height(upgrade) {
+        None => UpgradeState::Disabled,
+        Some(activation_height) if height >= activation_height => UpgradeState::Active,
+        Some(_) => UpgradeState::Pending,
+    }
+}
+
+/// Returns true if the rules of `upgrade` apply at `height`
+pub fn network_upgrade_active(
+    height: u32,
+    params: &ConsensusParams,
+    upgrade: NetworkUpgrade,
+) -> bool {
+    network_upgrade_state(height, params, upgrade) == UpgradeState::Active
+}
+
+/// Returns the most recent upgrade active at `height`
+pub fn current_epoch(height: u32, params: &ConsensusParams) -> NetworkUpgrade {
+    ALL_NETWORK_UPGRADES
+        .iter()
+        .rev()
+        .copied()
+        .find(|upgrade| network_upgrade_active(height, params, *upgrade))
+        .unwrap_or(NetworkUpgrade::Base)
+}
+
+/// Returns the consensus branch ID in effect at `height`
+pub fn current_epoch_branch_id(height: u32, params: &ConsensusParams) -> u32 {
+    current_epoch(height, params).branch_id()
+}
+
+/// Returns true if `branch_id` belongs to a known upgrade
+pub fn is_consensus_branch_id(branch_id: u32) -> bool {
+    ALL_NETWORK_UPGRADES
+        .iter()
+        .any(|upgrade| upgrade.branch_id() == branch_id)
+}
+
+/// Returns true if `height` is the first block of `upgrade`
+pub fn is_activation_height(
+    height: u32,
+    params: &ConsensusParams,
+    upgrade: NetworkUpgrade,
+) -> bool {
+    // Base is never activated, it is the starting state
+    upgrade != NetworkUpgrade::Base && params.activation_height(upgrade) == Some(height)
+}
+
+/// Returns true if `height` is the first block of any upgrade
+pub fn is_activation_height_for_any_upgrade(height: u32, params: &ConsensusParams) -> bool {
+    ALL_NETWORK_UPGRADES
+        .iter()
+        .any(|upgrade| is_activation_height(height, params, *upgrade))
+}
+
+/// Returns the first upgrade that is still pending at `height`
+pub fn next_epoch(height: u32, params: &ConsensusParams) -> Option<NetworkUpgrade> {
+    ALL_NETWORK_UPGRADES[1..]
+        .iter()
+        .copied()
+        .find(|upgrade| network_upgrade_state(height, params, *upgrade) == UpgradeState::Pending)
+}
+
+/// Returns the activation height of the next pending upgrade
+pub fn next_activation_height(height: u32, params: &ConsensusParams) -> Option<u32> {
+    next_epoch(height, params).and_then(|upgrade| params.activation_height(upgrade))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> ConsensusParams {
+        let mut params = ConsensusParams::regtest();
+        params.set_activation_height(NetworkUpgrade::Overwinter, Some(100));
+        params.set_activation_height(NetworkUpgrade::Sapling, Some(200));
+        params
+    }
+
+    #[test]
+    fn test_upgrade_state() {
+        let params = params();
+        let state = |height, upgrade| network_upgrade_state(height, &params, upgrade);
+        assert_eq!(state(0, NetworkUpgrade::Base), UpgradeState::Active);
+        assert_eq!(state(99, NetworkUpgrade::Overwinter), UpgradeState::Pending);
+        assert_eq!(state(100, NetworkUpgrade::Overwinter), UpgradeState::Active);
+        assert_eq!(state(1000, NetworkUpgrade::Blossom), UpgradeState::Disabled);
+    }
+
+    #[test]
+    fn test_current_epoch() {
+        let params = params();
+        assert_eq!(current_epoch(0, &params), NetworkUpgrade::Base);
+        assert_eq!(current_epoch(150, &params), NetworkUpgrade::Overwinter);
+        assert_eq!(current_epoch_branch_id(150, &params), 0x5ba81b19);
+        assert_eq!(current_epoch_branch_id(200, &params), 0x76b809bb);
+        assert_eq!(current_epoch_branch_id(99, &params), 0);
+    }
+
+    #[test]
+    fn test_activation_heights() {
+        let params = params();
+        assert!(is_activation_height(100, &params, NetworkUpgrade::Overwinter));
+        assert!(!is_activation_height(0, &params, NetworkUpgrade::Base));
+        assert!(is_activation_height_for_any_upgrade(200, &params));
+        assert!(!is_activation_height_for_any_upgrade(201, &params));
+
+        assert_eq!(next_epoch(0, &params), Some(NetworkUpgrade::Overwinter));
+        assert_eq!(next_activation_height(0, &params), Some(100));
+        assert_eq!(next_activation_height(150, &params), Some(200));
+        assert_eq!(next_activation_height(200, &params), None);
+    }
+
+    #[test]
+    fn test_branch_ids() {
+        assert!(is_consensus_branch_id(0x76b809bb));
+        assert!(!is_consensus_branch_id(0x12345678));
+        assert_eq!(NetworkUpgrade::Sapling.previous(), Some(NetworkUpgrade::Overwinter));
+        assert_eq!(NetworkUpgrade::Base.previous(), None);
+    }
 }
@@ -1,6 +1,953 @@
-use crate::block::Block;
+use crate::amount::{is_valid_amount, Amount, MAX_MONEY};
+use crate::coins::{CoinsView, CoinsViewCache, ShieldedType, SAPLING_EMPTY_ROOT};
+use crate::consensus::consensus::{
+    COINBASE_MATURITY, MAX_BLOCK_SIGOPS, MAX_BLOCK_SIZE, MAX_TX_SIZE_AFTER_SAPLING,
+    MAX_TX_SIZE_BEFORE_SAPLING, TX_EXPIRY_HEIGHT_THRESHOLD,
+};
+use crate::consensus::merkle::block_merkle_root;
+use crate::consensus::params::ConsensusParams;
+use crate::consensus::subsidy::{find_missing_funding_output, get_block_subsidy};
+use crate::consensus::upgrades::{current_epoch_branch_id, network_upgrade_active, NetworkUpgrade};
+use crate::incremental_merkle_tree::{SaplingMerkleTree, SproutMerkleTree};
+use crate::pow::{
+    check_equihash_solution, check_proof_of_work, get_next_work_required, BlockIndexView,
+};
+use crate::primitives::block::{Block, BlockHeader};
+use crate::primitives::transaction::{
+    OutPoint, Transaction, OVERWINTER_TX_VERSION, OVERWINTER_VERSION_GROUP_ID, SAPLING_TX_VERSION,
+    SAPLING_VERSION_GROUP_ID,
+};
+use crate::script::{
+    verify_script, TransactionSignatureChecker, SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY,
+    SCRIPT_VERIFY_P2SH,
+};
+use crate::sighash::PrecomputedTransactionData;
+use crate::timedata::TimeData;
+use crate::undo::{BlockUndo, TxUndo};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
 
-pub fn validate_block(block: &Block) -> bool {
-    // Implement block validation logic
-    true
+/// Reject code sent to peers for consensus-invalid data
+pub const REJECT_INVALID: u8 = 0x10;
+/// Reject code sent to peers for data using a retired format
+pub const REJECT_OBSOLETE: u8 = 0x11;
+/// Reject code sent to peers for data already known
+pub const REJECT_DUPLICATE: u8 = 0x12;
+/// Reject code sent to peers for transactions outside the relay policy
+pub const REJECT_NONSTANDARD: u8 = 0x40;
+/// Reject code sent to peers for transactions paying less than the relay fee
+pub const REJECT_INSUFFICIENTFEE: u8 = 0x42;
+
+/// Lowest block version accepted
+pub const MIN_BLOCK_VERSION: i32 = 4;
+/// How far ahead of network-adjusted time a block timestamp may be
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+/// Lowest transaction version before Overwinter
+pub const SPROUT_MIN_TX_VERSION: i32 = 1;
+
+/// Script rules enforced on every input of a connected block
+pub const BLOCK_SCRIPT_VERIFY_FLAGS: u32 = SCRIPT_VERIFY_P2SH | SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY;
+
+/// Reasons a transaction fails the context-free consensus checks
+#[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+pub enum TxValidationError {
+    #[error("bad-txns-version-too-low")]
+    VersionTooLow,
+    #[error("bad-tx-overwinter-version-too-low")]
+    OverwinterVersionTooLow,
+    #[error("bad-tx-version-group-id")]
+    VersionGroupId,
+    #[error("bad-tx-expiry-height-too-high")]
+    ExpiryHeightTooHigh,
+    #[error("bad-txns-vin-empty")]
+    VinEmpty,
+    #[error("bad-txns-vout-empty")]
+    VoutEmpty,
+    #[error("bad-txns-oversize")]
+    Oversize,
+    #[error("bad-txns-vout-negative")]
+    VoutNegative,
+    #[error("bad-txns-vout-toolarge")]
+    VoutTooLarge,
+    #[error("bad-txns-txouttotal-toolarge")]
+    TxOutTotalTooLarge,
+    #[error("bad-txns-valuebalance-nonzero")]
+    ValueBalanceNonZero,
+    #[error("bad-txns-valuebalance-toolarge")]
+    ValueBalanceTooLarge,
+    #[error("bad-txns-vpub_old-negative")]
+    VpubOldNegative,
+    #[error("bad-txns-vpub_new-negative")]
+    VpubNewNegative,
+    #[error("bad-txns-vpub_old-toolarge")]
+    VpubOldTooLarge,
+    #[error("bad-txns-vpub_new-toolarge")]
+    VpubNewTooLarge,
+    #[error("bad-txns-vpubs-both-nonzero")]
+    VpubsBothNonZero,
+    #[error("bad-txns-txintotal-toolarge")]
+    TxInTotalTooLarge,
+    #[error("bad-txns-inputs-duplicate")]
+    InputsDuplicate,
+    #[error("bad-joinsplits-nullifiers-duplicate")]
+    JoinSplitNullifiersDuplicate,
+    #[error("bad-spend-description-nullifiers-duplicate")]
+    SpendNullifiersDuplicate,
+    #[error("bad-cb-has-joinsplits")]
+    CoinbaseHasJoinSplits,
+    #[error("bad-cb-has-spend-description")]
+    CoinbaseHasSpendDescription,
+    #[error("bad-cb-has-output-description")]
+    CoinbaseHasOutputDescription,
+    #[error("bad-cb-length")]
+    CoinbaseLength,
+    #[error("bad-txns-prevout-null")]
+    PrevoutNull,
+    #[error("tx-overwinter-not-active")]
+    OverwinterNotActive,
+    #[error("tx-overwintered-flag-not-set")]
+    OverwinteredFlagNotSet,
+    #[error("bad-overwinter-tx-version-group-id")]
+    OverwinterVersionGroupIdInvalid,
+    #[error("bad-tx-overwinter-version-too-high")]
+    OverwinterVersionTooHigh,
+    #[error("bad-sapling-tx-version-group-id")]
+    SaplingVersionGroupIdInvalid,
+    #[error("bad-tx-sapling-version-too-low")]
+    SaplingVersionTooLow,
+    #[error("bad-tx-sapling-version-too-high")]
+    SaplingVersionTooHigh,
+    #[error("tx-overwinter-expired")]
+    Expired,
+    #[error("bad-txns-inputs-missingorspent")]
+    InputsMissingOrSpent,
+    #[error("bad-txns-premature-spend-of-coinbase")]
+    PrematureSpendOfCoinbase,
+    #[error("bad-txns-inputvalues-outofrange")]
+    InputValuesOutOfRange,
+    #[error("bad-txns-in-belowout")]
+    InBelowOut,
+    #[error("bad-txns-fee-outofrange")]
+    FeeOutOfRange,
+    #[error("bad-txns-joinsplit-requirements-not-met")]
+    ShieldedRequirementsNotMet,
+    #[error("mandatory-script-verify-flag-failed")]
+    ScriptVerifyFailed,
+    #[error("txn-already-in-mempool")]
+    AlreadyInMempool,
+    #[error("{0}")]
+    NonStandard(&'static str),
+    #[error("insufficient fee")]
+    InsufficientFee,
+}
+
+impl TxValidationError {
+    /// Misbehaviour score assigned to the peer that relayed the transaction
+    pub fn dos_score(&self) -> u32 {
+        match self {
+            TxValidationError::VinEmpty
+            | TxValidationError::VoutEmpty
+            | TxValidationError::PrevoutNull
+            | TxValidationError::Expired => 10,
+            // Spending coinbase outputs too early is only premature
+            TxValidationError::PrematureSpendOfCoinbase => 0,
+            // Relay policy failures don't make a peer misbehave
+            TxValidationError::AlreadyInMempool
+            | TxValidationError::NonStandard(_)
+            | TxValidationError::InsufficientFee => 0,
+            _ => 100,
+        }
+    }
+
+    /// Reject code sent back to the peer
+    pub fn reject_code(&self) -> u8 {
+        match self {
+            TxValidationError::VersionTooLow
+            | TxValidationError::OverwinterVersionTooLow
+            | TxValidationError::OverwinterVersionTooHigh
+            | TxValidationError::Expired => REJECT_OBSOLETE,
+            TxValidationError::AlreadyInMempool => REJECT_DUPLICATE,
+            TxValidationError::NonStandard(_) => REJECT_NONSTANDARD,
+            TxValidationError::InsufficientFee => REJECT_INSUFFICIENTFEE,
+            _ => REJECT_INVALID,
+        }
+    }
+}
+
+/// Checks that a running total of values stays within the money range
+fn add_value(total: Amount, value: Amount, error: TxValidationError) -> Result<Amount, TxValidationError> {
+    let total = total + value;
+    if is_valid_amount(total) {
+        Ok(total)
+    } else {
+        Err(error)
+    }
+}
+
+/// Checks the consensus rules that don't depend on the chain state
+pub fn check_transaction(tx: &Transaction) -> Result<(), TxValidationError> {
+    if tx.overwintered {
+        if tx.version < OVERWINTER_TX_VERSION {
+            return Err(TxValidationError::OverwinterVersionTooLow);
+        }
+        if tx.version_group_id != OVERWINTER_VERSION_GROUP_ID
+            && tx.version_group_id != SAPLING_VERSION_GROUP_ID
+        {
+            return Err(TxValidationError::VersionGroupId);
+        }
+        if tx.expiry_height >= TX_EXPIRY_HEIGHT_THRESHOLD {
+            return Err(TxValidationError::ExpiryHeightTooHigh);
+        }
+    } else if tx.version < SPROUT_MIN_TX_VERSION {
+        return Err(TxValidationError::VersionTooLow);
+    }
+
+    // Transparent inputs and outputs may be empty only when shielded ones
+    // take their place
+    if tx.inputs.is_empty() && tx.join_splits.is_empty() && tx.shielded_spends.is_empty() {
+        return Err(TxValidationError::VinEmpty);
+    }
+    if tx.outputs.is_empty() && tx.join_splits.is_empty() && tx.shielded_outputs.is_empty() {
+        return Err(TxValidationError::VoutEmpty);
+    }
+
+    // The per-era size limit is contextual; this is the absolute maximum
+    if tx.get_size() > MAX_TX_SIZE_AFTER_SAPLING {
+        return Err(TxValidationError::Oversize);
+    }
+
+    // Check for negative or overflowing output values
+    let mut value_out: Amount = 0;
+    for output in &tx.outputs {
+        if output.value < 0 {
+            return Err(TxValidationError::VoutNegative);
+        }
+        if output.value > MAX_MONEY {
+            return Err(TxValidationError::VoutTooLarge);
+        }
+        value_out = add_value(value_out, output.value, TxValidationError::TxOutTotalTooLarge)?;
+    }
+
+    // valueBalance is only meaningful alongside Sapling spends or outputs
+    if tx.shielded_spends.is_empty() && tx.shielded_outputs.is_empty() && tx.value_balance != 0 {
+        return Err(TxValidationError::ValueBalanceNonZero);
+    }
+    if tx.value_balance > MAX_MONEY || tx.value_balance < -MAX_MONEY {
+        return Err(TxValidationError::ValueBalanceTooLarge);
+    }
+    // A negative valueBalance moves value into the shielded pool
+    if tx.value_balance <= 0 {
+        value_out = add_value(value_out, -tx.value_balance, TxValidationError::TxOutTotalTooLarge)?;
+    }
+
+    // Ensure that JoinSplit values are well-formed
+    let mut value_in: Amount = 0;
+    for js in &tx.join_splits {
+        if js.vpub_old < 0 {
+            return Err(TxValidationError::VpubOldNegative);
+        }
+        if js.vpub_new < 0 {
+            return Err(TxValidationError::VpubNewNegative);
+        }
+        if js.vpub_old > MAX_MONEY {
+            return Err(TxValidationError::VpubOldTooLarge);
+        }
+        if js.vpub_new > MAX_MONEY {
+            return Err(TxValidationError::VpubNewTooLarge);
+        }
+        if js.vpub_old != 0 && js.vpub_new != 0 {
+            return Err(TxValidationError::VpubsBothNonZero);
+        }
+        value_out = add_value(value_out, js.vpub_old, TxValidationError::TxOutTotalTooLarge)?;
+        value_in = add_value(value_in, js.vpub_new, TxValidationError::TxInTotalTooLarge)?;
+    }
+    // A positive valueBalance moves value out of the shielded pool
+    if tx.value_balance >= 0 {
+        add_value(value_in, tx.value_balance, TxValidationError::TxInTotalTooLarge)?;
+    }
+
+    // Check for duplicate inputs
+    let mut prevouts = HashSet::with_capacity(tx.inputs.len());
+    if !tx.inputs.iter().all(|input| prevouts.insert(&input.prev_out)) {
+        return Err(TxValidationError::InputsDuplicate);
+    }
+
+    // Check for duplicate nullifiers, both within and across JoinSplits
+    let mut nullifiers = HashSet::new();
+    let unique = tx
+        .join_splits
+        .iter()
+        .flat_map(|js| js.nullifiers.iter())
+        .all(|nullifier| nullifiers.insert(nullifier));
+    if !unique {
+        return Err(TxValidationError::JoinSplitNullifiersDuplicate);
+    }
+
+    let mut nullifiers = HashSet::with_capacity(tx.shielded_spends.len());
+    if !tx.shielded_spends.iter().all(|spend| nullifiers.insert(&spend.nullifier)) {
+        return Err(TxValidationError::SpendNullifiersDuplicate);
+    }
+
+    if tx.is_coinbase() {
+        // Coinbase outputs can't be spent into or out of the shielded pool
+        if !tx.join_splits.is_empty() {
+            return Err(TxValidationError::CoinbaseHasJoinSplits);
+        }
+        if !tx.shielded_spends.is_empty() {
+            return Err(TxValidationError::CoinbaseHasSpendDescription);
+        }
+        if !tx.shielded_outputs.is_empty() {
+            return Err(TxValidationError::CoinbaseHasOutputDescription);
+        }
+
+        let script_sig_len = tx.inputs[0].script_sig.len();
+        if !(2..=100).contains(&script_sig_len) {
+            return Err(TxValidationError::CoinbaseLength);
+        }
+    } else if tx.inputs.iter().any(|input| input.prev_out.is_null()) {
+        return Err(TxValidationError::PrevoutNull);
+    }
+
+    Ok(())
+}
+
+/// Checks the consensus rules that depend on the height of the block the
+/// transaction will be included in, chiefly the version rules of the network
+/// upgrade active at that height
+pub fn contextual_check_transaction(
+    tx: &Transaction,
+    height: u32,
+    params: &ConsensusParams,
+) -> Result<(), TxValidationError> {
+    let overwinter_active = network_upgrade_active(height, params, NetworkUpgrade::Overwinter);
+    let sapling_active = network_upgrade_active(height, params, NetworkUpgrade::Sapling);
+
+    if !overwinter_active {
+        // Overwinter-format transactions are not valid before activation
+        if tx.overwintered {
+            return Err(TxValidationError::OverwinterNotActive);
+        }
+    } else {
+        if !tx.overwintered {
+            return Err(TxValidationError::OverwinteredFlagNotSet);
+        }
+
+        if sapling_active {
+            if tx.version_group_id != SAPLING_VERSION_GROUP_ID {
+                return Err(TxValidationError::SaplingVersionGroupIdInvalid);
+            }
+            if tx.version < SAPLING_TX_VERSION {
+                return Err(TxValidationError::SaplingVersionTooLow);
+            }
+            if tx.version > SAPLING_TX_VERSION {
+                return Err(TxValidationError::SaplingVersionTooHigh);
+            }
+        } else {
+            if tx.version_group_id != OVERWINTER_VERSION_GROUP_ID {
+                return Err(TxValidationError::OverwinterVersionGroupIdInvalid);
+            }
+            if tx.version > OVERWINTER_TX_VERSION {
+                return Err(TxValidationError::OverwinterVersionTooHigh);
+            }
+        }
+
+        // Coinbase transactions are exempt from expiry
+        if !tx.is_coinbase() && tx.expiry_height != 0 && height > tx.expiry_height {
+            return Err(TxValidationError::Expired);
+        }
+    }
+
+    if !sapling_active && tx.get_size() > MAX_TX_SIZE_BEFORE_SAPLING {
+        return Err(TxValidationError::Oversize);
+    }
+
+    Ok(())
+}
+
+/// Reasons a block fails the consensus checks
+#[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+pub enum BlockValidationError {
+    #[error("version-too-low")]
+    VersionTooLow,
+    #[error("invalid-solution")]
+    InvalidSolution,
+    #[error("invalid-solution-size")]
+    InvalidSolutionSize,
+    #[error("high-hash")]
+    HighHash,
+    #[error("time-too-new")]
+    TimeTooNew,
+    #[error("bad-diffbits")]
+    BadDiffBits,
+    #[error("time-too-old")]
+    TimeTooOld,
+    #[error("bad-txnmrklroot")]
+    BadMerkleRoot,
+    #[error("bad-txns-duplicate")]
+    DuplicateTransactions,
+    #[error("bad-blk-length")]
+    BadLength,
+    #[error("bad-cb-missing")]
+    CoinbaseMissing,
+    #[error("bad-cb-multiple")]
+    CoinbaseMultiple,
+    #[error("bad-blk-sigops")]
+    TooManySigOps,
+    #[error("cb-funding-stream-missing")]
+    CoinbaseFundingStreamMissing,
+    #[error("bad-cb-amount")]
+    CoinbaseAmount,
+    #[error("bad-txns-BIP30")]
+    OverwriteTransaction,
+    #[error("bad-txns-accumulated-fee-outofrange")]
+    AccumulatedFeeOutOfRange,
+    #[error("bad-sapling-root-in-block")]
+    BadSaplingRoot,
+    #[error("{0}")]
+    Transaction(TxValidationError),
+}
+
+impl BlockValidationError {
+    /// Misbehaviour score assigned to the peer that relayed the block
+    pub fn dos_score(&self) -> u32 {
+        match self {
+            BlockValidationError::HighHash => 50,
+            // The clock of either side may be wrong
+            BlockValidationError::TimeTooNew => 0,
+            BlockValidationError::Transaction(error) => error.dos_score(),
+            _ => 100,
+        }
+    }
+
+    /// Reject code sent back to the peer
+    pub fn reject_code(&self) -> u8 {
+        match self {
+            BlockValidationError::VersionTooLow => REJECT_OBSOLETE,
+            BlockValidationError::Transaction(error) => error.reject_code(),
+            _ => REJECT_INVALID,
+        }
+    }
+
+    /// True if the failure may come from corruption in transit rather than
+    /// from the block itself, so its hash must not be marked invalid
+    pub fn corruption_possible(&self) -> bool {
+        matches!(
+            self,
+            BlockValidationError::BadMerkleRoot | BlockValidationError::DuplicateTransactions
+        )
+    }
+
+    /// True if the block itself breaks the consensus rules, so it stays
+    /// invalid whatever the state of this node or the data it was sent with
+    pub fn is_consensus_failure(&self) -> bool {
+        !self.corruption_possible() && *self != BlockValidationError::TimeTooNew
+    }
+}
+
+/// Counts the signature operations in a transaction's scripts without
+/// evaluating P2SH redeem scripts
+pub fn get_legacy_sig_op_count(tx: &Transaction) -> usize {
+    let inputs: usize = tx.inputs.iter().map(|input| input.script_sig.get_sig_op_count(false)).sum();
+    let outputs: usize = tx
+        .outputs
+        .iter()
+        .map(|output| output.script_pubkey.get_sig_op_count(false))
+        .sum();
+    inputs + outputs
+}
+
+/// Checks a block header against the rules that need neither its parent nor
+/// its transactions
+pub fn check_block_header(
+    header: &BlockHeader,
+    params: &ConsensusParams,
+    time_data: &TimeData,
+    check_pow: bool,
+) -> Result<(), BlockValidationError> {
+    if header.version < MIN_BLOCK_VERSION {
+        return Err(BlockValidationError::VersionTooLow);
+    }
+
+    if check_pow {
+        if !check_equihash_solution(header, params) {
+            return Err(BlockValidationError::InvalidSolution);
+        }
+        if !check_proof_of_work(&header.hash(), header.bits, params) {
+            return Err(BlockValidationError::HighHash);
+        }
+    }
+
+    if header.timestamp as i64 > time_data.get_adjusted_time() + MAX_FUTURE_BLOCK_TIME {
+        return Err(BlockValidationError::TimeTooNew);
+    }
+
+    Ok(())
+}
+
+/// Checks the structure of a block: its header, Merkle root, coinbase
+/// placement, size and signature operation count, and every transaction
+pub fn check_block(
+    block: &Block,
+    params: &ConsensusParams,
+    time_data: &TimeData,
+    check_pow: bool,
+    check_merkle_root: bool,
+) -> Result<(), BlockValidationError> {
+    check_block_header(&block.header, params, time_data, check_pow)?;
+
+    if check_merkle_root {
+        let (merkle_root, mutated) = block_merkle_root(block);
+        if block.header.merkle_root != merkle_root {
+            return Err(BlockValidationError::BadMerkleRoot);
+        }
+
+        // Duplicated transactions give the same root as the honest block, so
+        // reject the mutated copy without condemning the hash
+        if mutated {
+            return Err(BlockValidationError::DuplicateTransactions);
+        }
+    }
+
+    // Size limits
+    if block.transactions.is_empty()
+        || block.transactions.len() > MAX_BLOCK_SIZE
+        || block.get_size() > MAX_BLOCK_SIZE
+    {
+        return Err(BlockValidationError::BadLength);
+    }
+
+    // The first and only the first transaction is a coinbase
+    if !block.transactions[0].is_coinbase() {
+        return Err(BlockValidationError::CoinbaseMissing);
+    }
+    if block.transactions[1..].iter().any(|tx| tx.is_coinbase()) {
+        return Err(BlockValidationError::CoinbaseMultiple);
+    }
+
+    for tx in &block.transactions {
+        check_transaction(tx).map_err(BlockValidationError::Transaction)?;
+    }
+
+    let sig_ops: usize = block.transactions.iter().map(get_legacy_sig_op_count).sum();
+    if sig_ops > MAX_BLOCK_SIGOPS {
+        return Err(BlockValidationError::TooManySigOps);
+    }
+
+    Ok(())
+}
+
+/// Checks the header rules that depend on its height: the solution must
+/// use Equihash parameters valid there
+pub fn contextual_check_block_header(
+    header: &BlockHeader,
+    height: u32,
+    params: &ConsensusParams,
+) -> Result<(), BlockValidationError> {
+    let solution_size = header.solution.len();
+    if !params
+        .valid_equihash_params(height)
+        .iter()
+        .any(|equihash| equihash.solution_size() == solution_size)
+    {
+        return Err(BlockValidationError::InvalidSolutionSize);
+    }
+    Ok(())
+}
+
+/// Checks the header rules that depend on the chain it extends, `prev`,
+/// whose tip is the parent of the header: the target must follow the
+/// difficulty adjustment and the time must be after the median time past
+pub fn check_header_against_chain<V: BlockIndexView + ?Sized>(
+    header: &BlockHeader,
+    prev: &V,
+    params: &ConsensusParams,
+) -> Result<(), BlockValidationError> {
+    if header.bits != get_next_work_required(prev, header.timestamp as i64, params) {
+        return Err(BlockValidationError::BadDiffBits);
+    }
+
+    if let Some(tip) = prev.tip_height() {
+        if header.timestamp as i64 <= prev.median_time_past(tip) {
+            return Err(BlockValidationError::TimeTooOld);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the rules that depend on the height of the block but not on the
+/// state of the UTXO set: the version rules of every transaction and the
+/// funding stream outputs of the coinbase
+pub fn contextual_check_block(
+    block: &Block,
+    height: u32,
+    params: &ConsensusParams,
+) -> Result<(), BlockValidationError> {
+    for tx in &block.transactions {
+        contextual_check_transaction(tx, height, params).map_err(BlockValidationError::Transaction)?;
+    }
+
+    if let Some(coinbase) = block.transactions.first() {
+        if find_missing_funding_output(coinbase, height, params).is_some() {
+            return Err(BlockValidationError::CoinbaseFundingStreamMissing);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the coinbase claims no more than the block subsidy plus the
+/// fees paid by the block's other transactions
+pub fn check_coinbase_value(
+    coinbase: &Transaction,
+    height: u32,
+    fees: Amount,
+    params: &ConsensusParams,
+) -> Result<(), BlockValidationError> {
+    let value_out: Amount = coinbase.outputs.iter().map(|output| output.value).sum();
+    if value_out > get_block_subsidy(height, params) + fees {
+        return Err(BlockValidationError::CoinbaseAmount);
+    }
+    Ok(())
+}
+
+/// Checks that the nullifiers revealed by `tx` are unspent and that its
+/// anchors are known. JoinSplits may also use the tree left by an earlier
+/// JoinSplit of the same transaction.
+pub fn check_shielded_requirements(
+    tx: &Transaction,
+    view: &dyn CoinsView,
+) -> Result<(), TxValidationError> {
+    let mut intermediates: HashMap<[u8; 32], SproutMerkleTree> = HashMap::new();
+    for join_split in &tx.join_splits {
+        for nullifier in &join_split.nullifiers {
+            if view.get_nullifier(nullifier, ShieldedType::Sprout) {
+                return Err(TxValidationError::ShieldedRequirementsNotMet);
+            }
+        }
+
+        let mut tree = match intermediates.get(&join_split.anchor) {
+            Some(tree) => tree.clone(),
+            None => view
+                .get_sprout_anchor_at(&join_split.anchor)
+                .ok_or(TxValidationError::ShieldedRequirementsNotMet)?,
+        };
+        for commitment in &join_split.commitments {
+            tree.append(*commitment)
+                .map_err(|_| TxValidationError::ShieldedRequirementsNotMet)?;
+        }
+        intermediates.insert(tree.root(), tree);
+    }
+
+    for spend in &tx.shielded_spends {
+        if view.get_nullifier(&spend.nullifier, ShieldedType::Sapling)
+            || !view.have_sapling_anchor(&spend.anchor)
+        {
+            return Err(TxValidationError::ShieldedRequirementsNotMet);
+        }
+    }
+    Ok(())
+}
+
+/// Checks the inputs of a non-coinbase transaction against the UTXO set at
+/// `height`: they must exist, coinbase outputs must be mature, and the
+/// inputs must cover the outputs. Returns the fee.
+pub fn check_tx_inputs(
+    tx: &Transaction,
+    view: &dyn CoinsView,
+    height: u32,
+) -> Result<Amount, TxValidationError> {
+    check_shielded_requirements(tx, view)?;
+
+    let out_of_range = TxValidationError::InputValuesOutOfRange;
+    let mut value_in = 0;
+    for input in &tx.inputs {
+        let coin = view
+            .get_coin(&input.prev_out)
+            .ok_or(TxValidationError::InputsMissingOrSpent)?;
+
+        // If prev is coinbase, check that it's matured
+        if coin.is_coinbase && height.saturating_sub(coin.height) < COINBASE_MATURITY {
+            return Err(TxValidationError::PrematureSpendOfCoinbase);
+        }
+
+        // Check for negative or overflow input values
+        if !is_valid_amount(coin.output.value) {
+            return Err(TxValidationError::InputValuesOutOfRange);
+        }
+        value_in = add_value(value_in, coin.output.value, out_of_range)?;
+    }
+
+    // Value entering the transparent pool from the shielded pools
+    if tx.value_balance > 0 {
+        value_in = add_value(value_in, tx.value_balance, out_of_range)?;
+    }
+    for join_split in &tx.join_splits {
+        value_in = add_value(value_in, join_split.vpub_new, out_of_range)?;
+    }
+
+    let mut value_out: Amount = tx.outputs.iter().map(|output| output.value).sum();
+    if tx.value_balance < 0 {
+        value_out += -tx.value_balance;
+    }
+    value_out += tx.join_splits.iter().map(|join_split| join_split.vpub_old).sum::<Amount>();
+
+    if value_in < value_out {
+        return Err(TxValidationError::InBelowOut);
+    }
+
+    // Tally transaction fees
+    let fee = value_in - value_out;
+    if !is_valid_amount(fee) {
+        return Err(TxValidationError::FeeOutOfRange);
+    }
+    Ok(fee)
+}
+
+/// Verifies the scripts of every input of a non-coinbase transaction, whose
+/// inputs must be in `view`
+pub fn check_input_scripts(
+    tx: &Transaction,
+    view: &dyn CoinsView,
+    flags: u32,
+    consensus_branch_id: u32,
+) -> Result<(), TxValidationError> {
+    let precomputed = PrecomputedTransactionData::new(tx);
+    for (n_in, input) in tx.inputs.iter().enumerate() {
+        let coin = view
+            .get_coin(&input.prev_out)
+            .ok_or(TxValidationError::InputsMissingOrSpent)?;
+        let checker = TransactionSignatureChecker::new(
+            tx,
+            n_in,
+            coin.output.value,
+            consensus_branch_id,
+            Some(&precomputed),
+        );
+        verify_script(&input.script_sig, &coin.output.script_pubkey, flags, &checker)
+            .map_err(|_| TxValidationError::ScriptVerifyFailed)?;
+    }
+    Ok(())
+}
+
+/// Outcome of disconnecting a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectResult {
+    /// The prior state was restored exactly
+    Ok,
+    /// The state was rolled back, but the undo data didn't match it exactly
+    Unclean,
+    /// The undo data doesn't belong to the block; the state is unusable
+    Failed,
+}
+
+/// Applies `block` at `height` on top of the best block of `view`: spends
+/// the coins its inputs refer to, adds its outputs, marks its nullifiers
+/// spent and records the note commitment tree anchors after it. Once Sapling
+/// is active the header must commit to the Sapling tree after the block.
+///
+/// `block` must have passed `check_block`. Returns the undo data needed by
+/// `disconnect_block`; if the block is invalid `view` is left unchanged.
+pub fn connect_block(
+    block: &Block,
+    height: u32,
+    view: &mut CoinsViewCache,
+    params: &ConsensusParams,
+) -> Result<BlockUndo, BlockValidationError> {
+    assert_eq!(
+        view.best_block(),
+        block.header.prev_block_hash,
+        "block must extend the best block of the view"
+    );
+
+    let old_sprout_root = view.get_best_anchor(ShieldedType::Sprout);
+    let mut sprout_tree = view
+        .get_sprout_anchor_at(&old_sprout_root)
+        .expect("the best Sprout anchor is always known");
+    let mut sapling_tree = view
+        .get_sapling_anchor_at(&view.get_best_anchor(ShieldedType::Sapling))
+        .expect("the best Sapling anchor is always known");
+    let mut undo = BlockUndo::new(old_sprout_root);
+
+    let mut applied = 0;
+    let mut result = connect_transactions(
+        block,
+        height,
+        view,
+        params,
+        &mut undo,
+        (&mut sprout_tree, &mut sapling_tree),
+        &mut applied,
+    );
+    if result.is_ok()
+        && network_upgrade_active(height, params, NetworkUpgrade::Sapling)
+        && block.header.final_sapling_root != sapling_tree.root()
+    {
+        result = Err(BlockValidationError::BadSaplingRoot);
+    }
+    if let Err(error) = result {
+        let transactions = &block.transactions[..applied];
+        disconnect_transactions(transactions, height, &undo.tx_undos, view);
+        return Err(error);
+    }
+
+    view.push_sprout_anchor(sprout_tree);
+    view.push_sapling_anchor(sapling_tree);
+    view.set_best_block(block.hash());
+    Ok(undo)
+}
+
+/// Applies the transactions of `block` in order, counting those fully
+/// applied in `applied` so that a failure can be rolled back
+fn connect_transactions(
+    block: &Block,
+    height: u32,
+    view: &mut CoinsViewCache,
+    params: &ConsensusParams,
+    undo: &mut BlockUndo,
+    (sprout_tree, sapling_tree): (&mut SproutMerkleTree, &mut SaplingMerkleTree),
+    applied: &mut usize,
+) -> Result<(), BlockValidationError> {
+    let branch_id = current_epoch_branch_id(height, params);
+    let mut fees: Amount = 0;
+
+    for tx in &block.transactions {
+        // Don't overwrite unspent outputs of an earlier transaction with the
+        // same txid
+        let txid = tx.hash();
+        let overwrites = (0..tx.outputs.len() as u32)
+            .any(|index| view.have_coin(&OutPoint { txid, index }));
+        if overwrites {
+            return Err(BlockValidationError::OverwriteTransaction);
+        }
+
+        let mut tx_undo = TxUndo::new();
+        if !tx.is_coinbase() {
+            let fee = check_tx_inputs(tx, view, height).map_err(BlockValidationError::Transaction)?;
+            check_input_scripts(tx, view, BLOCK_SCRIPT_VERIFY_FLAGS, branch_id)
+                .map_err(BlockValidationError::Transaction)?;
+            fees += fee;
+            if !is_valid_amount(fees) {
+                return Err(BlockValidationError::AccumulatedFeeOutOfRange);
+            }
+
+            for input in &tx.inputs {
+                let coin = view.spend_coin(&input.prev_out).expect("inputs were checked");
+                tx_undo.add_input(coin);
+            }
+        }
+        view.set_nullifiers(tx, true);
+        view.add_coins(tx, height);
+        if !tx.is_coinbase() {
+            undo.add_tx_undo(tx_undo);
+        }
+        *applied += 1;
+
+        for join_split in &tx.join_splits {
+            for commitment in &join_split.commitments {
+                sprout_tree.append(*commitment).expect("Sprout note commitment tree is full");
+            }
+        }
+        for output in &tx.shielded_outputs {
+            sapling_tree.append(output.cmu).expect("Sapling note commitment tree is full");
+        }
+    }
+
+    check_coinbase_value(&block.transactions[0], height, fees, params)
+}
+
+/// Reverts `transactions`, the first of which is a coinbase, using the undo
+/// data of the others
+fn disconnect_transactions(
+    transactions: &[Transaction],
+    height: u32,
+    tx_undos: &[TxUndo],
+    view: &mut CoinsViewCache,
+) -> DisconnectResult {
+    let mut clean = true;
+
+    for (i, tx) in transactions.iter().enumerate().rev() {
+        // Check that all outputs are available and match the outputs in the
+        // block itself exactly
+        let txid = tx.hash();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            if output.script_pubkey.is_unspendable() {
+                continue;
+            }
+            let outpoint = OutPoint {
+                txid,
+                index: index as u32,
+            };
+            match view.spend_coin(&outpoint) {
+                Some(coin)
+                    if coin.output == *output
+                        && coin.height == height
+                        && coin.is_coinbase == tx.is_coinbase() => {}
+                _ => clean = false,
+            }
+        }
+
+        view.set_nullifiers(tx, false);
+
+        // Restore inputs
+        if i > 0 {
+            let tx_undo = &tx_undos[i - 1];
+            if tx_undo.prev_coins.len() != tx.inputs.len() {
+                return DisconnectResult::Failed;
+            }
+            for (input, coin) in tx.inputs.iter().zip(&tx_undo.prev_coins).rev() {
+                let overwrite = view.have_coin(&input.prev_out);
+                if overwrite {
+                    clean = false;
+                }
+                view.add_coin(input.prev_out.clone(), coin.clone(), overwrite);
+            }
+        }
+    }
+
+    if clean {
+        DisconnectResult::Ok
+    } else {
+        DisconnectResult::Unclean
+    }
+}
+
+/// Reverts `block`, the best block of `view` at `height`, restoring the
+/// coins, nullifiers and anchors from before it was connected.
+/// `prev_sapling_root` is the final Sapling root of the previous block.
+pub fn disconnect_block(
+    block: &Block,
+    height: u32,
+    undo: &BlockUndo,
+    prev_sapling_root: [u8; 32],
+    view: &mut CoinsViewCache,
+    params: &ConsensusParams,
+) -> DisconnectResult {
+    assert_eq!(view.best_block(), block.hash(), "block must be the best block of the view");
+
+    if block.transactions.is_empty() || undo.tx_undos.len() + 1 != block.transactions.len() {
+        return DisconnectResult::Failed;
+    }
+    let result = disconnect_transactions(&block.transactions, height, &undo.tx_undos, view);
+    if result == DisconnectResult::Failed {
+        return result;
+    }
+
+    // Set the old best anchors back
+    view.pop_anchor(undo.old_sprout_tree_root, ShieldedType::Sprout);
+    if network_upgrade_active(height, params, NetworkUpgrade::Sapling) {
+        let prev_active =
+            height > 0 && network_upgrade_active(height - 1, params, NetworkUpgrade::Sapling);
+        let prev_root = if prev_active {
+            prev_sapling_root
+        } else {
+            SAPLING_EMPTY_ROOT
+        };
+        view.pop_anchor(prev_root, ShieldedType::Sapling);
+    }
+
+    // Move best block pointer to the previous block
+    view.set_best_block(block.header.prev_block_hash);
+    result
 }
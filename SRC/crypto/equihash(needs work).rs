@@ -1,20 +0,0 @@
-pub struct Equihash {
-    n: usize,
-    k: usize,
-}
-
-impl Equihash {
-    pub fn new(n: usize, k: usize) -> Self {
-        Self { n, k }
-    }
-
-    pub fn solve(&self, input: &[u8]) -> Vec<u8> {
-        // Equihash solving logic here
-        Vec::new()
-    }
-
-    pub fn validate(&self, solution: &[u8], input: &[u8]) -> bool {
-        // Equihash validation logic here
-        true
-    }
-}
@@ -0,0 +1,271 @@
+use blake2b_simd::{Params, State};
+use thiserror::Error;
+
+/// Personalization prefix of the original Zcash Equihash parameters
+pub const ZCASH_PERSONALIZATION: [u8; 8] = *b"ZcashPoW";
+/// Personalization prefix BitcoinZ adopted along with Equihash (144, 5)
+pub const BITCOINZ_PERSONALIZATION: [u8; 8] = *b"BitcoinZ";
+
+/// An Equihash instance: the (n, k) parameters and BLAKE2b personalization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EquihashParams {
+    pub n: u32,
+    pub k: u32,
+    pub personalization: [u8; 8],
+}
+
+/// Reasons an Equihash solution is rejected
+#[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+pub enum EquihashError {
+    #[error("Invalid solution size")]
+    SolutionSize,
+    #[error("Invalid collision length between rows")]
+    Collision,
+    #[error("Index tree incorrectly ordered")]
+    OutOfOrder,
+    #[error("Duplicate indices")]
+    DuplicateIndices,
+    #[error("Root hash is not zero")]
+    NonZeroRoot,
+}
+
+impl EquihashParams {
+    pub const fn new(n: u32, k: u32, personalization: [u8; 8]) -> Self {
+        EquihashParams { n, k, personalization }
+    }
+
+    /// Number of bits that must collide in each round
+    pub fn collision_bit_length(&self) -> usize {
+        (self.n / (self.k + 1)) as usize
+    }
+
+    pub fn collision_byte_length(&self) -> usize {
+        self.collision_bit_length().div_ceil(8)
+    }
+
+    /// Length of a hash once expanded to one collision chunk per byte group
+    pub fn hash_length(&self) -> usize {
+        (self.k as usize + 1) * self.collision_byte_length()
+    }
+
+    /// Number of n-bit hashes cut from one BLAKE2b output
+    pub fn indices_per_hash_output(&self) -> usize {
+        512 / self.n as usize
+    }
+
+    pub fn hash_output_length(&self) -> usize {
+        self.indices_per_hash_output() * self.n as usize / 8
+    }
+
+    /// Length in bytes of a minimally encoded solution
+    pub fn solution_size(&self) -> usize {
+        (1usize << self.k) * (self.collision_bit_length() + 1) / 8
+    }
+
+    /// Returns the BLAKE2b state after absorbing `input`, the header
+    /// without its solution
+    pub fn initialise_state(&self, input: &[u8]) -> State {
+        let mut personal = [0u8; 16];
+        personal[..8].copy_from_slice(&self.personalization);
+        personal[8..12].copy_from_slice(&self.n.to_le_bytes());
+        personal[12..].copy_from_slice(&self.k.to_le_bytes());
+
+        let mut state = Params::new()
+            .hash_length(self.hash_output_length())
+            .personal(&personal)
+            .to_state();
+        state.update(input);
+        state
+    }
+
+    /// Returns BLAKE2b output number `g`, which holds the hashes of indices
+    /// `g * indices_per_hash_output()` onwards
+    pub fn generate_hash(&self, state: &State, g: u32) -> Vec<u8> {
+        let mut state = state.clone();
+        state.update(&g.to_le_bytes());
+        state.finalize().as_bytes().to_vec()
+    }
+
+    /// Returns the expanded hash of `index`
+    pub fn hash_index(&self, state: &State, index: u32) -> Vec<u8> {
+        let per_output = self.indices_per_hash_output() as u32;
+        let output = self.generate_hash(state, index / per_output);
+        let width = self.n as usize / 8;
+        let start = (index % per_output) as usize * width;
+        expand_array(&output[start..start + width], self.collision_bit_length(), 0)
+    }
+}
+
+/// Splits `input` into `bit_len`-bit big-endian chunks, each written to
+/// its own group of whole bytes preceded by `byte_pad` zero bytes
+pub fn expand_array(input: &[u8], bit_len: usize, byte_pad: usize) -> Vec<u8> {
+    let out_width = bit_len.div_ceil(8) + byte_pad;
+    let bit_len_mask: u32 = (1 << bit_len) - 1;
+    let mut output = Vec::with_capacity(input.len() * 8 / bit_len * out_width);
+
+    // The acc_bits least-significant bits of acc_value represent a bit
+    // sequence in big-endian order
+    let mut acc_bits = 0;
+    let mut acc_value: u32 = 0;
+    for &byte in input {
+        acc_value = (acc_value << 8) | byte as u32;
+        acc_bits += 8;
+
+        // When we have bit_len or more bits in the accumulator, write the next
+        // output element
+        if acc_bits >= bit_len {
+            acc_bits -= bit_len;
+            output.extend(std::iter::repeat_n(0, byte_pad));
+            for x in byte_pad..out_width {
+                let shift = 8 * (out_width - x - 1);
+                output.push(((acc_value >> (acc_bits + shift)) & ((bit_len_mask >> shift) & 0xff)) as u8);
+            }
+        }
+    }
+    output
+}
+
+/// Inverse of `expand_array`
+pub fn compress_array(input: &[u8], bit_len: usize, byte_pad: usize) -> Vec<u8> {
+    let in_width = bit_len.div_ceil(8) + byte_pad;
+    let bit_len_mask: u32 = (1 << bit_len) - 1;
+    let out_len = input.len() / in_width * bit_len / 8;
+    let mut output = Vec::with_capacity(out_len);
+
+    let mut acc_bits = 0;
+    let mut acc_value: u32 = 0;
+    let mut j = 0;
+    for _ in 0..out_len {
+        // When we have fewer than 8 bits left in the accumulator, read the
+        // next input element
+        if acc_bits < 8 {
+            acc_value <<= bit_len;
+            for x in byte_pad..in_width {
+                let shift = 8 * (in_width - x - 1);
+                acc_value |= (input[j + x] as u32 & ((bit_len_mask >> shift) & 0xff)) << shift;
+            }
+            j += in_width;
+            acc_bits += bit_len;
+        }
+
+        acc_bits -= 8;
+        output.push((acc_value >> acc_bits) as u8);
+    }
+    output
+}
+
+/// Decodes the indices of a minimally encoded solution
+pub fn indices_from_minimal(minimal: &[u8], collision_bit_length: usize) -> Vec<u32> {
+    let byte_pad = 4 - (collision_bit_length + 1).div_ceil(8);
+    expand_array(minimal, collision_bit_length + 1, byte_pad)
+        .chunks(4)
+        .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+/// Encodes solution indices using `collision_bit_length + 1` bits each
+pub fn minimal_from_indices(indices: &[u32], collision_bit_length: usize) -> Vec<u8> {
+    let byte_pad = 4 - (collision_bit_length + 1).div_ceil(8);
+    let array: Vec<u8> = indices.iter().flat_map(|index| index.to_be_bytes()).collect();
+    compress_array(&array, collision_bit_length + 1, byte_pad)
+}
+
+/// A node of the solution's index tree: the XOR of its leaves' hashes, with
+/// the bytes that collided in earlier rounds removed
+struct Row {
+    hash: Vec<u8>,
+    indices: Vec<u32>,
+}
+
+fn combine_rows(left: &Row, right: &Row, trim: usize) -> Result<Row, EquihashError> {
+    if left.hash[..trim] != right.hash[..trim] {
+        return Err(EquihashError::Collision);
+    }
+    if right.indices[0] < left.indices[0] {
+        return Err(EquihashError::OutOfOrder);
+    }
+    if left.indices.iter().any(|index| right.indices.contains(index)) {
+        return Err(EquihashError::DuplicateIndices);
+    }
+
+    Ok(Row {
+        hash: left.hash[trim..]
+            .iter()
+            .zip(&right.hash[trim..])
+            .map(|(a, b)| a ^ b)
+            .collect(),
+        indices: [left.indices.as_slice(), right.indices.as_slice()].concat(),
+    })
+}
+
+/// Checks `solution` against `input`: every pair of subtrees must collide
+/// on the next chunk of bits, be ordered by first index and share no index,
+/// and the XOR of all 2^k hashes must be zero
+pub fn is_valid_solution(
+    params: &EquihashParams,
+    input: &[u8],
+    solution: &[u8],
+) -> Result<(), EquihashError> {
+    if solution.len() != params.solution_size() {
+        return Err(EquihashError::SolutionSize);
+    }
+
+    let state = params.initialise_state(input);
+    let mut rows: Vec<Row> = indices_from_minimal(solution, params.collision_bit_length())
+        .into_iter()
+        .map(|index| Row {
+            hash: params.hash_index(&state, index),
+            indices: vec![index],
+        })
+        .collect();
+
+    let trim = params.collision_byte_length();
+    while rows.len() > 1 {
+        rows = rows
+            .chunks(2)
+            .map(|pair| combine_rows(&pair[0], &pair[1], trim))
+            .collect::<Result<_, _>>()?;
+    }
+
+    if rows[0].hash.iter().any(|byte| *byte != 0) {
+        return Err(EquihashError::NonZeroRoot);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_compress() {
+        let input = [0xff, 0x00, 0xa5, 0x5a, 0x12, 0x34, 0x56, 0x78, 0x9a];
+        // 72 bits split evenly into each chunk size
+        for (bit_len, byte_pad) in [(8, 0), (9, 1), (12, 0), (18, 1)] {
+            let expanded = expand_array(&input, bit_len, byte_pad);
+            assert_eq!(expanded.len(), 72 / bit_len * (bit_len.div_ceil(8) + byte_pad));
+            assert_eq!(compress_array(&expanded, bit_len, byte_pad), input);
+        }
+
+        // 12-bit chunks of 0xabc def
+        assert_eq!(expand_array(&[0xab, 0xcd, 0xef], 12, 0), vec![0x0a, 0xbc, 0x0d, 0xef]);
+    }
+
+    #[test]
+    fn test_minimal_indices() {
+        let indices: Vec<u32> = (0..32).map(|i| i * 4093 % (1 << 17)).collect();
+        let minimal = minimal_from_indices(&indices, 16);
+        assert_eq!(minimal.len(), 32 * 17 / 8);
+        assert_eq!(indices_from_minimal(&minimal, 16), indices);
+    }
+
+    #[test]
+    fn test_params() {
+        let params = EquihashParams::new(200, 9, ZCASH_PERSONALIZATION);
+        assert_eq!(params.collision_bit_length(), 20);
+        assert_eq!(params.hash_length(), 30);
+        assert_eq!(params.hash_output_length(), 50);
+        assert_eq!(params.solution_size(), 1344);
+        assert_eq!(EquihashParams::new(144, 5, BITCOINZ_PERSONALIZATION).solution_size(), 100);
+    }
+}
@@ -0,0 +1,151 @@
+use blake2s_simd::Params;
+use ff::{Field, PrimeField};
+use group::cofactor::CofactorGroup;
+use group::{Curve, Group, GroupEncoding};
+use jubjub::{ExtendedPoint, Fr, SubgroupPoint};
+use std::sync::OnceLock;
+
+/// First 64 bytes of every group hash input, an ASCII hex string of 32 random
+/// bytes chosen for rigidity
+pub const GH_FIRST_BLOCK: &[u8; 64] =
+    b"096b36a5804bfacef1691e173c366a47ff5ba84a44f26ddd7e8d9f79d5b42df0";
+
+/// BLAKE2s personalization used to derive the Pedersen hash generators
+pub const PEDERSEN_HASH_GENERATORS_PERSONALIZATION: &[u8; 8] = b"Zcash_PH";
+
+/// Number of 3-bit chunks each generator covers
+const CHUNKS_PER_GENERATOR: usize = 63;
+
+/// Number of generators, enough for the longest input hashed in Sapling
+const GENERATOR_COUNT: u32 = 6;
+
+/// Domain separation prefixed to the input of a Pedersen hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Personalization {
+    NoteCommitment,
+    MerkleTree(usize),
+}
+
+impl Personalization {
+    fn bits(self) -> impl Iterator<Item = bool> {
+        (0..6).map(move |i| match self {
+            Personalization::NoteCommitment => true,
+            Personalization::MerkleTree(depth) => (depth >> i) & 1 == 1,
+        })
+    }
+}
+
+/// Hashes `tag` into a point of the prime-order subgroup, or returns None if
+/// the digest is not a valid encoding or lands on a small-order point.
+pub fn group_hash(tag: &[u8], personalization: &[u8; 8]) -> Option<SubgroupPoint> {
+    let hash = Params::new()
+        .hash_length(32)
+        .personal(personalization)
+        .to_state()
+        .update(GH_FIRST_BLOCK)
+        .update(tag)
+        .finalize();
+
+    let point = ExtendedPoint::from_bytes(hash.as_array());
+    if bool::from(point.is_none()) {
+        return None;
+    }
+    let point = point.unwrap().clear_cofactor();
+    if bool::from(point.is_identity()) {
+        None
+    } else {
+        Some(point)
+    }
+}
+
+/// Appends a counter byte to `message` and increments it until the group
+/// hash succeeds.
+pub fn find_group_hash(message: &[u8], personalization: &[u8; 8]) -> SubgroupPoint {
+    let mut tag = message.to_vec();
+    let counter = tag.len();
+    tag.push(0);
+    loop {
+        let point = group_hash(&tag, personalization);
+        assert!(tag[counter] != u8::MAX, "no group hash found for message");
+        tag[counter] += 1;
+        if let Some(point) = point {
+            return point;
+        }
+    }
+}
+
+fn generators() -> &'static [SubgroupPoint] {
+    static GENERATORS: OnceLock<Vec<SubgroupPoint>> = OnceLock::new();
+    GENERATORS.get_or_init(|| {
+        (0..GENERATOR_COUNT)
+            .map(|i| find_group_hash(&i.to_le_bytes(), PEDERSEN_HASH_GENERATORS_PERSONALIZATION))
+            .collect()
+    })
+}
+
+/// Computes the Sapling Pedersen hash of `bits`, prefixed by the bits of
+/// `personalization`.
+pub fn pedersen_hash<I>(personalization: Personalization, bits: I) -> SubgroupPoint
+where
+    I: IntoIterator<Item = bool>,
+{
+    let mut bits = personalization.bits().chain(bits);
+    let mut generators = generators().iter();
+    let mut result = SubgroupPoint::identity();
+
+    loop {
+        let mut acc = Fr::ZERO;
+        let mut cur = Fr::ONE;
+        let mut chunks_remaining = CHUNKS_PER_GENERATOR;
+        let mut encountered_bits = false;
+
+        // Each 3-bit chunk encodes a signed digit in {-4..4} \ {0}
+        while let Some(a) = bits.next() {
+            encountered_bits = true;
+            let b = bits.next().unwrap_or(false);
+            let c = bits.next().unwrap_or(false);
+
+            let mut tmp = cur;
+            if a {
+                tmp += cur;
+            }
+            cur = cur.double();
+            if b {
+                tmp += cur;
+            }
+            if c {
+                tmp = -tmp;
+            }
+            acc += tmp;
+
+            chunks_remaining -= 1;
+            if chunks_remaining == 0 {
+                break;
+            }
+            cur = cur.double().double().double();
+        }
+
+        if !encountered_bits {
+            break;
+        }
+        let generator = generators
+            .next()
+            .expect("input too long for the Pedersen hash generators");
+        result += generator * acc;
+    }
+    result
+}
+
+/// Combines two Sapling note commitment tree nodes at `depth` above the
+/// leaves. Nodes are little-endian encodings of Jubjub u-coordinates.
+pub fn merkle_hash(depth: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let node_bits = |node: &[u8; 32]| {
+        let node = *node;
+        (0..bls12_381::Scalar::NUM_BITS as usize).map(move |i| (node[i / 8] >> (i % 8)) & 1 == 1)
+    };
+    let point = pedersen_hash(
+        Personalization::MerkleTree(depth),
+        node_bits(left).chain(node_bits(right)),
+    );
+    ExtendedPoint::from(point).to_affine().get_u().to_bytes()
+}
@@ -1,7 +1,9 @@
-use sled::{Db, IVec};
+use sled::{Batch, Db, IVec};
 use std::path::Path;
 
-/// Represents a database wrapper for key-value storage.
+/// Represents a database wrapper for key-value storage. Clones share the
+/// same database.
+#[derive(Clone)]
 pub struct DBWrapper {
     db: Db,
 }
@@ -34,9 +36,30 @@ impl DBWrapper {
         Ok(())
     }
 
+    /// Reads the raw bytes stored under a binary key.
+    pub fn read_raw(&self, key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.db.get(key)
+    }
+
+    /// Applies all the writes and erases of a batch atomically.
+    pub fn write_batch(&self, batch: Batch) -> Result<(), sled::Error> {
+        self.db.apply_batch(batch)
+    }
+
+    /// Iterates over the entries whose key starts with `prefix`.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> sled::Iter {
+        self.db.scan_prefix(prefix)
+    }
+
+    /// Deletes every entry.
+    pub fn clear(&self) -> Result<(), sled::Error> {
+        self.db.clear()
+    }
+
     /// Flushes the database to disk.
     pub fn flush(&self) -> Result<(), sled::Error> {
-        self.db.flush()
+        self.db.flush()?;
+        Ok(())
     }
 }
 
@@ -1,20 +1,25 @@
-mod deserialize;
-mod transaction;
-
-use deserialize::deserialize_transaction;
-use transaction::Transaction;
+use bitcoinz::consensus::validation::check_transaction;
+use bitcoinz::primitives::transaction::Transaction;
+use bitcoinz::serialize::{Deserializable, Serializable};
 
 /// Fuzz test function for transaction deserialization.
 pub fn fuzz_transaction(data: &[u8]) -> Result<Transaction, String> {
     // Attempt to deserialize the transaction from the input data
-    match deserialize_transaction(data) {
-        Ok(tx) => {
-            println!("Deserialized transaction: {:?}", tx);
-            Ok(tx)
-        }
-        Err(e) => {
-            println!("Deserialization failed: {}", e);
-            Err(e)
-        }
-    }
+    let tx = Transaction::deserialize(&mut &data[..]).map_err(|e| e.to_string())?;
+
+    // Inputs may use non-canonical CompactSize lengths, so compare against the
+    // canonical re-serialization: it must parse back to the same transaction
+    // and serialize to the same bytes again
+    let mut bytes = Vec::new();
+    tx.serialize(&mut bytes).expect("Deserialized transaction must serialize");
+    let reparsed = Transaction::deserialize(&mut bytes.as_slice())
+        .expect("Serialized transaction must deserialize");
+    assert_eq!(reparsed, tx);
+    let mut reserialized = Vec::new();
+    reparsed.serialize(&mut reserialized).expect("Deserialized transaction must serialize");
+    assert_eq!(reserialized, bytes);
+
+    // The context-free checks must reach a verdict without panicking
+    let _ = check_transaction(&tx);
+    Ok(tx)
 }
@@ -1,7 +1,10 @@
 use std::fs::File;
 use std::io::{self, Read};
-use crate::validation::{check_block, ValidationError};
-use crate::block::{Block, deserialize_block};
+use bitcoinz::consensus::params::ConsensusParams;
+use bitcoinz::consensus::validation::{check_block, BlockValidationError};
+use bitcoinz::primitives::block::Block;
+use bitcoinz::serialize::Deserializable;
+use bitcoinz::timedata::TimeData;
 
 /// Fuzz tester for the CheckBlock functionality.
 pub struct Fuzzer {
@@ -16,12 +19,16 @@ impl Fuzzer {
 
     /// Runs the fuzzing process by attempting to deserialize the block
     /// and validating it using the `CheckBlock` function.
-    pub fn run(&self) -> Result<(), ValidationError> {
-        // Attempt to deserialize the block from the input data
-        let block = deserialize_block(&self.input_data)?;
-        
+    pub fn run(&self) -> Result<(), BlockValidationError> {
+        // Attempt to deserialize the block from the input data; inputs that
+        // don't parse are uninteresting
+        let block = match Block::deserialize(&mut self.input_data.as_slice()) {
+            Ok(block) => block,
+            Err(_) => return Ok(()),
+        };
+
         // Validate the block using the `CheckBlock` function
-        check_block(&block)
+        check_block(&block, &ConsensusParams::main(), &TimeData::new(), true, true)
     }
 }
 
@@ -34,7 +41,7 @@ pub fn read_input_from_file(file_path: &str) -> io::Result<Vec<u8>> {
 }
 
 /// Entry point for the fuzzing process.
-fn main() -> Result<(), ValidationError> {
+fn main() -> Result<(), BlockValidationError> {
     // Replace with an actual path to fuzzing input data
     let input_file_path = "fuzz_input.dat";
     
@@ -1,22 +1,171 @@
 #[cfg(test)]
 mod tests {
-    use crate::consensus::validation::check_block;
-    use crate::primitives::block::Block;
+    use crate::consensus::merkle::block_merkle_root;
+    use crate::consensus::params::ConsensusParams;
+    use crate::consensus::validation::{
+        check_block, check_header_against_chain, BlockValidationError, TxValidationError,
+        REJECT_INVALID, REJECT_OBSOLETE,
+    };
+    use crate::primitives::block::{Block, BlockHeader};
+    use crate::primitives::transaction::{OutPoint, Transaction, TxInput};
+    use crate::script::opcodes::OP_CHECKMULTISIG;
+    use crate::script::Script;
+    use crate::timedata::TimeData;
+
+    fn coinbase(tag: u8) -> Transaction {
+        Transaction {
+            inputs: vec![TxInput {
+                prev_out: OutPoint {
+                    txid: [0; 32],
+                    index: u32::MAX,
+                },
+                script_sig: Script::new(vec![tag, 0x51]),
+                sequence: u32::MAX,
+            }],
+            ..Transaction::new_dummy()
+        }
+    }
+
+    fn spend(tag: u8) -> Transaction {
+        let mut tx = Transaction::new_dummy();
+        tx.inputs[0].prev_out.txid = [tag; 32];
+        tx
+    }
+
+    fn block(transactions: Vec<Transaction>) -> Block {
+        let mut block = Block {
+            header: BlockHeader {
+                version: 4,
+                timestamp: TimeData::get_system_time() as u32,
+                bits: 0x200f0f0f,
+                ..BlockHeader::default()
+            },
+            transactions,
+        };
+        block.header.merkle_root = block_merkle_root(&block).0;
+        block
+    }
+
+    fn check(block: &Block) -> Result<(), BlockValidationError> {
+        check_block(block, &ConsensusParams::regtest(), &TimeData::new(), false, true)
+    }
 
     #[test]
     fn valid_block() {
-        let block = Block::default();
-        assert!(check_block(&block));
+        let block = block(vec![coinbase(1), spend(2), spend(3)]);
+        assert_eq!(check(&block), Ok(()));
     }
-}
 
-pub mod consensus {
-    pub mod validation {
-        use crate::primitives::block::Block;
+    #[test]
+    fn header_rules() {
+        let mut old = block(vec![coinbase(1)]);
+        old.header.version = 3;
+        assert_eq!(check(&old), Err(BlockValidationError::VersionTooLow));
+        assert_eq!(BlockValidationError::VersionTooLow.reject_code(), REJECT_OBSOLETE);
+
+        let mut future = block(vec![coinbase(1)]);
+        future.header.timestamp += 3 * 60 * 60;
+        assert_eq!(check(&future), Err(BlockValidationError::TimeTooNew));
+        assert_eq!(BlockValidationError::TimeTooNew.dos_score(), 0);
+        assert!(!BlockValidationError::TimeTooNew.is_consensus_failure());
+        assert!(BlockValidationError::VersionTooLow.is_consensus_failure());
 
-        pub fn check_block(block: &Block) -> bool {
-            // Implement block validation logic
-            true
-        }
+        // An empty solution is never valid once PoW is checked
+        let block = block(vec![coinbase(1)]);
+        assert_eq!(
+            check_block(&block, &ConsensusParams::regtest(), &TimeData::new(), true, true),
+            Err(BlockValidationError::InvalidSolution)
+        );
+    }
+
+    #[test]
+    fn merkle_root_mismatch() {
+        let mut block = block(vec![coinbase(1), spend(2)]);
+        block.header.merkle_root[0] ^= 1;
+        assert_eq!(check(&block), Err(BlockValidationError::BadMerkleRoot));
+        assert!(BlockValidationError::BadMerkleRoot.corruption_possible());
+        assert!(!BlockValidationError::BadMerkleRoot.is_consensus_failure());
+    }
+
+    #[test]
+    fn merkle_mutation() {
+        // The duplicated transaction leaves the root unchanged (CVE-2012-2459)
+        let honest = block(vec![coinbase(1), spend(2), spend(3)]);
+        let mut mutated = honest.clone();
+        mutated.transactions.push(spend(3));
+        assert_eq!(block_merkle_root(&mutated).0, honest.header.merkle_root);
+
+        assert_eq!(check(&mutated), Err(BlockValidationError::DuplicateTransactions));
+        assert!(BlockValidationError::DuplicateTransactions.corruption_possible());
+        assert_eq!(BlockValidationError::DuplicateTransactions.dos_score(), 100);
+    }
+
+    #[test]
+    fn coinbase_placement() {
+        assert_eq!(check(&block(vec![])), Err(BlockValidationError::BadLength));
+        assert_eq!(
+            check(&block(vec![spend(2), coinbase(1)])),
+            Err(BlockValidationError::CoinbaseMissing)
+        );
+        assert_eq!(
+            check(&block(vec![coinbase(1), coinbase(2)])),
+            Err(BlockValidationError::CoinbaseMultiple)
+        );
+    }
+
+    #[test]
+    fn transaction_failures_propagate() {
+        let mut bad = spend(2);
+        bad.outputs[0].value = -1;
+        let error = check(&block(vec![coinbase(1), bad])).unwrap_err();
+        assert_eq!(error, BlockValidationError::Transaction(TxValidationError::VoutNegative));
+        assert_eq!(error.to_string(), "bad-txns-vout-negative");
+        assert_eq!(error.reject_code(), REJECT_INVALID);
+    }
+
+    #[test]
+    fn sigop_limit() {
+        // Each bare CHECKMULTISIG counts as 20 legacy sigops
+        let mut heavy = spend(2);
+        heavy.outputs[0].script_pubkey = Script::new(vec![OP_CHECKMULTISIG; 1001]);
+        assert_eq!(
+            check(&block(vec![coinbase(1), heavy])),
+            Err(BlockValidationError::TooManySigOps)
+        );
+    }
+
+    #[test]
+    fn header_chain_rules() {
+        let params = ConsensusParams::regtest();
+        let chain: Vec<BlockHeader> = (0..11)
+            .map(|n| BlockHeader {
+                timestamp: 1_500_000_000 + n * 150,
+                bits: 0x200f0f0f,
+                ..BlockHeader::default()
+            })
+            .collect();
+        let mut header = BlockHeader {
+            timestamp: 1_500_000_000 + 11 * 150,
+            bits: 0x200f0f0f,
+            ..BlockHeader::default()
+        };
+        assert_eq!(check_header_against_chain(&header, &chain[..], &params), Ok(()));
+
+        // Regtest keeps the target of the parent
+        header.bits = 0x1f0f0f0f;
+        assert_eq!(
+            check_header_against_chain(&header, &chain[..], &params),
+            Err(BlockValidationError::BadDiffBits)
+        );
+
+        // The median of the last eleven timestamps is that of block 5
+        header.bits = 0x200f0f0f;
+        header.timestamp = 1_500_000_000 + 5 * 150;
+        assert_eq!(
+            check_header_against_chain(&header, &chain[..], &params),
+            Err(BlockValidationError::TimeTooOld)
+        );
+        header.timestamp += 1;
+        assert_eq!(check_header_against_chain(&header, &chain[..], &params), Ok(()));
     }
 }
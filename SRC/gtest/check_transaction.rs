@@ -1,36 +1,234 @@
 #[cfg(test)]
 mod tests {
-    use crate::consensus::validation::check_transaction;
-    use crate::primitives::transaction::Transaction;
+    use crate::amount::MAX_MONEY;
+    use crate::consensus::validation::{
+        check_transaction, TxValidationError, REJECT_DUPLICATE, REJECT_INSUFFICIENTFEE,
+        REJECT_INVALID, REJECT_NONSTANDARD, REJECT_OBSOLETE,
+    };
+    use crate::primitives::transaction::{
+        JSDescription, OutPoint, SpendDescription, SproutProof, Transaction, TxInput, TxOutput,
+        GROTH_PROOF_SIZE, SAPLING_TX_VERSION, SAPLING_VERSION_GROUP_ID, ZC_NUM_JS_OUTPUTS,
+        ZC_SPROUT_CIPHERTEXT_SIZE,
+    };
+    use crate::script::Script;
+
+    fn valid_tx() -> Transaction {
+        Transaction::new_dummy()
+    }
+
+    fn sapling_tx() -> Transaction {
+        Transaction {
+            overwintered: true,
+            version: SAPLING_TX_VERSION,
+            version_group_id: SAPLING_VERSION_GROUP_ID,
+            ..valid_tx()
+        }
+    }
+
+    fn joinsplit(nullifiers: [[u8; 32]; 2]) -> JSDescription {
+        JSDescription {
+            vpub_old: 0,
+            vpub_new: 0,
+            anchor: [1; 32],
+            nullifiers,
+            commitments: [[4; 32], [5; 32]],
+            ephemeral_key: [6; 32],
+            random_seed: [7; 32],
+            macs: [[8; 32], [9; 32]],
+            proof: SproutProof::Groth([11; GROTH_PROOF_SIZE]),
+            ciphertexts: [[10; ZC_SPROUT_CIPHERTEXT_SIZE]; ZC_NUM_JS_OUTPUTS],
+        }
+    }
+
+    fn spend(nullifier: [u8; 32]) -> SpendDescription {
+        SpendDescription {
+            cv: [1; 32],
+            anchor: [2; 32],
+            nullifier,
+            rk: [3; 32],
+            zkproof: [4; GROTH_PROOF_SIZE],
+            spend_auth_sig: [5; 64],
+        }
+    }
+
+    fn coinbase(script_sig_len: usize) -> Transaction {
+        Transaction {
+            inputs: vec![TxInput {
+                prev_out: OutPoint {
+                    txid: [0; 32],
+                    index: u32::MAX,
+                },
+                script_sig: Script::new(vec![0x51; script_sig_len]),
+                sequence: u32::MAX,
+            }],
+            ..valid_tx()
+        }
+    }
 
     #[test]
     fn valid_transaction() {
-        let tx = Transaction::default();
-        assert!(check_transaction(&tx));
+        assert_eq!(check_transaction(&valid_tx()), Ok(()));
+        assert_eq!(check_transaction(&sapling_tx()), Ok(()));
     }
 
     #[test]
     fn invalid_transaction() {
-        let mut tx = Transaction::default();
-        tx.vin.clear(); // Invalid: no inputs
-        assert!(!check_transaction(&tx));
+        let mut tx = valid_tx();
+        tx.inputs.clear(); // Invalid: no inputs
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VinEmpty));
+        assert_eq!(TxValidationError::VinEmpty.dos_score(), 10);
+
+        let mut tx = valid_tx();
+        tx.outputs.clear();
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VoutEmpty));
     }
-}
 
-pub mod consensus {
-    pub mod validation {
-        use crate::primitives::transaction::Transaction;
+    #[test]
+    fn shielded_inputs_replace_transparent_ones() {
+        let mut tx = sapling_tx();
+        tx.inputs.clear();
+        tx.join_splits.push(joinsplit([[2; 32], [3; 32]]));
+        assert_eq!(check_transaction(&tx), Ok(()));
+    }
 
-        pub fn check_transaction(tx: &Transaction) -> bool {
-            !tx.vin.is_empty() && !tx.vout.is_empty()
-        }
+    #[test]
+    fn bad_output_values() {
+        let mut tx = valid_tx();
+        tx.outputs[0].value = -1;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VoutNegative));
+        assert_eq!(TxValidationError::VoutNegative.to_string(), "bad-txns-vout-negative");
+        assert_eq!(TxValidationError::VoutNegative.dos_score(), 100);
+
+        tx.outputs[0].value = MAX_MONEY + 1;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VoutTooLarge));
+
+        tx.outputs[0].value = MAX_MONEY;
+        tx.outputs.push(TxOutput {
+            value: 1,
+            script_pubkey: Script::default(),
+        });
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::TxOutTotalTooLarge));
     }
-}
 
-pub mod primitives {
-    #[derive(Default)]
-    pub struct Transaction {
-        pub vin: Vec<String>,  // Replace `String` with input struct
-        pub vout: Vec<String>, // Replace `String` with output struct
+    #[test]
+    fn duplicate_inputs() {
+        let mut tx = valid_tx();
+        tx.inputs.push(tx.inputs[0].clone());
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::InputsDuplicate));
+    }
+
+    #[test]
+    fn prevout_null_outside_coinbase() {
+        let mut tx = valid_tx();
+        let mut input = coinbase(2).inputs[0].clone();
+        input.script_sig = Script::default();
+        tx.inputs.push(input);
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::PrevoutNull));
+    }
+
+    #[test]
+    fn coinbase_script_length() {
+        assert_eq!(check_transaction(&coinbase(1)), Err(TxValidationError::CoinbaseLength));
+        assert_eq!(check_transaction(&coinbase(2)), Ok(()));
+        assert_eq!(check_transaction(&coinbase(100)), Ok(()));
+        assert_eq!(check_transaction(&coinbase(101)), Err(TxValidationError::CoinbaseLength));
+    }
+
+    #[test]
+    fn coinbase_without_shielded_components() {
+        let mut tx = Transaction {
+            overwintered: true,
+            version: SAPLING_TX_VERSION,
+            version_group_id: SAPLING_VERSION_GROUP_ID,
+            ..coinbase(2)
+        };
+        tx.join_splits.push(joinsplit([[2; 32], [3; 32]]));
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::CoinbaseHasJoinSplits));
+
+        tx.join_splits.clear();
+        tx.shielded_spends.push(spend([1; 32]));
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::CoinbaseHasSpendDescription));
+    }
+
+    #[test]
+    fn duplicate_nullifiers() {
+        // Within one JoinSplit
+        let mut tx = sapling_tx();
+        tx.join_splits.push(joinsplit([[2; 32], [2; 32]]));
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::JoinSplitNullifiersDuplicate));
+
+        // Across JoinSplits
+        let mut tx = sapling_tx();
+        tx.join_splits.push(joinsplit([[2; 32], [3; 32]]));
+        tx.join_splits.push(joinsplit([[4; 32], [3; 32]]));
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::JoinSplitNullifiersDuplicate));
+
+        let mut tx = sapling_tx();
+        tx.shielded_spends.push(spend([1; 32]));
+        tx.shielded_spends.push(spend([1; 32]));
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::SpendNullifiersDuplicate));
+    }
+
+    #[test]
+    fn joinsplit_values() {
+        let mut tx = sapling_tx();
+        let mut js = joinsplit([[2; 32], [3; 32]]);
+        js.vpub_old = 1;
+        js.vpub_new = 1;
+        tx.join_splits.push(js);
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VpubsBothNonZero));
+
+        tx.join_splits[0].vpub_new = -1;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VpubNewNegative));
+
+        tx.join_splits[0].vpub_new = 0;
+        tx.join_splits[0].vpub_old = MAX_MONEY + 1;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VpubOldTooLarge));
+    }
+
+    #[test]
+    fn value_balance_bounds() {
+        let mut tx = sapling_tx();
+        tx.value_balance = 1;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::ValueBalanceNonZero));
+
+        tx.shielded_spends.push(spend([1; 32]));
+        assert_eq!(check_transaction(&tx), Ok(()));
+
+        tx.value_balance = -MAX_MONEY - 1;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::ValueBalanceTooLarge));
+    }
+
+    #[test]
+    fn overwinter_version_rules() {
+        let mut tx = sapling_tx();
+        tx.version_group_id = 0;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VersionGroupId));
+
+        let mut tx = sapling_tx();
+        tx.version = 2;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::OverwinterVersionTooLow));
+
+        let mut tx = sapling_tx();
+        tx.expiry_height = 500_000_000;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::ExpiryHeightTooHigh));
+
+        let mut tx = valid_tx();
+        tx.version = 0;
+        assert_eq!(check_transaction(&tx), Err(TxValidationError::VersionTooLow));
+    }
+
+    #[test]
+    fn reject_codes() {
+        assert_eq!(TxValidationError::VoutNegative.reject_code(), REJECT_INVALID);
+        assert_eq!(TxValidationError::OverwinterVersionTooLow.reject_code(), REJECT_OBSOLETE);
+        assert_eq!(TxValidationError::Expired.reject_code(), REJECT_OBSOLETE);
+        assert_eq!(TxValidationError::AlreadyInMempool.reject_code(), REJECT_DUPLICATE);
+        assert_eq!(TxValidationError::InsufficientFee.reject_code(), REJECT_INSUFFICIENTFEE);
+
+        let error = TxValidationError::NonStandard("dust");
+        assert_eq!(error.reject_code(), REJECT_NONSTANDARD);
+        assert_eq!(error.to_string(), "dust");
+        assert_eq!(error.dos_score(), 0);
     }
 }
@@ -1,21 +1,208 @@
 #[cfg(test)]
 mod tests {
-    use crate::equihash::validate_solution;
+    use crate::consensus::params::ConsensusParams;
+    use crate::consensus::validation::{contextual_check_block_header, BlockValidationError};
+    use crate::crypto::equihash::{
+        is_valid_solution, minimal_from_indices, EquihashError, EquihashParams,
+        BITCOINZ_PERSONALIZATION, ZCASH_PERSONALIZATION,
+    };
+    use crate::pow::check_equihash_solution;
+    use crate::pow::equi::EquihashSolver;
+    use std::sync::atomic::AtomicBool;
+    use crate::primitives::block::BlockHeader;
+    use crate::serialize::Deserializable;
+
+    /// Upstream Zcash test vector for (96, 5)
+    const ZCASH_INPUT: &[u8] =
+        b"Equihash is an asymmetric PoW based on the Generalised Birthday problem.";
+    const ZCASH_SOLUTION: [u32; 32] = [
+        2261, 15185, 36112, 104243, 23779, 118390, 118332, 130041, 32642, 69878, 76925, 80080,
+        45858, 116805, 92842, 111026, 15972, 115059, 85191, 90330, 68190, 122819, 81830, 91132,
+        23460, 49807, 52426, 80391, 69567, 114474, 104973, 122568,
+    ];
+
+    fn zcash_input() -> Vec<u8> {
+        let mut nonce = [0u8; 32];
+        nonce[0] = 1;
+        [ZCASH_INPUT, &nonce[..]].concat()
+    }
+
+    fn check_96_5(indices: &[u32]) -> Result<(), EquihashError> {
+        let params = EquihashParams::new(96, 5, ZCASH_PERSONALIZATION);
+        is_valid_solution(&params, &zcash_input(), &minimal_from_indices(indices, 16))
+    }
+
+    /// A regtest header with a (48, 5) solution under each personalization
+    fn header(nonce: u8, solution: &str) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: 4,
+            timestamp: 1_500_000_000,
+            bits: 0x200f0f0f,
+            solution: hex::decode(solution).unwrap(),
+            ..Default::default()
+        };
+        header.nonce[0] = nonce;
+        header
+    }
+
+    const ZCASH_48_5_SOLUTION: &str =
+        "02318aec11af115b551f67aede370e8f1feb11228d502201fad7d54eafa435e52f41f75c";
+    const BITCOINZ_48_5_SOLUTION: &str =
+        "04fdd58f411e806a470fdcde1b6c4f9b39c2225368dbe3144de73a39a9285a95fc59abd5";
 
     #[test]
     fn valid_solution() {
-        assert!(validate_solution("data", "solution"));
+        assert_eq!(check_96_5(&ZCASH_SOLUTION), Ok(()));
+        assert_eq!(
+            hex::encode(minimal_from_indices(&ZCASH_SOLUTION, 16)),
+            concat!(
+                "046a8ed451a2197332e71f39db9c79fbf93fc1443da58fb38d0599172116d555b1b21f32705ce998",
+                "f60da852f77f0e7f4d63fc2dd230a3d99953a0787dfefcab341bdec8",
+            )
+        );
     }
 
     #[test]
     fn invalid_solution() {
-        assert!(!validate_solution("data", "bad_solution"));
+        // Swapped leaves break the ordering of the first pair
+        let mut indices = ZCASH_SOLUTION;
+        indices.swap(0, 1);
+        assert_eq!(check_96_5(&indices), Err(EquihashError::OutOfOrder));
+
+        // Swapped subtrees are out of order at the root
+        let indices = [&ZCASH_SOLUTION[16..], &ZCASH_SOLUTION[..16]].concat();
+        assert_eq!(check_96_5(&indices), Err(EquihashError::OutOfOrder));
+
+        let mut indices = ZCASH_SOLUTION;
+        indices[1] = indices[0];
+        assert_eq!(check_96_5(&indices), Err(EquihashError::DuplicateIndices));
+
+        let mut indices = ZCASH_SOLUTION;
+        indices[31] ^= 1;
+        assert_eq!(check_96_5(&indices), Err(EquihashError::Collision));
+
+        let params = EquihashParams::new(96, 5, ZCASH_PERSONALIZATION);
+        assert_eq!(
+            is_valid_solution(&params, &zcash_input(), &[0; 67]),
+            Err(EquihashError::SolutionSize)
+        );
     }
-}
 
-pub mod equihash {
-    pub fn validate_solution(data: &str, solution: &str) -> bool {
-        // Example logic: Replace with actual Equihash validation
-        solution == "solution" && data == "data"
+    #[test]
+    fn personalization() {
+        let input = header(0, ZCASH_48_5_SOLUTION).header_without_solution();
+        let solution = hex::decode(ZCASH_48_5_SOLUTION).unwrap();
+        let zcash = EquihashParams::new(48, 5, ZCASH_PERSONALIZATION);
+        let bitcoinz = EquihashParams::new(48, 5, BITCOINZ_PERSONALIZATION);
+        assert_eq!(is_valid_solution(&zcash, &input, &solution), Ok(()));
+        assert_eq!(
+            is_valid_solution(&bitcoinz, &input, &solution),
+            Err(EquihashError::Collision)
+        );
+
+        let input = header(1, BITCOINZ_48_5_SOLUTION).header_without_solution();
+        let solution = hex::decode(BITCOINZ_48_5_SOLUTION).unwrap();
+        assert_eq!(is_valid_solution(&bitcoinz, &input, &solution), Ok(()));
+    }
+
+    #[test]
+    fn block_header_solution() {
+        let params = ConsensusParams::regtest();
+        let header = header(0, ZCASH_48_5_SOLUTION);
+        assert!(check_equihash_solution(&header, &params));
+
+        // The nonce is part of the hashed input
+        let mut other = header.clone();
+        other.nonce[0] = 1;
+        assert!(!check_equihash_solution(&other, &params));
+
+        // No parameter set has a 35-byte solution
+        let mut other = header;
+        other.solution.pop();
+        assert!(!check_equihash_solution(&other, &params));
+    }
+
+    #[test]
+    fn parameters_by_height() {
+        let params = ConsensusParams::main();
+        let fork_height = params.equihash_schedule[1].0;
+        assert_eq!(fork_height, 160_000);
+        assert_eq!(ConsensusParams::testnet().equihash_schedule[1].0, 7_590);
+
+        let before = params.equihash_params(fork_height - 1);
+        let after = params.equihash_params(fork_height);
+        assert_eq!((before.n, before.k, before.solution_size()), (200, 9, 1344));
+        assert_eq!((after.n, after.k, after.solution_size()), (144, 5, 100));
+        assert_eq!(after.personalization, BITCOINZ_PERSONALIZATION);
+        assert_eq!(params.equihash_params_for_solution_size(100), Some(after));
+        assert_eq!(ConsensusParams::regtest().equihash_params(0).solution_size(), 36);
+
+        // Both sets are valid from the fork until 10 blocks after it
+        assert_eq!(params.valid_equihash_params(fork_height - 1), vec![before]);
+        assert_eq!(params.valid_equihash_params(fork_height), vec![after, before]);
+        assert_eq!(params.valid_equihash_params(fork_height + 10), vec![after, before]);
+        assert_eq!(params.valid_equihash_params(fork_height + 11), vec![after]);
+
+        let header = BlockHeader {
+            solution: vec![0; 100],
+            ..Default::default()
+        };
+        assert_eq!(contextual_check_block_header(&header, fork_height, &params), Ok(()));
+        assert_eq!(
+            contextual_check_block_header(&header, fork_height - 1, &params),
+            Err(BlockValidationError::InvalidSolutionSize)
+        );
+
+        let header = BlockHeader {
+            solution: vec![0; 1344],
+            ..Default::default()
+        };
+        assert_eq!(contextual_check_block_header(&header, fork_height + 10, &params), Ok(()));
+        assert_eq!(
+            contextual_check_block_header(&header, fork_height + 11, &params),
+            Err(BlockValidationError::InvalidSolutionSize)
+        );
+    }
+
+    /// Mainnet headers, one per line as "<height> <serialized header hex>",
+    /// with (200, 9) headers from before the fork and (144, 5) ones after it
+    const MAINNET_HEADERS: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/SRC/gtest/data/mainnet_headers.txt");
+
+    #[test]
+    #[ignore = "needs BitcoinZ mainnet headers in SRC/gtest/data/mainnet_headers.txt"]
+    fn mainnet_headers() {
+        let params = ConsensusParams::main();
+        let data = std::fs::read_to_string(MAINNET_HEADERS).unwrap();
+        let mut sizes = Vec::new();
+
+        for line in data.lines().filter(|line| !line.trim().is_empty()) {
+            let (height, raw) = line.split_once(' ').unwrap();
+            let height: u32 = height.parse().unwrap();
+            let raw = hex::decode(raw.trim()).unwrap();
+            let header = BlockHeader::deserialize(&mut raw.as_slice()).unwrap();
+
+            assert!(check_equihash_solution(&header, &params), "height {}", height);
+            assert_eq!(contextual_check_block_header(&header, height, &params), Ok(()));
+            sizes.push(header.solution.len());
+        }
+
+        // Both parameter sets must be covered
+        assert!(sizes.contains(&1344) && sizes.contains(&100));
+    }
+
+    #[test]
+    fn solver_finds_known_solutions() {
+        let cancel = AtomicBool::new(false);
+
+        let solver = EquihashSolver::new(EquihashParams::new(96, 5, ZCASH_PERSONALIZATION));
+        let solutions = solver.solve(&zcash_input(), &cancel).unwrap();
+        assert_eq!(solutions.len(), 2);
+        assert!(solutions.contains(&minimal_from_indices(&ZCASH_SOLUTION, 16)));
+
+        let solver = EquihashSolver::new(ConsensusParams::regtest().equihash_params(0));
+        let header = header(0, ZCASH_48_5_SOLUTION);
+        let solutions = solver.solve(&header.header_without_solution(), &cancel).unwrap();
+        assert!(solutions.contains(&header.solution));
     }
 }
@@ -1,21 +1,100 @@
 #[cfg(test)]
 mod tests {
-    use crate::founders_reward::founders_reward;
+    use crate::amount::{Amount, COIN};
+    use crate::consensus::params::ConsensusParams;
+    use crate::consensus::subsidy::{
+        get_block_subsidy, get_funding_stream_outputs, get_miner_subsidy, FundingStream,
+    };
+    use crate::consensus::validation::{
+        check_coinbase_value, contextual_check_block, BlockValidationError,
+    };
+    use crate::miner::create_coinbase_transaction;
+    use crate::primitives::block::Block;
+    use crate::script::Script;
+
+    fn stream_script() -> Script {
+        Script::new(vec![0xa9, 0x14, 0x01])
+    }
+
+    /// Regtest with a 5% stream over heights 10..20
+    fn params() -> ConsensusParams {
+        let mut params = ConsensusParams::regtest();
+        params.funding_streams.push(FundingStream {
+            name: "test",
+            numerator: 5,
+            denominator: 100,
+            start_height: 10,
+            end_height: 20,
+            script_pubkey: stream_script(),
+        });
+        params
+    }
+
+    fn block_with_coinbase(height: u32, fees: Amount, params: &ConsensusParams) -> Block {
+        Block {
+            transactions: vec![create_coinbase_transaction(height, &Script::default(), fees, params)],
+            ..Default::default()
+        }
+    }
 
     #[test]
-    fn valid_reward() {
-        assert_eq!(founders_reward(1000), 50); // 5% of 1000
+    fn mainnet_has_no_founders_reward() {
+        let params = ConsensusParams::main();
+        for height in [1, 328_500, 840_000] {
+            assert!(get_funding_stream_outputs(height, &params).is_empty());
+            assert_eq!(get_miner_subsidy(height, &params), get_block_subsidy(height, &params));
+        }
     }
 
     #[test]
-    fn zero_reward() {
-        assert_eq!(founders_reward(0), 0);
+    fn funding_stream_heights() {
+        let params = params();
+        assert!(get_funding_stream_outputs(9, &params).is_empty());
+        assert_eq!(
+            get_funding_stream_outputs(10, &params),
+            vec![(stream_script(), 625 * COIN)]
+        );
+        assert!(get_funding_stream_outputs(20, &params).is_empty());
+        assert_eq!(get_miner_subsidy(10, &params), 11_875 * COIN);
     }
-}
 
-pub mod founders_reward {
-    pub fn founders_reward(block_reward: u64) -> u64 {
-        // Example calculation: 5% founders reward
-        (block_reward * 5) / 100
+    #[test]
+    fn coinbase_must_pay_funding_stream() {
+        let params = params();
+        let block = block_with_coinbase(15, 0, &params);
+        assert_eq!(contextual_check_block(&block, 15, &params), Ok(()));
+
+        let mut block = block_with_coinbase(15, 0, &params);
+        block.transactions[0].outputs.truncate(1);
+        assert_eq!(
+            contextual_check_block(&block, 15, &params),
+            Err(BlockValidationError::CoinbaseFundingStreamMissing)
+        );
+
+        // The stream must receive exactly its share
+        let mut block = block_with_coinbase(15, 0, &params);
+        block.transactions[0].outputs[1].value -= 1;
+        assert_eq!(
+            contextual_check_block(&block, 15, &params),
+            Err(BlockValidationError::CoinbaseFundingStreamMissing)
+        );
+    }
+
+    #[test]
+    fn coinbase_must_not_overpay() {
+        let params = params();
+        let coinbase = create_coinbase_transaction(15, &Script::default(), 1_000, &params);
+        assert_eq!(check_coinbase_value(&coinbase, 15, 1_000, &params), Ok(()));
+        assert_eq!(
+            check_coinbase_value(&coinbase, 15, 999, &params),
+            Err(BlockValidationError::CoinbaseAmount)
+        );
+
+        // After a halving the old subsidy is too much
+        let coinbase = create_coinbase_transaction(149, &Script::default(), 0, &params);
+        assert_eq!(
+            check_coinbase_value(&coinbase, 150, 0, &params),
+            Err(BlockValidationError::CoinbaseAmount)
+        );
     }
 }
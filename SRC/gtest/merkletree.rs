@@ -1,40 +1,97 @@
 #[cfg(test)]
 mod tests {
-    use crate::merkletree::compute_merkle_root;
+    use crate::incremental_merkle_tree::{
+        empty_root, sha256_compress, MerkleHash, SaplingHash, SaplingMerkleTree, SproutMerkleTree,
+        SAPLING_TREE_DEPTH, SPROUT_TREE_DEPTH,
+    };
 
-    #[test]
-    fn compute_root() {
-        let transactions = vec!["tx1".to_string(), "tx2".to_string(), "tx3".to_string(), "tx4".to_string()];
-        let root = compute_merkle_root(&transactions);
-        assert_eq!(root, "expected_merkle_root");
+    /// Root computed level by level over every leaf
+    fn naive_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        let mut level = leaves.to_vec();
+        for depth in 0..SPROUT_TREE_DEPTH {
+            if level.is_empty() {
+                return empty_root(SPROUT_TREE_DEPTH);
+            }
+            if level.len() % 2 == 1 {
+                level.push(empty_root(depth));
+            }
+            level = level.chunks(2).map(|pair| sha256_compress(&pair[0], &pair[1])).collect();
+        }
+        level[0]
     }
-}
 
-pub mod merkletree {
-    use sha2::{Digest, Sha256};
-
-    pub fn compute_merkle_root(transactions: &[String]) -> String {
-        if transactions.is_empty() {
-            return "empty_tree".to_string();
+    fn naive_sapling_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        let mut level = leaves.to_vec();
+        for depth in 0..SAPLING_TREE_DEPTH {
+            if level.is_empty() {
+                return SaplingHash::empty_root(SAPLING_TREE_DEPTH);
+            }
+            if level.len() % 2 == 1 {
+                level.push(SaplingHash::empty_root(depth));
+            }
+            level = level
+                .chunks(2)
+                .map(|pair| SaplingHash::combine(depth, &pair[0], &pair[1]))
+                .collect();
         }
+        level[0]
+    }
 
-        let mut hashes: Vec<String> = transactions.iter().map(|tx| hash(tx)).collect();
+    fn commitment(i: u32) -> [u8; 32] {
+        let mut commitment = [0u8; 32];
+        commitment[..4].copy_from_slice(&i.to_le_bytes());
+        commitment[31] = 0xcc;
+        commitment
+    }
 
-        while hashes.len() > 1 {
-            let mut new_hashes = Vec::new();
-            for pair in hashes.chunks(2) {
-                let concatenated = pair.join("");
-                new_hashes.push(hash(&concatenated));
-            }
-            hashes = new_hashes;
+    #[test]
+    fn empty_sprout_root() {
+        // Displayed upstream as 59d2cde5...c812c6d7
+        let mut root = SproutMerkleTree::empty_root();
+        root.reverse();
+        assert_eq!(
+            hex::encode(root),
+            "59d2cde5e65c1414c32ba54f0fe4bdb3d67618125286e6a191317917c812c6d7"
+        );
+        assert_eq!(SproutMerkleTree::new().root(), SproutMerkleTree::empty_root());
+        assert_eq!(SproutMerkleTree::new().size(), 0);
+    }
+
+    #[test]
+    fn incremental_root_matches_full_tree() {
+        let mut tree = SproutMerkleTree::new();
+        let mut leaves = Vec::new();
+        for i in 0..40 {
+            leaves.push(commitment(i));
+            tree.append(commitment(i)).unwrap();
+            assert_eq!(tree.size(), leaves.len() as u64);
+            assert_eq!(tree.root(), naive_root(&leaves), "{} leaves", leaves.len());
         }
+    }
 
-        hashes[0].clone()
+    #[test]
+    fn incremental_sapling_root_matches_full_tree() {
+        assert_eq!(SaplingMerkleTree::new().root(), SaplingMerkleTree::empty_root());
+        let mut tree = SaplingMerkleTree::new();
+        let mut leaves = Vec::new();
+        for i in 0..9 {
+            // Leaves are field elements, so keep the top byte small
+            let mut leaf = commitment(i);
+            leaf[31] = 0x0c;
+            leaves.push(leaf);
+            tree.append(leaf).unwrap();
+            assert_eq!(tree.size(), leaves.len() as u64);
+            assert_eq!(tree.root(), naive_sapling_root(&leaves), "{} leaves", leaves.len());
+        }
     }
 
-    fn hash(data: &str) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        format!("{:x}", hasher.finalize())
+    #[test]
+    fn appending_changes_the_root() {
+        let mut tree = SproutMerkleTree::new();
+        tree.append(commitment(1)).unwrap();
+        let before = tree.clone();
+        tree.append(commitment(2)).unwrap();
+        assert_ne!(tree.root(), before.root());
+        assert_ne!(tree, before);
     }
 }
@@ -1,33 +1,140 @@
 #[cfg(test)]
 mod tests {
-    use crate::miner::generate_block;
-    use crate::block::Block;
+    use crate::coins::{Coin, CoinsViewCache, UTXOSet};
+    use crate::consensus::params::ConsensusParams;
+    use crate::consensus::subsidy::get_block_subsidy;
+    use crate::consensus::upgrades::NetworkUpgrade;
+    use crate::consensus::validation::connect_block;
+    use crate::incremental_merkle_tree::SaplingMerkleTree;
+    use crate::miner::Miner;
+    use crate::pow::check_equihash_solution;
+    use crate::primitives::block::BlockHeader;
+    use crate::primitives::transaction::{
+        OutPoint, OutputDescription, Transaction, TxInput, TxOutput, GROTH_PROOF_SIZE,
+        SAPLING_ENC_CIPHERTEXT_SIZE, SAPLING_OUT_CIPHERTEXT_SIZE, SAPLING_TX_VERSION,
+        SAPLING_VERSION_GROUP_ID,
+    };
+    use crate::script::opcodes::OP_TRUE;
+    use crate::script::Script;
+    use crate::txmempool::Mempool;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    fn genesis() -> Vec<BlockHeader> {
+        vec![BlockHeader {
+            bits: 0x200f0f0f,
+            timestamp: 1_500_000_000,
+            ..Default::default()
+        }]
+    }
 
     #[test]
     fn generate_block() {
-        let block = generate_block();
-        assert_eq!(block.vtx.len(), 1); // Block contains coinbase transaction
+        let params = ConsensusParams::regtest();
+        let miner = Miner::new(Arc::new(Mempool::new()), params.clone());
+        let chain = genesis();
+        let view = UTXOSet::new();
+        let block = miner.create_block_template(
+            &chain[..],
+            &view,
+            [1; 32],
+            1_500_000_150,
+            &Script::default(),
+        );
+
+        assert_eq!(block.transactions.len(), 1); // Block contains coinbase transaction
+        assert!(block.transactions[0].is_coinbase());
+        assert_eq!(block.transactions[0].outputs[0].value, get_block_subsidy(1, &params));
+        assert_eq!(block.header.prev_block_hash, [1; 32]);
+        // Regtest keeps the difficulty of the tip
+        assert_eq!(block.header.bits, 0x200f0f0f);
     }
-}
 
-pub mod miner {
-    use crate::block::Block;
+    #[test]
+    fn mine_block() {
+        let miner = Miner::new(Arc::new(Mempool::new()), ConsensusParams::regtest());
+        let chain = genesis();
+        let view = UTXOSet::new();
+        let mut block = miner.create_block_template(
+            &chain[..],
+            &view,
+            [1; 32],
+            1_500_000_150,
+            &Script::default(),
+        );
+        assert!(miner.mine_block(&mut block, 1, 1_000, &AtomicBool::new(false)));
+        assert!(check_equihash_solution(&block.header, &ConsensusParams::regtest()));
+    }
 
-    pub fn generate_block() -> Block {
-        // Example: Create a block with a single coinbase transaction
-        Block {
-            vtx: vec!["coinbase".to_string()],
-            n_bits: 0,
-            n_nonce: 0,
+    fn sapling_spend(prev_out: OutPoint, value: i64, cmu: [u8; 32]) -> Transaction {
+        Transaction {
+            overwintered: true,
+            version: SAPLING_TX_VERSION,
+            version_group_id: SAPLING_VERSION_GROUP_ID,
+            inputs: vec![TxInput {
+                prev_out,
+                script_sig: Script::default(),
+                sequence: u32::MAX,
+            }],
+            outputs: vec![TxOutput {
+                value,
+                script_pubkey: Script::default().push_opcode(OP_TRUE),
+            }],
+            shielded_outputs: vec![OutputDescription {
+                cv: [8; 32],
+                cmu,
+                ephemeral_key: [10; 32],
+                enc_ciphertext: [11; SAPLING_ENC_CIPHERTEXT_SIZE],
+                out_ciphertext: [12; SAPLING_OUT_CIPHERTEXT_SIZE],
+                zkproof: [13; GROTH_PROOF_SIZE],
+            }],
+            ..Default::default()
         }
     }
-}
 
-pub mod block {
-    #[derive(Default)]
-    pub struct Block {
-        pub vtx: Vec<String>, // Replace with a proper transaction type
-        pub n_bits: u32,
-        pub n_nonce: u32,
+    #[test]
+    fn template_pays_fees_and_commits_to_sapling_tree() {
+        let mut params = ConsensusParams::regtest();
+        params.set_activation_height(NetworkUpgrade::Overwinter, Some(1));
+        params.set_activation_height(NetworkUpgrade::Sapling, Some(1));
+        let coin = OutPoint {
+            txid: [2; 32],
+            index: 0,
+        };
+        let mut view = UTXOSet::new();
+        let output = TxOutput {
+            value: 100_000,
+            script_pubkey: Script::default().push_opcode(OP_TRUE),
+        };
+        view.add_coin(coin.clone(), Coin::new(output, 0, false));
+        view.set_best_block([1; 32]);
+
+        let paying = sapling_spend(coin.clone(), 99_000, [9; 32]);
+        // Spends the same coin, so only one of the two can be mined
+        let conflicting = sapling_spend(coin, 98_000, [10; 32]);
+        let missing = OutPoint {
+            txid: [3; 32],
+            index: 0,
+        };
+        let unconfirmed = sapling_spend(missing, 1_000, [11; 32]);
+        let mut mempool = Mempool::new();
+        mempool.add_transaction(paying, 10).unwrap();
+        mempool.add_transaction(conflicting, 20).unwrap();
+        mempool.add_transaction(unconfirmed, 30).unwrap();
+
+        let miner = Miner::new(Arc::new(mempool), params.clone());
+        let chain = genesis();
+        let script = Script::default();
+        let block = miner.create_block_template(&chain[..], &view, [1; 32], 1_500_000_150, &script);
+        assert_eq!(block.transactions.len(), 2);
+        let included = &block.transactions[1];
+        let fee = 100_000 - included.outputs[0].value;
+        assert_eq!(block.transactions[0].outputs[0].value, get_block_subsidy(1, &params) + fee);
+
+        let mut tree = SaplingMerkleTree::new();
+        tree.append(included.shielded_outputs[0].cmu).unwrap();
+        assert_eq!(block.header.final_sapling_root, tree.root());
+        let mut cache = CoinsViewCache::new(&mut view);
+        assert!(connect_block(&block, 1, &mut cache, &params).is_ok());
     }
 }
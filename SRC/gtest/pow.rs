@@ -1,21 +1,127 @@
 #[cfg(test)]
 mod tests {
-    use crate::pow::check_proof_of_work;
+    use crate::arith_uint256::ArithUint256;
+    use crate::consensus::params::ConsensusParams;
+    use crate::pow::{
+        calculate_next_work_required, check_proof_of_work, get_next_work_required,
+    };
+    use crate::primitives::block::BlockHeader;
+
+    /// Builds `count` headers with the given bits, `spacing` seconds apart
+    fn chain(count: usize, bits: u32, spacing: u32) -> Vec<BlockHeader> {
+        (0..count)
+            .map(|height| BlockHeader {
+                bits,
+                timestamp: 1_500_000_000 + height as u32 * spacing,
+                ..Default::default()
+            })
+            .collect()
+    }
 
     #[test]
     fn valid_proof() {
-        assert!(check_proof_of_work("hash", 0x1d00ffff));
+        let params = ConsensusParams::main();
+        // A hash of zero meets any target
+        assert!(check_proof_of_work(&[0; 32], 0x1f07ffff, &params));
     }
 
     #[test]
     fn invalid_proof() {
-        assert!(!check_proof_of_work("invalid_hash", 0x1d00ffff));
+        let params = ConsensusParams::main();
+        // The most significant byte is last in the little-endian hash
+        let mut hash = [0; 32];
+        hash[31] = 0x01;
+        assert!(!check_proof_of_work(&hash, 0x1f07ffff, &params));
+        // A zero target or one above the limit is never met
+        assert!(!check_proof_of_work(&[0; 32], 0, &params));
+        assert!(!check_proof_of_work(&[0; 32], 0x2007ffff, &params));
     }
-}
 
-pub mod pow {
-    pub fn check_proof_of_work(hash: &str, n_bits: u32) -> bool {
-        // Example PoW validation (replace with real logic)
-        hash == "hash" && n_bits == 0x1d00ffff
+    #[test]
+    fn proof_at_target_boundary() {
+        let params = ConsensusParams::main();
+        let target = ArithUint256::from_compact(0x1f07ffff);
+        assert!(check_proof_of_work(&target.to_le_bytes(), 0x1f07ffff, &params));
+        let above = target + ArithUint256::one();
+        assert!(!check_proof_of_work(&above.to_le_bytes(), 0x1f07ffff, &params));
+    }
+
+    #[test]
+    fn negative_or_overflowing_bits_rejected() {
+        let params = ConsensusParams::main();
+        // Same magnitude as a valid target, but with the sign bit set
+        assert!(!check_proof_of_work(&[0; 32], 0x1f87ffff, &params));
+        assert!(!check_proof_of_work(&[0; 32], 0xff000001, &params));
+    }
+
+    #[test]
+    fn next_work_dampened() {
+        let params = ConsensusParams::main();
+        let average = ArithUint256::from_compact(0x1d00ffff);
+        // A 3570s window is dampened to 2805s, 10% slower than expected
+        assert_eq!(
+            calculate_next_work_required(average, 1_000_003_570, 1_000_000_000, &params),
+            0x1d011998
+        );
+    }
+
+    #[test]
+    fn next_work_bounded() {
+        let params = ConsensusParams::main();
+        let average = ArithUint256::from_compact(0x1d00ffff);
+        // Very slow windows are capped at 32% easier
+        assert_eq!(
+            calculate_next_work_required(average.clone(), 1_000_100_000, 1_000_000_000, &params),
+            0x1d0151ea
+        );
+
+        // Very fast windows are capped at 16% harder
+        let floor =
+            calculate_next_work_required(average.clone(), 1_000_000_500, 1_000_000_000, &params);
+        let instant = calculate_next_work_required(average, 1_000_000_000, 1_000_000_000, &params);
+        assert_eq!(instant, floor);
+
+        // Never easier than the pow limit
+        let limit = params.pow_limit.clone();
+        assert_eq!(
+            calculate_next_work_required(limit, 1_000_100_000, 1_000_000_000, &params),
+            0x1f07ffff
+        );
+    }
+
+    #[test]
+    fn next_work_from_chain() {
+        let params = ConsensusParams::main();
+        assert_eq!(get_next_work_required(&[][..], 0, &params), 0x1f07ffff);
+
+        // Too little history to fill the averaging window
+        let headers = chain(17, 0x1d011998, 150);
+        assert_eq!(get_next_work_required(&headers[..], 0, &params), 0x1f07ffff);
+
+        // On-schedule blocks keep the target, less integer division rounding
+        let headers = chain(40, 0x1d011998, 150);
+        assert_eq!(get_next_work_required(&headers[..], 0, &params), 0x1d011997);
+
+        // Slow blocks make the next one easier
+        let headers = chain(40, 0x1d011998, 300);
+        let bits = get_next_work_required(&headers[..], 0, &params);
+        assert!(ArithUint256::from_compact(bits) > ArithUint256::from_compact(0x1d011998));
+    }
+
+    #[test]
+    fn regtest_does_not_retarget() {
+        let params = ConsensusParams::regtest();
+        let headers = chain(40, 0x200f0f0f, 1);
+        assert_eq!(get_next_work_required(&headers[..], 0, &params), 0x200f0f0f);
+    }
+
+    #[test]
+    fn testnet_min_difficulty_blocks() {
+        let mut params = ConsensusParams::testnet();
+        params.pow_allow_min_difficulty_blocks_after_height = Some(30);
+        let headers = chain(40, 0x1d011998, 150);
+        let tip_time = headers[39].timestamp as i64;
+        assert_eq!(get_next_work_required(&headers[..], tip_time + 901, &params), 0x2007ffff);
+        assert_ne!(get_next_work_required(&headers[..], tip_time + 900, &params), 0x2007ffff);
     }
 }
@@ -0,0 +1,45 @@
+#[cfg(test)]
+mod tests {
+    use crate::primitives::transaction::Transaction;
+    use crate::script::Script;
+    use crate::serialize::Deserializable;
+    use crate::sighash::signature_hash;
+    use serde_json::Value;
+
+    /// Zcash's `src/test/data/sighash.json`, copied in unchanged. Its layout is
+    /// [raw_transaction, script, input_index, hashType, branchId, signature_hash]
+    const SIGHASH_VECTORS: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/SRC/gtest/data/sighash.json");
+
+    #[test]
+    #[ignore = "needs the upstream zcash src/test/data/sighash.json in SRC/gtest/data"]
+    fn sighash_from_data() {
+        let data = std::fs::read_to_string(SIGHASH_VECTORS).unwrap();
+        let vectors: Value = serde_json::from_str(&data).unwrap();
+        let mut checked = 0;
+
+        for vector in vectors.as_array().unwrap() {
+            let fields = vector.as_array().unwrap();
+            if fields.len() == 1 {
+                // Comment row
+                continue;
+            }
+
+            let raw_tx = hex::decode(fields[0].as_str().unwrap()).unwrap();
+            let script = Script::new(hex::decode(fields[1].as_str().unwrap()).unwrap());
+            let n_in = fields[2].as_u64().unwrap() as usize;
+            let hash_type = fields[3].as_i64().unwrap() as u32;
+            let branch_id = fields[4].as_u64().unwrap() as u32;
+            // Hashes are displayed byte-reversed, like every uint256
+            let mut expected = hex::decode(fields[5].as_str().unwrap()).unwrap();
+            expected.reverse();
+
+            let tx = Transaction::deserialize(&mut raw_tx.as_slice()).unwrap();
+            let hash = signature_hash(&script, &tx, n_in, hash_type, 0, branch_id, None).unwrap();
+            assert_eq!(hash.to_vec(), expected, "vector {}", fields[0]);
+            checked += 1;
+        }
+
+        assert!(checked > 0);
+    }
+}
@@ -1,23 +1,53 @@
 #[cfg(test)]
 mod tests {
-    use crate::primitives::block::Block;
+    use crate::primitives::block::{Block, BlockHeader, HEADER_SIZE};
+    use crate::serialize::{Deserializable, Serializable};
+
+    fn header() -> BlockHeader {
+        BlockHeader {
+            version: 4,
+            prev_block_hash: [1; 32],
+            merkle_root: [2; 32],
+            final_sapling_root: [3; 32],
+            timestamp: 1_530_000_000,
+            bits: 0x1f07ffff,
+            nonce: [4; 32],
+            solution: vec![5; 100],
+        }
+    }
 
     #[test]
     fn default_constructor() {
         let block = Block::default();
-        assert_eq!(block.vtx.len(), 0);
-        assert_eq!(block.n_bits, 0);
-        assert_eq!(block.n_nonce, 0);
+        assert_eq!(block.transactions.len(), 0);
+        assert_eq!(block.header.bits, 0);
+        assert_eq!(block.header.nonce, [0; 32]);
+        assert!(block.header.solution.is_empty());
     }
-}
 
-pub mod primitives {
-    pub mod block {
-        #[derive(Default)]
-        pub struct Block {
-            pub vtx: Vec<String>, // Replace `String` with actual transaction type
-            pub n_bits: u32,
-            pub n_nonce: u32,
-        }
+    #[test]
+    fn header_layout() {
+        let header = header();
+        let mut bytes = Vec::new();
+        header.serialize(&mut bytes).unwrap();
+
+        // 140 fixed bytes, a one-byte CompactSize and the solution
+        assert_eq!(bytes.len(), HEADER_SIZE + 1 + 100);
+        assert_eq!(&bytes[..HEADER_SIZE], &header.header_without_solution()[..]);
+        assert_eq!(&bytes[100..104], &1_530_000_000u32.to_le_bytes());
+        assert_eq!(&bytes[108..140], &[4; 32]);
+        assert_eq!(bytes[HEADER_SIZE], 100);
+
+        let decoded = BlockHeader::deserialize(&mut bytes.as_slice()).unwrap();
+        assert_eq!(decoded, header);
+    }
+
+    #[test]
+    fn hash_commits_to_solution() {
+        let header = header();
+        let mut other = header.clone();
+        other.solution[0] ^= 1;
+        assert_ne!(header.hash(), other.hash());
+        assert_eq!(header.header_without_solution(), other.header_without_solution());
     }
 }
@@ -1,39 +1,129 @@
 #[cfg(test)]
 mod tests {
-    use crate::upgrades::{Upgrade, Params};
+    use crate::consensus::params::ConsensusParams;
+    use crate::consensus::upgrades::{
+        current_epoch, current_epoch_branch_id, network_upgrade_active, network_upgrade_state,
+        next_activation_height, NetworkUpgrade, UpgradeState,
+    };
+    use crate::consensus::validation::{contextual_check_transaction, TxValidationError};
+    use crate::primitives::transaction::{
+        Transaction, OVERWINTER_TX_VERSION, OVERWINTER_VERSION_GROUP_ID, SAPLING_TX_VERSION,
+        SAPLING_VERSION_GROUP_ID,
+    };
+
+    fn regtest(overwinter: u32, sapling: u32) -> ConsensusParams {
+        let mut params = ConsensusParams::regtest();
+        params.set_activation_height(NetworkUpgrade::Overwinter, Some(overwinter));
+        params.set_activation_height(NetworkUpgrade::Sapling, Some(sapling));
+        params
+    }
+
+    fn overwinter_tx() -> Transaction {
+        Transaction {
+            overwintered: true,
+            version: OVERWINTER_TX_VERSION,
+            version_group_id: OVERWINTER_VERSION_GROUP_ID,
+            ..Transaction::new_dummy()
+        }
+    }
+
+    fn sapling_tx() -> Transaction {
+        Transaction {
+            overwintered: true,
+            version: SAPLING_TX_VERSION,
+            version_group_id: SAPLING_VERSION_GROUP_ID,
+            ..Transaction::new_dummy()
+        }
+    }
 
     #[test]
-    fn upgrade_active() {
-        let params = Params::default();
-        assert!(!params.upgrade_active(Upgrade::Sapling, 0));
-        assert!(params.upgrade_active(Upgrade::Sapling, 500000));
+    fn mainnet_schedule() {
+        let params = ConsensusParams::main();
+        assert!(!network_upgrade_active(328_499, &params, NetworkUpgrade::Sapling));
+        assert!(network_upgrade_active(328_500, &params, NetworkUpgrade::Overwinter));
+        assert!(network_upgrade_active(328_500, &params, NetworkUpgrade::Sapling));
+        assert_eq!(current_epoch(328_500, &params), NetworkUpgrade::Sapling);
+        assert_eq!(
+            network_upgrade_state(1_000_000, &params, NetworkUpgrade::Blossom),
+            UpgradeState::Disabled
+        );
+        assert_eq!(next_activation_height(0, &params), Some(328_500));
     }
-}
 
-pub mod upgrades {
-    #[derive(PartialEq, Eq, Hash, Clone)]
-    pub enum Upgrade {
-        Overwinter,
-        Sapling,
+    #[test]
+    fn regtest_has_no_upgrades() {
+        let params = ConsensusParams::regtest();
+        assert_eq!(current_epoch(1_000_000, &params), NetworkUpgrade::Base);
+        assert_eq!(current_epoch_branch_id(1_000_000, &params), 0);
+        assert_eq!(next_activation_height(0, &params), None);
     }
 
-    pub struct Params {
-        pub activation_heights: std::collections::HashMap<Upgrade, u32>,
+    #[test]
+    fn sprout_rules() {
+        let params = regtest(10, 20);
+        assert_eq!(contextual_check_transaction(&Transaction::new_dummy(), 9, &params), Ok(()));
+        assert_eq!(
+            contextual_check_transaction(&overwinter_tx(), 9, &params),
+            Err(TxValidationError::OverwinterNotActive)
+        );
     }
 
-    impl Params {
-        pub fn upgrade_active(&self, upgrade: Upgrade, height: u32) -> bool {
-            self.activation_heights
-                .get(&upgrade)
-                .map_or(false, |&activation_height| height >= activation_height)
-        }
+    #[test]
+    fn overwinter_rules() {
+        let params = regtest(10, 20);
+        assert_eq!(contextual_check_transaction(&overwinter_tx(), 10, &params), Ok(()));
+        assert_eq!(
+            contextual_check_transaction(&Transaction::new_dummy(), 10, &params),
+            Err(TxValidationError::OverwinteredFlagNotSet)
+        );
+        assert_eq!(
+            contextual_check_transaction(&sapling_tx(), 10, &params),
+            Err(TxValidationError::OverwinterVersionGroupIdInvalid)
+        );
+
+        let mut tx = overwinter_tx();
+        tx.version = SAPLING_TX_VERSION;
+        assert_eq!(
+            contextual_check_transaction(&tx, 10, &params),
+            Err(TxValidationError::OverwinterVersionTooHigh)
+        );
     }
 
-    impl Default for Params {
-        fn default() -> Self {
-            let mut activation_heights = std::collections::HashMap::new();
-            activation_heights.insert(Upgrade::Sapling, 500000);
-            Params { activation_heights }
-        }
+    #[test]
+    fn sapling_rules() {
+        let params = regtest(10, 20);
+        assert_eq!(contextual_check_transaction(&sapling_tx(), 20, &params), Ok(()));
+        assert_eq!(
+            contextual_check_transaction(&overwinter_tx(), 20, &params),
+            Err(TxValidationError::SaplingVersionGroupIdInvalid)
+        );
+
+        let mut tx = sapling_tx();
+        tx.version = OVERWINTER_TX_VERSION;
+        assert_eq!(
+            contextual_check_transaction(&tx, 20, &params),
+            Err(TxValidationError::SaplingVersionTooLow)
+        );
+        tx.version = SAPLING_TX_VERSION + 1;
+        assert_eq!(
+            contextual_check_transaction(&tx, 20, &params),
+            Err(TxValidationError::SaplingVersionTooHigh)
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let params = regtest(10, 20);
+        let mut tx = sapling_tx();
+        tx.expiry_height = 30;
+        assert_eq!(contextual_check_transaction(&tx, 30, &params), Ok(()));
+        assert_eq!(
+            contextual_check_transaction(&tx, 31, &params),
+            Err(TxValidationError::Expired)
+        );
+
+        // Zero means the transaction never expires
+        tx.expiry_height = 0;
+        assert_eq!(contextual_check_transaction(&tx, 1_000, &params), Ok(()));
     }
 }
@@ -1,34 +1,473 @@
 #[cfg(test)]
 mod tests {
-    use crate::validation::check_block;
-    use crate::block::Block;
+    use crate::amount::{Amount, COIN};
+    use crate::coins::{Coin, CoinsViewCache, ShieldedType, UTXOSet, SAPLING_EMPTY_ROOT};
+    use crate::consensus::consensus::COINBASE_MATURITY;
+    use crate::consensus::params::ConsensusParams;
+    use crate::consensus::upgrades::NetworkUpgrade;
+    use crate::consensus::validation::{
+        connect_block, disconnect_block, BlockValidationError, DisconnectResult, TxValidationError,
+    };
+    use crate::incremental_merkle_tree::{SaplingMerkleTree, SproutMerkleTree};
+    use crate::primitives::block::{Block, BlockHeader};
+    use crate::primitives::transaction::{
+        JSDescription, OutPoint, OutputDescription, SproutProof, Transaction, TxInput, TxOutput,
+        GROTH_PROOF_SIZE, PHGR_PROOF_SIZE, SAPLING_ENC_CIPHERTEXT_SIZE, SAPLING_OUT_CIPHERTEXT_SIZE,
+        SAPLING_TX_VERSION, SAPLING_VERSION_GROUP_ID, ZC_NUM_JS_OUTPUTS, ZC_SPROUT_CIPHERTEXT_SIZE,
+    };
+    use crate::script::opcodes::{OP_0, OP_RETURN, OP_TRUE};
+    use crate::script::Script;
+    use crate::undo::BlockUndo;
+
+    /// Small deterministic generator, so that failures reproduce
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn hash(&mut self) -> [u8; 32] {
+            let mut hash = [0u8; 32];
+            for chunk in hash.chunks_exact_mut(8) {
+                chunk.copy_from_slice(&self.next().to_le_bytes());
+            }
+            hash
+        }
+    }
+
+    fn anyone_can_spend(value: Amount) -> TxOutput {
+        TxOutput {
+            value,
+            script_pubkey: Script::default().push_opcode(OP_TRUE),
+        }
+    }
+
+    fn coinbase(height: u32, value: Amount) -> Transaction {
+        Transaction {
+            inputs: vec![TxInput {
+                prev_out: OutPoint {
+                    txid: [0; 32],
+                    index: u32::MAX,
+                },
+                script_sig: Script::default().push_int(height as i64).push_opcode(OP_0),
+                sequence: u32::MAX,
+            }],
+            outputs: vec![anyone_can_spend(value)],
+            ..Default::default()
+        }
+    }
+
+    /// Spends `prevouts` into `count` outputs, paying a small fee
+    fn spend(prevouts: &[(OutPoint, Amount)], count: usize) -> Transaction {
+        let total: Amount = prevouts.iter().map(|(_, value)| value).sum();
+        let available = total - total.min(10);
+        let mut outputs: Vec<_> =
+            (0..count).map(|_| anyone_can_spend(available / count as Amount)).collect();
+        outputs[0].value += available % count as Amount;
+        Transaction {
+            inputs: prevouts
+                .iter()
+                .map(|(prev_out, _)| TxInput {
+                    prev_out: prev_out.clone(),
+                    script_sig: Script::default(),
+                    sequence: u32::MAX,
+                })
+                .collect(),
+            outputs,
+            ..Default::default()
+        }
+    }
+
+    fn join_split(
+        anchor: [u8; 32],
+        nullifiers: [[u8; 32]; 2],
+        commitments: [[u8; 32]; 2],
+    ) -> JSDescription {
+        JSDescription {
+            vpub_old: 0,
+            vpub_new: 0,
+            anchor,
+            nullifiers,
+            commitments,
+            ephemeral_key: [6; 32],
+            random_seed: [7; 32],
+            macs: [[8; 32], [9; 32]],
+            proof: SproutProof::Phgr([11; PHGR_PROOF_SIZE]),
+            ciphertexts: [[10; ZC_SPROUT_CIPHERTEXT_SIZE]; ZC_NUM_JS_OUTPUTS],
+        }
+    }
+
+    fn block(view: &UTXOSet, height: u32, transactions: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 4,
+                prev_block_hash: view.best_block(),
+                timestamp: 1_500_000_000 + height,
+                ..Default::default()
+            },
+            transactions,
+        }
+    }
+
+    /// Connects `block` through a cache on top of `base`. The cache is
+    /// flushed even if the block is invalid, so that anything left behind by
+    /// the failed block would show up in `base`.
+    fn connect(
+        block: &Block,
+        height: u32,
+        base: &mut UTXOSet,
+        params: &ConsensusParams,
+    ) -> Result<BlockUndo, BlockValidationError> {
+        let mut view = CoinsViewCache::new(base);
+        let result = connect_block(block, height, &mut view, params);
+        view.flush().unwrap();
+        result
+    }
+
+    fn disconnect(
+        block: &Block,
+        height: u32,
+        undo: &BlockUndo,
+        base: &mut UTXOSet,
+        params: &ConsensusParams,
+    ) -> DisconnectResult {
+        let mut view = CoinsViewCache::new(base);
+        let result = disconnect_block(block, height, undo, [0; 32], &mut view, params);
+        view.flush().unwrap();
+        result
+    }
+
+    /// Thirty mature, non-coinbase coins to start from
+    fn initial_view() -> UTXOSet {
+        let mut view = UTXOSet::new();
+        for i in 0..30u8 {
+            let outpoint = OutPoint {
+                txid: [i + 1; 32],
+                index: 0,
+            };
+            view.add_coin(outpoint, Coin::new(anyone_can_spend(100_000), 0, false));
+        }
+        view
+    }
+
+    fn first_outpoint(view: &UTXOSet) -> (OutPoint, Amount) {
+        let mut coins: Vec<_> = view
+            .coins()
+            .map(|(outpoint, coin)| (outpoint.clone(), coin.output.value))
+            .collect();
+        coins.sort_by_key(|(outpoint, _)| (outpoint.txid, outpoint.index));
+        coins[0].clone()
+    }
+
+    fn random_block(rng: &mut XorShift, view: &UTXOSet, height: u32) -> Block {
+        let mut spendable: Vec<(OutPoint, Amount)> = view
+            .coins()
+            .filter(|(_, coin)| !coin.is_coinbase || height - coin.height >= COINBASE_MATURITY)
+            .map(|(outpoint, coin)| (outpoint.clone(), coin.output.value))
+            .collect();
+        spendable.sort_by_key(|(outpoint, _)| (outpoint.txid, outpoint.index));
+
+        let mut transactions = vec![coinbase(height, 50_000)];
+        for _ in 0..rng.below(6) {
+            if spendable.is_empty() {
+                break;
+            }
+            let count = (1 + rng.below(3)).min(spendable.len());
+            let prevouts: Vec<_> = (0..count)
+                .map(|_| spendable.swap_remove(rng.below(spendable.len())))
+                .collect();
+            let mut tx = spend(&prevouts, 1 + rng.below(3));
+            if rng.below(4) == 0 {
+                let memo = Script::default().push_opcode(OP_RETURN).push_slice(b"memo");
+                tx.outputs.push(TxOutput {
+                    value: 0,
+                    script_pubkey: memo,
+                });
+            }
+            if rng.below(3) == 0 {
+                tx.version = 2;
+                let anchor = view.get_best_anchor(ShieldedType::Sprout);
+                let nullifiers = [rng.hash(), rng.hash()];
+                let commitments = [rng.hash(), rng.hash()];
+                tx.join_splits.push(join_split(anchor, nullifiers, commitments));
+            }
+
+            // Later transactions in the block may spend these outputs
+            let txid = tx.hash();
+            for (index, output) in tx.outputs.iter().enumerate() {
+                if !output.script_pubkey.is_unspendable() {
+                    spendable.push((OutPoint { txid, index: index as u32 }, output.value));
+                }
+            }
+            transactions.push(tx);
+        }
+        block(view, height, transactions)
+    }
+
+    #[test]
+    fn connect_and_disconnect_random_blocks() {
+        let params = ConsensusParams::regtest();
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        let mut view = initial_view();
+        let initial = view.clone();
+        // Each connected block with its undo data and the state before it
+        let mut connected: Vec<(Block, BlockUndo, UTXOSet)> = Vec::new();
+
+        for _ in 0..300 {
+            if !connected.is_empty() && rng.below(4) == 0 {
+                // Disconnect a few blocks, as in a reorg
+                for _ in 0..1 + rng.below(3) {
+                    if let Some((block, undo, before)) = connected.pop() {
+                        let height = connected.len() as u32 + 1;
+                        let result = disconnect(&block, height, &undo, &mut view, &params);
+                        assert_eq!(result, DisconnectResult::Ok);
+                        assert_eq!(view, before);
+                    }
+                }
+                continue;
+            }
+
+            let height = connected.len() as u32 + 1;
+            let block = random_block(&mut rng, &view, height);
+            let before = view.clone();
+            let undo = connect(&block, height, &mut view, &params).unwrap();
+            assert_eq!(undo.tx_undos.len() + 1, block.transactions.len());
+            assert_eq!(view.best_block(), block.hash());
+            connected.push((block, undo, before));
+        }
+        assert!(connected.len() > 10);
+
+        while let Some((block, undo, before)) = connected.pop() {
+            let height = connected.len() as u32 + 1;
+            let result = disconnect(&block, height, &undo, &mut view, &params);
+            assert_eq!(result, DisconnectResult::Ok);
+            assert_eq!(view, before);
+        }
+        assert_eq!(view, initial);
+    }
 
     #[test]
-    fn check_block() {
-        let block = Block::default();
-        assert!(check_block(&block));
+    fn undo_records_spent_coins_in_input_order() {
+        let params = ConsensusParams::regtest();
+        let mut view = initial_view();
+        let first = first_outpoint(&view);
+        let second = (OutPoint { txid: [30; 32], index: 0 }, 100_000);
+        let tx = spend(&[second.clone(), first.clone()], 2);
+        let block = block(&view, 1, vec![coinbase(1, 50_000), tx.clone()]);
+
+        let undo = connect(&block, 1, &mut view, &params).unwrap();
+        assert_eq!(undo.tx_undos.len(), 1);
+        assert_eq!(undo.tx_undos[0].prev_coins.len(), 2);
+        assert_eq!(undo.tx_undos[0].prev_coins[0], Coin::new(anyone_can_spend(second.1), 0, false));
+        assert!(!view.have_coin(&first.0));
+        assert!(view.have_coin(&OutPoint { txid: tx.hash(), index: 1 }));
+        let coinbase_output = OutPoint {
+            txid: block.transactions[0].hash(),
+            index: 0,
+        };
+        assert!(view.access_coin(&coinbase_output).unwrap().is_coinbase);
     }
 
     #[test]
-    fn check_invalid_block() {
-        let mut block = Block::default();
-        block.n_bits = 0; // Invalid block
-        assert!(!check_block(&block));
+    fn invalid_blocks_leave_view_unchanged() {
+        let params = ConsensusParams::regtest();
+        let mut view = initial_view();
+        let before = view.clone();
+        let prevout = first_outpoint(&view);
+
+        // Double spend across two transactions of one block
+        let block_double_spend = block(
+            &view,
+            1,
+            vec![
+                coinbase(1, 50_000),
+                spend(std::slice::from_ref(&prevout), 1),
+                spend(std::slice::from_ref(&prevout), 2),
+            ],
+        );
+        assert_eq!(
+            connect(&block_double_spend, 1, &mut view, &params),
+            Err(BlockValidationError::Transaction(TxValidationError::InputsMissingOrSpent))
+        );
+        assert_eq!(view, before);
+
+        // Coinbase claims more than the subsidy plus fees
+        let greedy_coinbase = coinbase(1, 12_500 * COIN + 11);
+        let greedy_spend = spend(std::slice::from_ref(&prevout), 1);
+        let greedy = block(&view, 1, vec![greedy_coinbase, greedy_spend]);
+        assert_eq!(
+            connect(&greedy, 1, &mut view, &params),
+            Err(BlockValidationError::CoinbaseAmount)
+        );
+        assert_eq!(view, before);
+
+        // Outputs can't be worth more than the inputs
+        let mut tx = spend(&[prevout], 1);
+        tx.outputs[0].value = 100_001;
+        let overspend = block(&view, 1, vec![coinbase(1, 50_000), tx]);
+        assert_eq!(
+            connect(&overspend, 1, &mut view, &params),
+            Err(BlockValidationError::Transaction(TxValidationError::InBelowOut))
+        );
+        assert_eq!(view, before);
     }
-}
 
-pub mod validation {
-    use crate::block::Block;
+    #[test]
+    fn coinbase_outputs_mature() {
+        let params = ConsensusParams::regtest();
+        let mut view = initial_view();
+        let first = block(&view, 1, vec![coinbase(1, 50_000)]);
+        connect(&first, 1, &mut view, &params).unwrap();
+        let prevout = (OutPoint { txid: first.transactions[0].hash(), index: 0 }, 50_000);
+
+        let height = COINBASE_MATURITY;
+        let premature = spend(std::slice::from_ref(&prevout), 1);
+        let early = block(&view, height, vec![coinbase(height, 50_000), premature]);
+        let error = connect(&early, height, &mut view, &params).unwrap_err();
+        assert_eq!(
+            error,
+            BlockValidationError::Transaction(TxValidationError::PrematureSpendOfCoinbase)
+        );
+        assert_eq!(error.dos_score(), 0);
 
-    pub fn check_block(block: &Block) -> bool {
-        block.n_bits > 0 && !block.vtx.is_empty()
+        let height = COINBASE_MATURITY + 1;
+        let mature = block(&view, height, vec![coinbase(height, 50_000), spend(&[prevout], 1)]);
+        assert!(connect(&mature, height, &mut view, &params).is_ok());
     }
-}
 
-pub mod block {
-    #[derive(Default)]
-    pub struct Block {
-        pub vtx: Vec<String>, // Replace with actual transaction type
-        pub n_bits: u32,
+    #[test]
+    fn shielded_state_follows_blocks() {
+        let params = ConsensusParams::regtest();
+        let mut view = initial_view();
+        let empty_root = SproutMerkleTree::empty_root();
+
+        let mut tx = spend(&[first_outpoint(&view)], 1);
+        tx.version = 2;
+        tx.join_splits.push(join_split(empty_root, [[1; 32], [2; 32]], [[3; 32], [4; 32]]));
+        // A second JoinSplit may use the tree left by the first
+        let mut tree = SproutMerkleTree::new();
+        tree.append([3; 32]).unwrap();
+        tree.append([4; 32]).unwrap();
+        tx.join_splits.push(join_split(tree.root(), [[5; 32], [6; 32]], [[7; 32], [8; 32]]));
+        tree.append([7; 32]).unwrap();
+        tree.append([8; 32]).unwrap();
+
+        let first = block(&view, 1, vec![coinbase(1, 50_000), tx]);
+        let undo = connect(&first, 1, &mut view, &params).unwrap();
+        assert!(view.get_nullifier(&[5; 32], ShieldedType::Sprout));
+        assert_eq!(view.get_best_anchor(ShieldedType::Sprout), tree.root());
+        assert_eq!(view.get_sprout_anchor_at(&tree.root()), Some(tree.clone()));
+        assert_eq!(undo.old_sprout_tree_root, empty_root);
+
+        // A nullifier can only be revealed once
+        let mut reuse = spend(&[first_outpoint(&view)], 1);
+        reuse.version = 2;
+        reuse.join_splits.push(join_split(tree.root(), [[1; 32], [9; 32]], [[10; 32], [11; 32]]));
+        let second = block(&view, 2, vec![coinbase(2, 50_000), reuse]);
+        assert_eq!(
+            connect(&second, 2, &mut view, &params),
+            Err(BlockValidationError::Transaction(TxValidationError::ShieldedRequirementsNotMet))
+        );
+
+        // And anchors must be known
+        let mut unanchored = spend(&[first_outpoint(&view)], 1);
+        unanchored.version = 2;
+        let description = join_split([12; 32], [[13; 32], [14; 32]], [[15; 32], [16; 32]]);
+        unanchored.join_splits.push(description);
+        let second = block(&view, 2, vec![coinbase(2, 50_000), unanchored]);
+        assert_eq!(
+            connect(&second, 2, &mut view, &params),
+            Err(BlockValidationError::Transaction(TxValidationError::ShieldedRequirementsNotMet))
+        );
+
+        assert_eq!(
+            disconnect(&first, 1, &undo, &mut view, &params),
+            DisconnectResult::Ok
+        );
+        assert!(!view.get_nullifier(&[5; 32], ShieldedType::Sprout));
+        assert_eq!(view.get_best_anchor(ShieldedType::Sprout), empty_root);
+        assert_eq!(view.get_sprout_anchor_at(&tree.root()), None);
+        assert_eq!(view, initial_view());
+    }
+
+    #[test]
+    fn sapling_root_follows_outputs() {
+        let mut params = ConsensusParams::regtest();
+        params.set_activation_height(NetworkUpgrade::Overwinter, Some(1));
+        params.set_activation_height(NetworkUpgrade::Sapling, Some(1));
+        let mut view = initial_view();
+        let before = view.clone();
+
+        let mut cb = coinbase(1, 50_000);
+        cb.overwintered = true;
+        cb.version = SAPLING_TX_VERSION;
+        cb.version_group_id = SAPLING_VERSION_GROUP_ID;
+
+        let mut shielded = spend(&[first_outpoint(&view)], 1);
+        shielded.shielded_outputs.push(OutputDescription {
+            cv: [8; 32],
+            cmu: [9; 32],
+            ephemeral_key: [10; 32],
+            enc_ciphertext: [11; SAPLING_ENC_CIPHERTEXT_SIZE],
+            out_ciphertext: [12; SAPLING_OUT_CIPHERTEXT_SIZE],
+            zkproof: [13; GROTH_PROOF_SIZE],
+        });
+        let mut tree = SaplingMerkleTree::new();
+        tree.append([9; 32]).unwrap();
+
+        // The header must commit to the tree after the block's outputs
+        let mut unchanged = block(&view, 1, vec![cb.clone(), shielded.clone()]);
+        unchanged.header.final_sapling_root = SAPLING_EMPTY_ROOT;
+        let error = connect(&unchanged, 1, &mut view, &params).unwrap_err();
+        assert_eq!(error, BlockValidationError::BadSaplingRoot);
+        assert_eq!(error.dos_score(), 100);
+        assert_eq!(view, before);
+
+        let mut first = block(&view, 1, vec![cb, shielded]);
+        first.header.final_sapling_root = tree.root();
+        let undo = connect(&first, 1, &mut view, &params).unwrap();
+        assert_eq!(view.get_best_anchor(ShieldedType::Sapling), tree.root());
+        assert_eq!(view.get_sapling_anchor_at(&tree.root()), Some(tree.clone()));
+
+        assert_eq!(
+            disconnect(&first, 1, &undo, &mut view, &params),
+            DisconnectResult::Ok
+        );
+        assert_eq!(view.get_best_anchor(ShieldedType::Sapling), SAPLING_EMPTY_ROOT);
+        assert_eq!(view.get_sapling_anchor_at(&tree.root()), None);
+        assert_eq!(view, before);
+    }
+
+    #[test]
+    fn disconnect_detects_bad_undo_data() {
+        let params = ConsensusParams::regtest();
+        let mut view = initial_view();
+        let tx = spend(&[first_outpoint(&view)], 1);
+        let block = block(&view, 1, vec![coinbase(1, 50_000), tx.clone()]);
+        let undo = connect(&block, 1, &mut view, &params).unwrap();
+
+        let mut missing = undo.clone();
+        missing.tx_undos.clear();
+        assert_eq!(
+            disconnect(&block, 1, &missing, &mut view.clone(), &params),
+            DisconnectResult::Failed
+        );
+
+        // A created output that has gone missing makes the rollback unclean
+        let mut damaged = view.clone();
+        damaged.spend_coin(&OutPoint { txid: tx.hash(), index: 0 });
+        assert_eq!(
+            disconnect(&block, 1, &undo, &mut damaged, &params),
+            DisconnectResult::Unclean
+        );
+        assert_eq!(damaged, initial_view());
     }
 }
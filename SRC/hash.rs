@@ -1,5 +1,6 @@
 use sha2::{Digest, Sha256};
 use ripemd160::Ripemd160;
+use std::io::{self, Write};
 
 /// Performs a single SHA-256 hash on the input data.
 pub fn sha256(data: &[u8]) -> Vec<u8> {
@@ -12,6 +13,13 @@ pub fn double_sha256(data: &[u8]) -> Vec<u8> {
     Sha256::digest(&first_hash).to_vec()
 }
 
+/// Performs a double SHA-256 hash and returns it as a fixed-size array, the form
+/// used for txids and block hashes.
+pub fn hash256(data: &[u8]) -> [u8; 32] {
+    let first_hash = Sha256::digest(data);
+    Sha256::digest(first_hash).into()
+}
+
 /// Performs a RIPEMD-160 hash on the input data.
 pub fn ripemd160(data: &[u8]) -> Vec<u8> {
     Ripemd160::digest(data).to_vec()
@@ -23,6 +31,35 @@ pub fn hash160(data: &[u8]) -> Vec<u8> {
     Ripemd160::digest(&sha256_hash).to_vec()
 }
 
+/// Computes `hash256` over everything written to it, so that data can be
+/// hashed as it is serialized (`CHashWriter` upstream).
+#[derive(Default)]
+pub struct HashWriter {
+    hasher: Sha256,
+}
+
+impl HashWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the double SHA-256 of the data written so far.
+    pub fn finalize(self) -> [u8; 32] {
+        Sha256::digest(self.hasher.finalize()).into()
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,4 +103,12 @@ mod tests {
             "b6a9c8c230722b7c748331a8b450f05566dc7d0f"
         );
     }
+
+    #[test]
+    fn test_hash_writer() {
+        let mut writer = HashWriter::new();
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        assert_eq!(hash256(b"hello world"), writer.finalize());
+    }
 }
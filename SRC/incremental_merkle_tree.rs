@@ -0,0 +1,270 @@
+use crate::crypto::pedersen_hash::merkle_hash;
+use crate::memusage::mem_usage_vec;
+use crate::serialize::{CompactSize, Deserializable, Serializable, SerializationError};
+use sha2::digest::generic_array::GenericArray;
+use std::fmt;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::sync::OnceLock;
+use thiserror::Error;
+
+/// Depth of the Sprout note commitment tree
+pub const SPROUT_TREE_DEPTH: usize = 29;
+/// Depth of the Sapling note commitment tree
+pub const SAPLING_TREE_DEPTH: usize = 32;
+
+const SHA256_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
+pub enum MerkleTreeError {
+    #[error("tree is full")]
+    Full,
+}
+
+/// Applies the SHA-256 compression function to `left || right`, without the
+/// padding and length block of a full SHA-256 hash
+pub fn sha256_compress(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    block[..32].copy_from_slice(left);
+    block[32..].copy_from_slice(right);
+
+    let mut state = SHA256_IV;
+    sha2::compress256(&mut state, &[GenericArray::clone_from_slice(&block)]);
+
+    let mut out = [0u8; 32];
+    for (chunk, word) in out.chunks_exact_mut(4).zip(state.iter()) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+/// Root of an empty Sprout subtree `depth` levels above the leaves
+pub fn empty_root(depth: usize) -> [u8; 32] {
+    static EMPTY_ROOTS: OnceLock<Vec<[u8; 32]>> = OnceLock::new();
+    let roots = EMPTY_ROOTS.get_or_init(|| {
+        // Uncommitted leaves are all zero
+        let mut roots = vec![[0u8; 32]];
+        for depth in 0..SPROUT_TREE_DEPTH {
+            roots.push(sha256_compress(&roots[depth], &roots[depth]));
+        }
+        roots
+    });
+    roots[depth]
+}
+
+/// The node hash of a note commitment tree
+pub trait MerkleHash: fmt::Debug + Clone + Copy + PartialEq + Eq + Default {
+    /// Number of levels between the leaves and the root
+    const DEPTH: usize;
+
+    /// Hashes two sibling nodes `depth` levels above the leaves.
+    fn combine(depth: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32];
+
+    /// Root of an empty subtree `depth` levels above the leaves.
+    fn empty_root(depth: usize) -> [u8; 32];
+}
+
+/// SHA-256 compression, used by the Sprout tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SproutHash;
+
+impl MerkleHash for SproutHash {
+    const DEPTH: usize = SPROUT_TREE_DEPTH;
+
+    fn combine(_depth: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        sha256_compress(left, right)
+    }
+
+    fn empty_root(depth: usize) -> [u8; 32] {
+        empty_root(depth)
+    }
+}
+
+/// The Pedersen hash over Jubjub, used by the Sapling tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SaplingHash;
+
+impl MerkleHash for SaplingHash {
+    const DEPTH: usize = SAPLING_TREE_DEPTH;
+
+    fn combine(depth: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        merkle_hash(depth, left, right)
+    }
+
+    fn empty_root(depth: usize) -> [u8; 32] {
+        static EMPTY_ROOTS: OnceLock<Vec<[u8; 32]>> = OnceLock::new();
+        let roots = EMPTY_ROOTS.get_or_init(|| {
+            // Uncommitted leaves are the field element one
+            let mut leaf = [0u8; 32];
+            leaf[0] = 1;
+            let mut roots = vec![leaf];
+            for depth in 0..SAPLING_TREE_DEPTH {
+                roots.push(merkle_hash(depth, &roots[depth], &roots[depth]));
+            }
+            roots
+        });
+        roots[depth]
+    }
+}
+
+/// A note commitment tree, storing only the frontier needed to append
+/// commitments and compute the root.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IncrementalMerkleTree<H> {
+    left: Option<[u8; 32]>,
+    right: Option<[u8; 32]>,
+    /// Roots of the full subtrees left of the frontier, lowest first
+    parents: Vec<Option<[u8; 32]>>,
+    hash: PhantomData<H>,
+}
+
+/// The Sprout note commitment tree
+pub type SproutMerkleTree = IncrementalMerkleTree<SproutHash>;
+/// The Sapling note commitment tree
+pub type SaplingMerkleTree = IncrementalMerkleTree<SaplingHash>;
+
+impl<H: MerkleHash> IncrementalMerkleTree<H> {
+    /// Creates an empty tree.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Root of the empty tree.
+    pub fn empty_root() -> [u8; 32] {
+        H::empty_root(H::DEPTH)
+    }
+
+    /// Number of commitments appended so far.
+    pub fn size(&self) -> u64 {
+        let mut size = self.left.is_some() as u64 + self.right.is_some() as u64;
+        for (i, parent) in self.parents.iter().enumerate() {
+            if parent.is_some() {
+                size += 1 << (i + 1);
+            }
+        }
+        size
+    }
+
+    /// Heap memory used by the frontier.
+    pub fn dynamic_memory_usage(&self) -> usize {
+        mem_usage_vec(&self.parents)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.left.is_some()
+            && self.right.is_some()
+            && self.parents.len() == H::DEPTH - 1
+            && self.parents.iter().all(Option::is_some)
+    }
+
+    /// Appends a note commitment.
+    pub fn append(&mut self, commitment: [u8; 32]) -> Result<(), MerkleTreeError> {
+        if self.is_complete() {
+            return Err(MerkleTreeError::Full);
+        }
+
+        let (left, right) = match (self.left, self.right) {
+            (None, _) => {
+                self.left = Some(commitment);
+                return Ok(());
+            }
+            (Some(_), None) => {
+                self.right = Some(commitment);
+                return Ok(());
+            }
+            (Some(left), Some(right)) => (left, right),
+        };
+
+        // Both leaves are full, so move their hash up into the parents
+        let mut combined = H::combine(0, &left, &right);
+        self.left = Some(commitment);
+        self.right = None;
+        for (i, parent) in self.parents.iter_mut().enumerate() {
+            match parent.take() {
+                Some(sibling) => combined = H::combine(i + 1, &sibling, &combined),
+                None => {
+                    *parent = Some(combined);
+                    return Ok(());
+                }
+            }
+        }
+        self.parents.push(Some(combined));
+        Ok(())
+    }
+
+    /// Computes the root, filling the positions right of the frontier with
+    /// empty subtrees.
+    pub fn root(&self) -> [u8; 32] {
+        let left = self.left.unwrap_or_else(|| H::empty_root(0));
+        let right = self.right.unwrap_or_else(|| H::empty_root(0));
+        let mut root = H::combine(0, &left, &right);
+
+        let mut depth = 1;
+        for parent in &self.parents {
+            root = match parent {
+                Some(parent) => H::combine(depth, parent, &root),
+                None => H::combine(depth, &root, &H::empty_root(depth)),
+            };
+            depth += 1;
+        }
+        while depth < H::DEPTH {
+            root = H::combine(depth, &root, &H::empty_root(depth));
+            depth += 1;
+        }
+        root
+    }
+}
+
+fn write_optional<W: Write>(
+    writer: &mut W,
+    node: &Option<[u8; 32]>,
+) -> Result<(), SerializationError> {
+    match node {
+        Some(node) => {
+            writer.write_all(&[1])?;
+            writer.write_all(node)?;
+        }
+        None => writer.write_all(&[0])?,
+    }
+    Ok(())
+}
+
+fn read_optional<R: Read>(reader: &mut R) -> Result<Option<[u8; 32]>, SerializationError> {
+    match <[u8; 1]>::deserialize(reader)?[0] {
+        0 => Ok(None),
+        1 => Ok(Some(<[u8; 32]>::deserialize(reader)?)),
+        _ => Err(SerializationError::InvalidData),
+    }
+}
+
+impl<H> Serializable for IncrementalMerkleTree<H> {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        write_optional(writer, &self.left)?;
+        write_optional(writer, &self.right)?;
+        CompactSize(self.parents.len() as u64).serialize(writer)?;
+        for parent in &self.parents {
+            write_optional(writer, parent)?;
+        }
+        Ok(())
+    }
+}
+
+impl<H: MerkleHash> Deserializable for IncrementalMerkleTree<H> {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        let left = read_optional(reader)?;
+        let right = read_optional(reader)?;
+        let count = CompactSize::deserialize(reader)?.0 as usize;
+        if count >= H::DEPTH {
+            return Err(SerializationError::ParseError("too many parents in tree frontier"));
+        }
+        let parents = (0..count).map(|_| read_optional(reader)).collect::<Result<_, _>>()?;
+        Ok(IncrementalMerkleTree {
+            left,
+            right,
+            parents,
+            hash: PhantomData,
+        })
+    }
+}
@@ -3,6 +3,8 @@ use thiserror::Error;
 use crate::base58::{Base58, Base58Error};
 use crate::keys::{PublicKey, PrivateKey};
 use crate::script::Script;
+use crate::script::standard::TxDestination;
+use crate::base58::{decode_base58_with_checksum, encode_base58_with_checksum};
 
 // Custom errors for KeyIO operations
 #[derive(Debug, Error)]
@@ -74,6 +76,39 @@ impl Address {
     }
 }
 
+/// Base58 prefix of transparent P2PKH addresses ("t1...")
+pub const PUBKEY_ADDRESS_PREFIX: [u8; 2] = [0x1C, 0xB8];
+/// Base58 prefix of transparent P2SH addresses ("t3...")
+pub const SCRIPT_ADDRESS_PREFIX: [u8; 2] = [0x1C, 0xBD];
+
+/// Encodes a transparent destination as a Base58Check address
+pub fn encode_destination(destination: &TxDestination) -> String {
+    let (prefix, hash) = match destination {
+        TxDestination::KeyId(hash) => (PUBKEY_ADDRESS_PREFIX, hash),
+        TxDestination::ScriptId(hash) => (SCRIPT_ADDRESS_PREFIX, hash),
+    };
+    let mut payload = prefix.to_vec();
+    payload.extend_from_slice(hash);
+    encode_base58_with_checksum(&payload)
+}
+
+/// Decodes a Base58Check transparent address
+pub fn decode_destination(address: &str) -> Option<TxDestination> {
+    let payload = decode_base58_with_checksum(address)?;
+    if payload.len() != 22 {
+        return None;
+    }
+    let mut hash = [0u8; 20];
+    hash.copy_from_slice(&payload[2..]);
+    if payload[..2] == PUBKEY_ADDRESS_PREFIX {
+        Some(TxDestination::KeyId(hash))
+    } else if payload[..2] == SCRIPT_ADDRESS_PREFIX {
+        Some(TxDestination::ScriptId(hash))
+    } else {
+        None
+    }
+}
+
 /// Utility function to perform a double SHA256 hash
 fn double_sha256(data: &[u8]) -> Vec<u8> {
     use sha2::{Digest, Sha256};
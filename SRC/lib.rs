@@ -3,27 +3,59 @@
 // Declare existing modules
 pub mod addrman;
 pub mod amount;
+pub mod arith_uint256;
 pub mod base58;
+pub mod blockstore;
 pub mod bloom;
+pub mod chain;
 pub mod chainparams;
+pub mod chainstate;
 pub mod checkpoints;
+pub mod coins;
 pub mod compat;
+pub mod compressor;
+#[path = "consensus/mastermod.rs"]
+pub mod consensus;
+pub mod crypto {
+    pub mod equihash;
+    pub mod pedersen_hash;
+    pub mod sha1;
+}
+pub mod dbwrapper;
+pub mod hash;
+pub mod incremental_merkle_tree;
 pub mod init;
 pub mod key;
+pub mod key_io;
 pub mod main;
+pub mod memusage;
 pub mod miner;
 pub mod mruset;
 pub mod net;
+pub mod net_processing;
 pub mod netbase;
+pub mod policy {
+    pub mod fees;
+    pub mod policy;
+}
+pub mod pow;
+pub mod primitives {
+    pub mod block;
+    pub mod transaction;
+}
 pub mod rpcserver;
 pub mod script;
 pub mod serialize;
+pub mod sighash;
 pub mod sync;
+pub mod timedata;
 pub mod txdb;
 pub mod txmempool;
 pub mod ui_interface;
+pub mod undo;
 pub mod util;
 pub mod utilmoneystr;
 pub mod utilstrencodings;
+pub mod utxo_snapshot;
 pub mod version;
 pub mod wallet;
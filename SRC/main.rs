@@ -4,8 +4,18 @@ mod consensus;
 mod wallet;
 mod mempool;
 
+use crate::blockstore::BlockStore;
+use crate::chainparams;
+use crate::chainstate::{
+    Chainstate, PruneMode, DEFAULT_CHECKBLOCKS, DEFAULT_CHECKLEVEL, MIN_DISK_SPACE_FOR_BLOCK_FILES,
+};
+use crate::timedata::TimeData;
+use crate::txdb::{self, BlockTreeDb, CoinsViewDb};
+use crate::ui_interface::{UiInterface, INIT_MESSAGE, SHOW_PROGRESS};
 use clap::{App, Arg};
-use log::{info, error};
+use log::{info, warn, error};
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::runtime::Runtime;
 
@@ -14,6 +24,15 @@ struct AppConfig {
     data_dir: String,
     log_file: String,
     network_port: u16,
+    db_cache: i64, // MiB, split between the databases and the coins cache
+    load_blocks: Vec<String>, // Block files to import on startup
+    reindex: bool,
+    reindex_chainstate: bool,
+    prune: i64, // MiB of block files to keep, 0 disables pruning and 1 allows manual pruning
+    tx_index: bool,
+    address_index: bool,
+    check_level: u32, // How thoroughly blocks are verified at startup, 0 to 4
+    check_blocks: u32, // Blocks verified at startup, 0 for all
 }
 
 /// Initializes the application configuration
@@ -31,11 +50,40 @@ fn init_config(matches: clap::ArgMatches) -> AppConfig {
         .unwrap_or("8333")
         .parse::<u16>()
         .unwrap_or(8333);
+    let db_cache = matches
+        .value_of("dbcache")
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(txdb::DEFAULT_DB_CACHE);
+    let load_blocks = matches
+        .values_of("loadblock")
+        .map(|values| values.map(str::to_string).collect())
+        .unwrap_or_default();
+    let prune = matches
+        .value_of("prune")
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(0);
+    let check_level = matches
+        .value_of("checklevel")
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_CHECKLEVEL);
+    let check_blocks = matches
+        .value_of("checkblocks")
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_CHECKBLOCKS);
 
     AppConfig {
         data_dir,
         log_file,
         network_port,
+        db_cache,
+        load_blocks,
+        reindex: matches.is_present("reindex"),
+        reindex_chainstate: matches.is_present("reindex_chainstate"),
+        prune,
+        tx_index: matches.is_present("txindex"),
+        address_index: matches.is_present("addressindex"),
+        check_level,
+        check_blocks,
     }
 }
 
@@ -54,6 +102,114 @@ fn init_logging(log_file: &str) {
     }
 }
 
+/// Checks the `-prune` setting against the other options and returns the
+/// prune mode it selects
+fn prune_mode(config: &AppConfig) -> Result<PruneMode, String> {
+    let mode = match config.prune {
+        0 => return Ok(PruneMode::Disabled),
+        1 => PruneMode::Manual,
+        mib if mib < 0 => return Err("Prune cannot be configured with a negative value.".into()),
+        mib => {
+            let target = (mib as u64) << 20;
+            if target < MIN_DISK_SPACE_FOR_BLOCK_FILES {
+                return Err(format!(
+                    "Prune configured below the minimum of {} MiB.  Please use a higher number.",
+                    MIN_DISK_SPACE_FOR_BLOCK_FILES >> 20
+                ));
+            }
+            PruneMode::Automatic(target)
+        }
+    };
+    if config.tx_index {
+        return Err("Prune mode is incompatible with -txindex.".into());
+    }
+    if config.address_index {
+        return Err("Prune mode is incompatible with -addressindex.".into());
+    }
+    Ok(mode)
+}
+
+/// Opens the blk files, the block index and the chainstate database, loads
+/// the saved index, then rebuilds the chainstate or imports blocks as
+/// requested. `-reindex` rebuilds the index from the blk files as well,
+/// `-reindex-chainstate` only the coins from the saved index.
+fn load_chainstate(config: &AppConfig, coins_cache: usize) -> Result<Chainstate, String> {
+    let prune_mode = prune_mode(config)?;
+    let data_dir = Path::new(&config.data_dir);
+    let chainstate_dir = data_dir.join("chainstate");
+    let blocks_dir = data_dir.join("blocks");
+    let index_dir = blocks_dir.join("index");
+    let reindex = config.reindex || config.reindex_chainstate;
+    if reindex && chainstate_dir.exists() {
+        info!("Wiping the chainstate database for reindexing");
+        fs::remove_dir_all(&chainstate_dir).map_err(|e| e.to_string())?;
+    }
+    if config.reindex && index_dir.exists() {
+        info!("Wiping the block index database for reindexing");
+        fs::remove_dir_all(&index_dir).map_err(|e| e.to_string())?;
+    }
+
+    let params = chainparams::mainnet_params();
+    let block_tree = BlockTreeDb::new(&index_dir).map_err(|e| e.to_string())?;
+    let (files, last_file) = block_tree.read_block_files().map_err(|e| e.to_string())?;
+    let blocks = BlockStore::with_files(&blocks_dir, params.magic_bytes, files, last_file)
+        .map_err(|e| e.to_string())?;
+    let coins = CoinsViewDb::new(&chainstate_dir).map_err(|e| e.to_string())?;
+    let mut chainstate = Chainstate::new(blocks, block_tree, coins, params.consensus, coins_cache);
+    if let PruneMode::Automatic(target) = prune_mode {
+        info!("Prune configured to target {}MiB on disk for block and undo files", target >> 20);
+    }
+    chainstate.set_prune_mode(prune_mode, params.prune_after_height);
+    chainstate.set_assume_utxo(params.assume_utxo);
+
+    let ui = UiInterface::new();
+    ui.register_callback(INIT_MESSAGE, |message| info!("{}", message));
+    ui.register_callback(SHOW_PROGRESS, |message| info!("{}", message));
+    let time_data = TimeData::new();
+    ui.init_message("Loading block index...");
+    chainstate.load_block_index().map_err(|e| e.to_string())?;
+    if config.reindex {
+        let loaded = chainstate.reindex(&time_data, &ui).map_err(|e| e.to_string())?;
+        info!("Reindexed {} blocks", loaded);
+    } else if config.reindex_chainstate {
+        ui.init_message("Rebuilding the chainstate...");
+        chainstate.activate_best_chain().map_err(|e| e.to_string())?;
+        chainstate.flush().map_err(|e| e.to_string())?;
+        info!("Rebuilt the chainstate up to height {}", chainstate.chain().height().unwrap_or(0));
+    }
+    // The chain parameters don't carry a serialized genesis block, so an
+    // empty index takes the first block imported below as genesis
+    if chainstate.chain().is_empty() {
+        warn!("The block index is empty; the first imported block becomes genesis");
+    }
+
+    // A bootstrap.dat in the data directory is imported once
+    let bootstrap = data_dir.join("bootstrap.dat");
+    if bootstrap.exists() {
+        chainstate
+            .import_files(&[&bootstrap], &time_data, &ui)
+            .map_err(|e| e.to_string())?;
+        fs::rename(&bootstrap, data_dir.join("bootstrap.dat.old")).map_err(|e| e.to_string())?;
+    }
+    if !config.load_blocks.is_empty() {
+        let loaded = chainstate
+            .import_files(&config.load_blocks, &time_data, &ui)
+            .map_err(|e| e.to_string())?;
+        info!("Imported {} blocks", loaded);
+    }
+
+    // Blocks just rebuilt from the blk files were validated already
+    if !reindex {
+        info!("Verifying last {} blocks at level {}", config.check_blocks, config.check_level);
+        chainstate
+            .verify_db(config.check_level, config.check_blocks, &time_data, &ui)
+            .map_err(|e| {
+                format!("Corrupted block database detected ({}), restart with -reindex", e)
+            })?;
+    }
+    Ok(chainstate)
+}
+
 /// Starts the networking service
 async fn start_network_service(port: u16) -> Result<(), String> {
     let network = Arc::new(network::Network::new());
@@ -73,6 +229,10 @@ fn run_app(config: AppConfig) -> Result<(), String> {
     runtime.block_on(async {
         info!("BitcoinZ Node Starting...");
         info!("Data directory: {}", config.data_dir);
+        let coins_cache = txdb::coins_cache_budget(config.db_cache);
+        info!("Using {:.1}MiB for in-memory UTXO set", coins_cache as f64 / (1 << 20) as f64);
+        let chainstate = load_chainstate(&config, coins_cache)?;
+        info!("Chain height: {:?}", chainstate.chain().height());
 
         if let Err(e) = start_network_service(config.network_port).await {
             error!("Network service failed: {}", e);
@@ -104,6 +264,60 @@ fn main() {
                 .takes_value(true)
                 .help("Specify the log file path"),
         )
+        .arg(
+            Arg::new("dbcache")
+                .long("dbcache")
+                .takes_value(true)
+                .help("Set database cache size in megabytes"),
+        )
+        .arg(
+            Arg::new("loadblock")
+                .long("loadblock")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Import blocks from an external blk000??.dat or bootstrap file on startup"),
+        )
+        .arg(
+            Arg::new("reindex")
+                .long("reindex")
+                .help("Rebuild the block index and chainstate from the blk files on disk"),
+        )
+        .arg(
+            Arg::new("reindex_chainstate")
+                .long("reindex-chainstate")
+                .help("Rebuild the chainstate from the blocks on disk"),
+        )
+        .arg(
+            Arg::new("prune")
+                .long("prune")
+                .takes_value(true)
+                .help(
+                    "Delete old blocks to keep block files under this many MiB (at least 550), \
+                     or 1 to only prune with the pruneblockchain RPC",
+                ),
+        )
+        .arg(
+            Arg::new("txindex")
+                .long("txindex")
+                .help("Maintain a full transaction index (incompatible with -prune)"),
+        )
+        .arg(
+            Arg::new("addressindex")
+                .long("addressindex")
+                .help("Maintain an index of transactions by address (incompatible with -prune)"),
+        )
+        .arg(
+            Arg::new("checklevel")
+                .long("checklevel")
+                .takes_value(true)
+                .help("How thorough the block verification at startup is (0-4, default: 3)"),
+        )
+        .arg(
+            Arg::new("checkblocks")
+                .long("checkblocks")
+                .takes_value(true)
+                .help("How many blocks to check at startup (default: 288, 0 = all)"),
+        )
         .arg(
             Arg::new("port")
                 .short('p')
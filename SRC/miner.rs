@@ -1,80 +1,222 @@
-use crate::blockchain::{Block, BlockHeader};
-use crate::mempool::Mempool;
-use crate::consensus::{check_proof_of_work, calculate_next_work_required};
-use sha2::{Digest, Sha256};
-use std::sync::{Arc, Mutex};
+use crate::amount::Amount;
+use crate::chain::BlockId;
+use crate::chainstate::{Chainstate, ChainstateError};
+use crate::coins::{CoinsView, ShieldedType};
+use crate::consensus::merkle::block_merkle_root;
+use crate::consensus::params::ConsensusParams;
+use crate::consensus::subsidy::{get_funding_stream_outputs, get_miner_subsidy};
+use crate::consensus::upgrades::{network_upgrade_active, NetworkUpgrade};
+use crate::consensus::validation::{check_tx_inputs, contextual_check_transaction};
+use crate::pow::equi::EquihashSolver;
+use crate::pow::{check_proof_of_work, get_next_work_required, BlockIndexView};
+use crate::primitives::block::{Block, BlockHeader};
+use crate::primitives::transaction::{
+    OutPoint, Transaction, TxInput, TxOutput, OVERWINTER_TX_VERSION, OVERWINTER_VERSION_GROUP_ID,
+    SAPLING_TX_VERSION, SAPLING_VERSION_GROUP_ID,
+};
+use crate::script::opcodes::OP_0;
+use crate::script::Script;
+use crate::timedata::TimeData;
+use crate::txmempool::Mempool;
+use crate::utxo_snapshot::display_hash;
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Version of the blocks created by the miner
+pub const BLOCK_VERSION: i32 = 4;
 
 /// Represents a miner that prepares and mines blocks
 pub struct Miner {
     mempool: Arc<Mempool>,
-    difficulty_bits: u32,
+    params: ConsensusParams,
 }
 
 impl Miner {
     /// Creates a new Miner instance
-    pub fn new(mempool: Arc<Mempool>, difficulty_bits: u32) -> Self {
-        Miner {
-            mempool,
-            difficulty_bits,
-        }
+    pub fn new(mempool: Arc<Mempool>, params: ConsensusParams) -> Self {
+        Miner { mempool, params }
     }
 
-    /// Prepares a block template for mining
-    pub fn create_block_template(&self, previous_block_hash: &[u8], coinbase_tx: Vec<u8>) -> Block {
-        let mut block = Block::new(previous_block_hash.to_vec());
+    /// Prepares a block template extending the tip of `chain`, whose hash is
+    /// `previous_block_hash` and whose UTXO set is `view`. The coinbase pays
+    /// the miner's share of the subsidy plus the fees of the mempool
+    /// transactions included to `miner_script`.
+    pub fn create_block_template<V: BlockIndexView + ?Sized>(
+        &self,
+        chain: &V,
+        view: &dyn CoinsView,
+        previous_block_hash: [u8; 32],
+        block_time: u32,
+        miner_script: &Script,
+    ) -> Block {
+        let height = chain.tip_height().map_or(0, |tip| tip + 1);
 
-        // Add the coinbase transaction
-        block.add_transaction(coinbase_tx);
+        // Add the coinbase transaction, then transactions from the mempool
+        let (selected, fees) = self.select_transactions(view, height);
+        let coinbase = create_coinbase_transaction(height, miner_script, fees, &self.params);
+        let mut transactions = vec![coinbase];
+        transactions.extend(selected);
 
-        // Add transactions from the mempool
-        let mempool_transactions = self.mempool.get_transactions();
-        for tx in mempool_transactions {
-            block.add_transaction(tx);
-        }
+        let mut block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block_hash: previous_block_hash,
+                timestamp: block_time,
+                bits: get_next_work_required(chain, block_time as i64, &self.params),
+                ..Default::default()
+            },
+            transactions,
+        };
+        block.header.merkle_root = block_merkle_root(&block).0;
 
+        // Commit to the Sapling tree after the block
+        if network_upgrade_active(height, &self.params, NetworkUpgrade::Sapling) {
+            let mut tree = view
+                .get_sapling_anchor_at(&view.get_best_anchor(ShieldedType::Sapling))
+                .expect("the best Sapling anchor is always known");
+            for tx in &block.transactions {
+                for output in &tx.shielded_outputs {
+                    tree.append(output.cmu).expect("the Sapling tree has room for the block");
+                }
+            }
+            block.header.final_sapling_root = tree.root();
+        }
         block
     }
 
-    /// Mines a block by finding a valid nonce
-    pub fn mine_block(&self, block: &mut Block) -> Option<Block> {
-        let mut header = block.header.clone();
-        let mut nonce: u64 = 0;
+    /// Picks the mempool transactions that can be mined at `height` on top
+    /// of `view`, highest fee rate first. Returns them with their total fee.
+    fn select_transactions(&self, view: &dyn CoinsView, height: u32) -> (Vec<Transaction>, Amount) {
+        let mut selected = Vec::new();
+        let mut fees = 0;
+        // Coins and nullifiers spent by the transactions picked so far
+        let mut spent = HashSet::new();
+        let mut nullifiers = HashSet::new();
 
-        loop {
-            header.nonce = nonce;
-            let hash = Miner::calculate_hash(&header);
+        for tx in self.mempool.get_highest_fee_transactions() {
+            // Transactions spending unconfirmed outputs wait for a later block
+            let fee = match contextual_check_transaction(&tx, height, &self.params)
+                .and_then(|()| check_tx_inputs(&tx, view, height))
+            {
+                Ok(fee) => fee,
+                Err(error) => {
+                    debug!("Leaving {} out of the block template: {}", tx.txid(), error);
+                    continue;
+                }
+            };
 
-            if check_proof_of_work(&hash, self.difficulty_bits) {
-                block.header = header;
-                return Some(block.clone());
+            let sprout = tx.join_splits.iter().flat_map(|join_split| join_split.nullifiers);
+            let sapling = tx.shielded_spends.iter().map(|spend| spend.nullifier);
+            let tx_nullifiers: Vec<(ShieldedType, [u8; 32])> = sprout
+                .map(|nullifier| (ShieldedType::Sprout, nullifier))
+                .chain(sapling.map(|nullifier| (ShieldedType::Sapling, nullifier)))
+                .collect();
+            let conflicts = tx.inputs.iter().any(|input| spent.contains(&input.prev_out))
+                || tx_nullifiers.iter().any(|nullifier| nullifiers.contains(nullifier));
+            if conflicts {
+                debug!("Leaving {} out of the block template: conflicting spend", tx.txid());
+                continue;
             }
 
-            nonce += 1;
+            spent.extend(tx.inputs.iter().map(|input| input.prev_out.clone()));
+            nullifiers.extend(tx_nullifiers);
+            fees += fee;
+            selected.push(tx);
+        }
+        (selected, fees)
+    }
+
+    /// Searches up to `max_tries` nonces for an Equihash solution whose header
+    /// meets its target, returning true once one is found. Returns false
+    /// early if `cancel` is set, e.g. because the tip changed.
+    pub fn mine_block(
+        &self,
+        block: &mut Block,
+        height: u32,
+        max_tries: u64,
+        cancel: &AtomicBool,
+    ) -> bool {
+        let solver = EquihashSolver::new(self.params.equihash_params(height));
+        for attempt in 0..max_tries {
+            block.header.nonce[..8].copy_from_slice(&attempt.to_le_bytes());
+            let input = block.header.header_without_solution();
+            let solutions = match solver.solve(&input, cancel) {
+                Some(solutions) => solutions,
+                None => return false,
+            };
 
-            if nonce == u64::MAX {
-                break; // Failsafe to avoid infinite loops
+            for solution in solutions {
+                block.header.solution = solution;
+                if check_proof_of_work(&block.header.hash(), block.header.bits, &self.params) {
+                    return true;
+                }
             }
         }
-
-        None // Mining failed
+        false // Nonce range exhausted
     }
 
-    /// Submits a mined block to the blockchain
-    pub fn submit_block(&self, block: Block) -> bool {
-        // Validate the block (e.g., via consensus rules)
-        if check_proof_of_work(&Miner::calculate_hash(&block.header), self.difficulty_bits) {
-            println!("Block submitted successfully!");
-            return true;
-        } else {
-            println!("Block submission failed!");
-            return false;
+    /// Submits a mined block to `chainstate`. The block must extend the tip
+    /// and pass every check, up to connecting it to the UTXO set, before it
+    /// is stored and becomes the new tip.
+    pub fn submit_block(
+        &self,
+        chainstate: &mut Chainstate,
+        block: &Block,
+        time_data: &TimeData,
+    ) -> Result<BlockId, ChainstateError> {
+        let result = chainstate
+            .test_block_validity(block, time_data)
+            .and_then(|()| chainstate.process_new_block(block, time_data));
+        match &result {
+            Ok(_) => info!("Block {} submitted successfully!", display_hash(&block.hash())),
+            Err(error) => warn!("Block submission failed: {}", error),
         }
+        result
     }
+}
+
+/// Builds the coinbase for a block at `height`, paying every active funding
+/// stream and the remainder of the subsidy plus `fees` to `miner_script`
+pub fn create_coinbase_transaction(
+    height: u32,
+    miner_script: &Script,
+    fees: Amount,
+    params: &ConsensusParams,
+) -> Transaction {
+    let mut outputs = vec![TxOutput {
+        value: get_miner_subsidy(height, params) + fees,
+        script_pubkey: miner_script.clone(),
+    }];
+    outputs.extend(
+        get_funding_stream_outputs(height, params)
+            .into_iter()
+            .map(|(script_pubkey, value)| TxOutput { value, script_pubkey }),
+    );
+
+    let mut tx = Transaction {
+        inputs: vec![TxInput {
+            prev_out: OutPoint {
+                txid: [0; 32],
+                index: u32::MAX,
+            },
+            // The height keeps coinbases unique; OP_0 pads it to the 2-byte minimum
+            script_sig: Script::default().push_int(height as i64).push_opcode(OP_0),
+            sequence: u32::MAX,
+        }],
+        outputs,
+        ..Default::default()
+    };
 
-    /// Calculates the hash of a block header
-    fn calculate_hash(header: &BlockHeader) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(&header.to_bytes());
-        hasher.finalize().to_vec()
+    if network_upgrade_active(height, params, NetworkUpgrade::Sapling) {
+        tx.overwintered = true;
+        tx.version = SAPLING_TX_VERSION;
+        tx.version_group_id = SAPLING_VERSION_GROUP_ID;
+    } else if network_upgrade_active(height, params, NetworkUpgrade::Overwinter) {
+        tx.overwintered = true;
+        tx.version = OVERWINTER_TX_VERSION;
+        tx.version_group_id = OVERWINTER_VERSION_GROUP_ID;
     }
+    tx
 }
@@ -1,33 +1,177 @@
-use std::collections::HashMap;
+use crate::chain::{BlockHash, BlockId, Blockchain, ChainError};
+use crate::chainstate::{Chainstate, ChainstateError, DEFAULT_MAX_TIP_AGE};
+use crate::primitives::block::{Block, BlockHeader};
+use crate::serialize::{Deserializable, Serializable};
+use crate::timedata::TimeData;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, Mutex, RwLock};
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+/// Most headers sent in one "headers" message
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+/// Most blocks requested from a single peer at a time
+pub const MAX_BLOCKS_IN_TRANSIT_PER_PEER: usize = 16;
+/// Blocks are only requested this far past the last block in common with
+/// the peer, so that one slow peer can't hold back the download for long
+pub const BLOCK_DOWNLOAD_WINDOW: u32 = 1024;
+/// Peers whose misbehaviour score reaches this are disconnected
+pub const DEFAULT_BANSCORE_THRESHOLD: u32 = 100;
+/// Seconds a requested block may take to arrive before it can be requested
+/// from another peer
+pub const BLOCK_DOWNLOAD_TIMEOUT: i64 = 10 * 60;
+
 /// Represents a peer in the network.
 pub struct Peer {
     pub id: u64,
     pub address: String,
+    /// Block with the most work the peer is known to have
+    pub best_known_block: Option<BlockId>,
+    /// Last block the peer announced before we had its header
+    pub last_unknown_block: Option<BlockHash>,
+    /// Blocks requested from the peer that haven't arrived yet, with the
+    /// time they were requested
+    pub blocks_in_flight: HashMap<BlockHash, i64>,
+    /// Whether headers were requested from the peer
+    pub sync_started: bool,
+    pub misbehavior: u32,
+}
+
+impl Peer {
+    pub fn new(id: u64, address: String) -> Self {
+        Peer {
+            id,
+            address,
+            best_known_block: None,
+            last_unknown_block: None,
+            blocks_in_flight: HashMap::new(),
+            sync_started: false,
+            misbehavior: 0,
+        }
+    }
+
+    /// Forgets the blocks requested before `cutoff`, so that they can be
+    /// requested from another peer. Returns how many were dropped.
+    pub fn expire_blocks_in_flight(&mut self, cutoff: i64) -> usize {
+        let before = self.blocks_in_flight.len();
+        self.blocks_in_flight.retain(|_, requested| *requested >= cutoff);
+        before - self.blocks_in_flight.len()
+    }
 }
 
-/// Represents a message received from a peer.
-#[derive(Deserialize)]
+/// Represents a message received from or sent to a peer. Hashes, headers
+/// and blocks are carried hex-encoded in their wire format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerMessage {
     pub command: String,
     pub payload: serde_json::Value,
 }
 
+impl PeerMessage {
+    pub fn new(command: &str, payload: serde_json::Value) -> Self {
+        PeerMessage {
+            command: command.to_string(),
+            payload,
+        }
+    }
+}
+
+fn encode<T: Serializable>(value: &T) -> String {
+    let mut bytes = Vec::new();
+    value.serialize(&mut bytes).expect("writing to a Vec can't fail");
+    hex::encode(bytes)
+}
+
+fn decode<T: Deserializable>(value: &serde_json::Value) -> Option<T> {
+    let bytes = hex::decode(value.as_str()?).ok()?;
+    T::deserialize(&mut bytes.as_slice()).ok()
+}
+
+fn decode_list<T: Deserializable>(value: &serde_json::Value) -> Option<Vec<T>> {
+    value.as_array()?.iter().map(decode).collect()
+}
+
+/// Picks up to `count` blocks to download from a peer whose best block is
+/// `best_known`: the first blocks after the fork with the active chain that
+/// are neither stored nor already requested, within the download window.
+pub fn find_next_blocks_to_download(
+    chain: &Blockchain,
+    best_known: BlockId,
+    in_flight: &HashSet<BlockHash>,
+    count: usize,
+) -> Vec<BlockId> {
+    let mut blocks = Vec::new();
+    let tip = match chain.tip() {
+        Some(tip) => tip,
+        None => return blocks,
+    };
+    if chain.get(best_known).chain_work < chain.get(tip).chain_work {
+        return blocks;
+    }
+    let fork = match chain.last_common_ancestor(best_known, tip) {
+        Some(fork) => chain.get(fork).height,
+        None => return blocks,
+    };
+
+    let window_end = chain.get(best_known).height.min(fork + BLOCK_DOWNLOAD_WINDOW);
+    for height in fork + 1..=window_end {
+        let id = chain.get_ancestor(best_known, height).expect("height is within the chain");
+        let entry = chain.get(id);
+        // Nothing past an invalid block is worth downloading
+        if entry.is_failed() {
+            break;
+        }
+        if entry.have_data() || in_flight.contains(&entry.hash) {
+            continue;
+        }
+        blocks.push(id);
+        if blocks.len() == count {
+            break;
+        }
+    }
+    blocks
+}
+
 /// Handles the processing of messages from peers.
 pub struct NetProcessor {
     peers: Arc<RwLock<HashMap<u64, Peer>>>,
+    chainstate: Arc<Mutex<Chainstate>>,
+    time_data: Arc<TimeData>,
+    /// Messages to send, with the peer they are for
+    outbox: mpsc::UnboundedSender<(u64, PeerMessage)>,
 }
 
 impl NetProcessor {
-    /// Creates a new NetProcessor instance.
-    pub fn new() -> Self {
-        NetProcessor {
+    /// Creates a new NetProcessor instance, along with the receiver of the
+    /// messages it sends.
+    pub fn new(
+        chainstate: Arc<Mutex<Chainstate>>,
+        time_data: Arc<TimeData>,
+    ) -> (Self, mpsc::UnboundedReceiver<(u64, PeerMessage)>) {
+        let (outbox, receiver) = mpsc::unbounded_channel();
+        let processor = NetProcessor {
             peers: Arc::new(RwLock::new(HashMap::new())),
-        }
+            chainstate,
+            time_data,
+            outbox,
+        };
+        (processor, receiver)
+    }
+
+    /// Registers a newly connected peer and starts syncing headers with it.
+    pub async fn add_peer(&self, peer_id: u64, address: String) {
+        let chainstate = self.chainstate.lock().await;
+        let mut peers = self.peers.write().await;
+        peers.insert(peer_id, Peer::new(peer_id, address));
+        self.maybe_start_sync(&chainstate, &mut peers, peer_id);
+    }
+
+    /// Forgets a disconnected peer; the blocks it had in flight can be
+    /// requested from others.
+    pub async fn remove_peer(&self, peer_id: u64) {
+        self.peers.write().await.remove(&peer_id);
     }
 
     /// Processes an incoming message from a peer.
@@ -36,39 +180,377 @@ impl NetProcessor {
             "tx" => {
                 self.handle_transaction(peer_id, message.payload).await;
             }
+            "getheaders" => {
+                self.handle_getheaders(peer_id, message.payload).await;
+            }
+            "headers" => {
+                self.handle_headers(peer_id, message.payload).await;
+            }
             "block" => {
                 self.handle_block(peer_id, message.payload).await;
             }
+            "inv" => {
+                self.handle_inv(peer_id, message.payload).await;
+            }
             _ => {
-                eprintln!("Unknown command: {}", message.command);
+                debug!("Unknown command {} from peer {}", message.command, peer_id);
+            }
+        }
+    }
+
+    fn send(&self, peer_id: u64, command: &str, payload: serde_json::Value) {
+        // The receiver only goes away when the node shuts down
+        let _ = self.outbox.send((peer_id, PeerMessage::new(command, payload)));
+    }
+
+    fn send_getheaders(&self, peer_id: u64, locator: Vec<BlockHash>) {
+        let locator: Vec<_> = locator.iter().map(encode).collect();
+        let hash_stop = encode(&[0u8; 32]);
+        self.send(peer_id, "getheaders", json!({ "locator": locator, "hashstop": hash_stop }));
+    }
+
+    /// Adds to the misbehaviour score of a peer, disconnecting it once the
+    /// score reaches `DEFAULT_BANSCORE_THRESHOLD`.
+    fn misbehaving(&self, peers: &mut HashMap<u64, Peer>, peer_id: u64, score: u32, reason: &str) {
+        let peer = match peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.misbehavior += score;
+        warn!("Peer {} misbehaving ({}): {}", peer_id, peer.misbehavior, reason);
+        if peer.misbehavior >= DEFAULT_BANSCORE_THRESHOLD {
+            peers.remove(&peer_id);
+        }
+    }
+
+    /// Asks a peer for headers. Only one peer is synced from until the best
+    /// header is recent; after that every peer is asked.
+    fn maybe_start_sync(
+        &self,
+        chainstate: &Chainstate,
+        peers: &mut HashMap<u64, Peer>,
+        peer_id: u64,
+    ) {
+        let chain = chainstate.chain();
+        let best_header = chain.best_header();
+        let recent = best_header.is_some_and(|id| {
+            chain.get(id).time() > self.time_data.get_adjusted_time() - DEFAULT_MAX_TIP_AGE
+        });
+        let syncing = peers.values().any(|peer| peer.sync_started);
+        let peer = match peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if peer.sync_started || (syncing && !recent) {
+            return;
+        }
+        peer.sync_started = true;
+
+        // Start from the parent of the best header, so that a peer on the
+        // same chain still answers with a header that tells us its tip
+        let start = best_header.map(|id| chain.get(id).prev.unwrap_or(id));
+        self.send_getheaders(peer_id, chain.get_locator(start));
+    }
+
+    /// Records that a peer has `id`, if it has more work than what it was
+    /// known to have.
+    fn update_best_known(chain: &Blockchain, peer: &mut Peer, id: BlockId) {
+        let better = peer
+            .best_known_block
+            .is_none_or(|best| chain.get(id).chain_work > chain.get(best).chain_work);
+        if better {
+            peer.best_known_block = Some(id);
+        }
+    }
+
+    /// Moves the block a peer announced before we had its header to its
+    /// best known block, once the header is in the index.
+    fn process_block_availability(chain: &Blockchain, peer: &mut Peer) {
+        if let Some(id) = peer.last_unknown_block.and_then(|hash| chain.lookup(&hash)) {
+            Self::update_best_known(chain, peer, id);
+            peer.last_unknown_block = None;
+        }
+    }
+
+    /// Records that a peer announced `hash`, remembering it until its
+    /// header arrives if it isn't in the index yet.
+    fn update_block_availability(chain: &Blockchain, peer: &mut Peer, hash: BlockHash) {
+        Self::process_block_availability(chain, peer);
+        match chain.lookup(&hash) {
+            Some(id) => Self::update_best_known(chain, peer, id),
+            None => peer.last_unknown_block = Some(hash),
+        }
+    }
+
+    /// Frees the blocks that have been in flight for longer than
+    /// `BLOCK_DOWNLOAD_TIMEOUT`, so that other peers can be asked for them.
+    fn expire_stalled_blocks(peers: &mut HashMap<u64, Peer>) {
+        let cutoff = TimeData::get_system_time() - BLOCK_DOWNLOAD_TIMEOUT;
+        for peer in peers.values_mut() {
+            let expired = peer.expire_blocks_in_flight(cutoff);
+            if expired > 0 {
+                warn!("Peer {} stalled on {} blocks, requesting them elsewhere", peer.id, expired);
             }
         }
     }
 
+    /// Frees stalled block downloads and requests blocks from every peer
+    /// with room. Called periodically, as blocks stop arriving from a
+    /// stalled peer.
+    pub async fn check_block_downloads(&self) {
+        let chainstate = self.chainstate.lock().await;
+        let mut peers = self.peers.write().await;
+        let peer_ids: Vec<u64> = peers.keys().copied().collect();
+        for peer_id in peer_ids {
+            self.request_blocks(&chainstate, &mut peers, peer_id);
+        }
+    }
+
+    /// Requests the next blocks of the peer's best chain.
+    fn request_blocks(
+        &self,
+        chainstate: &Chainstate,
+        peers: &mut HashMap<u64, Peer>,
+        peer_id: u64,
+    ) {
+        Self::expire_stalled_blocks(peers);
+        let in_flight: HashSet<BlockHash> =
+            peers.values().flat_map(|peer| peer.blocks_in_flight.keys().copied()).collect();
+        let chain = chainstate.chain();
+        let peer = match peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        Self::process_block_availability(chain, peer);
+        let (best_known, room) = match peer.best_known_block {
+            Some(best) => (best, MAX_BLOCKS_IN_TRANSIT_PER_PEER - peer.blocks_in_flight.len()),
+            None => return,
+        };
+        if room == 0 {
+            return;
+        }
+
+        let next = find_next_blocks_to_download(chain, best_known, &in_flight, room);
+        let hashes: Vec<BlockHash> = next.into_iter().map(|id| chain.get(id).hash).collect();
+        if hashes.is_empty() {
+            return;
+        }
+        let now = TimeData::get_system_time();
+        peer.blocks_in_flight.extend(hashes.iter().map(|&hash| (hash, now)));
+        let hashes: Vec<_> = hashes.iter().map(encode).collect();
+        self.send(peer_id, "getdata", json!({ "blocks": hashes }));
+    }
+
     /// Handles a "tx" (transaction) message.
     async fn handle_transaction(&self, peer_id: u64, payload: serde_json::Value) {
-        println!(
-            "Processing transaction from peer {}: {:?}",
-            peer_id, payload
-        );
+        debug!("Processing transaction from peer {}: {:?}", peer_id, payload);
         // Add validation and relay logic here
     }
 
-    /// Handles a "block" (block announcement) message.
+    /// Handles a "getheaders" message: sends the headers of the active chain
+    /// after the last locator entry we have, up to `hashstop`.
+    async fn handle_getheaders(&self, peer_id: u64, payload: serde_json::Value) {
+        let chainstate = self.chainstate.lock().await;
+        // Our chain may be far behind, so don't serve it while catching up
+        if chainstate.is_initial_block_download() {
+            return;
+        }
+        let (locator, hash_stop) = match (
+            decode_list::<BlockHash>(&payload["locator"]),
+            decode::<BlockHash>(&payload["hashstop"]),
+        ) {
+            (Some(locator), Some(hash_stop)) => (locator, hash_stop),
+            _ => {
+                let mut peers = self.peers.write().await;
+                self.misbehaving(&mut peers, peer_id, 20, "malformed getheaders");
+                return;
+            }
+        };
+
+        let chain = chainstate.chain();
+        let mut headers = Vec::new();
+        let mut walk = chain.find_fork_in_locator(&locator).and_then(|id| chain.next(id));
+        while let Some(id) = walk {
+            let entry = chain.get(id);
+            headers.push(encode(&entry.header));
+            if headers.len() == MAX_HEADERS_RESULTS || entry.hash == hash_stop {
+                break;
+            }
+            walk = chain.next(id);
+        }
+        self.send(peer_id, "headers", json!({ "headers": headers }));
+    }
+
+    /// Handles a "headers" message: validates and stores the headers, asks
+    /// for more if the batch was full and requests the blocks they announce.
+    async fn handle_headers(&self, peer_id: u64, payload: serde_json::Value) {
+        let mut chainstate = self.chainstate.lock().await;
+        let mut peers = self.peers.write().await;
+        let headers = match decode_list::<BlockHeader>(&payload["headers"]) {
+            Some(headers) if headers.len() <= MAX_HEADERS_RESULTS => headers,
+            _ => return self.misbehaving(&mut peers, peer_id, 20, "malformed headers"),
+        };
+        if headers.is_empty() {
+            return;
+        }
+        let continuous =
+            headers.windows(2).all(|pair| pair[1].prev_block_hash == pair[0].hash());
+        if !continuous {
+            return self.misbehaving(&mut peers, peer_id, 20, "non-continuous headers sequence");
+        }
+
+        // The peer is on a chain we don't know the start of, probably
+        // announcing a new block; ask for what leads up to it
+        let chain = chainstate.chain();
+        if chain.lookup(&headers[0].prev_block_hash).is_none() {
+            if let Some(peer) = peers.get_mut(&peer_id) {
+                let last = headers.last().expect("headers is not empty").hash();
+                Self::update_block_availability(chain, peer, last);
+            }
+            self.send_getheaders(peer_id, chain.get_locator(chain.best_header()));
+            return;
+        }
+
+        let last = match chainstate.process_new_block_headers(&headers, &self.time_data) {
+            Ok(last) => last.expect("headers is not empty"),
+            Err(error) => {
+                let reason = format!("invalid header: {}", error);
+                return self.misbehaving(&mut peers, peer_id, error.dos_score(), &reason);
+            }
+        };
+        if let Some(peer) = peers.get_mut(&peer_id) {
+            Self::update_best_known(chainstate.chain(), peer, last);
+        }
+
+        // A full batch means the peer has more
+        if headers.len() == MAX_HEADERS_RESULTS {
+            self.send_getheaders(peer_id, chainstate.chain().get_locator(Some(last)));
+        }
+        self.request_blocks(&chainstate, &mut peers, peer_id);
+
+        // Other peers may have announced blocks these headers lead up to
+        let others: Vec<u64> = peers.keys().copied().filter(|&id| id != peer_id).collect();
+        for other in others {
+            if peers[&other].last_unknown_block.is_some() {
+                self.request_blocks(&chainstate, &mut peers, other);
+            }
+        }
+    }
+
+    /// Handles an "inv" message announcing blocks: records what the peer
+    /// has, asks for the headers of blocks we don't know and requests the
+    /// blocks we can download.
+    async fn handle_inv(&self, peer_id: u64, payload: serde_json::Value) {
+        let chainstate = self.chainstate.lock().await;
+        let mut peers = self.peers.write().await;
+        let hashes = match decode_list::<BlockHash>(&payload["blocks"]) {
+            Some(hashes) => hashes,
+            None => return self.misbehaving(&mut peers, peer_id, 20, "malformed inv"),
+        };
+        let chain = chainstate.chain();
+        let peer = match peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let mut unknown = false;
+        for hash in hashes {
+            Self::update_block_availability(chain, peer, hash);
+            unknown |= chain.lookup(&hash).is_none();
+        }
+        if unknown {
+            self.send_getheaders(peer_id, chain.get_locator(chain.best_header()));
+        }
+        self.request_blocks(&chainstate, &mut peers, peer_id);
+    }
+
+    /// Handles a "block" message: stores the block, moves the tip forward
+    /// and requests the next blocks.
     async fn handle_block(&self, peer_id: u64, payload: serde_json::Value) {
-        println!("Processing block from peer {}: {:?}", peer_id, payload);
-        // Add validation and block request logic here
+        let mut chainstate = self.chainstate.lock().await;
+        let mut peers = self.peers.write().await;
+        let block = match decode::<Block>(&payload["block"]) {
+            Some(block) => block,
+            None => return self.misbehaving(&mut peers, peer_id, 20, "malformed block"),
+        };
+        let hash = block.hash();
+        if let Some(peer) = peers.get_mut(&peer_id) {
+            peer.blocks_in_flight.remove(&hash);
+        }
+
+        match chainstate.process_new_block(&block, &self.time_data) {
+            Ok(id) => {
+                if let Some(peer) = peers.get_mut(&peer_id) {
+                    Self::update_best_known(chainstate.chain(), peer, id);
+                }
+            }
+            // An unrequested block whose header we don't have
+            Err(ChainstateError::Chain(ChainError::PrevBlockNotFound)) => {}
+            Err(error) if error.dos_score() > 0 => {
+                let reason = format!("invalid block: {}", error);
+                return self.misbehaving(&mut peers, peer_id, error.dos_score(), &reason);
+            }
+            Err(error) => warn!("Failed to process block from peer {}: {}", peer_id, error),
+        }
+        self.request_blocks(&chainstate, &mut peers, peer_id);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::sync::RwLock;
+    use crate::blockstore::BlockStore;
+    use crate::coins::UTXOSet;
+    use crate::consensus::params::ConsensusParams;
+    use crate::miner::Miner;
+    use crate::script::Script;
+    use crate::txdb::{BlockTreeDb, CoinsViewDb};
+    use crate::txmempool::Mempool;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+
+    /// Mines a regtest chain of `count` blocks, starting with genesis
+    fn mine_chain(count: u32) -> Vec<Block> {
+        let miner = Miner::new(Arc::new(Mempool::new()), ConsensusParams::regtest());
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        // The blocks only pay their coinbase, so no coins are needed
+        let view = UTXOSet::new();
+        for height in 0..count {
+            let prev_hash = blocks.last().map_or([0; 32], |block| block.hash());
+            let time = 1_500_000_000 + height * 150;
+            let script = Script::new(vec![0x51]);
+            let mut block =
+                miner.create_block_template(&headers[..], &view, prev_hash, time, &script);
+            assert!(miner.mine_block(&mut block, height, 1_000, &AtomicBool::new(false)));
+            headers.push(block.header.clone());
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    // The processor is only driven from the test's own runtime
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn processor(
+        dir: &Path,
+        genesis: &Block,
+    ) -> (NetProcessor, mpsc::UnboundedReceiver<(u64, PeerMessage)>) {
+        let _ = fs::remove_dir_all(dir);
+        let blocks = BlockStore::new(&dir.join("blocks"), [0xaa, 0xe8, 0x3f, 0x5f]).unwrap();
+        let block_tree = BlockTreeDb::new(&dir.join("blocks/index")).unwrap();
+        let coins = CoinsViewDb::new(&dir.join("chainstate")).unwrap();
+        let params = ConsensusParams::regtest();
+        let mut chainstate = Chainstate::new(blocks, block_tree, coins, params, 1 << 20);
+        chainstate.load_genesis(genesis).unwrap();
+        NetProcessor::new(Arc::new(Mutex::new(chainstate)), Arc::new(TimeData::new()))
+    }
 
     #[tokio::test]
     async fn test_message_processing() {
-        let net_processor = NetProcessor::new();
+        let dir = env::temp_dir().join("net_processing_messages");
+        let (net_processor, _outbox) = processor(&dir, &mine_chain(1)[0]);
+        net_processor.add_peer(1, "127.0.0.1:1989".to_string()).await;
 
         let peer_message = PeerMessage {
             command: "tx".to_string(),
@@ -83,6 +565,7 @@ mod tests {
         };
 
         net_processor.process_message(1, peer_message).await;
+        assert_eq!(net_processor.peers.read().await[&1].misbehavior, 20);
 
         let peer_message = PeerMessage {
             command: "unknown".to_string(),
@@ -90,5 +573,87 @@ mod tests {
         };
 
         net_processor.process_message(1, peer_message).await;
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_headers_first_sync() {
+        let dir = env::temp_dir().join("net_processing_sync");
+        let blocks = mine_chain(4);
+        let (net_processor, mut outbox) = processor(&dir, &blocks[0]);
+
+        net_processor.add_peer(1, "127.0.0.1:1989".to_string()).await;
+        let (peer_id, message) = outbox.try_recv().unwrap();
+        assert_eq!((peer_id, message.command.as_str()), (1, "getheaders"));
+        let locator: Vec<BlockHash> = decode_list(&message.payload["locator"]).unwrap();
+        assert_eq!(locator, vec![blocks[0].hash()]);
+
+        // Only one peer is synced from while the best header is old
+        net_processor.add_peer(2, "127.0.0.1:1990".to_string()).await;
+        assert!(outbox.try_recv().is_err());
+
+        let headers: Vec<_> = blocks[1..].iter().map(|block| encode(&block.header)).collect();
+        let message = PeerMessage::new("headers", json!({ "headers": headers }));
+        net_processor.process_message(1, message).await;
+        let (_, message) = outbox.try_recv().unwrap();
+        assert_eq!(message.command, "getdata");
+        let requested: Vec<BlockHash> = decode_list(&message.payload["blocks"]).unwrap();
+        let expected: Vec<_> = blocks[1..].iter().map(|block| block.hash()).collect();
+        assert_eq!(requested, expected);
+
+        for block in blocks[1..].iter().rev() {
+            let message = PeerMessage::new("block", json!({ "block": encode(block) }));
+            net_processor.process_message(1, message).await;
+        }
+        let chainstate = net_processor.chainstate.lock().await;
+        assert_eq!(chainstate.chain().height(), Some(3));
+        assert_eq!(chainstate.chain().best_header(), chainstate.chain().tip());
+        let peers = net_processor.peers.read().await;
+        assert!(peers[&1].blocks_in_flight.is_empty());
+        assert_eq!(peers[&1].misbehavior, 0);
+        assert!(outbox.try_recv().is_err());
+        drop((chainstate, peers));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_announcements() {
+        let dir = env::temp_dir().join("net_processing_announcements");
+        let blocks = mine_chain(3);
+        let (net_processor, mut outbox) = processor(&dir, &blocks[0]);
+        net_processor.add_peer(1, "127.0.0.1:1989".to_string()).await;
+        net_processor.add_peer(2, "127.0.0.1:1990".to_string()).await;
+        assert_eq!(outbox.try_recv().unwrap().0, 1);
+
+        // A block announced before its header is asked about and remembered
+        let message = PeerMessage::new("inv", json!({ "blocks": [encode(&blocks[2].hash())] }));
+        net_processor.process_message(2, message).await;
+        let (peer_id, message) = outbox.try_recv().unwrap();
+        assert_eq!((peer_id, message.command.as_str()), (2, "getheaders"));
+        assert_eq!(net_processor.peers.read().await[&2].last_unknown_block, Some(blocks[2].hash()));
+
+        // Headers from another peer tell what the announcing peer has
+        let headers: Vec<_> = blocks[1..].iter().map(|block| encode(&block.header)).collect();
+        let message = PeerMessage::new("headers", json!({ "headers": headers }));
+        net_processor.process_message(1, message).await;
+        let (peer_id, message) = outbox.try_recv().unwrap();
+        assert_eq!((peer_id, message.command.as_str()), (1, "getdata"));
+        assert!(outbox.try_recv().is_err());
+        let chainstate = net_processor.chainstate.lock().await;
+        let peers = net_processor.peers.read().await;
+        assert_eq!(peers[&2].best_known_block, chainstate.chain().lookup(&blocks[2].hash()));
+        assert_eq!(peers[&2].last_unknown_block, None);
+        drop((chainstate, peers));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expire_blocks_in_flight() {
+        let mut peer = Peer::new(1, "127.0.0.1:1989".to_string());
+        peer.blocks_in_flight.insert([1; 32], 100);
+        peer.blocks_in_flight.insert([2; 32], 200);
+        assert_eq!(peer.expire_blocks_in_flight(100), 0);
+        assert_eq!(peer.expire_blocks_in_flight(150), 1);
+        assert_eq!(peer.blocks_in_flight.keys().collect::<Vec<_>>(), vec![&[2; 32]]);
     }
 }
@@ -1,4 +1,4 @@
-use crate::transaction::Transaction;
+use crate::primitives::transaction::Transaction;
 
 /// Minimum relay fee per kilobyte (in satoshis)
 pub const MIN_RELAY_FEE_PER_KB: u64 = 1000; // 1000 satoshis per KB
@@ -10,13 +10,12 @@ impl FeePolicy {
     /// Calculates the required fee for a transaction based on its size
     pub fn calculate_fee(transaction: &Transaction) -> u64 {
         let size = transaction.get_size(); // Transaction size in bytes
-        let fee = (size as u64 * MIN_RELAY_FEE_PER_KB + 999) / 1000; // Ceiling division
-        fee
+        (size as u64 * MIN_RELAY_FEE_PER_KB).div_ceil(1000)
     }
 
-    /// Validates that a transaction meets the minimum relay fee requirements
-    pub fn validate_fee(transaction: &Transaction) -> bool {
-        let required_fee = Self::calculate_fee(transaction);
-        transaction.get_fee() >= required_fee
+    /// Validates that `fee`, paid by a transaction, meets the minimum relay
+    /// fee requirements
+    pub fn validate_fee(transaction: &Transaction, fee: u64) -> bool {
+        fee >= Self::calculate_fee(transaction)
     }
 }
@@ -1,10 +1,67 @@
-use crate::transaction::Transaction;
-use crate::script::Script;
+use crate::amount::Amount;
+use crate::policy::fees::MIN_RELAY_FEE_PER_KB;
+use crate::primitives::transaction::{Transaction, TxOutput, OVERWINTER_TX_VERSION, SAPLING_TX_VERSION};
+use crate::script::standard::{solver, TxnOutType, MAX_OP_RETURN_RELAY};
+use crate::script::{eval_script, BaseSignatureChecker, Script, SCRIPT_VERIFY_NONE};
+use crate::serialize::Serializable;
 
 /// Policy-related constants
 pub const MIN_TX_SIZE: usize = 100; // Minimum size in bytes
 pub const MAX_TX_SIZE: usize = 100000; // Maximum size in bytes
 
+/// Maximum scriptSig size of a standard input, enough for a 15-of-15
+/// CHECKMULTISIG P2SH spend with compressed keys
+pub const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+/// Maximum number of signature operations in a standard P2SH redeem script
+pub const MAX_P2SH_SIGOPS: usize = 15;
+/// Maximum number of keys in a standard bare multisig output
+pub const MAX_STANDARD_MULTISIG_KEYS: u8 = 3;
+
+/// Highest pre-Overwinter transaction version relayed
+pub const SPROUT_MAX_CURRENT_VERSION: i32 = 2;
+
+/// Returns the template of `script_pubkey` if it is standard
+pub fn is_standard(script_pubkey: &Script) -> Option<TxnOutType> {
+    let (out_type, solutions) = solver(script_pubkey);
+    match out_type {
+        TxnOutType::NonStandard => None,
+        TxnOutType::MultiSig => {
+            let required = solutions[0][0];
+            let key_count = solutions[solutions.len() - 1][0];
+            // Support up to x-of-3 multisig to limit UTXO set bloat
+            if !(1..=MAX_STANDARD_MULTISIG_KEYS).contains(&key_count)
+                || !(1..=key_count).contains(&required)
+            {
+                return None;
+            }
+            Some(out_type)
+        }
+        TxnOutType::NullData if script_pubkey.len() > MAX_OP_RETURN_RELAY => None,
+        _ => Some(out_type),
+    }
+}
+
+/// Returns true if spending `output` would cost more than a third of its value
+/// in relay fees
+pub fn is_dust(output: &TxOutput) -> bool {
+    // Unspendable outputs are never dust
+    if output.script_pubkey.as_bytes().first() == Some(&crate::script::opcodes::OP_RETURN) {
+        return false;
+    }
+
+    // A typical spending input is 148 bytes; the output itself is serialized
+    // once to create it
+    let mut buffer = Vec::new();
+    output.serialize(&mut buffer).expect("Output serialization failed");
+    let spend_size = (buffer.len() + 148) as Amount;
+    let fee_per_kb = MIN_RELAY_FEE_PER_KB as Amount;
+    let mut fee = spend_size * fee_per_kb / 1000;
+    if fee == 0 {
+        fee = fee_per_kb;
+    }
+    output.value < 3 * fee
+}
+
 /// Transaction policy enforcement
 pub struct Policy;
 
@@ -21,8 +78,7 @@ impl Policy {
 
     /// Validates a transaction's script for standardness
     pub fn validate_script(script: &Script) -> bool {
-        // Add logic to verify script standardness
-        script.is_standard()
+        is_standard(script).is_some()
     }
 
     /// Validates a transaction against all policy rules
@@ -31,4 +87,191 @@ impl Policy {
             && Self::validate_maximum_size(transaction)
             && Self::validate_script(script)
     }
+
+    /// Checks the relay rules that don't need the spent outputs, returning the
+    /// reject reason on failure
+    pub fn is_standard_tx(transaction: &Transaction) -> Result<(), &'static str> {
+        if transaction.overwintered {
+            if transaction.version < OVERWINTER_TX_VERSION || transaction.version > SAPLING_TX_VERSION {
+                return Err("overwinter-version");
+            }
+        } else if transaction.version < 1 || transaction.version > SPROUT_MAX_CURRENT_VERSION {
+            return Err("version");
+        }
+
+        // Extremely large transactions with lots of inputs can cost the
+        // network almost as much to process as they cost the sender in fees
+        if transaction.get_size() >= MAX_TX_SIZE {
+            return Err("tx-size");
+        }
+
+        for input in &transaction.inputs {
+            if input.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+                return Err("scriptsig-size");
+            }
+            if !input.script_sig.is_push_only() {
+                return Err("scriptsig-not-pushonly");
+            }
+        }
+
+        let mut data_outputs = 0;
+        for output in &transaction.outputs {
+            match is_standard(&output.script_pubkey) {
+                None => return Err("scriptpubkey"),
+                Some(TxnOutType::NullData) => data_outputs += 1,
+                Some(_) if is_dust(output) => return Err("dust"),
+                Some(_) => {}
+            }
+        }
+
+        // Only one OP_RETURN output is permitted
+        if data_outputs > 1 {
+            return Err("multi-op-return");
+        }
+        Ok(())
+    }
+
+    /// Checks that every input spends a standard output, and that P2SH redeem
+    /// scripts stay within the signature operation limit. `spent_outputs`
+    /// holds the output spent by each input, in order.
+    pub fn are_inputs_standard(transaction: &Transaction, spent_outputs: &[TxOutput]) -> bool {
+        if transaction.is_coinbase() {
+            return true;
+        }
+        if spent_outputs.len() != transaction.inputs.len() {
+            return false;
+        }
+
+        for (input, prev) in transaction.inputs.iter().zip(spent_outputs) {
+            let (out_type, _) = solver(&prev.script_pubkey);
+            match out_type {
+                TxnOutType::NonStandard => return false,
+                TxnOutType::ScriptHash => {
+                    // Evaluate scriptSig on its own to recover the redeem script
+                    let mut stack = Vec::new();
+                    if eval_script(&mut stack, &input.script_sig, SCRIPT_VERIFY_NONE, &BaseSignatureChecker)
+                        .is_err()
+                    {
+                        return false;
+                    }
+                    let redeem_script = match stack.pop() {
+                        Some(redeem_script) => Script::new(redeem_script),
+                        None => return false,
+                    };
+                    if redeem_script.get_sig_op_count(true) > MAX_P2SH_SIGOPS {
+                        return false;
+                    }
+                }
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::transaction::{OutPoint, TxInput};
+    use crate::script::opcodes::*;
+    use crate::script::standard::{get_script_for_destination, get_script_for_multisig, TxDestination};
+
+    fn pubkey(tag: u8) -> Vec<u8> {
+        let mut key = vec![0x03];
+        key.extend_from_slice(&[tag; 32]);
+        key
+    }
+
+    fn transaction(outputs: Vec<TxOutput>) -> Transaction {
+        Transaction {
+            inputs: vec![TxInput {
+                prev_out: OutPoint {
+                    txid: [1; 32],
+                    index: 0,
+                },
+                script_sig: Script::default().push_slice(&[0x30; 72]).push_slice(&pubkey(1)),
+                sequence: u32::MAX,
+            }],
+            outputs,
+            ..Default::default()
+        }
+    }
+
+    fn p2pkh_output(value: Amount) -> TxOutput {
+        TxOutput {
+            value,
+            script_pubkey: get_script_for_destination(&TxDestination::KeyId([7; 20])),
+        }
+    }
+
+    #[test]
+    fn test_is_standard() {
+        let keys: Vec<_> = (1..=4).map(pubkey).collect();
+        assert_eq!(is_standard(&get_script_for_multisig(2, &keys[..3])), Some(TxnOutType::MultiSig));
+        assert_eq!(is_standard(&get_script_for_multisig(2, &keys)), None);
+
+        let memo = Script::default().push_opcode(OP_RETURN).push_slice(&[0; 220]);
+        assert_eq!(is_standard(&memo), Some(TxnOutType::NullData));
+        let memo = Script::default().push_opcode(OP_RETURN).push_slice(&[0; 221]);
+        assert_eq!(is_standard(&memo), None);
+
+        assert!(Policy::validate_script(&p2pkh_output(0).script_pubkey));
+    }
+
+    #[test]
+    fn test_is_standard_tx() {
+        assert_eq!(Policy::is_standard_tx(&transaction(vec![p2pkh_output(100_000)])), Ok(()));
+        assert_eq!(Policy::is_standard_tx(&transaction(vec![p2pkh_output(545)])), Err("dust"));
+
+        let memo = TxOutput {
+            value: 0,
+            script_pubkey: Script::default().push_opcode(OP_RETURN).push_slice(b"memo"),
+        };
+        assert_eq!(Policy::is_standard_tx(&transaction(vec![memo.clone()])), Ok(()));
+        assert_eq!(
+            Policy::is_standard_tx(&transaction(vec![memo.clone(), memo])),
+            Err("multi-op-return")
+        );
+
+        let mut tx = transaction(vec![p2pkh_output(100_000)]);
+        tx.inputs[0].script_sig = tx.inputs[0].script_sig.clone().push_opcode(OP_DUP);
+        assert_eq!(Policy::is_standard_tx(&tx), Err("scriptsig-not-pushonly"));
+
+        let mut tx = transaction(vec![p2pkh_output(100_000)]);
+        tx.version = 3;
+        assert_eq!(Policy::is_standard_tx(&tx), Err("version"));
+    }
+
+    #[test]
+    fn test_are_inputs_standard() {
+        let redeem_script = get_script_for_multisig(1, &(1..=3).map(pubkey).collect::<Vec<_>>());
+        let p2sh = TxOutput {
+            value: 100_000,
+            script_pubkey: get_script_for_destination(&TxDestination::ScriptId([0; 20])),
+        };
+
+        let mut tx = transaction(vec![p2pkh_output(100_000)]);
+        tx.inputs[0].script_sig = Script::default()
+            .push_opcode(OP_0)
+            .push_slice(&[0x30; 72])
+            .push_slice(redeem_script.as_bytes());
+        assert!(Policy::are_inputs_standard(&tx, std::slice::from_ref(&p2sh)));
+
+        // 16 CHECKSIGs exceed the P2SH sigop limit
+        let mut heavy = Script::default();
+        for _ in 0..16 {
+            heavy = heavy.push_opcode(OP_CHECKSIG);
+        }
+        tx.inputs[0].script_sig = Script::default().push_slice(heavy.as_bytes());
+        assert!(!Policy::are_inputs_standard(&tx, &[p2sh]));
+
+        let nonstandard = TxOutput {
+            value: 100_000,
+            script_pubkey: Script::default().push_opcode(OP_NOP),
+        };
+        assert!(!Policy::are_inputs_standard(&tx, &[nonstandard]));
+
+        // Every input needs its spent output
+        assert!(!Policy::are_inputs_standard(&tx, &[]));
+    }
 }
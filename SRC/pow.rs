@@ -0,0 +1,149 @@
+use crate::arith_uint256::ArithUint256;
+use crate::consensus::params::ConsensusParams;
+use crate::crypto::equihash::is_valid_solution;
+use crate::primitives::block::BlockHeader;
+
+pub mod equi;
+pub mod equi_miner;
+pub mod osx_barrier;
+
+/// Number of blocks whose timestamps make up the median time past
+pub const MEDIAN_TIME_SPAN: u32 = 11;
+
+/// Read access to the chain ending at the block being built on. Heights are
+/// those of the tip and its ancestors.
+pub trait BlockIndexView {
+    /// Height of the tip, or None for an empty chain
+    fn tip_height(&self) -> Option<u32>;
+    /// Compact target of the block at `height`
+    fn bits(&self, height: u32) -> u32;
+    /// Timestamp of the block at `height`
+    fn time(&self, height: u32) -> i64;
+
+    /// Median timestamp of the block at `height` and up to ten ancestors
+    fn median_time_past(&self, height: u32) -> i64 {
+        let first = (height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+        let mut times: Vec<i64> = (first..=height).map(|h| self.time(h)).collect();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+}
+
+/// A slice of headers indexed by height, starting at genesis
+impl BlockIndexView for [BlockHeader] {
+    fn tip_height(&self) -> Option<u32> {
+        self.len().checked_sub(1).map(|height| height as u32)
+    }
+
+    fn bits(&self, height: u32) -> u32 {
+        self[height as usize].bits
+    }
+
+    fn time(&self, height: u32) -> i64 {
+        self[height as usize].timestamp as i64
+    }
+}
+
+/// Returns the compact target required of the block following the tip of
+/// `chain`, to be timestamped `block_time`
+pub fn get_next_work_required<V: BlockIndexView + ?Sized>(
+    chain: &V,
+    block_time: i64,
+    params: &ConsensusParams,
+) -> u32 {
+    let pow_limit = params.pow_limit.to_compact();
+
+    // Genesis block
+    let tip = match chain.tip_height() {
+        Some(tip) => tip,
+        None => return pow_limit,
+    };
+
+    // Regtest
+    if params.pow_no_retargeting {
+        return chain.bits(tip);
+    }
+
+    // Testnet allows a minimum-difficulty block once the chain has stalled
+    // for six block intervals
+    if let Some(after_height) = params.pow_allow_min_difficulty_blocks_after_height {
+        if tip >= after_height && block_time > chain.time(tip) + params.pow_target_spacing * 6 {
+            return pow_limit;
+        }
+    }
+
+    // The window needs a block before it to measure its timespan
+    let window = params.pow_averaging_window as u32;
+    if tip < window {
+        return pow_limit;
+    }
+    let first = tip - window;
+
+    // Average the targets of the last window blocks
+    let total = ((first + 1)..=tip).fold(ArithUint256::zero(), |total, height| {
+        total + ArithUint256::from_compact(chain.bits(height))
+    });
+    let average = total / ArithUint256::from_u64(window as u64);
+
+    calculate_next_work_required(
+        average,
+        chain.median_time_past(tip),
+        chain.median_time_past(first),
+        params,
+    )
+}
+
+/// Scales the average target of the window by how long the window took to
+/// mine, measured between median times to resist timestamp manipulation
+pub fn calculate_next_work_required(
+    average: ArithUint256,
+    last_block_time: i64,
+    first_block_time: i64,
+    params: &ConsensusParams,
+) -> u32 {
+    let window_timespan = params.averaging_window_timespan();
+
+    // Dampen the adjustment to a quarter, then limit the step
+    let actual_timespan = last_block_time - first_block_time;
+    let actual_timespan = window_timespan + (actual_timespan - window_timespan) / 4;
+    let actual_timespan = actual_timespan
+        .max(params.min_actual_timespan())
+        .min(params.max_actual_timespan());
+
+    // Retarget
+    let target = average / ArithUint256::from_u64(window_timespan as u64)
+        * ArithUint256::from_u64(actual_timespan as u64);
+    if target > params.pow_limit {
+        return params.pow_limit.to_compact();
+    }
+    target.to_compact()
+}
+
+/// Checks the header's Equihash solution under the parameter set its length
+/// selects. Whether that set is the one in force at the header's height is
+/// checked once the height is known.
+pub fn check_equihash_solution(header: &BlockHeader, params: &ConsensusParams) -> bool {
+    match params.equihash_params_for_solution_size(header.solution.len()) {
+        Some(equihash) => {
+            is_valid_solution(&equihash, &header.header_without_solution(), &header.solution).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Checks that `hash` satisfies the target encoded in `bits`
+pub fn check_proof_of_work(hash: &[u8; 32], bits: u32, params: &ConsensusParams) -> bool {
+    let target = ArithUint256::set_compact(bits);
+
+    // Check range
+    if target.negative
+        || target.overflow
+        || target.value.is_zero()
+        || target.value > params.pow_limit
+    {
+        return false;
+    }
+
+    // Check proof of work matches claimed amount
+    ArithUint256::from_le_bytes(hash) <= target.value
+}
@@ -1,64 +1,243 @@
-use std::collections::HashMap;
-
-/// Constants specific to Equihash implementation
-pub const HEADER_LEN: usize = 140;
-pub const COLLISION_BIT_LENGTH: u32 = 20;
-pub const SOLUTION_WIDTH: usize = 512; // In bytes
-
-/// Equihash Context
-pub struct EquihashContext {
-    pub n: u32,          // Security parameter N
-    pub k: u32,          // Security parameter K
-    pub seed: [u8; 32],  // Seed for Equihash solution
-    pub solutions: Vec<Vec<u8>>, // Solutions found
+use crate::crypto::equihash::{expand_array, minimal_from_indices, EquihashParams};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Number of leading collision bits used to bucket rows before sorting
+const BUCKET_BITS: usize = 12;
+/// Runs of colliding rows processed between checks of the cancel flag
+const CANCEL_CHECK_INTERVAL: usize = 4096;
+
+/// Rows of one round of Wagner's algorithm. Hashes are stored back to back,
+/// each with the chunks that collided in earlier rounds removed.
+struct Round {
+    hashes: Vec<u8>,
+    hash_len: usize,
 }
 
-impl EquihashContext {
-    /// Creates a new Equihash context with default parameters
-    pub fn new(n: u32, k: u32, seed: [u8; 32]) -> Self {
-        EquihashContext {
-            n,
-            k,
-            seed,
-            solutions: Vec::new(),
-        }
+impl Round {
+    fn len(&self) -> usize {
+        self.hashes.len() / self.hash_len
+    }
+
+    fn hash(&self, row: u32) -> &[u8] {
+        let start = row as usize * self.hash_len;
+        &self.hashes[start..start + self.hash_len]
+    }
+
+    /// The collision chunk of `row` as a big-endian number
+    fn key(&self, row: u32, chunk_len: usize) -> u32 {
+        self.hash(row)[..chunk_len]
+            .iter()
+            .fold(0, |key, byte| (key << 8) | *byte as u32)
+    }
+}
+
+/// Equihash solver using Wagner's algorithm. Each round bucket-sorts the rows
+/// on their next collision chunk and XORs colliding pairs; rows keep only a
+/// reference to the pair they came from, and the index tree of a solution
+/// is rebuilt from those references at the end.
+///
+/// Every round keeps at most as many rows as there are leaves, dropping the
+/// rare surplus collisions, so memory stays bounded by the leaf count: about
+/// 2 GB at peak for (144, 5), and a few MB for the regtest (48, 5).
+pub struct EquihashSolver {
+    params: EquihashParams,
+}
+
+impl EquihashSolver {
+    pub fn new(params: EquihashParams) -> Self {
+        EquihashSolver { params }
     }
 
-    /// Core Equihash solver logic
-    pub fn solve(&mut self, header: &[u8]) -> bool {
-        let mut buckets = HashMap::new();
+    pub fn params(&self) -> &EquihashParams {
+        &self.params
+    }
+
+    /// Returns every solution for `input`, the header without its solution,
+    /// minimally encoded. Returns None if `cancel` is set before the search
+    /// completes.
+    pub fn solve(&self, input: &[u8], cancel: &AtomicBool) -> Option<Vec<Vec<u8>>> {
+        let k = self.params.k as usize;
+        let trim = self.params.collision_byte_length();
+
+        let mut round = self.generate_leaves(input, cancel)?;
+        let max_rows = round.len();
+        // trees[r] holds, for each row of round r + 1, the two rows of round r
+        // it combines
+        let mut trees: Vec<Vec<(u32, u32)>> = Vec::with_capacity(k);
+
+        for r in 0..k {
+            if cancel.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            // The last round needs the two remaining chunks to cancel out
+            let last = r + 1 == k;
+            let order = self.sort_rows(&round, trim);
+            let mut pairs = Vec::with_capacity(if last { 0 } else { max_rows });
+            let next_len = if last { 0 } else { max_rows * (round.hash_len - trim) };
+            let mut next = Vec::with_capacity(next_len);
+
+            let mut start = 0;
+            let mut runs = 0;
+            while start < order.len() {
+                runs += 1;
+                if runs % CANCEL_CHECK_INTERVAL == 0 && cancel.load(Ordering::Relaxed) {
+                    return None;
+                }
+
+                let key = round.key(order[start], trim);
+                let mut end = start + 1;
+                while end < order.len() && round.key(order[end], trim) == key {
+                    end += 1;
+                }
+
+                let run = &order[start..end];
+                for (i, &a) in run.iter().enumerate() {
+                    for &b in &run[i + 1..] {
+                        let (hash_a, hash_b) = (round.hash(a), round.hash(b));
+                        if last {
+                            if hash_a == hash_b {
+                                pairs.push((a, b));
+                            }
+                            continue;
+                        }
+                        if pairs.len() == max_rows {
+                            break;
+                        }
+                        next.extend(hash_a[trim..].iter().zip(&hash_b[trim..]).map(|(x, y)| x ^ y));
+                        pairs.push((a, b));
+                    }
+                }
+                start = end;
+            }
+
+            trees.push(pairs);
+            round = Round {
+                hashes: next,
+                hash_len: round.hash_len - trim,
+            };
+        }
+
+        let mut solutions: Vec<Vec<u8>> = Vec::new();
+        for row in 0..trees[k - 1].len() {
+            let indices = collect_indices(&trees, k, row as u32);
+
+            // Trees sharing a leaf are not solutions
+            let mut sorted = indices.clone();
+            sorted.sort_unstable();
+            if sorted.windows(2).any(|pair| pair[0] == pair[1]) {
+                continue;
+            }
+
+            let minimal = minimal_from_indices(&indices, self.params.collision_bit_length());
+            if !solutions.contains(&minimal) {
+                solutions.push(minimal);
+            }
+        }
+        Some(solutions)
+    }
 
-        for i in 0..(1 << self.k) {
-            let hash = self.hash_with_seed(header, i as u32);
-            let bucket = hash >> (256 - COLLISION_BIT_LENGTH);
-            buckets.entry(bucket).or_insert(Vec::new()).push(hash);
+    /// Expands the hash of every index into the first round
+    fn generate_leaves(&self, input: &[u8], cancel: &AtomicBool) -> Option<Round> {
+        let params = &self.params;
+        let collision_bit_length = params.collision_bit_length();
+        let leaf_count = 1usize << (collision_bit_length + 1);
+        let per_output = params.indices_per_hash_output();
+        let width = params.n as usize / 8;
 
-            if buckets[&bucket].len() > 2 {
-                self.check_solution(&buckets[&bucket]);
+        let state = params.initialise_state(input);
+        let mut hashes = Vec::with_capacity(leaf_count * params.hash_length());
+        for g in 0..leaf_count.div_ceil(per_output) {
+            if g % 4096 == 0 && cancel.load(Ordering::Relaxed) {
+                return None;
+            }
+            let output = params.generate_hash(&state, g as u32);
+            let count = per_output.min(leaf_count - g * per_output);
+            for chunk in output.chunks(width).take(count) {
+                hashes.extend(expand_array(chunk, collision_bit_length, 0));
             }
         }
 
-        !self.solutions.is_empty()
+        Some(Round {
+            hashes,
+            hash_len: params.hash_length(),
+        })
+    }
+
+    /// Returns the rows of `round` ordered by collision chunk: a counting
+    /// sort on the leading bits, then a sort within each bucket
+    fn sort_rows(&self, round: &Round, chunk_len: usize) -> Vec<u32> {
+        let collision_bit_length = self.params.collision_bit_length();
+        let bucket_bits = BUCKET_BITS.min(collision_bit_length);
+        let shift = collision_bit_length - bucket_bits;
+        let bucket = |row: u32| (round.key(row, chunk_len) >> shift) as usize;
+
+        let mut starts = vec![0usize; (1 << bucket_bits) + 1];
+        for row in 0..round.len() as u32 {
+            starts[bucket(row) + 1] += 1;
+        }
+        for i in 1..starts.len() {
+            starts[i] += starts[i - 1];
+        }
+
+        let mut order = vec![0u32; round.len()];
+        let mut next = starts.clone();
+        for row in 0..round.len() as u32 {
+            let slot = &mut next[bucket(row)];
+            order[*slot] = row;
+            *slot += 1;
+        }
+
+        for window in starts.windows(2) {
+            order[window[0]..window[1]].sort_unstable_by_key(|row| round.key(*row, chunk_len));
+        }
+        order
     }
+}
 
-    /// Hashing with seed (specific to BTCZ Equihash)
-    fn hash_with_seed(&self, header: &[u8], nonce: u32) -> u64 {
-        use sha2::{Digest, Sha256};
-        let mut hasher = Sha256::new();
-        hasher.update(header);
-        hasher.update(&self.seed);
-        hasher.update(&nonce.to_le_bytes());
-        let hash = hasher.finalize();
-        u64::from_le_bytes(hash[..8].try_into().unwrap())
+/// Rebuilds the leaves under `row` of round `level`, placing the subtree with
+/// the lower first index on the left as the verifier requires
+fn collect_indices(trees: &[Vec<(u32, u32)>], level: usize, row: u32) -> Vec<u32> {
+    if level == 0 {
+        return vec![row];
     }
+    let (a, b) = trees[level - 1][row as usize];
+    let left = collect_indices(trees, level - 1, a);
+    let right = collect_indices(trees, level - 1, b);
+    if right[0] < left[0] {
+        [right, left].concat()
+    } else {
+        [left, right].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::equihash::{is_valid_solution, ZCASH_PERSONALIZATION};
 
-    /// Verifies if a solution satisfies the Equihash constraints
-    fn check_solution(&mut self, collision_group: &[u64]) {
-        if collision_group.len() != 2 {
-            return;
+    #[test]
+    fn test_solutions_verify() {
+        let params = EquihashParams::new(48, 5, ZCASH_PERSONALIZATION);
+        let solver = EquihashSolver::new(params);
+        let cancel = AtomicBool::new(false);
+
+        let mut found = 0;
+        for nonce in 0..20u8 {
+            let mut input = [0u8; 140];
+            input[108] = nonce;
+            let solutions = solver.solve(&input, &cancel).unwrap();
+            for solution in &solutions {
+                assert_eq!(is_valid_solution(&params, &input, solution), Ok(()));
+            }
+            found += solutions.len();
         }
+        assert!(found > 0);
+    }
 
-        let solution = collision_group.iter().map(|&x| x.to_le_bytes()).flatten().collect();
-        self.solutions.push(solution);
+    #[test]
+    fn test_cancel() {
+        let solver = EquihashSolver::new(EquihashParams::new(48, 5, ZCASH_PERSONALIZATION));
+        assert_eq!(solver.solve(&[0; 140], &AtomicBool::new(true)), None);
     }
 }
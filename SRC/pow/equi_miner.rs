@@ -1,36 +1,61 @@
-use crate::pow::tromp::equi::EquihashContext;
+use crate::crypto::equihash::EquihashParams;
+use crate::pow::equi::EquihashSolver;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+/// Solves Equihash for successive headers. The search can be interrupted from
+/// another thread, e.g. when the chain tip changes.
 pub struct EquihashMiner {
-    context: EquihashContext,
+    solver: EquihashSolver,
+    cancel: Arc<AtomicBool>,
 }
 
 impl EquihashMiner {
     /// Initializes a new miner with Equihash parameters
-    pub fn new(n: u32, k: u32, seed: [u8; 32]) -> Self {
+    pub fn new(params: EquihashParams) -> Self {
         EquihashMiner {
-            context: EquihashContext::new(n, k, seed),
+            solver: EquihashSolver::new(params),
+            cancel: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Mines solutions for the given header
-    pub fn mine(&mut self, header: &[u8]) -> Vec<Vec<u8>> {
-        self.context.solve(header);
-        self.context.solutions.clone()
+    /// Returns the flag that stops the running search when set
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancel)
+    }
+
+    /// Clears a previous cancellation so mining can resume
+    pub fn reset(&self) {
+        self.cancel.store(false, Ordering::Relaxed);
+    }
+
+    /// Mines all solutions for the given header, or None if cancelled
+    pub fn mine(&self, header: &[u8]) -> Option<Vec<Vec<u8>>> {
+        self.solver.solve(header, &self.cancel)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::equihash::{is_valid_solution, ZCASH_PERSONALIZATION};
 
     #[test]
     fn test_equihash_miner() {
-        let header = [0u8; 140]; // Mock header
-        let seed = [0u8; 32];
+        let params = EquihashParams::new(48, 5, ZCASH_PERSONALIZATION);
+        let miner = EquihashMiner::new(params);
+        let mut header = [0u8; 140];
+        header[108] = 1; // Nonce with a known solution
 
-        let mut miner = EquihashMiner::new(200, 9, seed);
-        let solutions = miner.mine(&header);
+        let solutions = miner.mine(&header).unwrap();
+        assert!(!solutions.is_empty());
+        for solution in &solutions {
+            assert_eq!(is_valid_solution(&params, &header, solution), Ok(()));
+        }
 
-        println!("Found {} solutions", solutions.len());
+        miner.cancel_handle().store(true, Ordering::Relaxed);
+        assert_eq!(miner.mine(&header), None);
+        miner.reset();
+        assert_eq!(miner.mine(&header), Some(solutions));
     }
 }
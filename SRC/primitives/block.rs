@@ -1,37 +1,60 @@
-use crate::transaction::Transaction;
-use crate::serialize::{Serializable, Deserializable, SerializationError};
-use crate::hash::{double_sha256, hash_merkle_root};
+use crate::consensus::merkle::block_merkle_root;
+use crate::primitives::transaction::Transaction;
+use crate::serialize::{Serializable, Deserializable, SerializationError, SerializeHelper};
+use crate::hash::hash256;
 use crate::serialize::CompactSize;
 use std::io::{Read, Write};
 
+/// Size of the serialized block header excluding the Equihash solution
+pub const HEADER_SIZE: usize = 4 + 32 + 32 + 32 + 4 + 4 + 32;
+
 /// Represents a BitcoinZ block header
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct BlockHeader {
     pub version: i32,
     pub prev_block_hash: [u8; 32],
     pub merkle_root: [u8; 32],
+    /// Sapling note commitment tree root after this block (reserved before Sapling)
+    pub final_sapling_root: [u8; 32],
     pub timestamp: u32,
     pub bits: u32,
-    pub nonce: u32,
+    pub nonce: [u8; 32],
+    pub solution: Vec<u8>,
 }
 
 impl BlockHeader {
-    /// Computes the block header hash (double SHA-256)
+    /// Computes the block header hash (double SHA-256 over the header and solution)
     pub fn hash(&self) -> [u8; 32] {
-        let mut buffer = Vec::new();
+        let mut buffer = Vec::with_capacity(HEADER_SIZE + 3 + self.solution.len());
         self.serialize(&mut buffer).expect("BlockHeader serialization failed");
-        double_sha256(&buffer)
+        hash256(&buffer)
     }
-}
 
-impl Serializable for BlockHeader {
-    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+    /// Returns the 140-byte header encoding without the solution, which is the
+    /// input the Equihash solver and verifier feed into BLAKE2b
+    pub fn header_without_solution(&self) -> [u8; HEADER_SIZE] {
+        let mut buffer = [0u8; HEADER_SIZE];
+        self.write_without_solution(&mut &mut buffer[..])
+            .expect("BlockHeader serialization failed");
+        buffer
+    }
+
+    fn write_without_solution<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
         writer.write_all(&self.version.to_le_bytes())?;
         writer.write_all(&self.prev_block_hash)?;
         writer.write_all(&self.merkle_root)?;
+        writer.write_all(&self.final_sapling_root)?;
         writer.write_all(&self.timestamp.to_le_bytes())?;
         writer.write_all(&self.bits.to_le_bytes())?;
-        writer.write_all(&self.nonce.to_le_bytes())?;
+        writer.write_all(&self.nonce)?;
+        Ok(())
+    }
+}
+
+impl Serializable for BlockHeader {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        self.write_without_solution(writer)?;
+        SerializeHelper::write_bytes(writer, &self.solution)?;
         Ok(())
     }
 }
@@ -41,30 +64,35 @@ impl Deserializable for BlockHeader {
         let mut version = [0u8; 4];
         let mut prev_block_hash = [0u8; 32];
         let mut merkle_root = [0u8; 32];
+        let mut final_sapling_root = [0u8; 32];
         let mut timestamp = [0u8; 4];
         let mut bits = [0u8; 4];
-        let mut nonce = [0u8; 4];
+        let mut nonce = [0u8; 32];
 
         reader.read_exact(&mut version)?;
         reader.read_exact(&mut prev_block_hash)?;
         reader.read_exact(&mut merkle_root)?;
+        reader.read_exact(&mut final_sapling_root)?;
         reader.read_exact(&mut timestamp)?;
         reader.read_exact(&mut bits)?;
         reader.read_exact(&mut nonce)?;
+        let solution = SerializeHelper::read_bytes(reader)?;
 
         Ok(BlockHeader {
             version: i32::from_le_bytes(version),
             prev_block_hash,
             merkle_root,
+            final_sapling_root,
             timestamp: u32::from_le_bytes(timestamp),
             bits: u32::from_le_bytes(bits),
-            nonce: u32::from_le_bytes(nonce),
+            nonce,
+            solution,
         })
     }
 }
 
 /// Represents a BitcoinZ block
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Block {
     pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
@@ -76,9 +104,16 @@ impl Block {
         self.header.hash()
     }
 
+    /// Returns the size of the serialized block in bytes
+    pub fn get_size(&self) -> usize {
+        let mut buffer = Vec::new();
+        self.serialize(&mut buffer).expect("Block serialization failed");
+        buffer.len()
+    }
+
     /// Computes the Merkle root of the block's transactions
     pub fn merkle_root(&self) -> [u8; 32] {
-        hash_merkle_root(&self.transactions)
+        block_merkle_root(self).0
     }
 }
 
@@ -96,12 +131,7 @@ impl Serializable for Block {
 impl Deserializable for Block {
     fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
         let header = BlockHeader::deserialize(reader)?;
-        let num_txs = CompactSize::deserialize(reader)?.0 as usize;
-        let mut transactions = Vec::with_capacity(num_txs);
-
-        for _ in 0..num_txs {
-            transactions.push(Transaction::deserialize(reader)?);
-        }
+        let transactions = SerializeHelper::read_vec(reader)?;
 
         Ok(Block { header, transactions })
     }
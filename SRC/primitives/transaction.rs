@@ -1,9 +1,243 @@
-use crate::serialize::{Serializable, Deserializable, SerializationError};
-use crate::hash::{double_sha256, hash_single};
+use crate::amount::Amount;
+use crate::serialize::{Serializable, Deserializable, SerializationError, SerializeHelper};
+use crate::hash::hash256;
 use crate::script::Script;
 use crate::serialize::CompactSize;
 use std::io::{Read, Write};
 
+/// Version group ID of Overwinter (v3) transactions
+pub const OVERWINTER_VERSION_GROUP_ID: u32 = 0x03C48270;
+/// Version group ID of Sapling (v4) transactions
+pub const SAPLING_VERSION_GROUP_ID: u32 = 0x892F2085;
+
+/// Transaction version introduced by Overwinter
+pub const OVERWINTER_TX_VERSION: i32 = 3;
+/// Transaction version introduced by Sapling
+pub const SAPLING_TX_VERSION: i32 = 4;
+
+/// Bit of the header field that flags an Overwinter-or-later transaction
+const OVERWINTERED_FLAG: u32 = 1 << 31;
+
+/// Size of a Groth16 proof in compressed form
+pub const GROTH_PROOF_SIZE: usize = 48 + 96 + 48;
+/// Size of a Sapling note ciphertext
+pub const SAPLING_ENC_CIPHERTEXT_SIZE: usize = 580;
+/// Size of a Sapling outgoing ciphertext
+pub const SAPLING_OUT_CIPHERTEXT_SIZE: usize = 80;
+
+/// A compressed Groth16 proof
+pub type GrothProof = [u8; GROTH_PROOF_SIZE];
+
+/// Number of notes spent by a Sprout JoinSplit
+pub const ZC_NUM_JS_INPUTS: usize = 2;
+/// Number of notes created by a Sprout JoinSplit
+pub const ZC_NUM_JS_OUTPUTS: usize = 2;
+/// Size of a PHGR13 proof in compressed form (seven G1 points and one G2 point)
+pub const PHGR_PROOF_SIZE: usize = 33 + 33 + 65 + 33 + 33 + 33 + 33 + 33;
+/// Size of a Sprout note ciphertext (plaintext plus Poly1305 tag)
+pub const ZC_SPROUT_CIPHERTEXT_SIZE: usize = 1 + 8 + 32 + 32 + 512 + 16;
+
+/// A compressed PHGR13 proof, used by Sprout JoinSplits before Sapling
+pub type PhgrProof = [u8; PHGR_PROOF_SIZE];
+
+/// A Sprout note ciphertext
+pub type SproutCiphertext = [u8; ZC_SPROUT_CIPHERTEXT_SIZE];
+
+/// Zero-knowledge proof attached to a JoinSplit
+///
+/// Which variant appears on the wire is decided by the transaction version:
+/// v4 (Sapling) transactions carry Groth16 proofs, earlier ones PHGR13.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SproutProof {
+    Phgr(PhgrProof),
+    Groth(GrothProof),
+}
+
+impl Serializable for SproutProof {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        match self {
+            SproutProof::Phgr(proof) => writer.write_all(proof)?,
+            SproutProof::Groth(proof) => writer.write_all(proof)?,
+        }
+        Ok(())
+    }
+}
+
+/// A Sprout JoinSplit description
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JSDescription {
+    /// Value that enters the shielded pool from the transparent value pool
+    pub vpub_old: i64,
+    /// Value that leaves the shielded pool to the transparent value pool
+    pub vpub_new: i64,
+    /// Root of the Sprout note commitment tree the inputs are proven against
+    pub anchor: [u8; 32],
+    pub nullifiers: [[u8; 32]; ZC_NUM_JS_INPUTS],
+    pub commitments: [[u8; 32]; ZC_NUM_JS_OUTPUTS],
+    /// Curve25519 public key used for note encryption
+    pub ephemeral_key: [u8; 32],
+    /// Random seed used to derive the `h_sig` value
+    pub random_seed: [u8; 32],
+    /// MACs binding `h_sig` to each input's spending key
+    pub macs: [[u8; 32]; ZC_NUM_JS_INPUTS],
+    pub proof: SproutProof,
+    pub ciphertexts: [SproutCiphertext; ZC_NUM_JS_OUTPUTS],
+}
+
+impl JSDescription {
+    /// Deserializes a JoinSplit, reading a Groth16 proof if `use_groth` is set
+    /// and a PHGR13 proof otherwise.
+    pub fn deserialize_with_proof<R: Read>(
+        reader: &mut R,
+        use_groth: bool,
+    ) -> Result<Self, SerializationError> {
+        let mut vpub_old = [0u8; 8];
+        let mut vpub_new = [0u8; 8];
+        reader.read_exact(&mut vpub_old)?;
+        reader.read_exact(&mut vpub_new)?;
+        let anchor = <[u8; 32]>::deserialize(reader)?;
+        let mut nullifiers = [[0u8; 32]; ZC_NUM_JS_INPUTS];
+        for nullifier in nullifiers.iter_mut() {
+            reader.read_exact(nullifier)?;
+        }
+        let mut commitments = [[0u8; 32]; ZC_NUM_JS_OUTPUTS];
+        for commitment in commitments.iter_mut() {
+            reader.read_exact(commitment)?;
+        }
+        let ephemeral_key = <[u8; 32]>::deserialize(reader)?;
+        let random_seed = <[u8; 32]>::deserialize(reader)?;
+        let mut macs = [[0u8; 32]; ZC_NUM_JS_INPUTS];
+        for mac in macs.iter_mut() {
+            reader.read_exact(mac)?;
+        }
+        let proof = if use_groth {
+            SproutProof::Groth(<GrothProof>::deserialize(reader)?)
+        } else {
+            SproutProof::Phgr(<PhgrProof>::deserialize(reader)?)
+        };
+        let mut ciphertexts = [[0u8; ZC_SPROUT_CIPHERTEXT_SIZE]; ZC_NUM_JS_OUTPUTS];
+        for ciphertext in ciphertexts.iter_mut() {
+            reader.read_exact(ciphertext)?;
+        }
+
+        Ok(JSDescription {
+            vpub_old: i64::from_le_bytes(vpub_old),
+            vpub_new: i64::from_le_bytes(vpub_new),
+            anchor,
+            nullifiers,
+            commitments,
+            ephemeral_key,
+            random_seed,
+            macs,
+            proof,
+            ciphertexts,
+        })
+    }
+}
+
+impl Serializable for JSDescription {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        writer.write_all(&self.vpub_old.to_le_bytes())?;
+        writer.write_all(&self.vpub_new.to_le_bytes())?;
+        writer.write_all(&self.anchor)?;
+        for nullifier in &self.nullifiers {
+            writer.write_all(nullifier)?;
+        }
+        for commitment in &self.commitments {
+            writer.write_all(commitment)?;
+        }
+        writer.write_all(&self.ephemeral_key)?;
+        writer.write_all(&self.random_seed)?;
+        for mac in &self.macs {
+            writer.write_all(mac)?;
+        }
+        self.proof.serialize(writer)?;
+        for ciphertext in &self.ciphertexts {
+            writer.write_all(ciphertext)?;
+        }
+        Ok(())
+    }
+}
+
+/// A Sapling spend description
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendDescription {
+    /// Value commitment to the spent note
+    pub cv: [u8; 32],
+    /// Merkle root of the Sapling note commitment tree
+    pub anchor: [u8; 32],
+    /// Nullifier of the spent note
+    pub nullifier: [u8; 32],
+    /// Randomized public key for `spend_auth_sig`
+    pub rk: [u8; 32],
+    pub zkproof: GrothProof,
+    pub spend_auth_sig: [u8; 64],
+}
+
+impl Serializable for SpendDescription {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        writer.write_all(&self.cv)?;
+        writer.write_all(&self.anchor)?;
+        writer.write_all(&self.nullifier)?;
+        writer.write_all(&self.rk)?;
+        writer.write_all(&self.zkproof)?;
+        writer.write_all(&self.spend_auth_sig)?;
+        Ok(())
+    }
+}
+
+impl Deserializable for SpendDescription {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        Ok(SpendDescription {
+            cv: <[u8; 32]>::deserialize(reader)?,
+            anchor: <[u8; 32]>::deserialize(reader)?,
+            nullifier: <[u8; 32]>::deserialize(reader)?,
+            rk: <[u8; 32]>::deserialize(reader)?,
+            zkproof: <GrothProof>::deserialize(reader)?,
+            spend_auth_sig: <[u8; 64]>::deserialize(reader)?,
+        })
+    }
+}
+
+/// A Sapling output description
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputDescription {
+    /// Value commitment to the new note
+    pub cv: [u8; 32],
+    /// u-coordinate of the new note commitment
+    pub cmu: [u8; 32],
+    /// Jubjub public key used for note encryption
+    pub ephemeral_key: [u8; 32],
+    pub enc_ciphertext: [u8; SAPLING_ENC_CIPHERTEXT_SIZE],
+    pub out_ciphertext: [u8; SAPLING_OUT_CIPHERTEXT_SIZE],
+    pub zkproof: GrothProof,
+}
+
+impl Serializable for OutputDescription {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        writer.write_all(&self.cv)?;
+        writer.write_all(&self.cmu)?;
+        writer.write_all(&self.ephemeral_key)?;
+        writer.write_all(&self.enc_ciphertext)?;
+        writer.write_all(&self.out_ciphertext)?;
+        writer.write_all(&self.zkproof)?;
+        Ok(())
+    }
+}
+
+impl Deserializable for OutputDescription {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        Ok(OutputDescription {
+            cv: <[u8; 32]>::deserialize(reader)?,
+            cmu: <[u8; 32]>::deserialize(reader)?,
+            ephemeral_key: <[u8; 32]>::deserialize(reader)?,
+            enc_ciphertext: <[u8; SAPLING_ENC_CIPHERTEXT_SIZE]>::deserialize(reader)?,
+            out_ciphertext: <[u8; SAPLING_OUT_CIPHERTEXT_SIZE]>::deserialize(reader)?,
+            zkproof: <GrothProof>::deserialize(reader)?,
+        })
+    }
+}
+
 /// Represents a transaction input
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TxInput {
@@ -38,7 +272,7 @@ impl Deserializable for TxInput {
 /// Represents a transaction output
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TxOutput {
-    pub value: u64,
+    pub value: Amount,
     pub script_pubkey: Script,
 }
 
@@ -56,19 +290,26 @@ impl Deserializable for TxOutput {
         reader.read_exact(&mut value)?;
         let script_pubkey = Script::deserialize(reader)?;
         Ok(TxOutput {
-            value: u64::from_le_bytes(value),
+            value: Amount::from_le_bytes(value),
             script_pubkey,
         })
     }
 }
 
 /// Represents a unique identifier for a previous transaction output
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub struct OutPoint {
     pub txid: [u8; 32],
     pub index: u32,
 }
 
+impl OutPoint {
+    /// Returns true for the null prevout referenced by coinbase inputs
+    pub fn is_null(&self) -> bool {
+        self.txid == [0; 32] && self.index == u32::MAX
+    }
+}
+
 impl Serializable for OutPoint {
     fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
         writer.write_all(&self.txid)?;
@@ -91,26 +332,109 @@ impl Deserializable for OutPoint {
 }
 
 /// Represents a BitcoinZ transaction
+///
+/// Covers the legacy (v1), Sprout (v2), Overwinter (v3) and Sapling (v4) wire formats.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Transaction {
+    /// Set for Overwinter-and-later transactions (high bit of the header)
+    pub overwintered: bool,
     pub version: i32,
+    pub version_group_id: u32,
     pub inputs: Vec<TxInput>,
     pub outputs: Vec<TxOutput>,
     pub lock_time: u32,
+    pub expiry_height: u32,
+    /// Net value of Sapling spends minus outputs, in satoshis
+    pub value_balance: i64,
+    pub shielded_spends: Vec<SpendDescription>,
+    pub shielded_outputs: Vec<OutputDescription>,
+    pub join_splits: Vec<JSDescription>,
+    /// Ed25519 key that `join_split_sig` is checked against
+    pub join_split_pub_key: [u8; 32],
+    pub join_split_sig: [u8; 64],
+    pub binding_sig: [u8; 64],
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Transaction {
+            overwintered: false,
+            version: 1,
+            version_group_id: 0,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            lock_time: 0,
+            expiry_height: 0,
+            value_balance: 0,
+            shielded_spends: Vec::new(),
+            shielded_outputs: Vec::new(),
+            join_splits: Vec::new(),
+            join_split_pub_key: [0; 32],
+            join_split_sig: [0; 64],
+            binding_sig: [0; 64],
+        }
+    }
 }
 
 impl Transaction {
-    /// Computes the transaction hash (double SHA-256 of the serialized transaction)
+    /// Computes the transaction id (double SHA-256 of the serialized transaction)
     pub fn hash(&self) -> [u8; 32] {
         let mut buffer = Vec::new();
         self.serialize(&mut buffer).expect("Transaction serialization failed");
-        double_sha256(&buffer)
+        hash256(&buffer)
+    }
+
+    /// Returns the txid as displayed by RPC: byte-reversed hex
+    pub fn txid(&self) -> String {
+        let mut hash = self.hash();
+        hash.reverse();
+        hex::encode(hash)
+    }
+
+    /// Returns the 32-bit header field: the version with the fOverwintered bit
+    pub fn header(&self) -> u32 {
+        let version = self.version as u32;
+        if self.overwintered {
+            version | OVERWINTERED_FLAG
+        } else {
+            version
+        }
+    }
+
+    /// Returns true if this is an Overwinter (v3) transaction
+    pub fn is_overwinter_v3(&self) -> bool {
+        self.overwintered
+            && self.version_group_id == OVERWINTER_VERSION_GROUP_ID
+            && self.version == OVERWINTER_TX_VERSION
+    }
+
+    /// Returns true if this is a Sapling (v4) transaction
+    pub fn is_sapling_v4(&self) -> bool {
+        self.overwintered
+            && self.version_group_id == SAPLING_VERSION_GROUP_ID
+            && self.version == SAPLING_TX_VERSION
+    }
+
+    /// Returns true if JoinSplits in this transaction carry Groth16 proofs
+    pub fn uses_groth_proofs(&self) -> bool {
+        self.overwintered && self.version >= SAPLING_TX_VERSION
+    }
+
+    /// Returns true if this transaction spends the null prevout
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].prev_out.is_null()
+    }
+
+    /// Returns the size of the serialized transaction in bytes
+    pub fn get_size(&self) -> usize {
+        let mut buffer = Vec::new();
+        self.serialize(&mut buffer).expect("Transaction serialization failed");
+        buffer.len()
     }
 
     /// Dummy transaction for testing purposes
     pub fn new_dummy() -> Self {
         Transaction {
-            version: 1,
             inputs: vec![TxInput {
                 prev_out: OutPoint {
                     txid: [0; 32],
@@ -123,49 +447,111 @@ impl Transaction {
                 value: 5000000000, // 50 BTCZ
                 script_pubkey: Script::new(vec![0x76, 0xa9, 0x14]), // OP_DUP OP_HASH160 <pubkeyhash> OP_EQUALVERIFY OP_CHECKSIG
             }],
-            lock_time: 0,
+            ..Default::default()
         }
     }
 }
 
 impl Serializable for Transaction {
     fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
-        writer.write_all(&self.version.to_le_bytes())?;
-        CompactSize(self.inputs.len() as u64).serialize(writer)?;
-        for input in &self.inputs {
-            input.serialize(writer)?;
+        if self.overwintered && !(self.is_overwinter_v3() || self.is_sapling_v4()) {
+            return Err(SerializationError::ParseError("Unknown transaction format"));
         }
-        CompactSize(self.outputs.len() as u64).serialize(writer)?;
-        for output in &self.outputs {
-            output.serialize(writer)?;
+
+        writer.write_all(&self.header().to_le_bytes())?;
+        if self.overwintered {
+            writer.write_all(&self.version_group_id.to_le_bytes())?;
         }
+        SerializeHelper::write_vec(writer, &self.inputs)?;
+        SerializeHelper::write_vec(writer, &self.outputs)?;
         writer.write_all(&self.lock_time.to_le_bytes())?;
+        if self.overwintered {
+            writer.write_all(&self.expiry_height.to_le_bytes())?;
+        }
+        let is_sapling_v4 = self.is_sapling_v4();
+        if is_sapling_v4 {
+            writer.write_all(&self.value_balance.to_le_bytes())?;
+            SerializeHelper::write_vec(writer, &self.shielded_spends)?;
+            SerializeHelper::write_vec(writer, &self.shielded_outputs)?;
+        }
+        if self.version >= 2 {
+            let use_groth = self.uses_groth_proofs();
+            for join_split in &self.join_splits {
+                if matches!(join_split.proof, SproutProof::Groth(_)) != use_groth {
+                    return Err(SerializationError::ParseError(
+                        "JoinSplit proof type does not match transaction version",
+                    ));
+                }
+            }
+            SerializeHelper::write_vec(writer, &self.join_splits)?;
+            if !self.join_splits.is_empty() {
+                writer.write_all(&self.join_split_pub_key)?;
+                writer.write_all(&self.join_split_sig)?;
+            }
+        }
+        if is_sapling_v4 && !(self.shielded_spends.is_empty() && self.shielded_outputs.is_empty()) {
+            writer.write_all(&self.binding_sig)?;
+        }
         Ok(())
     }
 }
 
 impl Deserializable for Transaction {
     fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
-        let mut version = [0u8; 4];
-        reader.read_exact(&mut version)?;
-        let num_inputs = CompactSize::deserialize(reader)?.0 as usize;
-        let mut inputs = Vec::with_capacity(num_inputs);
-        for _ in 0..num_inputs {
-            inputs.push(TxInput::deserialize(reader)?);
-        }
-        let num_outputs = CompactSize::deserialize(reader)?.0 as usize;
-        let mut outputs = Vec::with_capacity(num_outputs);
-        for _ in 0..num_outputs {
-            outputs.push(TxOutput::deserialize(reader)?);
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let header = u32::from_le_bytes(header);
+
+        let mut tx = Transaction {
+            overwintered: header & OVERWINTERED_FLAG != 0,
+            version: (header & !OVERWINTERED_FLAG) as i32,
+            ..Default::default()
+        };
+
+        if tx.overwintered {
+            let mut version_group_id = [0u8; 4];
+            reader.read_exact(&mut version_group_id)?;
+            tx.version_group_id = u32::from_le_bytes(version_group_id);
+            if !(tx.is_overwinter_v3() || tx.is_sapling_v4()) {
+                return Err(SerializationError::ParseError("Unknown transaction format"));
+            }
         }
+
+        tx.inputs = SerializeHelper::read_vec(reader)?;
+        tx.outputs = SerializeHelper::read_vec(reader)?;
         let mut lock_time = [0u8; 4];
         reader.read_exact(&mut lock_time)?;
+        tx.lock_time = u32::from_le_bytes(lock_time);
+        if tx.overwintered {
+            let mut expiry_height = [0u8; 4];
+            reader.read_exact(&mut expiry_height)?;
+            tx.expiry_height = u32::from_le_bytes(expiry_height);
+        }
 
-        Ok(Transaction {
-            version: i32::from_le_bytes(version),
-            inputs,
-            outputs,
-            lock_time: u32::from_le_bytes(lock_time),
-        })
+        let is_sapling_v4 = tx.is_sapling_v4();
+        if is_sapling_v4 {
+            let mut value_balance = [0u8; 8];
+            reader.read_exact(&mut value_balance)?;
+            tx.value_balance = i64::from_le_bytes(value_balance);
+            tx.shielded_spends = SerializeHelper::read_vec(reader)?;
+            tx.shielded_outputs = SerializeHelper::read_vec(reader)?;
+        }
+        if tx.version >= 2 {
+            let use_groth = tx.uses_groth_proofs();
+            let count = CompactSize::deserialize(reader)?.0 as usize;
+            tx.join_splits = Vec::with_capacity(count.min(64));
+            for _ in 0..count {
+                tx.join_splits.push(JSDescription::deserialize_with_proof(reader, use_groth)?);
+            }
+            if !tx.join_splits.is_empty() {
+                reader.read_exact(&mut tx.join_split_pub_key)?;
+                reader.read_exact(&mut tx.join_split_sig)?;
+            }
+        }
+        if is_sapling_v4 && !(tx.shielded_spends.is_empty() && tx.shielded_outputs.is_empty()) {
+            reader.read_exact(&mut tx.binding_sig)?;
+        }
+
+        Ok(tx)
     }
 }
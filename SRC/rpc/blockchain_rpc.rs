@@ -1,17 +1,57 @@
 use crate::blockchain::{Blockchain, Block};
+use crate::chain::BlockId;
+use crate::amount::satoshis_to_btcz;
+use crate::chainstate::{
+    Chainstate, PruneMode, DEFAULT_CHECKBLOCKS, DEFAULT_CHECKLEVEL, MIN_BLOCKS_TO_KEEP,
+};
+use crate::consensus::params::ConsensusParams;
+use crate::consensus::upgrades::{
+    current_epoch_branch_id, network_upgrade_state, ALL_NETWORK_UPGRADES,
+};
+use crate::timedata::TimeData;
 use crate::transaction::{Transaction};
+use crate::ui_interface::UiInterface;
 use crate::utils::hash::Hash256;
 use crate::rpc::{RpcRequest, RpcResponse, RpcError};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Formats a block hash the way it is displayed, byte-reversed
+fn hash_hex(hash: &[u8; 32]) -> String {
+    let mut hash = *hash;
+    hash.reverse();
+    hex::encode(hash)
+}
+
+/// Parses a block hash in its displayed, byte-reversed form
+fn parse_hash(hex: &str) -> Option<[u8; 32]> {
+    let mut hash: [u8; 32] = hex::decode(hex).ok()?.try_into().ok()?;
+    hash.reverse();
+    Some(hash)
+}
 
 /// Handles blockchain-related RPC requests
 pub struct BlockchainRpc {
     blockchain: Blockchain,
+    chainstate: Arc<Mutex<Chainstate>>,
+    params: ConsensusParams,
 }
 
 impl BlockchainRpc {
     /// Creates a new BlockchainRpc handler
-    pub fn new(blockchain: Blockchain) -> Self {
-        BlockchainRpc { blockchain }
+    pub fn new(
+        blockchain: Blockchain,
+        chainstate: Arc<Mutex<Chainstate>>,
+        params: ConsensusParams,
+    ) -> Self {
+        BlockchainRpc {
+            blockchain,
+            chainstate,
+            params,
+        }
     }
 
     /// Handles incoming RPC requests
@@ -21,6 +61,14 @@ impl BlockchainRpc {
             "getblock" => self.get_block(request),
             "getblockhash" => self.get_block_hash(request),
             "getrawtransaction" => self.get_raw_transaction(request),
+            "pruneblockchain" => self.prune_blockchain(request),
+            "getchaintips" => self.get_chain_tips(),
+            "invalidateblock" => self.invalidate_block(request),
+            "reconsiderblock" => self.reconsider_block(request),
+            "gettxoutsetinfo" => self.get_txout_set_info(),
+            "dumptxoutset" => self.dump_txout_set(request),
+            "loadtxoutset" => self.load_txout_set(request),
+            "verifychain" => self.verify_chain(request),
             _ => RpcResponse::error(RpcError::method_not_found(request.method)),
         }
     }
@@ -28,17 +76,65 @@ impl BlockchainRpc {
     /// Returns blockchain information
     fn get_blockchain_info(&self) -> RpcResponse {
         let info = self.blockchain.get_info();
-        RpcResponse::success(serde_json::json!({
+        let chainstate = self.chainstate.blocking_lock();
+        let chain = chainstate.chain();
+        let tip = chain.tip();
+        let height = chain.height().unwrap_or(0);
+        let headers = chain.best_header().map_or(-1, |id| chain.get(id).height as i64);
+        let prune_mode = chainstate.prune_mode();
+        let mut result = serde_json::json!({
             "chain": info.chain,
-            "blocks": info.blocks,
-            "bestblockhash": info.best_block_hash,
+            "blocks": height,
+            "headers": headers,
+            "bestblockhash": tip.map(|tip| hash_hex(&chain.get(tip).hash)),
             "difficulty": info.difficulty,
-        }))
+            "verificationprogress": chainstate.guess_verification_progress(tip),
+            "initialblockdownload": chainstate.is_initial_block_download(),
+            "upgrades": self.upgrades_json(height),
+            "consensus": {
+                "chaintip": format!("{:08x}", current_epoch_branch_id(height, &self.params)),
+                "nextblock": format!("{:08x}", current_epoch_branch_id(height + 1, &self.params)),
+            },
+            "pruned": prune_mode != PruneMode::Disabled,
+        });
+        if prune_mode != PruneMode::Disabled {
+            result["pruneheight"] = serde_json::json!(chainstate.prune_height().unwrap_or(0));
+            result["automatic_pruning"] =
+                serde_json::json!(matches!(prune_mode, PruneMode::Automatic(_)));
+        }
+        if let PruneMode::Automatic(target) = prune_mode {
+            result["prune_target_size"] = serde_json::json!(target);
+        }
+        RpcResponse::success(result)
+    }
+
+    /// Describes every scheduled network upgrade, keyed by branch ID
+    fn upgrades_json(&self, height: u32) -> serde_json::Value {
+        let mut upgrades = serde_json::Map::new();
+        for upgrade in &ALL_NETWORK_UPGRADES[1..] {
+            if let Some(activation_height) = self.params.activation_height(*upgrade) {
+                upgrades.insert(
+                    format!("{:08x}", upgrade.branch_id()),
+                    serde_json::json!({
+                        "name": upgrade.name(),
+                        "activationheight": activation_height,
+                        "status": network_upgrade_state(height, &self.params, *upgrade).name(),
+                        "info": upgrade.info(),
+                    }),
+                );
+            }
+        }
+        serde_json::Value::Object(upgrades)
     }
 
     /// Returns a block by hash or height
     fn get_block(&self, request: RpcRequest) -> RpcResponse {
         if let Some(hash_or_height) = request.params.get(0) {
+            if self.is_pruned(hash_or_height) {
+                return RpcResponse::error(RpcError::internal_error(
+                    "Block not available (pruned data)",
+                ));
+            }
             match self.blockchain.get_block(hash_or_height) {
                 Some(block) => RpcResponse::success(serde_json::json!(block)),
                 None => RpcResponse::error(RpcError::block_not_found(hash_or_height.to_string())),
@@ -48,6 +144,244 @@ impl BlockchainRpc {
         }
     }
 
+    /// Checks if a block given by hash or height is in the block index but
+    /// its data has been pruned
+    fn is_pruned(&self, hash_or_height: &serde_json::Value) -> bool {
+        let chainstate = self.chainstate.blocking_lock();
+        if !chainstate.have_pruned() {
+            return false;
+        }
+        let chain = chainstate.chain();
+        let id = match hash_or_height {
+            serde_json::Value::Number(height) => height
+                .as_u64()
+                .and_then(|height| u32::try_from(height).ok())
+                .and_then(|height| chain.at_height(height)),
+            serde_json::Value::String(hash) => {
+                parse_hash(hash).and_then(|hash| chain.lookup(&hash))
+            }
+            _ => None,
+        };
+        id.map_or(false, |id| !chain.get(id).have_data())
+    }
+
+    /// Deletes the block files holding only blocks up to the given height,
+    /// keeping the last `MIN_BLOCKS_TO_KEEP` blocks. Returns the height
+    /// pruned up to.
+    fn prune_blockchain(&self, request: RpcRequest) -> RpcResponse {
+        let height = match request.params.get(0).and_then(|p| p.as_i64()) {
+            Some(height) => height,
+            None => return RpcResponse::error(RpcError::invalid_params("Missing block height")),
+        };
+        let mut chainstate = self.chainstate.blocking_lock();
+        if chainstate.prune_mode() == PruneMode::Disabled {
+            return RpcResponse::error(RpcError::internal_error(
+                "Cannot prune blocks because node is not in prune mode.",
+            ));
+        }
+        if height < 0 {
+            return RpcResponse::error(RpcError::invalid_params("Negative block height."));
+        }
+
+        let chain_height = chainstate.chain().height().unwrap_or(0);
+        if chain_height < chainstate.prune_after_height() {
+            return RpcResponse::error(RpcError::internal_error(
+                "Blockchain is too short for pruning.",
+            ));
+        }
+        if height > chain_height as i64 {
+            return RpcResponse::error(RpcError::invalid_params(
+                "Blockchain is shorter than the attempted prune height.",
+            ));
+        }
+        let height = (height as u32).min(chain_height.saturating_sub(MIN_BLOCKS_TO_KEEP));
+        match chainstate.prune_block_files_manual(height) {
+            Ok(()) => RpcResponse::success(serde_json::json!(height)),
+            Err(e) => RpcResponse::error(RpcError::internal_error(e.to_string())),
+        }
+    }
+
+    /// Lists the tips of all branches of the block tree, with the length of
+    /// each branch off the active chain and its state
+    fn get_chain_tips(&self) -> RpcResponse {
+        let chainstate = self.chainstate.blocking_lock();
+        let chain = chainstate.chain();
+        let tips: Vec<_> = chain
+            .chain_tips()
+            .into_iter()
+            .map(|id| {
+                let entry = chain.get(id);
+                let fork_height = chain.find_fork(id).map_or(0, |fork| chain.get(fork).height);
+                serde_json::json!({
+                    "height": entry.height,
+                    "hash": hash_hex(&entry.hash),
+                    "branchlen": entry.height - fork_height,
+                    "status": chain.tip_status(id).name(),
+                })
+            })
+            .collect();
+        RpcResponse::success(serde_json::json!(tips))
+    }
+
+    /// Finds the block whose hash is the first parameter
+    fn block_param(request: &RpcRequest, chainstate: &Chainstate) -> Result<BlockId, RpcError> {
+        let hash = request
+            .params
+            .get(0)
+            .and_then(|p| p.as_str())
+            .ok_or_else(|| RpcError::invalid_params("Missing block hash"))?;
+        parse_hash(hash)
+            .and_then(|parsed| chainstate.chain().lookup(&parsed))
+            .ok_or_else(|| RpcError::block_not_found(hash.to_string()))
+    }
+
+    /// Permanently marks a block and its descendants as invalid, as if it
+    /// broke a consensus rule, and moves to the best remaining chain
+    fn invalidate_block(&self, request: RpcRequest) -> RpcResponse {
+        let mut chainstate = self.chainstate.blocking_lock();
+        let id = match Self::block_param(&request, &chainstate) {
+            Ok(id) => id,
+            Err(e) => return RpcResponse::error(e),
+        };
+        match chainstate.invalidate_block(id) {
+            Ok(()) => RpcResponse::success(serde_json::Value::Null),
+            Err(e) => RpcResponse::error(RpcError::internal_error(e.to_string())),
+        }
+    }
+
+    /// Removes the invalidity status of a block, its descendants and
+    /// ancestors, undoing `invalidateblock`
+    fn reconsider_block(&self, request: RpcRequest) -> RpcResponse {
+        let mut chainstate = self.chainstate.blocking_lock();
+        let id = match Self::block_param(&request, &chainstate) {
+            Ok(id) => id,
+            Err(e) => return RpcResponse::error(e),
+        };
+        match chainstate.reconsider_block(id) {
+            Ok(()) => RpcResponse::success(serde_json::Value::Null),
+            Err(e) => RpcResponse::error(RpcError::internal_error(e.to_string())),
+        }
+    }
+
+    /// Returns statistics about the UTXO set, with a hash of it that
+    /// `loadtxoutset` checks snapshots against
+    fn get_txout_set_info(&self) -> RpcResponse {
+        let mut chainstate = self.chainstate.blocking_lock();
+        match chainstate.utxo_stats() {
+            Ok(stats) => RpcResponse::success(serde_json::json!({
+                "height": stats.height,
+                "bestblock": hash_hex(&stats.best_block),
+                "transactions": stats.transactions,
+                "txouts": stats.transaction_outputs,
+                "bytes_serialized": stats.serialized_size,
+                "hash_serialized": hash_hex(&stats.hash_serialized),
+                "total_amount": satoshis_to_btcz(stats.total_amount),
+            })),
+            Err(e) => RpcResponse::error(RpcError::internal_error(e.to_string())),
+        }
+    }
+
+    /// Writes the UTXO set at the tip to a new file as a snapshot. The file
+    /// only gets its name once it is complete.
+    fn dump_txout_set(&self, request: RpcRequest) -> RpcResponse {
+        let path = match request.params.get(0).and_then(|p| p.as_str()) {
+            Some(path) => Path::new(path),
+            None => return RpcResponse::error(RpcError::invalid_params("Missing path")),
+        };
+        if path.exists() {
+            return RpcResponse::error(RpcError::invalid_params(format!(
+                "{} already exists. If you are sure this is what you want, \
+                 move it out of the way first",
+                path.display()
+            )));
+        }
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".incomplete");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut chainstate = self.chainstate.blocking_lock();
+        let written = File::create(&temp_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                chainstate.dump_snapshot(&mut writer).map_err(|e| e.to_string())
+            })
+            .and_then(|metadata| {
+                fs::rename(&temp_path, path).map_err(|e| e.to_string())?;
+                Ok(metadata)
+            });
+        let metadata = match written {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return RpcResponse::error(RpcError::internal_error(e));
+            }
+        };
+        let chain = chainstate.chain();
+        let base_height = chain.lookup(&metadata.base_block).map(|id| chain.get(id).height);
+        RpcResponse::success(serde_json::json!({
+            "coins_written": metadata.coins_count,
+            "base_hash": hash_hex(&metadata.base_block),
+            "base_height": base_height,
+            "path": path.display().to_string(),
+        }))
+    }
+
+    /// Loads a snapshot written by `dumptxoutset` into an empty chainstate,
+    /// if the chain parameters allow its base block and hash
+    fn load_txout_set(&self, request: RpcRequest) -> RpcResponse {
+        let path = match request.params.get(0).and_then(|p| p.as_str()) {
+            Some(path) => Path::new(path),
+            None => return RpcResponse::error(RpcError::invalid_params("Missing path")),
+        };
+        let mut reader = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) => {
+                return RpcResponse::error(RpcError::invalid_params(format!(
+                    "Couldn't open {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+        let mut chainstate = self.chainstate.blocking_lock();
+        match chainstate.load_snapshot(&mut reader) {
+            Ok(metadata) => RpcResponse::success(serde_json::json!({
+                "coins_loaded": metadata.coins_count,
+                "base_hash": hash_hex(&metadata.base_block),
+                "path": path.display().to_string(),
+            })),
+            Err(e) => RpcResponse::error(RpcError::internal_error(e.to_string())),
+        }
+    }
+
+    /// Verifies the blocks at the tip like the startup check. Returns true,
+    /// or an error naming the height of the first bad block.
+    fn verify_chain(&self, request: RpcRequest) -> RpcResponse {
+        let mut params = [DEFAULT_CHECKLEVEL, DEFAULT_CHECKBLOCKS];
+        for (index, param) in params.iter_mut().enumerate() {
+            if let Some(value) = request.params.get(index) {
+                match value.as_u64().and_then(|value| u32::try_from(value).ok()) {
+                    Some(value) => *param = value,
+                    None => {
+                        return RpcResponse::error(RpcError::invalid_params(
+                            "checklevel and nblocks must be non-negative integers",
+                        ))
+                    }
+                }
+            }
+        }
+        let [check_level, check_blocks] = params;
+
+        let mut chainstate = self.chainstate.blocking_lock();
+        let verified =
+            chainstate.verify_db(check_level, check_blocks, &TimeData::new(), &UiInterface::new());
+        match verified {
+            Ok(()) => RpcResponse::success(serde_json::json!(true)),
+            Err(e) => RpcResponse::error(RpcError::internal_error(e.to_string())),
+        }
+    }
+
     /// Returns the block hash for a given height
     fn get_block_hash(&self, request: RpcRequest) -> RpcResponse {
         if let Some(height) = request.params.get(0).and_then(|p| p.as_u64()) {
@@ -1,7 +1,94 @@
+use crate::amount::satoshis_to_btcz;
 use crate::blockchain::{Blockchain, Transaction};
+use crate::key_io::encode_destination;
+use crate::primitives::transaction::Transaction as RawTransaction;
 use crate::rpc::{RpcRequest, RpcResponse, RpcError};
+use crate::script::standard::{extract_destination, extract_destinations, solver, TxDestination};
+use crate::script::{script_to_asm, Script};
+use crate::serialize::Deserializable;
 use crate::utils::hex::decode_hex;
-use serde_json::json;
+use serde_json::{json, Value};
+
+/// Describes a scriptPubKey: its assembly, template and destinations
+fn script_pub_key_to_json(script: &Script, include_hex: bool) -> Value {
+    let mut out = json!({ "asm": script_to_asm(script) });
+    if include_hex {
+        out["hex"] = json!(hex::encode(script.as_bytes()));
+    }
+
+    match extract_destinations(script) {
+        Some((out_type, destinations, required)) => {
+            let addresses: Vec<String> = destinations.iter().map(encode_destination).collect();
+            out["reqSigs"] = json!(required);
+            out["type"] = json!(out_type.name());
+            out["addresses"] = json!(addresses);
+        }
+        None => {
+            out["type"] = json!(solver(script).0.name());
+        }
+    }
+    out
+}
+
+/// Describes a transaction in the layout of `decoderawtransaction`
+fn transaction_to_json(tx: &RawTransaction) -> Value {
+    let vin: Vec<Value> = tx
+        .inputs
+        .iter()
+        .map(|input| {
+            if tx.is_coinbase() {
+                json!({
+                    "coinbase": hex::encode(input.script_sig.as_bytes()),
+                    "sequence": input.sequence,
+                })
+            } else {
+                let mut txid = input.prev_out.txid;
+                txid.reverse();
+                json!({
+                    "txid": hex::encode(txid),
+                    "vout": input.prev_out.index,
+                    "scriptSig": {
+                        "asm": script_to_asm(&input.script_sig),
+                        "hex": hex::encode(input.script_sig.as_bytes()),
+                    },
+                    "sequence": input.sequence,
+                })
+            }
+        })
+        .collect();
+
+    let vout: Vec<Value> = tx
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(n, output)| {
+            json!({
+                "value": satoshis_to_btcz(output.value),
+                "valueZat": output.value,
+                "n": n,
+                "scriptPubKey": script_pub_key_to_json(&output.script_pubkey, true),
+            })
+        })
+        .collect();
+
+    let mut out = json!({
+        "txid": tx.txid(),
+        "overwintered": tx.overwintered,
+        "version": tx.version,
+        "locktime": tx.lock_time,
+        "vin": vin,
+        "vout": vout,
+    });
+    if tx.overwintered {
+        out["versiongroupid"] = json!(format!("{:08x}", tx.version_group_id));
+        out["expiryheight"] = json!(tx.expiry_height);
+    }
+    if tx.is_sapling_v4() {
+        out["valueBalance"] = json!(satoshis_to_btcz(tx.value_balance));
+        out["valueBalanceZat"] = json!(tx.value_balance);
+    }
+    out
+}
 
 /// Handles raw transaction-related RPC requests
 pub struct RawTransactionRpc {
@@ -19,6 +106,7 @@ impl RawTransactionRpc {
         match request.method.as_str() {
             "createrawtransaction" => self.create_raw_transaction(request),
             "decoderawtransaction" => self.decode_raw_transaction(request),
+            "decodescript" => self.decode_script(request),
             "sendrawtransaction" => self.send_raw_transaction(request),
             _ => RpcResponse::error(RpcError::method_not_found(request.method)),
         }
@@ -62,15 +150,40 @@ impl RawTransactionRpc {
     /// Decodes a raw transaction into human-readable format
     fn decode_raw_transaction(&self, request: RpcRequest) -> RpcResponse {
         if let Some(hex) = request.params.get(0).and_then(|p| p.as_str()) {
-            match decode_hex(hex).and_then(|bytes| Transaction::deserialize(&bytes)) {
-                Ok(tx) => RpcResponse::success(json!(tx)),
-                Err(_) => RpcResponse::error(RpcError::invalid_params("Invalid transaction hex")),
+            let tx = hex::decode(hex)
+                .ok()
+                .and_then(|bytes| RawTransaction::deserialize(&mut bytes.as_slice()).ok());
+            match tx {
+                Some(tx) => RpcResponse::success(transaction_to_json(&tx)),
+                None => RpcResponse::error(RpcError::invalid_params("Invalid transaction hex")),
             }
         } else {
             RpcResponse::error(RpcError::invalid_params("Missing transaction hex"))
         }
     }
 
+    /// Decodes a hex-encoded script
+    fn decode_script(&self, request: RpcRequest) -> RpcResponse {
+        let bytes = match request.params.get(0).and_then(|p| p.as_str()) {
+            Some(hex) => match hex::decode(hex) {
+                Ok(bytes) => bytes,
+                Err(_) => return RpcResponse::error(RpcError::invalid_params("Invalid script hex")),
+            },
+            None => return RpcResponse::error(RpcError::invalid_params("Missing script hex")),
+        };
+
+        let script = Script::new(bytes);
+        let mut out = script_pub_key_to_json(&script, false);
+        // The P2SH address is only meaningful for scripts that aren't P2SH
+        if extract_destination(&script).map_or(true, |dest| !matches!(dest, TxDestination::ScriptId(_))) {
+            let script_id = crate::hash::hash160(script.as_bytes());
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(&script_id);
+            out["p2sh"] = json!(encode_destination(&TxDestination::ScriptId(hash)));
+        }
+        RpcResponse::success(out)
+    }
+
     /// Sends a raw transaction to the network
     fn send_raw_transaction(&self, request: RpcRequest) -> RpcResponse {
         if let Some(hex) = request.params.get(0).and_then(|p| p.as_str()) {
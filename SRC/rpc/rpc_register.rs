@@ -33,6 +33,14 @@ impl RpcRegistry {
         self.register("getblock", move |req| blockchain_rpc.handle_request(req));
         self.register("getblockhash", move |req| blockchain_rpc.handle_request(req));
         self.register("getrawtransaction", move |req| blockchain_rpc.handle_request(req));
+        self.register("pruneblockchain", move |req| blockchain_rpc.handle_request(req));
+        self.register("getchaintips", move |req| blockchain_rpc.handle_request(req));
+        self.register("invalidateblock", move |req| blockchain_rpc.handle_request(req));
+        self.register("reconsiderblock", move |req| blockchain_rpc.handle_request(req));
+        self.register("gettxoutsetinfo", move |req| blockchain_rpc.handle_request(req));
+        self.register("dumptxoutset", move |req| blockchain_rpc.handle_request(req));
+        self.register("loadtxoutset", move |req| blockchain_rpc.handle_request(req));
+        self.register("verifychain", move |req| blockchain_rpc.handle_request(req));
 
         self.register("getblocktemplate", move |req| mining_rpc.handle_request(req));
         self.register("submitblock", move |req| mining_rpc.handle_request(req));
@@ -1,66 +1,1719 @@
-use bitcoin::blockdata::script::{Instruction, Script};
-use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::util::hash::Sha256dHash;
+use crate::amount::Amount;
+use crate::primitives::transaction::Transaction;
+use crate::serialize::{Deserializable, Serializable, SerializationError, SerializeHelper};
+use crate::sighash::{signature_hash, PrecomputedTransactionData};
+use secp256k1::ecdsa::Signature;
+use secp256k1::{Message, PublicKey, Secp256k1, VerifyOnly};
+use std::io::{Read, Write};
+use thiserror::Error;
 
-/// Verifies that the input script satisfies the output script.
-pub fn verify_script(input_script: &Script, output_script: &Script, tx: &Transaction, input_index: usize) -> bool {
-    // Simplified implementation: real implementations require transaction context
-    // and script execution (e.g., using a VM like Bitcoin Core)
-    !input_script.is_empty() && !output_script.is_empty() // Example validation logic
+pub mod standard;
+
+/// Script opcodes
+pub mod opcodes {
+    // push value
+    pub const OP_0: u8 = 0x00;
+    pub const OP_FALSE: u8 = OP_0;
+    pub const OP_PUSHDATA1: u8 = 0x4c;
+    pub const OP_PUSHDATA2: u8 = 0x4d;
+    pub const OP_PUSHDATA4: u8 = 0x4e;
+    pub const OP_1NEGATE: u8 = 0x4f;
+    pub const OP_RESERVED: u8 = 0x50;
+    pub const OP_1: u8 = 0x51;
+    pub const OP_TRUE: u8 = OP_1;
+    pub const OP_2: u8 = 0x52;
+    pub const OP_3: u8 = 0x53;
+    pub const OP_4: u8 = 0x54;
+    pub const OP_5: u8 = 0x55;
+    pub const OP_6: u8 = 0x56;
+    pub const OP_7: u8 = 0x57;
+    pub const OP_8: u8 = 0x58;
+    pub const OP_9: u8 = 0x59;
+    pub const OP_10: u8 = 0x5a;
+    pub const OP_11: u8 = 0x5b;
+    pub const OP_12: u8 = 0x5c;
+    pub const OP_13: u8 = 0x5d;
+    pub const OP_14: u8 = 0x5e;
+    pub const OP_15: u8 = 0x5f;
+    pub const OP_16: u8 = 0x60;
+
+    // control
+    pub const OP_NOP: u8 = 0x61;
+    pub const OP_VER: u8 = 0x62;
+    pub const OP_IF: u8 = 0x63;
+    pub const OP_NOTIF: u8 = 0x64;
+    pub const OP_VERIF: u8 = 0x65;
+    pub const OP_VERNOTIF: u8 = 0x66;
+    pub const OP_ELSE: u8 = 0x67;
+    pub const OP_ENDIF: u8 = 0x68;
+    pub const OP_VERIFY: u8 = 0x69;
+    pub const OP_RETURN: u8 = 0x6a;
+
+    // stack ops
+    pub const OP_TOALTSTACK: u8 = 0x6b;
+    pub const OP_FROMALTSTACK: u8 = 0x6c;
+    pub const OP_2DROP: u8 = 0x6d;
+    pub const OP_2DUP: u8 = 0x6e;
+    pub const OP_3DUP: u8 = 0x6f;
+    pub const OP_2OVER: u8 = 0x70;
+    pub const OP_2ROT: u8 = 0x71;
+    pub const OP_2SWAP: u8 = 0x72;
+    pub const OP_IFDUP: u8 = 0x73;
+    pub const OP_DEPTH: u8 = 0x74;
+    pub const OP_DROP: u8 = 0x75;
+    pub const OP_DUP: u8 = 0x76;
+    pub const OP_NIP: u8 = 0x77;
+    pub const OP_OVER: u8 = 0x78;
+    pub const OP_PICK: u8 = 0x79;
+    pub const OP_ROLL: u8 = 0x7a;
+    pub const OP_ROT: u8 = 0x7b;
+    pub const OP_SWAP: u8 = 0x7c;
+    pub const OP_TUCK: u8 = 0x7d;
+
+    // splice ops
+    pub const OP_CAT: u8 = 0x7e;
+    pub const OP_SUBSTR: u8 = 0x7f;
+    pub const OP_LEFT: u8 = 0x80;
+    pub const OP_RIGHT: u8 = 0x81;
+    pub const OP_SIZE: u8 = 0x82;
+
+    // bit logic
+    pub const OP_INVERT: u8 = 0x83;
+    pub const OP_AND: u8 = 0x84;
+    pub const OP_OR: u8 = 0x85;
+    pub const OP_XOR: u8 = 0x86;
+    pub const OP_EQUAL: u8 = 0x87;
+    pub const OP_EQUALVERIFY: u8 = 0x88;
+    pub const OP_RESERVED1: u8 = 0x89;
+    pub const OP_RESERVED2: u8 = 0x8a;
+
+    // numeric
+    pub const OP_1ADD: u8 = 0x8b;
+    pub const OP_1SUB: u8 = 0x8c;
+    pub const OP_2MUL: u8 = 0x8d;
+    pub const OP_2DIV: u8 = 0x8e;
+    pub const OP_NEGATE: u8 = 0x8f;
+    pub const OP_ABS: u8 = 0x90;
+    pub const OP_NOT: u8 = 0x91;
+    pub const OP_0NOTEQUAL: u8 = 0x92;
+    pub const OP_ADD: u8 = 0x93;
+    pub const OP_SUB: u8 = 0x94;
+    pub const OP_MUL: u8 = 0x95;
+    pub const OP_DIV: u8 = 0x96;
+    pub const OP_MOD: u8 = 0x97;
+    pub const OP_LSHIFT: u8 = 0x98;
+    pub const OP_RSHIFT: u8 = 0x99;
+    pub const OP_BOOLAND: u8 = 0x9a;
+    pub const OP_BOOLOR: u8 = 0x9b;
+    pub const OP_NUMEQUAL: u8 = 0x9c;
+    pub const OP_NUMEQUALVERIFY: u8 = 0x9d;
+    pub const OP_NUMNOTEQUAL: u8 = 0x9e;
+    pub const OP_LESSTHAN: u8 = 0x9f;
+    pub const OP_GREATERTHAN: u8 = 0xa0;
+    pub const OP_LESSTHANOREQUAL: u8 = 0xa1;
+    pub const OP_GREATERTHANOREQUAL: u8 = 0xa2;
+    pub const OP_MIN: u8 = 0xa3;
+    pub const OP_MAX: u8 = 0xa4;
+    pub const OP_WITHIN: u8 = 0xa5;
+
+    // crypto
+    pub const OP_RIPEMD160: u8 = 0xa6;
+    pub const OP_SHA1: u8 = 0xa7;
+    pub const OP_SHA256: u8 = 0xa8;
+    pub const OP_HASH160: u8 = 0xa9;
+    pub const OP_HASH256: u8 = 0xaa;
+    pub const OP_CODESEPARATOR: u8 = 0xab;
+    pub const OP_CHECKSIG: u8 = 0xac;
+    pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+    pub const OP_CHECKMULTISIG: u8 = 0xae;
+    pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+
+    // expansion
+    pub const OP_NOP1: u8 = 0xb0;
+    pub const OP_NOP2: u8 = 0xb1;
+    pub const OP_CHECKLOCKTIMEVERIFY: u8 = OP_NOP2;
+    pub const OP_NOP3: u8 = 0xb2;
+    pub const OP_NOP4: u8 = 0xb3;
+    pub const OP_NOP5: u8 = 0xb4;
+    pub const OP_NOP6: u8 = 0xb5;
+    pub const OP_NOP7: u8 = 0xb6;
+    pub const OP_NOP8: u8 = 0xb7;
+    pub const OP_NOP9: u8 = 0xb8;
+    pub const OP_NOP10: u8 = 0xb9;
+
+    pub const OP_INVALIDOPCODE: u8 = 0xff;
+
+    /// Returns the mnemonic of an opcode as printed by `script_to_asm`
+    pub fn name(opcode: u8) -> &'static str {
+        match opcode {
+            OP_0 => "0",
+            OP_PUSHDATA1 => "OP_PUSHDATA1",
+            OP_PUSHDATA2 => "OP_PUSHDATA2",
+            OP_PUSHDATA4 => "OP_PUSHDATA4",
+            OP_1NEGATE => "-1",
+            OP_RESERVED => "OP_RESERVED",
+            OP_1 => "1",
+            OP_2 => "2",
+            OP_3 => "3",
+            OP_4 => "4",
+            OP_5 => "5",
+            OP_6 => "6",
+            OP_7 => "7",
+            OP_8 => "8",
+            OP_9 => "9",
+            OP_10 => "10",
+            OP_11 => "11",
+            OP_12 => "12",
+            OP_13 => "13",
+            OP_14 => "14",
+            OP_15 => "15",
+            OP_16 => "16",
+            OP_NOP => "OP_NOP",
+            OP_VER => "OP_VER",
+            OP_IF => "OP_IF",
+            OP_NOTIF => "OP_NOTIF",
+            OP_VERIF => "OP_VERIF",
+            OP_VERNOTIF => "OP_VERNOTIF",
+            OP_ELSE => "OP_ELSE",
+            OP_ENDIF => "OP_ENDIF",
+            OP_VERIFY => "OP_VERIFY",
+            OP_RETURN => "OP_RETURN",
+            OP_TOALTSTACK => "OP_TOALTSTACK",
+            OP_FROMALTSTACK => "OP_FROMALTSTACK",
+            OP_2DROP => "OP_2DROP",
+            OP_2DUP => "OP_2DUP",
+            OP_3DUP => "OP_3DUP",
+            OP_2OVER => "OP_2OVER",
+            OP_2ROT => "OP_2ROT",
+            OP_2SWAP => "OP_2SWAP",
+            OP_IFDUP => "OP_IFDUP",
+            OP_DEPTH => "OP_DEPTH",
+            OP_DROP => "OP_DROP",
+            OP_DUP => "OP_DUP",
+            OP_NIP => "OP_NIP",
+            OP_OVER => "OP_OVER",
+            OP_PICK => "OP_PICK",
+            OP_ROLL => "OP_ROLL",
+            OP_ROT => "OP_ROT",
+            OP_SWAP => "OP_SWAP",
+            OP_TUCK => "OP_TUCK",
+            OP_CAT => "OP_CAT",
+            OP_SUBSTR => "OP_SUBSTR",
+            OP_LEFT => "OP_LEFT",
+            OP_RIGHT => "OP_RIGHT",
+            OP_SIZE => "OP_SIZE",
+            OP_INVERT => "OP_INVERT",
+            OP_AND => "OP_AND",
+            OP_OR => "OP_OR",
+            OP_XOR => "OP_XOR",
+            OP_EQUAL => "OP_EQUAL",
+            OP_EQUALVERIFY => "OP_EQUALVERIFY",
+            OP_RESERVED1 => "OP_RESERVED1",
+            OP_RESERVED2 => "OP_RESERVED2",
+            OP_1ADD => "OP_1ADD",
+            OP_1SUB => "OP_1SUB",
+            OP_2MUL => "OP_2MUL",
+            OP_2DIV => "OP_2DIV",
+            OP_NEGATE => "OP_NEGATE",
+            OP_ABS => "OP_ABS",
+            OP_NOT => "OP_NOT",
+            OP_0NOTEQUAL => "OP_0NOTEQUAL",
+            OP_ADD => "OP_ADD",
+            OP_SUB => "OP_SUB",
+            OP_MUL => "OP_MUL",
+            OP_DIV => "OP_DIV",
+            OP_MOD => "OP_MOD",
+            OP_LSHIFT => "OP_LSHIFT",
+            OP_RSHIFT => "OP_RSHIFT",
+            OP_BOOLAND => "OP_BOOLAND",
+            OP_BOOLOR => "OP_BOOLOR",
+            OP_NUMEQUAL => "OP_NUMEQUAL",
+            OP_NUMEQUALVERIFY => "OP_NUMEQUALVERIFY",
+            OP_NUMNOTEQUAL => "OP_NUMNOTEQUAL",
+            OP_LESSTHAN => "OP_LESSTHAN",
+            OP_GREATERTHAN => "OP_GREATERTHAN",
+            OP_LESSTHANOREQUAL => "OP_LESSTHANOREQUAL",
+            OP_GREATERTHANOREQUAL => "OP_GREATERTHANOREQUAL",
+            OP_MIN => "OP_MIN",
+            OP_MAX => "OP_MAX",
+            OP_WITHIN => "OP_WITHIN",
+            OP_RIPEMD160 => "OP_RIPEMD160",
+            OP_SHA1 => "OP_SHA1",
+            OP_SHA256 => "OP_SHA256",
+            OP_HASH160 => "OP_HASH160",
+            OP_HASH256 => "OP_HASH256",
+            OP_CODESEPARATOR => "OP_CODESEPARATOR",
+            OP_CHECKSIG => "OP_CHECKSIG",
+            OP_CHECKSIGVERIFY => "OP_CHECKSIGVERIFY",
+            OP_CHECKMULTISIG => "OP_CHECKMULTISIG",
+            OP_CHECKMULTISIGVERIFY => "OP_CHECKMULTISIGVERIFY",
+            OP_NOP1 => "OP_NOP1",
+            OP_CHECKLOCKTIMEVERIFY => "OP_CHECKLOCKTIMEVERIFY",
+            OP_NOP3 => "OP_NOP3",
+            OP_NOP4 => "OP_NOP4",
+            OP_NOP5 => "OP_NOP5",
+            OP_NOP6 => "OP_NOP6",
+            OP_NOP7 => "OP_NOP7",
+            OP_NOP8 => "OP_NOP8",
+            OP_NOP9 => "OP_NOP9",
+            OP_NOP10 => "OP_NOP10",
+            OP_INVALIDOPCODE => "OP_INVALIDOPCODE",
+            _ => "OP_UNKNOWN",
+        }
+    }
+}
+
+use opcodes::*;
+
+/// A serialized script, as found in scriptSig and scriptPubKey fields
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Script(Vec<u8>);
+
+impl Script {
+    /// Wraps raw script bytes
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Script(bytes)
+    }
+
+    /// Returns the raw script bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the script length in bytes
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if the script has no bytes
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reads the operation starting at `pc`.
+    ///
+    /// Returns the opcode, any data it pushes and the position of the next
+    /// operation, or `None` at the end of the script or on a truncated push.
+    pub fn get_op(&self, pc: usize) -> Option<(u8, &[u8], usize)> {
+        let script = &self.0;
+        let opcode = *script.get(pc)?;
+        let mut pos = pc + 1;
+
+        let size = if opcode < OP_PUSHDATA1 {
+            opcode as usize
+        } else if opcode == OP_PUSHDATA1 {
+            let size = *script.get(pos)? as usize;
+            pos += 1;
+            size
+        } else if opcode == OP_PUSHDATA2 {
+            let bytes = script.get(pos..pos + 2)?;
+            pos += 2;
+            u16::from_le_bytes([bytes[0], bytes[1]]) as usize
+        } else if opcode == OP_PUSHDATA4 {
+            let bytes = script.get(pos..pos + 4)?;
+            pos += 4;
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+        } else {
+            0
+        };
+
+        let data = script.get(pos..pos.checked_add(size)?)?;
+        Some((opcode, data, pos + size))
+    }
+
+    /// Appends an opcode
+    pub fn push_opcode(mut self, opcode: u8) -> Self {
+        self.0.push(opcode);
+        self
+    }
+
+    /// Appends a push of `data` using the smallest push opcode
+    pub fn push_slice(mut self, data: &[u8]) -> Self {
+        let len = data.len();
+        if len < OP_PUSHDATA1 as usize {
+            self.0.push(len as u8);
+        } else if len <= 0xff {
+            self.0.push(OP_PUSHDATA1);
+            self.0.push(len as u8);
+        } else if len <= 0xffff {
+            self.0.push(OP_PUSHDATA2);
+            self.0.extend_from_slice(&(len as u16).to_le_bytes());
+        } else {
+            self.0.push(OP_PUSHDATA4);
+            self.0.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// Appends a number, using OP_0/OP_1NEGATE/OP_1..OP_16 where possible
+    pub fn push_int(self, n: i64) -> Self {
+        if n == -1 || (1..=16).contains(&n) {
+            self.push_opcode((n + (OP_1 as i64 - 1)) as u8)
+        } else if n == 0 {
+            self.push_opcode(OP_0)
+        } else {
+            self.push_slice(&ScriptNum(n).to_bytes())
+        }
+    }
+
+    /// Returns true if the script only pushes data
+    pub fn is_push_only(&self) -> bool {
+        let mut pc = 0;
+        while pc < self.0.len() {
+            match self.get_op(pc) {
+                Some((opcode, _, next)) if opcode <= OP_16 => pc = next,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Counts signature operations. With `accurate`, CHECKMULTISIG preceded
+    /// by OP_1..OP_16 counts that many keys instead of the maximum.
+    pub fn get_sig_op_count(&self, accurate: bool) -> usize {
+        let mut count = 0;
+        let mut last_opcode = OP_INVALIDOPCODE;
+        let mut pc = 0;
+        while let Some((opcode, _, next)) = self.get_op(pc) {
+            match opcode {
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    if accurate && (OP_1..=OP_16).contains(&last_opcode) {
+                        count += (last_opcode - OP_1 + 1) as usize;
+                    } else {
+                        count += MAX_PUBKEYS_PER_MULTISIG as usize;
+                    }
+                }
+                _ => {}
+            }
+            last_opcode = opcode;
+            pc = next;
+        }
+        count
+    }
+
+    /// Counts the signature operations of the redeem script that `script_sig`
+    /// provides when this script is P2SH
+    pub fn get_p2sh_sig_op_count(&self, script_sig: &Script) -> usize {
+        if !self.is_pay_to_script_hash() {
+            return self.get_sig_op_count(true);
+        }
+
+        // The redeem script is the last push of scriptSig
+        let mut redeem_script: &[u8] = &[];
+        let mut pc = 0;
+        while let Some((opcode, data, next)) = script_sig.get_op(pc) {
+            if opcode > OP_16 {
+                return 0;
+            }
+            redeem_script = data;
+            pc = next;
+        }
+        Script::new(redeem_script.to_vec()).get_sig_op_count(true)
+    }
+
+    /// Returns true for the BIP16 template OP_HASH160 <20 bytes> OP_EQUAL
+    pub fn is_pay_to_script_hash(&self) -> bool {
+        let script = &self.0;
+        script.len() == 23 && script[0] == OP_HASH160 && script[1] == 0x14 && script[22] == OP_EQUAL
+    }
+
+    /// Returns true if the script can never be satisfied, so outputs paying
+    /// to it needn't be kept in the UTXO set
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&OP_RETURN) || self.0.len() > MAX_SCRIPT_SIZE
+    }
+
+    /// Returns a copy of the script with every occurrence of `pattern` that
+    /// starts on an opcode boundary removed
+    pub fn find_and_delete(&self, pattern: &Script) -> Script {
+        let bytes = &self.0;
+        let pattern = &pattern.0;
+        if pattern.is_empty() {
+            return self.clone();
+        }
+
+        let mut result = Vec::with_capacity(bytes.len());
+        let mut found = false;
+        let mut pc = 0;
+        let mut pc2 = 0;
+        loop {
+            result.extend_from_slice(&bytes[pc2..pc]);
+            while bytes.len() - pc >= pattern.len() && bytes[pc..pc + pattern.len()] == pattern[..] {
+                pc += pattern.len();
+                found = true;
+            }
+            pc2 = pc;
+            match self.get_op(pc) {
+                Some((_, _, next)) => pc = next,
+                None => break,
+            }
+        }
+
+        if found {
+            result.extend_from_slice(&bytes[pc2..]);
+            Script(result)
+        } else {
+            self.clone()
+        }
+    }
+
+    /// Returns a copy of the script with every OP_CODESEPARATOR removed
+    pub fn without_codeseparators(&self) -> Script {
+        let mut result = Vec::with_capacity(self.0.len());
+        let mut pc = 0;
+        while let Some((opcode, _, next)) = self.get_op(pc) {
+            if opcode != OP_CODESEPARATOR {
+                result.extend_from_slice(&self.0[pc..next]);
+            }
+            pc = next;
+        }
+        // Keep any trailing bytes of a truncated push untouched
+        result.extend_from_slice(&self.0[pc..]);
+        Script(result)
+    }
+}
+
+impl Serializable for Script {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        SerializeHelper::write_bytes(writer, &self.0)
+    }
+}
+
+impl Deserializable for Script {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        Ok(Script(SerializeHelper::read_bytes(reader)?))
+    }
+}
+
+/// Maximum number of bytes pushable to the stack
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+/// Maximum number of non-push operations per script
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+/// Maximum number of public keys per multisig
+pub const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+/// Maximum script length in bytes
+pub const MAX_SCRIPT_SIZE: usize = 10000;
+/// Maximum combined size of the main and alt stacks
+pub const MAX_STACK_SIZE: usize = 1000;
+/// nLockTime values below this are block heights, above it UNIX timestamps
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Script verification flags
+pub const SCRIPT_VERIFY_NONE: u32 = 0;
+/// Evaluate P2SH subscripts (BIP16)
+pub const SCRIPT_VERIFY_P2SH: u32 = 1 << 0;
+/// Require canonical public key and signature hash type encodings
+pub const SCRIPT_VERIFY_STRICTENC: u32 = 1 << 1;
+/// Require the S value of signatures to be in the lower half of the curve order
+pub const SCRIPT_VERIFY_LOW_S: u32 = 1 << 3;
+/// Require the CHECKMULTISIG dummy element to be empty
+pub const SCRIPT_VERIFY_NULLDUMMY: u32 = 1 << 4;
+/// Require scriptSig to contain only pushes
+pub const SCRIPT_VERIFY_SIGPUSHONLY: u32 = 1 << 5;
+/// Require pushes and numbers to use their minimal encoding
+pub const SCRIPT_VERIFY_MINIMALDATA: u32 = 1 << 6;
+/// Fail on the NOPs reserved for soft-fork upgrades
+pub const SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS: u32 = 1 << 7;
+/// Require exactly one element on the stack after evaluation (needs P2SH)
+pub const SCRIPT_VERIFY_CLEANSTACK: u32 = 1 << 8;
+/// Enable OP_CHECKLOCKTIMEVERIFY (BIP65)
+pub const SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY: u32 = 1 << 9;
+
+/// Flags every block must satisfy
+pub const MANDATORY_SCRIPT_VERIFY_FLAGS: u32 = SCRIPT_VERIFY_P2SH;
+
+/// Flags enforced for mempool acceptance and relay
+pub const STANDARD_SCRIPT_VERIFY_FLAGS: u32 = MANDATORY_SCRIPT_VERIFY_FLAGS
+    | SCRIPT_VERIFY_STRICTENC
+    | SCRIPT_VERIFY_MINIMALDATA
+    | SCRIPT_VERIFY_NULLDUMMY
+    | SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS
+    | SCRIPT_VERIFY_CLEANSTACK
+    | SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY
+    | SCRIPT_VERIFY_LOW_S;
+
+/// Reasons a script fails to verify
+#[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+pub enum ScriptError {
+    #[error("Script evaluated without error but finished with a false/empty top stack element")]
+    EvalFalse,
+    #[error("OP_RETURN was encountered")]
+    OpReturn,
+    #[error("Script is too big")]
+    ScriptSize,
+    #[error("Push value size limit exceeded")]
+    PushSize,
+    #[error("Operation limit exceeded")]
+    OpCount,
+    #[error("Stack size limit exceeded")]
+    StackSize,
+    #[error("Signature count negative or greater than pubkey count")]
+    SigCount,
+    #[error("Pubkey count negative or limit exceeded")]
+    PubkeyCount,
+    #[error("Script failed an OP_VERIFY operation")]
+    Verify,
+    #[error("Script failed an OP_EQUALVERIFY operation")]
+    EqualVerify,
+    #[error("Script failed an OP_CHECKMULTISIGVERIFY operation")]
+    CheckMultisigVerify,
+    #[error("Script failed an OP_CHECKSIGVERIFY operation")]
+    CheckSigVerify,
+    #[error("Script failed an OP_NUMEQUALVERIFY operation")]
+    NumEqualVerify,
+    #[error("Opcode missing or not understood")]
+    BadOpcode,
+    #[error("Attempted to use a disabled opcode")]
+    DisabledOpcode,
+    #[error("Operation not valid with the current stack size")]
+    InvalidStackOperation,
+    #[error("Operation not valid with the current altstack size")]
+    InvalidAltstackOperation,
+    #[error("Invalid OP_IF construction")]
+    UnbalancedConditional,
+    #[error("Negative locktime")]
+    NegativeLocktime,
+    #[error("Locktime requirement not satisfied")]
+    UnsatisfiedLocktime,
+    #[error("Signature hash type missing or not understood")]
+    SigHashType,
+    #[error("Non-canonical DER signature")]
+    SigDer,
+    #[error("Data push larger than necessary")]
+    MinimalData,
+    #[error("Only non-push operators allowed in signatures")]
+    SigPushOnly,
+    #[error("Non-canonical signature: S value is unnecessarily high")]
+    SigHighS,
+    #[error("Dummy CHECKMULTISIG argument must be zero")]
+    SigNullDummy,
+    #[error("Public key is neither compressed or uncompressed")]
+    PubkeyType,
+    #[error("Extra items left on stack after execution")]
+    CleanStack,
+    #[error("NOPx reserved for soft-fork upgrades")]
+    DiscourageUpgradableNops,
+    #[error("Script number overflow")]
+    NumOverflow,
+    #[error("Non-minimally encoded script number")]
+    NonMinimalNum,
+}
+
+/// A number as interpreted by the script arithmetic opcodes.
+///
+/// Operands are limited to 4 bytes (5 for CHECKLOCKTIMEVERIFY), but results
+/// may overflow that range and are serialized as-is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScriptNum(pub i64);
+
+impl ScriptNum {
+    /// Default maximum operand size in bytes
+    pub const DEFAULT_MAX_SIZE: usize = 4;
+
+    /// Decodes a little-endian sign-magnitude stack element
+    pub fn from_bytes(data: &[u8], require_minimal: bool, max_size: usize) -> Result<Self, ScriptError> {
+        if data.len() > max_size {
+            return Err(ScriptError::NumOverflow);
+        }
+        if require_minimal && !data.is_empty() {
+            // The most significant byte may only be 0x00 or 0x80 if the
+            // next byte needs its sign bit
+            let last = data[data.len() - 1];
+            if last & 0x7f == 0 && (data.len() <= 1 || data[data.len() - 2] & 0x80 == 0) {
+                return Err(ScriptError::NonMinimalNum);
+            }
+        }
+        if data.is_empty() {
+            return Ok(ScriptNum(0));
+        }
+
+        let mut result: i64 = 0;
+        for (i, byte) in data.iter().enumerate() {
+            result |= (*byte as i64) << (8 * i);
+        }
+        let last = data[data.len() - 1];
+        if last & 0x80 != 0 {
+            let mask = !(0x80i64 << (8 * (data.len() - 1)));
+            return Ok(ScriptNum(-(result & mask)));
+        }
+        Ok(ScriptNum(result))
+    }
+
+    /// Encodes the number as a minimal stack element
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.0 == 0 {
+            return Vec::new();
+        }
+        let negative = self.0 < 0;
+        let mut abs = self.0.unsigned_abs();
+        let mut result = Vec::with_capacity(9);
+        while abs > 0 {
+            result.push((abs & 0xff) as u8);
+            abs >>= 8;
+        }
+        let last = result.len() - 1;
+        if result[last] & 0x80 != 0 {
+            result.push(if negative { 0x80 } else { 0 });
+        } else if negative {
+            result[last] |= 0x80;
+        }
+        result
+    }
+
+    /// Returns the value clamped to the i32 range
+    pub fn get_int(&self) -> i32 {
+        self.0.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+}
+
+/// Interprets a stack element as a boolean; negative zero is false
+pub fn cast_to_bool(data: &[u8]) -> bool {
+    for (i, byte) in data.iter().enumerate() {
+        if *byte != 0 {
+            // Negative zero is still zero
+            return !(i == data.len() - 1 && *byte == 0x80);
+        }
+    }
+    false
+}
+
+/// Checks that a push uses the smallest possible opcode for its data
+fn check_minimal_push(data: &[u8], opcode: u8) -> bool {
+    match data.len() {
+        0 => opcode == OP_0,
+        1 if (1..=16).contains(&data[0]) => opcode == OP_1 + data[0] - 1,
+        1 if data[0] == 0x81 => opcode == OP_1NEGATE,
+        len if len <= 75 => opcode as usize == len,
+        len if len <= 255 => opcode == OP_PUSHDATA1,
+        len if len <= 65535 => opcode == OP_PUSHDATA2,
+        _ => true,
+    }
+}
+
+/// Checks strict DER encoding (BIP66) of a signature with its hash type byte
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    // Format: 0x30 [total-length] 0x02 [R-length] [R] 0x02 [S-length] [S] [sighash]
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+    if sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+
+    if sig[2] != 0x02 || len_r == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+    if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+
+    if sig[len_r + 4] != 0x02 || len_s == 0 || sig[len_r + 6] & 0x80 != 0 {
+        return false;
+    }
+    if len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0 {
+        return false;
+    }
+    true
+}
+
+/// Half the secp256k1 group order, big-endian
+const SECP256K1_HALF_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+/// Checks that the S value of a strictly DER-encoded signature is low
+fn is_low_der_signature(sig: &[u8]) -> bool {
+    let len_r = sig[3] as usize;
+    let len_s = sig[5 + len_r] as usize;
+    let s = &sig[6 + len_r..6 + len_r + len_s];
+    // Strip the sign padding byte, then compare as big-endian integers
+    let s = if s[0] == 0 { &s[1..] } else { s };
+    if s.len() != 32 {
+        return s.len() < 32;
+    }
+    s <= &SECP256K1_HALF_ORDER[..]
+}
+
+fn is_defined_hashtype_signature(sig: &[u8]) -> bool {
+    let hash_type = (sig[sig.len() - 1] as u32) & !crate::sighash::SIGHASH_ANYONECANPAY;
+    (crate::sighash::SIGHASH_ALL..=crate::sighash::SIGHASH_SINGLE).contains(&hash_type)
+}
+
+fn check_signature_encoding(sig: &[u8], flags: u32) -> Result<(), ScriptError> {
+    // An empty signature is a compact way to provide an invalid one
+    if sig.is_empty() {
+        return Ok(());
+    }
+    if !is_valid_signature_encoding(sig) {
+        return Err(ScriptError::SigDer);
+    }
+    if flags & SCRIPT_VERIFY_LOW_S != 0 && !is_low_der_signature(sig) {
+        return Err(ScriptError::SigHighS);
+    }
+    if flags & SCRIPT_VERIFY_STRICTENC != 0 && !is_defined_hashtype_signature(sig) {
+        return Err(ScriptError::SigHashType);
+    }
+    Ok(())
+}
+
+fn check_pubkey_encoding(pubkey: &[u8], flags: u32) -> Result<(), ScriptError> {
+    if flags & SCRIPT_VERIFY_STRICTENC == 0 {
+        return Ok(());
+    }
+    let valid = match pubkey.first() {
+        Some(0x04) => pubkey.len() == 65,
+        Some(0x02) | Some(0x03) => pubkey.len() == 33,
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ScriptError::PubkeyType)
+    }
+}
+
+/// Source of signature and lock time validity for CHECKSIG-family and
+/// CHECKLOCKTIMEVERIFY opcodes, so that the interpreter can be shared by
+/// validation, the mempool and the wallet
+pub trait SignatureChecker {
+    /// Returns true if `sig` (with its hash type byte) is valid for `pubkey`
+    fn check_sig(&self, _sig: &[u8], _pubkey: &[u8], _script_code: &Script) -> bool {
+        false
+    }
+
+    /// Returns true if the spending transaction satisfies `lock_time`
+    fn check_lock_time(&self, _lock_time: &ScriptNum) -> bool {
+        false
+    }
+}
+
+/// A checker that rejects every signature and lock time, for evaluating
+/// scripts without a transaction
+pub struct BaseSignatureChecker;
+
+impl SignatureChecker for BaseSignatureChecker {}
+
+/// Checks transaction signatures for one input against the consensus
+/// signature hash
+pub struct TransactionSignatureChecker<'a> {
+    tx: &'a Transaction,
+    n_in: usize,
+    amount: Amount,
+    consensus_branch_id: u32,
+    cache: Option<&'a PrecomputedTransactionData>,
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl<'a> TransactionSignatureChecker<'a> {
+    /// Creates a checker for input `n_in` of `tx`, which spends `amount`
+    pub fn new(
+        tx: &'a Transaction,
+        n_in: usize,
+        amount: Amount,
+        consensus_branch_id: u32,
+        cache: Option<&'a PrecomputedTransactionData>,
+    ) -> Self {
+        TransactionSignatureChecker {
+            tx,
+            n_in,
+            amount,
+            consensus_branch_id,
+            cache,
+            secp: Secp256k1::verification_only(),
+        }
+    }
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    fn check_sig(&self, sig: &[u8], pubkey: &[u8], script_code: &Script) -> bool {
+        let (hash_type, der) = match sig.split_last() {
+            Some((hash_type, der)) => (*hash_type as u32, der),
+            None => return false,
+        };
+        let pubkey = match PublicKey::from_slice(pubkey) {
+            Ok(pubkey) => pubkey,
+            Err(_) => return false,
+        };
+        let mut signature = match Signature::from_der_lax(der) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        // libsecp256k1 only accepts low-S signatures; consensus accepts both
+        signature.normalize_s();
+
+        let hash = match signature_hash(
+            script_code,
+            self.tx,
+            self.n_in,
+            hash_type,
+            self.amount,
+            self.consensus_branch_id,
+            self.cache,
+        ) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        let message = Message::from_slice(&hash).expect("32-byte hash");
+        self.secp.verify_ecdsa(&message, &signature, &pubkey).is_ok()
+    }
+
+    fn check_lock_time(&self, lock_time: &ScriptNum) -> bool {
+        let tx_lock_time = self.tx.lock_time as i64;
+        let threshold = LOCKTIME_THRESHOLD as i64;
+
+        // Heights and timestamps can't be compared with each other
+        if (tx_lock_time < threshold) != (lock_time.0 < threshold) {
+            return false;
+        }
+        if lock_time.0 > tx_lock_time {
+            return false;
+        }
+        // A final input would let nLockTime be bypassed entirely
+        self.tx.inputs[self.n_in].sequence != u32::MAX
+    }
+}
+
+fn is_disabled_opcode(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        OP_CAT
+            | OP_SUBSTR
+            | OP_LEFT
+            | OP_RIGHT
+            | OP_INVERT
+            | OP_AND
+            | OP_OR
+            | OP_XOR
+            | OP_2MUL
+            | OP_2DIV
+            | OP_MUL
+            | OP_DIV
+            | OP_MOD
+            | OP_LSHIFT
+            | OP_RSHIFT
+    )
+}
+
+fn bool_element(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Returns the element `depth` positions below the top of the stack
+fn stack_top(stack: &[Vec<u8>], depth: usize) -> Result<&Vec<u8>, ScriptError> {
+    if depth >= stack.len() {
+        return Err(ScriptError::InvalidStackOperation);
+    }
+    Ok(&stack[stack.len() - 1 - depth])
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::InvalidStackOperation)
+}
+
+fn require(stack: &[Vec<u8>], count: usize) -> Result<(), ScriptError> {
+    if stack.len() < count {
+        Err(ScriptError::InvalidStackOperation)
+    } else {
+        Ok(())
+    }
+}
+
+/// Executes `script` on `stack`
+pub fn eval_script(
+    stack: &mut Vec<Vec<u8>>,
+    script: &Script,
+    flags: u32,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+
+    let require_minimal = flags & SCRIPT_VERIFY_MINIMALDATA != 0;
+    let num = |data: &[u8]| ScriptNum::from_bytes(data, require_minimal, ScriptNum::DEFAULT_MAX_SIZE);
+
+    let mut pc = 0;
+    let mut begin_code_hash = 0;
+    let mut exec_stack: Vec<bool> = Vec::new();
+    let mut altstack: Vec<Vec<u8>> = Vec::new();
+    let mut op_count = 0;
+
+    while pc < script.len() {
+        let executing = !exec_stack.contains(&false);
+
+        let (opcode, push_value, next) = script.get_op(pc).ok_or(ScriptError::BadOpcode)?;
+        pc = next;
+
+        if push_value.len() > MAX_SCRIPT_ELEMENT_SIZE {
+            return Err(ScriptError::PushSize);
+        }
+
+        // Note how OP_RESERVED does not count towards the opcode limit
+        if opcode > OP_16 {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(ScriptError::OpCount);
+            }
+        }
+
+        // Disabled opcodes fail even in an unexecuted branch
+        if is_disabled_opcode(opcode) {
+            return Err(ScriptError::DisabledOpcode);
+        }
+
+        if executing && opcode <= OP_PUSHDATA4 {
+            if require_minimal && !check_minimal_push(push_value, opcode) {
+                return Err(ScriptError::MinimalData);
+            }
+            stack.push(push_value.to_vec());
+        } else if executing || (OP_IF..=OP_ENDIF).contains(&opcode) {
+            match opcode {
+                OP_1NEGATE | OP_1..=OP_16 => {
+                    let value = opcode as i64 - (OP_1 as i64 - 1);
+                    stack.push(ScriptNum(value).to_bytes());
+                }
+
+                OP_NOP => {}
+
+                OP_CHECKLOCKTIMEVERIFY => {
+                    if flags & SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY == 0 {
+                        // Not enabled; treat as a NOP2
+                        if flags & SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS != 0 {
+                            return Err(ScriptError::DiscourageUpgradableNops);
+                        }
+                    } else {
+                        // nLockTime is a u32, so 5-byte operands are allowed
+                        let lock_time = ScriptNum::from_bytes(stack_top(stack, 0)?, require_minimal, 5)?;
+                        if lock_time.0 < 0 {
+                            return Err(ScriptError::NegativeLocktime);
+                        }
+                        if !checker.check_lock_time(&lock_time) {
+                            return Err(ScriptError::UnsatisfiedLocktime);
+                        }
+                    }
+                }
+
+                OP_NOP1 | OP_NOP3..=OP_NOP10 => {
+                    if flags & SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS != 0 {
+                        return Err(ScriptError::DiscourageUpgradableNops);
+                    }
+                }
+
+                OP_IF | OP_NOTIF => {
+                    let mut value = false;
+                    if executing {
+                        let top = stack.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                        value = cast_to_bool(&top);
+                        if opcode == OP_NOTIF {
+                            value = !value;
+                        }
+                    }
+                    exec_stack.push(value);
+                }
+
+                OP_ELSE => {
+                    let last = exec_stack.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                    *last = !*last;
+                }
+
+                OP_ENDIF => {
+                    exec_stack.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+
+                OP_VERIFY => {
+                    if !cast_to_bool(stack_top(stack, 0)?) {
+                        return Err(ScriptError::Verify);
+                    }
+                    stack.pop();
+                }
+
+                OP_RETURN => return Err(ScriptError::OpReturn),
+
+                OP_TOALTSTACK => {
+                    let top = pop(stack)?;
+                    altstack.push(top);
+                }
+
+                OP_FROMALTSTACK => {
+                    let top = altstack.pop().ok_or(ScriptError::InvalidAltstackOperation)?;
+                    stack.push(top);
+                }
+
+                OP_2DROP => {
+                    require(stack, 2)?;
+                    stack.truncate(stack.len() - 2);
+                }
+
+                OP_2DUP => {
+                    require(stack, 2)?;
+                    let len = stack.len();
+                    stack.extend_from_within(len - 2..);
+                }
+
+                OP_3DUP => {
+                    require(stack, 3)?;
+                    let len = stack.len();
+                    stack.extend_from_within(len - 3..);
+                }
+
+                OP_2OVER => {
+                    require(stack, 4)?;
+                    let len = stack.len();
+                    stack.extend_from_within(len - 4..len - 2);
+                }
+
+                OP_2ROT => {
+                    require(stack, 6)?;
+                    let len = stack.len();
+                    let moved: Vec<_> = stack.drain(len - 6..len - 4).collect();
+                    stack.extend(moved);
+                }
+
+                OP_2SWAP => {
+                    require(stack, 4)?;
+                    let len = stack.len();
+                    stack.swap(len - 4, len - 2);
+                    stack.swap(len - 3, len - 1);
+                }
+
+                OP_IFDUP => {
+                    let top = stack_top(stack, 0)?.clone();
+                    if cast_to_bool(&top) {
+                        stack.push(top);
+                    }
+                }
+
+                OP_DEPTH => {
+                    let depth = ScriptNum(stack.len() as i64);
+                    stack.push(depth.to_bytes());
+                }
+
+                OP_DROP => {
+                    pop(stack)?;
+                }
+
+                OP_DUP => {
+                    let top = stack_top(stack, 0)?.clone();
+                    stack.push(top);
+                }
+
+                OP_NIP => {
+                    require(stack, 2)?;
+                    let len = stack.len();
+                    stack.remove(len - 2);
+                }
+
+                OP_OVER => {
+                    let second = stack_top(stack, 1)?.clone();
+                    stack.push(second);
+                }
+
+                OP_PICK | OP_ROLL => {
+                    let n = num(&pop(stack)?)?.get_int();
+                    if n < 0 || n as usize >= stack.len() {
+                        return Err(ScriptError::InvalidStackOperation);
+                    }
+                    let index = stack.len() - 1 - n as usize;
+                    let value = if opcode == OP_ROLL {
+                        stack.remove(index)
+                    } else {
+                        stack[index].clone()
+                    };
+                    stack.push(value);
+                }
+
+                OP_ROT => {
+                    require(stack, 3)?;
+                    let len = stack.len();
+                    let third = stack.remove(len - 3);
+                    stack.push(third);
+                }
+
+                OP_SWAP => {
+                    require(stack, 2)?;
+                    let len = stack.len();
+                    stack.swap(len - 2, len - 1);
+                }
+
+                OP_TUCK => {
+                    require(stack, 2)?;
+                    let top = stack[stack.len() - 1].clone();
+                    let len = stack.len();
+                    stack.insert(len - 2, top);
+                }
+
+                OP_SIZE => {
+                    let size = ScriptNum(stack_top(stack, 0)?.len() as i64);
+                    stack.push(size.to_bytes());
+                }
+
+                OP_EQUAL | OP_EQUALVERIFY => {
+                    require(stack, 2)?;
+                    let b = pop(stack)?;
+                    let a = pop(stack)?;
+                    let equal = a == b;
+                    stack.push(bool_element(equal));
+                    if opcode == OP_EQUALVERIFY {
+                        if !equal {
+                            return Err(ScriptError::EqualVerify);
+                        }
+                        stack.pop();
+                    }
+                }
+
+                OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                    let a = num(stack_top(stack, 0)?)?.0;
+                    let result = match opcode {
+                        OP_1ADD => a + 1,
+                        OP_1SUB => a - 1,
+                        OP_NEGATE => -a,
+                        OP_ABS => a.abs(),
+                        OP_NOT => (a == 0) as i64,
+                        _ => (a != 0) as i64,
+                    };
+                    stack.pop();
+                    stack.push(ScriptNum(result).to_bytes());
+                }
+
+                OP_ADD | OP_SUB | OP_BOOLAND | OP_BOOLOR | OP_NUMEQUAL | OP_NUMEQUALVERIFY
+                | OP_NUMNOTEQUAL | OP_LESSTHAN | OP_GREATERTHAN | OP_LESSTHANOREQUAL
+                | OP_GREATERTHANOREQUAL | OP_MIN | OP_MAX => {
+                    require(stack, 2)?;
+                    let a = num(stack_top(stack, 1)?)?.0;
+                    let b = num(stack_top(stack, 0)?)?.0;
+                    let result = match opcode {
+                        OP_ADD => a + b,
+                        OP_SUB => a - b,
+                        OP_BOOLAND => (a != 0 && b != 0) as i64,
+                        OP_BOOLOR => (a != 0 || b != 0) as i64,
+                        OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                        OP_NUMNOTEQUAL => (a != b) as i64,
+                        OP_LESSTHAN => (a < b) as i64,
+                        OP_GREATERTHAN => (a > b) as i64,
+                        OP_LESSTHANOREQUAL => (a <= b) as i64,
+                        OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                        OP_MIN => a.min(b),
+                        _ => a.max(b),
+                    };
+                    stack.truncate(stack.len() - 2);
+                    stack.push(ScriptNum(result).to_bytes());
+                    if opcode == OP_NUMEQUALVERIFY {
+                        if !cast_to_bool(stack_top(stack, 0)?) {
+                            return Err(ScriptError::NumEqualVerify);
+                        }
+                        stack.pop();
+                    }
+                }
+
+                OP_WITHIN => {
+                    require(stack, 3)?;
+                    let x = num(stack_top(stack, 2)?)?.0;
+                    let min = num(stack_top(stack, 1)?)?.0;
+                    let max = num(stack_top(stack, 0)?)?.0;
+                    stack.truncate(stack.len() - 3);
+                    stack.push(bool_element(min <= x && x < max));
+                }
+
+                OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                    let data = pop(stack)?;
+                    let hash = match opcode {
+                        OP_RIPEMD160 => crate::hash::ripemd160(&data),
+                        OP_SHA1 => crate::crypto::sha1::sha1_hash(&data),
+                        OP_SHA256 => crate::hash::sha256(&data),
+                        OP_HASH160 => crate::hash::hash160(&data),
+                        _ => crate::hash::hash256(&data).to_vec(),
+                    };
+                    stack.push(hash);
+                }
+
+                OP_CODESEPARATOR => {
+                    // Signatures only cover the script after the most recent separator
+                    begin_code_hash = pc;
+                }
+
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                    require(stack, 2)?;
+                    let pubkey = pop(stack)?;
+                    let sig = pop(stack)?;
+
+                    // Drop the signature from the signed code, since a
+                    // signature can't sign itself
+                    let script_code = Script::new(script.as_bytes()[begin_code_hash..].to_vec())
+                        .find_and_delete(&Script::default().push_slice(&sig));
+
+                    check_signature_encoding(&sig, flags)?;
+                    check_pubkey_encoding(&pubkey, flags)?;
+                    let success = checker.check_sig(&sig, &pubkey, &script_code);
+
+                    if opcode == OP_CHECKSIGVERIFY {
+                        if !success {
+                            return Err(ScriptError::CheckSigVerify);
+                        }
+                    } else {
+                        stack.push(bool_element(success));
+                    }
+                }
+
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    let mut i = 1;
+                    let key_count = num(stack_top(stack, i - 1)?)?.0;
+                    if !(0..=MAX_PUBKEYS_PER_MULTISIG).contains(&key_count) {
+                        return Err(ScriptError::PubkeyCount);
+                    }
+                    let key_count = key_count as usize;
+                    op_count += key_count;
+                    if op_count > MAX_OPS_PER_SCRIPT {
+                        return Err(ScriptError::OpCount);
+                    }
+                    i += 1;
+                    let mut ikey = i;
+                    i += key_count;
+
+                    let sig_count = num(stack_top(stack, i - 1)?)?.0;
+                    if sig_count < 0 || sig_count as usize > key_count {
+                        return Err(ScriptError::SigCount);
+                    }
+                    let mut sigs_left = sig_count as usize;
+                    i += 1;
+                    let mut isig = i;
+                    i += sigs_left;
+                    // One extra element is consumed because of a historic off-by-one
+                    require(stack, i)?;
+
+                    let mut script_code = Script::new(script.as_bytes()[begin_code_hash..].to_vec());
+                    for k in 0..sigs_left {
+                        let sig = stack_top(stack, isig + k - 1)?;
+                        script_code = script_code.find_and_delete(&Script::default().push_slice(sig));
+                    }
+
+                    let mut keys_left = key_count;
+                    let mut success = true;
+                    while success && sigs_left > 0 {
+                        let sig = stack_top(stack, isig - 1)?;
+                        let pubkey = stack_top(stack, ikey - 1)?;
+
+                        check_signature_encoding(sig, flags)?;
+                        check_pubkey_encoding(pubkey, flags)?;
+
+                        if checker.check_sig(sig, pubkey, &script_code) {
+                            isig += 1;
+                            sigs_left -= 1;
+                        }
+                        ikey += 1;
+                        keys_left -= 1;
+
+                        // More signatures left than keys means some can't match
+                        if sigs_left > keys_left {
+                            success = false;
+                        }
+                    }
+
+                    // Pop every argument, checking the dummy element last
+                    for _ in 1..i {
+                        stack.pop();
+                    }
+                    let dummy = pop(stack)?;
+                    if flags & SCRIPT_VERIFY_NULLDUMMY != 0 && !dummy.is_empty() {
+                        return Err(ScriptError::SigNullDummy);
+                    }
+
+                    if opcode == OP_CHECKMULTISIGVERIFY {
+                        if !success {
+                            return Err(ScriptError::CheckMultisigVerify);
+                        }
+                    } else {
+                        stack.push(bool_element(success));
+                    }
+                }
+
+                _ => return Err(ScriptError::BadOpcode),
+            }
+        }
+
+        if stack.len() + altstack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+
+    if !exec_stack.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+/// Verifies that `script_sig` satisfies `script_pubkey` under `flags`
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    flags: u32,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if flags & SCRIPT_VERIFY_SIGPUSHONLY != 0 && !script_sig.is_push_only() {
+        return Err(ScriptError::SigPushOnly);
+    }
+
+    let mut stack = Vec::new();
+    eval_script(&mut stack, script_sig, flags, checker)?;
+    let stack_copy = if flags & SCRIPT_VERIFY_P2SH != 0 {
+        stack.clone()
+    } else {
+        Vec::new()
+    };
+    eval_script(&mut stack, script_pubkey, flags, checker)?;
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => {}
+        _ => return Err(ScriptError::EvalFalse),
+    }
+
+    // Additional validation for spend-to-script-hash transactions
+    if flags & SCRIPT_VERIFY_P2SH != 0 && script_pubkey.is_pay_to_script_hash() {
+        // scriptSig must be literals-only or validation fails
+        if !script_sig.is_push_only() {
+            return Err(ScriptError::SigPushOnly);
+        }
+
+        // Restore the stack as it was after scriptSig, and run the
+        // serialized redeem script on top of it
+        stack = stack_copy;
+        let redeem_script = Script::new(stack.pop().expect("scriptSig left the hash preimage"));
+        eval_script(&mut stack, &redeem_script, flags, checker)?;
+        match stack.last() {
+            Some(top) if cast_to_bool(top) => {}
+            _ => return Err(ScriptError::EvalFalse),
+        }
+    }
+
+    // CLEANSTACK is only meaningful together with P2SH, since otherwise a
+    // P2SH spend would leave the redeem script's arguments behind
+    if flags & SCRIPT_VERIFY_CLEANSTACK != 0 {
+        debug_assert!(flags & SCRIPT_VERIFY_P2SH != 0);
+        if stack.len() != 1 {
+            return Err(ScriptError::CleanStack);
+        }
+    }
+
+    Ok(())
 }
 
 /// Converts a script to an assembly-like string.
 pub fn script_to_asm(script: &Script) -> String {
-    script
-        .instructions()
-        .map(|instr| match instr {
-            Ok(Instruction::Op(op)) => format!("{:?}", op),
-            Ok(Instruction::PushBytes(bytes)) => hex::encode(bytes),
-            Err(_) => "INVALID".to_string(),
-        })
-        .collect::<Vec<_>>()
-        .join(" ")
+    let mut parts = Vec::new();
+    let mut pc = 0;
+    while pc < script.len() {
+        match script.get_op(pc) {
+            Some((opcode, data, next)) => {
+                if opcode > OP_0 && opcode <= OP_PUSHDATA4 {
+                    parts.push(hex::encode(data));
+                } else {
+                    parts.push(opcodes::name(opcode).to_string());
+                }
+                pc = next;
+            }
+            None => {
+                parts.push("[error]".to_string());
+                break;
+            }
+        }
+    }
+    parts.join(" ")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bitcoin::blockdata::script::Builder;
+
+    fn push(data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn p2pkh_like_script() -> Script {
+        let mut bytes = vec![OP_DUP, OP_HASH160];
+        bytes.extend(push(b"pubkeyhash"));
+        bytes.extend([OP_EQUALVERIFY, OP_CHECKSIG]);
+        Script::new(bytes)
+    }
+
+    fn run(script_sig: &Script, script_pubkey: &Script, flags: u32) -> Result<(), ScriptError> {
+        verify_script(script_sig, script_pubkey, flags, &BaseSignatureChecker)
+    }
+
+    fn spending_tx(lock_time: u32, sequence: u32) -> Transaction {
+        use crate::primitives::transaction::{OutPoint, TxInput, TxOutput};
+
+        Transaction {
+            inputs: vec![TxInput {
+                prev_out: OutPoint {
+                    txid: [7; 32],
+                    index: 0,
+                },
+                script_sig: Script::default(),
+                sequence,
+            }],
+            outputs: vec![TxOutput {
+                value: 1000,
+                script_pubkey: Script::default().push_opcode(OP_TRUE),
+            }],
+            lock_time,
+            ..Default::default()
+        }
+    }
+
+    fn sign(
+        tx: &Transaction,
+        key: &secp256k1::SecretKey,
+        script_code: &Script,
+        hash_type: u32,
+    ) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let hash = signature_hash(script_code, tx, 0, hash_type, 0, 0, None).unwrap();
+        let message = Message::from_slice(&hash).unwrap();
+        let mut sig = secp.sign_ecdsa(&message, key).serialize_der().to_vec();
+        sig.push(hash_type as u8);
+        sig
+    }
+
+    fn pubkey(key: &secp256k1::SecretKey) -> Vec<u8> {
+        PublicKey::from_secret_key(&Secp256k1::new(), key).serialize().to_vec()
+    }
 
     #[test]
-    fn test_verify_script() {
-        let input_script = Builder::new()
-            .push_slice(b"signature")
-            .push_slice(b"pubkey")
-            .into_script();
-
-        let output_script = Builder::new()
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_DUP)
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_HASH160)
-            .push_slice(b"pubkeyhash")
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_EQUALVERIFY)
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
-            .into_script();
-
-        let tx = Transaction {
-            version: 1,
-            lock_time: 0,
-            input: vec![],
-            output: vec![],
-        };
+    fn test_verify_p2pkh() {
+        let key = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+        let pubkey = pubkey(&key);
+        let script_pubkey = Script::default()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(&crate::hash::hash160(&pubkey))
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG);
+
+        let tx = spending_tx(0, u32::MAX);
+        let sig = sign(&tx, &key, &script_pubkey, crate::sighash::SIGHASH_ALL);
+        let script_sig = Script::default().push_slice(&sig).push_slice(&pubkey);
 
-        assert!(verify_script(&input_script, &output_script, &tx, 0));
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0, 0, None);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, STANDARD_SCRIPT_VERIFY_FLAGS, &checker),
+            Ok(())
+        );
+
+        // The same signature doesn't cover a different transaction
+        let other = spending_tx(1, u32::MAX);
+        let checker = TransactionSignatureChecker::new(&other, 0, 0, 0, None);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, STANDARD_SCRIPT_VERIFY_FLAGS, &checker),
+            Err(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn test_verify_multisig() {
+        let keys: Vec<_> = (1..=3u8)
+            .map(|i| secp256k1::SecretKey::from_slice(&[i; 32]).unwrap())
+            .collect();
+        let mut script_pubkey = Script::default().push_opcode(OP_2);
+        for key in &keys {
+            script_pubkey = script_pubkey.push_slice(&pubkey(key));
+        }
+        let script_pubkey = script_pubkey.push_opcode(OP_3).push_opcode(OP_CHECKMULTISIG);
+
+        let tx = spending_tx(0, u32::MAX);
+        let all = crate::sighash::SIGHASH_ALL;
+        let sig1 = sign(&tx, &keys[0], &script_pubkey, all);
+        let sig3 = sign(&tx, &keys[2], &script_pubkey, all);
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0, 0, None);
+
+        let script_sig = Script::default().push_opcode(OP_0).push_slice(&sig1).push_slice(&sig3);
+        assert_eq!(verify_script(&script_sig, &script_pubkey, STANDARD_SCRIPT_VERIFY_FLAGS, &checker), Ok(()));
+
+        // Signatures must appear in the same order as their keys
+        let script_sig = Script::default().push_opcode(OP_0).push_slice(&sig3).push_slice(&sig1);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, STANDARD_SCRIPT_VERIFY_FLAGS, &checker),
+            Err(ScriptError::EvalFalse)
+        );
+
+        let script_sig = Script::default().push_opcode(OP_1).push_slice(&sig1).push_slice(&sig3);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, STANDARD_SCRIPT_VERIFY_FLAGS, &checker),
+            Err(ScriptError::SigNullDummy)
+        );
+        assert_eq!(verify_script(&script_sig, &script_pubkey, SCRIPT_VERIFY_P2SH, &checker), Ok(()));
+    }
+
+    #[test]
+    fn test_arithmetic_and_conditionals() {
+        let script_sig = Script::default().push_int(2).push_int(3);
+        let script_pubkey = Script::default()
+            .push_opcode(OP_ADD)
+            .push_int(5)
+            .push_opcode(OP_NUMEQUAL);
+        assert_eq!(run(&script_sig, &script_pubkey, SCRIPT_VERIFY_NONE), Ok(()));
+
+        let script_pubkey = Script::default()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_ELSE)
+            .push_int(1)
+            .push_opcode(OP_ENDIF);
+        assert_eq!(run(&Script::default().push_int(0), &script_pubkey, SCRIPT_VERIFY_NONE), Ok(()));
+        assert_eq!(
+            run(&Script::default().push_int(1), &script_pubkey, SCRIPT_VERIFY_NONE),
+            Err(ScriptError::OpReturn)
+        );
+
+        let script_pubkey = Script::default().push_opcode(OP_IF).push_int(1);
+        assert_eq!(
+            run(&Script::default().push_int(1), &script_pubkey, SCRIPT_VERIFY_NONE),
+            Err(ScriptError::UnbalancedConditional)
+        );
+    }
+
+    #[test]
+    fn test_disabled_opcode_in_unexecuted_branch() {
+        let script_pubkey = Script::default()
+            .push_int(0)
+            .push_opcode(OP_IF)
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_ENDIF)
+            .push_int(1);
+        assert_eq!(
+            run(&Script::default(), &script_pubkey, SCRIPT_VERIFY_NONE),
+            Err(ScriptError::DisabledOpcode)
+        );
+    }
+
+    #[test]
+    fn test_script_num() {
+        for n in [0i64, 1, -1, 127, 128, -128, 255, 256, 0x7fffffff, -0x7fffffff] {
+            let bytes = ScriptNum(n).to_bytes();
+            assert_eq!(ScriptNum::from_bytes(&bytes, true, 4), Ok(ScriptNum(n)));
+        }
+        assert_eq!(ScriptNum(-1).to_bytes(), vec![0x81]);
+        assert_eq!(ScriptNum(128).to_bytes(), vec![0x80, 0x00]);
+        assert_eq!(
+            ScriptNum::from_bytes(&[0x01, 0x00], true, 4),
+            Err(ScriptError::NonMinimalNum)
+        );
+        assert_eq!(ScriptNum::from_bytes(&[0x01, 0x00], false, 4), Ok(ScriptNum(1)));
+        assert_eq!(
+            ScriptNum::from_bytes(&[1, 2, 3, 4, 5], false, 4),
+            Err(ScriptError::NumOverflow)
+        );
+        assert!(!cast_to_bool(&[0x00, 0x80]));
+        assert!(cast_to_bool(&[0x80, 0x00]));
+    }
+
+    #[test]
+    fn test_minimal_data() {
+        let script_sig = Script::new(vec![0x01, 0x05]);
+        let script_pubkey = Script::default().push_int(5).push_opcode(OP_EQUAL);
+        assert_eq!(run(&script_sig, &script_pubkey, SCRIPT_VERIFY_NONE), Ok(()));
+        assert_eq!(
+            run(&script_sig, &script_pubkey, SCRIPT_VERIFY_MINIMALDATA),
+            Err(ScriptError::MinimalData)
+        );
+    }
+
+    #[test]
+    fn test_check_lock_time_verify() {
+        let script_pubkey = Script::default()
+            .push_int(100)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP)
+            .push_int(1);
+        let script_sig = Script::default();
+        let flags = SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY;
+
+        let tx = spending_tx(100, 0);
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0, 0, None);
+        assert_eq!(verify_script(&script_sig, &script_pubkey, flags, &checker), Ok(()));
+
+        let tx = spending_tx(99, 0);
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0, 0, None);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, flags, &checker),
+            Err(ScriptError::UnsatisfiedLocktime)
+        );
+
+        // A final input disables nLockTime, so CLTV must fail
+        let tx = spending_tx(100, u32::MAX);
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0, 0, None);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, flags, &checker),
+            Err(ScriptError::UnsatisfiedLocktime)
+        );
+
+        // Without the flag CLTV is a NOP
+        assert_eq!(verify_script(&script_sig, &script_pubkey, SCRIPT_VERIFY_NONE, &checker), Ok(()));
+    }
+
+    #[test]
+    fn test_pay_to_script_hash() {
+        let redeem_script = Script::default().push_int(7).push_opcode(OP_EQUAL);
+        let script_pubkey = Script::default()
+            .push_opcode(OP_HASH160)
+            .push_slice(&crate::hash::hash160(redeem_script.as_bytes()))
+            .push_opcode(OP_EQUAL);
+        assert!(script_pubkey.is_pay_to_script_hash());
+
+        let script_sig = Script::default().push_int(7).push_slice(redeem_script.as_bytes());
+        assert_eq!(run(&script_sig, &script_pubkey, STANDARD_SCRIPT_VERIFY_FLAGS), Ok(()));
+
+        let script_sig = Script::default().push_int(8).push_slice(redeem_script.as_bytes());
+        assert_eq!(
+            run(&script_sig, &script_pubkey, STANDARD_SCRIPT_VERIFY_FLAGS),
+            Err(ScriptError::EvalFalse)
+        );
+        // Without P2SH only the hash is checked
+        assert_eq!(run(&script_sig, &script_pubkey, SCRIPT_VERIFY_NONE), Ok(()));
+    }
+
+    #[test]
+    fn test_clean_stack() {
+        let script_sig = Script::default().push_int(1).push_int(1);
+        let script_pubkey = Script::default().push_opcode(OP_NOP);
+        assert_eq!(run(&script_sig, &script_pubkey, SCRIPT_VERIFY_P2SH), Ok(()));
+        assert_eq!(
+            run(&script_sig, &script_pubkey, SCRIPT_VERIFY_P2SH | SCRIPT_VERIFY_CLEANSTACK),
+            Err(ScriptError::CleanStack)
+        );
+    }
+
+    #[test]
+    fn test_find_and_delete() {
+        let sig = Script::default().push_slice(&[0xaa, 0xbb]);
+        let script = Script::default()
+            .push_slice(&[0xaa, 0xbb])
+            .push_opcode(OP_DUP)
+            .push_slice(&[0x02, 0xaa, 0xbb]);
+        // Only matches on opcode boundaries are removed
+        assert_eq!(
+            script.find_and_delete(&sig).as_bytes(),
+            &[OP_DUP, 0x03, 0x02, 0xaa, 0xbb]
+        );
     }
 
     #[test]
     fn test_script_to_asm() {
-        let script = Builder::new()
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_DUP)
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_HASH160)
-            .push_slice(b"pubkeyhash")
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_EQUALVERIFY)
-            .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
-            .into_script();
+        let script = p2pkh_like_script();
 
         let asm = script_to_asm(&script);
         assert_eq!(
@@ -68,4 +1721,25 @@ mod tests {
             "OP_DUP OP_HASH160 7075626b657968617368 OP_EQUALVERIFY OP_CHECKSIG"
         );
     }
+
+    #[test]
+    fn test_without_codeseparators() {
+        let mut bytes = vec![OP_CODESEPARATOR, OP_DUP];
+        bytes.extend(push(&[OP_CODESEPARATOR]));
+        bytes.push(OP_CODESEPARATOR);
+        let script = Script::new(bytes);
+
+        // The pushed 0xab byte is data, not an opcode, and must survive
+        assert_eq!(
+            script.without_codeseparators().as_bytes(),
+            &[OP_DUP, 0x01, OP_CODESEPARATOR]
+        );
+    }
+
+    #[test]
+    fn test_truncated_push() {
+        let script = Script::new(vec![OP_PUSHDATA1, 0x05, 0x01]);
+        assert!(script.get_op(0).is_none());
+        assert_eq!(script_to_asm(&script), "[error]");
+    }
 }
@@ -0,0 +1,264 @@
+use super::opcodes::*;
+use super::Script;
+use crate::hash::hash160;
+
+/// Maximum size of a standard OP_RETURN output, including the OP_RETURN and
+/// pushdata opcodes
+pub const MAX_OP_RETURN_RELAY: usize = 223;
+
+/// The standard output script templates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TxnOutType {
+    NonStandard,
+    PubKey,
+    PubKeyHash,
+    ScriptHash,
+    MultiSig,
+    NullData,
+}
+
+impl TxnOutType {
+    /// Returns the name used for the `type` field of RPC results
+    pub fn name(&self) -> &'static str {
+        match self {
+            TxnOutType::NonStandard => "nonstandard",
+            TxnOutType::PubKey => "pubkey",
+            TxnOutType::PubKeyHash => "pubkeyhash",
+            TxnOutType::ScriptHash => "scripthash",
+            TxnOutType::MultiSig => "multisig",
+            TxnOutType::NullData => "nulldata",
+        }
+    }
+}
+
+/// A transparent payment destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TxDestination {
+    /// HASH160 of a public key
+    KeyId([u8; 20]),
+    /// HASH160 of a redeem script
+    ScriptId([u8; 20]),
+}
+
+fn is_pubkey(data: &[u8]) -> bool {
+    (33..=65).contains(&data.len())
+}
+
+/// Decodes OP_0 and OP_1..OP_16
+fn decode_small_int(opcode: u8) -> Option<u8> {
+    match opcode {
+        OP_0 => Some(0),
+        OP_1..=OP_16 => Some(opcode - OP_1 + 1),
+        _ => None,
+    }
+}
+
+fn to_hash(data: &[u8]) -> [u8; 20] {
+    let mut hash = [0u8; 20];
+    hash.copy_from_slice(data);
+    hash
+}
+
+/// Splits a script into (opcode, push data) pairs, or returns None if a push
+/// is truncated
+fn parse_ops(script: &Script) -> Option<Vec<(u8, &[u8])>> {
+    let mut ops = Vec::new();
+    let mut pc = 0;
+    while pc < script.len() {
+        let (opcode, data, next) = script.get_op(pc)?;
+        ops.push((opcode, data));
+        pc = next;
+    }
+    Some(ops)
+}
+
+fn solve_multisig(ops: &[(u8, &[u8])]) -> Option<Vec<Vec<u8>>> {
+    if ops.len() < 4 || ops[ops.len() - 1].0 != OP_CHECKMULTISIG {
+        return None;
+    }
+    let required = decode_small_int(ops[0].0)?;
+    let key_count = decode_small_int(ops[ops.len() - 2].0)?;
+    let keys = &ops[1..ops.len() - 2];
+    if required < 1 || required > key_count || keys.len() != key_count as usize {
+        return None;
+    }
+
+    let mut solutions = vec![vec![required]];
+    for (opcode, data) in keys {
+        if *opcode > OP_PUSHDATA4 || !is_pubkey(data) {
+            return None;
+        }
+        solutions.push(data.to_vec());
+    }
+    solutions.push(vec![key_count]);
+    Some(solutions)
+}
+
+/// Classifies `script_pubkey` against the standard templates.
+///
+/// Returns the template and its solutions: the public key for `PubKey`, the
+/// hash for `PubKeyHash` and `ScriptHash`, and `[m], keys..., [n]` for
+/// `MultiSig`.
+pub fn solver(script_pubkey: &Script) -> (TxnOutType, Vec<Vec<u8>>) {
+    let bytes = script_pubkey.as_bytes();
+
+    // Shortcut for pay-to-script-hash, which is more constrained than the
+    // other templates
+    if script_pubkey.is_pay_to_script_hash() {
+        return (TxnOutType::ScriptHash, vec![bytes[2..22].to_vec()]);
+    }
+
+    // Provably prunable, data-carrying output
+    if bytes.first() == Some(&OP_RETURN) && Script::new(bytes[1..].to_vec()).is_push_only() {
+        return (TxnOutType::NullData, Vec::new());
+    }
+
+    let ops = match parse_ops(script_pubkey) {
+        Some(ops) => ops,
+        None => return (TxnOutType::NonStandard, Vec::new()),
+    };
+
+    match ops.as_slice() {
+        [(push, pubkey), (OP_CHECKSIG, _)] if *push <= OP_PUSHDATA4 && is_pubkey(pubkey) => {
+            (TxnOutType::PubKey, vec![pubkey.to_vec()])
+        }
+        [(OP_DUP, _), (OP_HASH160, _), (push, hash), (OP_EQUALVERIFY, _), (OP_CHECKSIG, _)]
+            if *push <= OP_PUSHDATA4 && hash.len() == 20 =>
+        {
+            (TxnOutType::PubKeyHash, vec![hash.to_vec()])
+        }
+        _ => match solve_multisig(&ops) {
+            Some(solutions) => (TxnOutType::MultiSig, solutions),
+            None => (TxnOutType::NonStandard, Vec::new()),
+        },
+    }
+}
+
+/// Returns the single destination paid by `script_pubkey`, if any
+pub fn extract_destination(script_pubkey: &Script) -> Option<TxDestination> {
+    let (out_type, solutions) = solver(script_pubkey);
+    match out_type {
+        TxnOutType::PubKey => Some(TxDestination::KeyId(to_hash(&hash160(&solutions[0])))),
+        TxnOutType::PubKeyHash => Some(TxDestination::KeyId(to_hash(&solutions[0]))),
+        TxnOutType::ScriptHash => Some(TxDestination::ScriptId(to_hash(&solutions[0]))),
+        _ => None,
+    }
+}
+
+/// Returns the template, the destinations and the number of signatures
+/// required to spend `script_pubkey`
+pub fn extract_destinations(script_pubkey: &Script) -> Option<(TxnOutType, Vec<TxDestination>, usize)> {
+    let (out_type, solutions) = solver(script_pubkey);
+    match out_type {
+        TxnOutType::NonStandard | TxnOutType::NullData => None,
+        TxnOutType::MultiSig => {
+            let required = solutions[0][0] as usize;
+            let destinations = solutions[1..solutions.len() - 1]
+                .iter()
+                .map(|pubkey| TxDestination::KeyId(to_hash(&hash160(pubkey))))
+                .collect();
+            Some((out_type, destinations, required))
+        }
+        _ => extract_destination(script_pubkey).map(|destination| (out_type, vec![destination], 1)),
+    }
+}
+
+/// Builds the standard scriptPubKey paying to `destination`
+pub fn get_script_for_destination(destination: &TxDestination) -> Script {
+    match destination {
+        TxDestination::KeyId(hash) => Script::default()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG),
+        TxDestination::ScriptId(hash) => Script::default()
+            .push_opcode(OP_HASH160)
+            .push_slice(hash)
+            .push_opcode(OP_EQUAL),
+    }
+}
+
+/// Builds an m-of-n bare multisig scriptPubKey
+pub fn get_script_for_multisig(required: usize, pubkeys: &[Vec<u8>]) -> Script {
+    let mut script = Script::default().push_int(required as i64);
+    for pubkey in pubkeys {
+        script = script.push_slice(pubkey);
+    }
+    script.push_int(pubkeys.len() as i64).push_opcode(OP_CHECKMULTISIG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pubkey(tag: u8) -> Vec<u8> {
+        let mut key = vec![0x02];
+        key.extend_from_slice(&[tag; 32]);
+        key
+    }
+
+    #[test]
+    fn test_solver_templates() {
+        let key_id = TxDestination::KeyId([1; 20]);
+        let script = get_script_for_destination(&key_id);
+        assert_eq!(solver(&script), (TxnOutType::PubKeyHash, vec![vec![1; 20]]));
+        assert_eq!(extract_destination(&script), Some(key_id));
+
+        let script_id = TxDestination::ScriptId([2; 20]);
+        let script = get_script_for_destination(&script_id);
+        assert_eq!(solver(&script), (TxnOutType::ScriptHash, vec![vec![2; 20]]));
+        assert_eq!(extract_destination(&script), Some(script_id));
+
+        let script = Script::default().push_slice(&pubkey(3)).push_opcode(OP_CHECKSIG);
+        assert_eq!(solver(&script), (TxnOutType::PubKey, vec![pubkey(3)]));
+        assert_eq!(
+            extract_destination(&script),
+            Some(TxDestination::KeyId(to_hash(&hash160(&pubkey(3)))))
+        );
+
+        let script = Script::default().push_opcode(OP_RETURN).push_slice(b"memo");
+        assert_eq!(solver(&script).0, TxnOutType::NullData);
+        assert_eq!(extract_destinations(&script), None);
+    }
+
+    #[test]
+    fn test_solver_multisig() {
+        let keys = vec![pubkey(1), pubkey(2), pubkey(3)];
+        let script = get_script_for_multisig(2, &keys);
+        let (out_type, solutions) = solver(&script);
+        assert_eq!(out_type, TxnOutType::MultiSig);
+        assert_eq!(solutions.len(), 5);
+        assert_eq!(solutions[0], vec![2]);
+        assert_eq!(solutions[4], vec![3]);
+
+        let (_, destinations, required) = extract_destinations(&script).unwrap();
+        assert_eq!(required, 2);
+        assert_eq!(destinations.len(), 3);
+
+        // m > n and a key count mismatch are not multisig
+        let script = get_script_for_multisig(3, &keys[..2]);
+        assert_eq!(solver(&script).0, TxnOutType::NonStandard);
+        let script = Script::default()
+            .push_opcode(OP_1)
+            .push_slice(&pubkey(1))
+            .push_opcode(OP_2)
+            .push_opcode(OP_CHECKMULTISIG);
+        assert_eq!(solver(&script).0, TxnOutType::NonStandard);
+    }
+
+    #[test]
+    fn test_solver_nonstandard() {
+        assert_eq!(solver(&Script::default()).0, TxnOutType::NonStandard);
+        let script = Script::default().push_opcode(OP_RETURN).push_opcode(OP_DUP);
+        assert_eq!(solver(&script).0, TxnOutType::NonStandard);
+        // A 19-byte hash doesn't match the P2PKH template
+        let script = Script::default()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(&[0; 19])
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG);
+        assert_eq!(solver(&script).0, TxnOutType::NonStandard);
+    }
+}
@@ -10,6 +10,8 @@ pub enum SerializationError {
     IoError(#[from] io::Error),
     #[error("Invalid data format")]
     InvalidData,
+    #[error("Parse error: {0}")]
+    ParseError(&'static str),
 }
 
 /// Trait for serializing data to a writable stream
@@ -22,6 +24,25 @@ pub trait Deserializable: Sized {
     fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError>;
 }
 
+impl<const N: usize> Serializable for [u8; N] {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        writer.write_all(self)?;
+        Ok(())
+    }
+}
+
+impl<const N: usize> Deserializable for [u8; N] {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        let mut buf = [0u8; N];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Largest size or item count a length prefix may announce (`MAX_SIZE`
+/// upstream)
+pub const MAX_SIZE: u64 = 0x0200_0000;
+
 /// Compact size representation (used for variable-length integers in BitcoinZ)
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub struct CompactSize(pub u64);
@@ -75,6 +96,49 @@ impl CompactSize {
     }
 }
 
+/// Variable-length integer in the base-128 encoding used by the chainstate
+/// database (`VARINT` upstream). Unlike `CompactSize` it has a unique
+/// encoding for every value: each continuation byte also adds one.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct VarInt(pub u64);
+
+impl VarInt {
+    /// Encodes the integer into a writer
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        let mut n = self.0;
+        let mut buf = [0u8; 10];
+        let mut len = 0;
+        loop {
+            buf[len] = (n & 0x7F) as u8 | if len > 0 { 0x80 } else { 0x00 };
+            if n <= 0x7F {
+                break;
+            }
+            n = (n >> 7) - 1;
+            len += 1;
+        }
+        buf[..=len].reverse();
+        writer.write_all(&buf[..=len])?;
+        Ok(())
+    }
+
+    /// Decodes the integer from a reader
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        let mut n: u64 = 0;
+        loop {
+            let mut byte = [0u8; 1];
+            reader.read_exact(&mut byte)?;
+            if n > u64::MAX >> 7 {
+                return Err(SerializationError::ParseError("VarInt too large"));
+            }
+            n = (n << 7) | (byte[0] & 0x7F) as u64;
+            if byte[0] & 0x80 == 0 {
+                return Ok(VarInt(n));
+            }
+            n = n.checked_add(1).ok_or(SerializationError::ParseError("VarInt too large"))?;
+        }
+    }
+}
+
 /// A helper struct for serializing and deserializing BitcoinZ data
 #[derive(Debug)]
 pub struct SerializeHelper;
@@ -89,9 +153,93 @@ impl SerializeHelper {
 
     /// Reads a vector of bytes
     pub fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>, SerializationError> {
-        let size = CompactSize::deserialize(reader)?.0 as usize;
-        let mut buffer = vec![0; size];
-        reader.read_exact(&mut buffer)?;
+        let size = CompactSize::deserialize(reader)?.0;
+        if size > MAX_SIZE {
+            return Err(SerializationError::ParseError("size too large"));
+        }
+        // Grow with the data actually read rather than trusting the prefix
+        let mut buffer = Vec::new();
+        reader.by_ref().take(size).read_to_end(&mut buffer)?;
+        if buffer.len() as u64 != size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
         Ok(buffer)
     }
+
+    /// Writes a CompactSize-prefixed vector of serializable items
+    pub fn write_vec<W: Write, T: Serializable>(writer: &mut W, items: &[T]) -> Result<(), SerializationError> {
+        CompactSize(items.len() as u64).serialize(writer)?;
+        for item in items {
+            item.serialize(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a CompactSize-prefixed vector of deserializable items
+    pub fn read_vec<R: Read, T: Deserializable>(reader: &mut R) -> Result<Vec<T>, SerializationError> {
+        let count = CompactSize::deserialize(reader)?.0;
+        if count > MAX_SIZE {
+            return Err(SerializationError::ParseError("size too large"));
+        }
+        let count = count as usize;
+        // Cap the preallocation so a bogus length prefix cannot exhaust memory
+        let mut items = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            items.push(T::deserialize(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(n: u64) -> String {
+        let mut bytes = Vec::new();
+        VarInt(n).serialize(&mut bytes).unwrap();
+        hex::encode(bytes)
+    }
+
+    #[test]
+    fn test_varint_bit_patterns() {
+        assert_eq!(encode(0), "00");
+        assert_eq!(encode(0x7f), "7f");
+        assert_eq!(encode(0x80), "8000");
+        assert_eq!(encode(0x1234), "a334");
+        assert_eq!(encode(0xffff), "82fe7f");
+        assert_eq!(encode(0x123456), "c7e756");
+        assert_eq!(encode(0x80123456), "86ffc7e756");
+        assert_eq!(encode(0xffffffff), "8efefefe7f");
+        assert_eq!(encode(0x7fffffffffffffff), "fefefefefefefefe7f");
+        assert_eq!(encode(0xffffffffffffffff), "80fefefefefefefefe7f");
+    }
+
+    #[test]
+    fn test_read_bytes_size_limit() {
+        let mut bytes = Vec::new();
+        SerializeHelper::write_bytes(&mut bytes, &[1, 2, 3]).unwrap();
+        assert_eq!(SerializeHelper::read_bytes(&mut bytes.as_slice()).unwrap(), vec![1, 2, 3]);
+        assert!(SerializeHelper::read_bytes(&mut &bytes[..3]).is_err());
+
+        // A huge length prefix is rejected before anything is allocated
+        let mut huge = Vec::new();
+        CompactSize(MAX_SIZE + 1).serialize(&mut huge).unwrap();
+        assert!(SerializeHelper::read_bytes(&mut huge.as_slice()).is_err());
+        assert!(SerializeHelper::read_vec::<_, [u8; 1]>(&mut huge.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_varint_round_trip() {
+        let mut n = 0u64;
+        while n < u64::MAX / 3 {
+            let mut bytes = Vec::new();
+            VarInt(n).serialize(&mut bytes).unwrap();
+            assert_eq!(VarInt::deserialize(&mut bytes.as_slice()).unwrap(), VarInt(n));
+            n = n * 3 + 1;
+        }
+        assert!(VarInt::deserialize(&mut &[0x80][..]).is_err());
+        let too_large = hex::decode("fffefefefefefefefe7f").unwrap();
+        assert!(VarInt::deserialize(&mut too_large.as_slice()).is_err());
+    }
 }
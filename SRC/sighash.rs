@@ -0,0 +1,459 @@
+use crate::amount::Amount;
+use crate::hash::hash256;
+use crate::primitives::transaction::{JSDescription, Transaction, TxOutput};
+use crate::script::Script;
+use crate::serialize::{CompactSize, Serializable, SerializeHelper};
+use thiserror::Error;
+
+/// Sign all inputs and outputs
+pub const SIGHASH_ALL: u32 = 1;
+/// Sign all inputs and no outputs
+pub const SIGHASH_NONE: u32 = 2;
+/// Sign all inputs and the output with the same index as the signed input
+pub const SIGHASH_SINGLE: u32 = 3;
+/// Flag restricting the signature to the input being signed
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+/// Input index used when hashing for the JoinSplit signature, which covers
+/// the whole transaction rather than a single input
+pub const NOT_AN_INPUT: usize = usize::MAX;
+
+const ZCASH_SIGHASH_PERSONALIZATION_PREFIX: &[u8; 12] = b"ZcashSigHash";
+const ZCASH_PREVOUTS_HASH_PERSONALIZATION: &[u8; 16] = b"ZcashPrevoutHash";
+const ZCASH_SEQUENCE_HASH_PERSONALIZATION: &[u8; 16] = b"ZcashSequencHash";
+const ZCASH_OUTPUTS_HASH_PERSONALIZATION: &[u8; 16] = b"ZcashOutputsHash";
+const ZCASH_JOINSPLITS_HASH_PERSONALIZATION: &[u8; 16] = b"ZcashJSplitsHash";
+const ZCASH_SHIELDED_SPENDS_HASH_PERSONALIZATION: &[u8; 16] = b"ZcashSSpendsHash";
+const ZCASH_SHIELDED_OUTPUTS_HASH_PERSONALIZATION: &[u8; 16] = b"ZcashSOutputHash";
+
+/// Errors returned by the signature hash computation
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SighashError {
+    #[error("Input index {0} is out of range")]
+    InputIndexOutOfRange(usize),
+    #[error("SIGHASH_ANYONECANPAY requires an input to sign")]
+    AnyoneCanPayWithoutInput,
+    #[error("Unknown transaction format")]
+    UnknownTransactionFormat,
+}
+
+/// Signature hash algorithm selected by the transaction format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigVersion {
+    /// Pre-Overwinter `SignatureHash`, inherited from Bitcoin
+    Sprout,
+    /// ZIP-143
+    Overwinter,
+    /// ZIP-243
+    Sapling,
+}
+
+impl SigVersion {
+    /// Returns the signature hash algorithm for a transaction
+    pub fn for_transaction(tx: &Transaction) -> Result<Self, SighashError> {
+        if !tx.overwintered {
+            Ok(SigVersion::Sprout)
+        } else if tx.is_sapling_v4() {
+            Ok(SigVersion::Sapling)
+        } else if tx.is_overwinter_v3() {
+            Ok(SigVersion::Overwinter)
+        } else {
+            Err(SighashError::UnknownTransactionFormat)
+        }
+    }
+}
+
+/// Digests over a whole transaction that every ZIP-143/243 signature hash
+/// shares, so that signing or verifying many inputs stays linear
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrecomputedTransactionData {
+    pub hash_prevouts: [u8; 32],
+    pub hash_sequence: [u8; 32],
+    pub hash_outputs: [u8; 32],
+    pub hash_join_splits: [u8; 32],
+    pub hash_shielded_spends: [u8; 32],
+    pub hash_shielded_outputs: [u8; 32],
+}
+
+impl PrecomputedTransactionData {
+    /// Computes the shared digests for a transaction
+    pub fn new(tx: &Transaction) -> Self {
+        PrecomputedTransactionData {
+            hash_prevouts: get_prevout_hash(tx),
+            hash_sequence: get_sequence_hash(tx),
+            hash_outputs: get_outputs_hash(&tx.outputs),
+            hash_join_splits: get_join_splits_hash(tx),
+            hash_shielded_spends: get_shielded_spends_hash(tx),
+            hash_shielded_outputs: get_shielded_outputs_hash(tx),
+        }
+    }
+}
+
+fn blake2b_256(personalization: &[u8], data: &[u8]) -> [u8; 32] {
+    let hash = blake2b_simd::Params::new()
+        .hash_length(32)
+        .personal(personalization)
+        .hash(data);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(hash.as_bytes());
+    out
+}
+
+fn get_prevout_hash(tx: &Transaction) -> [u8; 32] {
+    let mut buffer = Vec::with_capacity(tx.inputs.len() * 36);
+    for input in &tx.inputs {
+        input.prev_out.serialize(&mut buffer).expect("write to Vec");
+    }
+    blake2b_256(ZCASH_PREVOUTS_HASH_PERSONALIZATION, &buffer)
+}
+
+fn get_sequence_hash(tx: &Transaction) -> [u8; 32] {
+    let mut buffer = Vec::with_capacity(tx.inputs.len() * 4);
+    for input in &tx.inputs {
+        buffer.extend_from_slice(&input.sequence.to_le_bytes());
+    }
+    blake2b_256(ZCASH_SEQUENCE_HASH_PERSONALIZATION, &buffer)
+}
+
+fn get_outputs_hash(outputs: &[TxOutput]) -> [u8; 32] {
+    let mut buffer = Vec::new();
+    for output in outputs {
+        output.serialize(&mut buffer).expect("write to Vec");
+    }
+    blake2b_256(ZCASH_OUTPUTS_HASH_PERSONALIZATION, &buffer)
+}
+
+fn get_join_splits_hash(tx: &Transaction) -> [u8; 32] {
+    let mut buffer = Vec::new();
+    for join_split in &tx.join_splits {
+        join_split.serialize(&mut buffer).expect("write to Vec");
+    }
+    buffer.extend_from_slice(&tx.join_split_pub_key);
+    blake2b_256(ZCASH_JOINSPLITS_HASH_PERSONALIZATION, &buffer)
+}
+
+fn get_shielded_spends_hash(tx: &Transaction) -> [u8; 32] {
+    let mut buffer = Vec::new();
+    for spend in &tx.shielded_spends {
+        // spendAuthSig is not covered: it signs this very digest
+        buffer.extend_from_slice(&spend.cv);
+        buffer.extend_from_slice(&spend.anchor);
+        buffer.extend_from_slice(&spend.nullifier);
+        buffer.extend_from_slice(&spend.rk);
+        buffer.extend_from_slice(&spend.zkproof);
+    }
+    blake2b_256(ZCASH_SHIELDED_SPENDS_HASH_PERSONALIZATION, &buffer)
+}
+
+fn get_shielded_outputs_hash(tx: &Transaction) -> [u8; 32] {
+    let mut buffer = Vec::new();
+    for output in &tx.shielded_outputs {
+        output.serialize(&mut buffer).expect("write to Vec");
+    }
+    blake2b_256(ZCASH_SHIELDED_OUTPUTS_HASH_PERSONALIZATION, &buffer)
+}
+
+/// Computes the digest signed by a transparent input (or, with `NOT_AN_INPUT`,
+/// by the JoinSplit signature).
+///
+/// Overwinter and Sapling transactions use the ZIP-143 and ZIP-243 digests,
+/// personalized with `consensus_branch_id`; older transactions use the
+/// legacy Bitcoin algorithm, in which case `amount` and the branch ID are
+/// not committed to.
+pub fn signature_hash(
+    script_code: &Script,
+    tx: &Transaction,
+    n_in: usize,
+    hash_type: u32,
+    amount: Amount,
+    consensus_branch_id: u32,
+    cache: Option<&PrecomputedTransactionData>,
+) -> Result<[u8; 32], SighashError> {
+    if n_in >= tx.inputs.len() && n_in != NOT_AN_INPUT {
+        return Err(SighashError::InputIndexOutOfRange(n_in));
+    }
+    if n_in == NOT_AN_INPUT && hash_type & SIGHASH_ANYONECANPAY != 0 {
+        return Err(SighashError::AnyoneCanPayWithoutInput);
+    }
+
+    match SigVersion::for_transaction(tx)? {
+        SigVersion::Sprout => Ok(legacy_signature_hash(script_code, tx, n_in, hash_type)),
+        sig_version => Ok(zip243_signature_hash(
+            script_code,
+            tx,
+            n_in,
+            hash_type,
+            amount,
+            consensus_branch_id,
+            sig_version == SigVersion::Sapling,
+            cache,
+        )),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn zip243_signature_hash(
+    script_code: &Script,
+    tx: &Transaction,
+    n_in: usize,
+    hash_type: u32,
+    amount: Amount,
+    consensus_branch_id: u32,
+    is_sapling: bool,
+    cache: Option<&PrecomputedTransactionData>,
+) -> [u8; 32] {
+    let base_type = hash_type & 0x1f;
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+
+    let mut hash_prevouts = [0u8; 32];
+    let mut hash_sequence = [0u8; 32];
+    let mut hash_outputs = [0u8; 32];
+    let mut hash_join_splits = [0u8; 32];
+    let mut hash_shielded_spends = [0u8; 32];
+    let mut hash_shielded_outputs = [0u8; 32];
+
+    if !anyone_can_pay {
+        hash_prevouts = cache.map_or_else(|| get_prevout_hash(tx), |c| c.hash_prevouts);
+    }
+
+    if !anyone_can_pay && base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+        hash_sequence = cache.map_or_else(|| get_sequence_hash(tx), |c| c.hash_sequence);
+    }
+
+    if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+        hash_outputs = cache.map_or_else(|| get_outputs_hash(&tx.outputs), |c| c.hash_outputs);
+    } else if base_type == SIGHASH_SINGLE && n_in < tx.outputs.len() {
+        hash_outputs = get_outputs_hash(&tx.outputs[n_in..=n_in]);
+    }
+
+    if !tx.join_splits.is_empty() {
+        hash_join_splits = cache.map_or_else(|| get_join_splits_hash(tx), |c| c.hash_join_splits);
+    }
+
+    if is_sapling {
+        if !tx.shielded_spends.is_empty() {
+            hash_shielded_spends =
+                cache.map_or_else(|| get_shielded_spends_hash(tx), |c| c.hash_shielded_spends);
+        }
+        if !tx.shielded_outputs.is_empty() {
+            hash_shielded_outputs =
+                cache.map_or_else(|| get_shielded_outputs_hash(tx), |c| c.hash_shielded_outputs);
+        }
+    }
+
+    let mut personalization = [0u8; 16];
+    personalization[..12].copy_from_slice(ZCASH_SIGHASH_PERSONALIZATION_PREFIX);
+    personalization[12..].copy_from_slice(&consensus_branch_id.to_le_bytes());
+
+    let mut preimage = Vec::with_capacity(256 + script_code.len());
+    preimage.extend_from_slice(&tx.header().to_le_bytes());
+    preimage.extend_from_slice(&tx.version_group_id.to_le_bytes());
+    preimage.extend_from_slice(&hash_prevouts);
+    preimage.extend_from_slice(&hash_sequence);
+    preimage.extend_from_slice(&hash_outputs);
+    preimage.extend_from_slice(&hash_join_splits);
+    if is_sapling {
+        preimage.extend_from_slice(&hash_shielded_spends);
+        preimage.extend_from_slice(&hash_shielded_outputs);
+    }
+    preimage.extend_from_slice(&tx.lock_time.to_le_bytes());
+    preimage.extend_from_slice(&tx.expiry_height.to_le_bytes());
+    if is_sapling {
+        preimage.extend_from_slice(&tx.value_balance.to_le_bytes());
+    }
+    preimage.extend_from_slice(&hash_type.to_le_bytes());
+
+    if n_in != NOT_AN_INPUT {
+        let input = &tx.inputs[n_in];
+        input.prev_out.serialize(&mut preimage).expect("write to Vec");
+        script_code.serialize(&mut preimage).expect("write to Vec");
+        preimage.extend_from_slice(&amount.to_le_bytes());
+        preimage.extend_from_slice(&input.sequence.to_le_bytes());
+    }
+
+    blake2b_256(&personalization, &preimage)
+}
+
+/// The original Bitcoin algorithm: the transaction is reserialized with the
+/// other inputs' scripts blanked and outputs trimmed according to the hash
+/// type, and the result double-SHA256 hashed.
+fn legacy_signature_hash(script_code: &Script, tx: &Transaction, n_in: usize, hash_type: u32) -> [u8; 32] {
+    let base_type = hash_type & 0x1f;
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+
+    if base_type == SIGHASH_SINGLE && n_in >= tx.outputs.len() {
+        // Historic bug: signing a SIGHASH_SINGLE input without a matching
+        // output signs the number one instead of failing
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        return one;
+    }
+
+    let script_code = script_code.without_codeseparators();
+    let empty_script = Script::default();
+    let mut buffer = Vec::new();
+
+    buffer.extend_from_slice(&tx.header().to_le_bytes());
+
+    let input_indices: Vec<usize> = if anyone_can_pay {
+        vec![n_in]
+    } else {
+        (0..tx.inputs.len()).collect()
+    };
+    CompactSize(input_indices.len() as u64).serialize(&mut buffer).expect("write to Vec");
+    for index in input_indices {
+        let input = &tx.inputs[index];
+        input.prev_out.serialize(&mut buffer).expect("write to Vec");
+        let script = if index == n_in { &script_code } else { &empty_script };
+        script.serialize(&mut buffer).expect("write to Vec");
+        let sequence = if index != n_in && (base_type == SIGHASH_SINGLE || base_type == SIGHASH_NONE) {
+            0
+        } else {
+            input.sequence
+        };
+        buffer.extend_from_slice(&sequence.to_le_bytes());
+    }
+
+    let output_count = match base_type {
+        SIGHASH_NONE => 0,
+        SIGHASH_SINGLE => n_in + 1,
+        _ => tx.outputs.len(),
+    };
+    CompactSize(output_count as u64).serialize(&mut buffer).expect("write to Vec");
+    for (index, output) in tx.outputs.iter().take(output_count).enumerate() {
+        if base_type == SIGHASH_SINGLE && index != n_in {
+            // Outputs before the signed one are replaced by a "null" output
+            buffer.extend_from_slice(&(-1i64).to_le_bytes());
+            empty_script.serialize(&mut buffer).expect("write to Vec");
+        } else {
+            output.serialize(&mut buffer).expect("write to Vec");
+        }
+    }
+
+    buffer.extend_from_slice(&tx.lock_time.to_le_bytes());
+
+    if tx.version >= 2 {
+        SerializeHelper::write_vec::<_, JSDescription>(&mut buffer, &tx.join_splits)
+            .expect("write to Vec");
+        if !tx.join_splits.is_empty() {
+            buffer.extend_from_slice(&tx.join_split_pub_key);
+            // The JoinSplit signature never signs itself
+            buffer.extend_from_slice(&[0u8; 64]);
+        }
+    }
+
+    buffer.extend_from_slice(&hash_type.to_le_bytes());
+    hash256(&buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::upgrades::NetworkUpgrade;
+    use crate::primitives::transaction::{
+        OutPoint, TxInput, SAPLING_TX_VERSION, SAPLING_VERSION_GROUP_ID,
+    };
+
+    const SAPLING_BRANCH_ID: u32 = NetworkUpgrade::Sapling.branch_id();
+
+    fn tx_with(inputs: usize, outputs: usize) -> Transaction {
+        Transaction {
+            inputs: (0..inputs)
+                .map(|i| TxInput {
+                    prev_out: OutPoint { txid: [i as u8 + 1; 32], index: i as u32 },
+                    script_sig: Script::new(vec![0x51]),
+                    sequence: 0xFFFFFFFF,
+                })
+                .collect(),
+            outputs: (0..outputs)
+                .map(|i| TxOutput {
+                    value: 1_000 * (i as Amount + 1),
+                    script_pubkey: Script::new(vec![0x51]),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn sapling(tx: Transaction) -> Transaction {
+        Transaction {
+            overwintered: true,
+            version: SAPLING_TX_VERSION,
+            version_group_id: SAPLING_VERSION_GROUP_ID,
+            ..tx
+        }
+    }
+
+    #[test]
+    fn legacy_single_without_output_signs_one() {
+        let tx = tx_with(2, 1);
+        let hash = signature_hash(&Script::default(), &tx, 1, SIGHASH_SINGLE, 0, 0, None).unwrap();
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        assert_eq!(hash, one);
+    }
+
+    #[test]
+    fn input_index_out_of_range() {
+        let tx = tx_with(1, 1);
+        assert_eq!(
+            signature_hash(&Script::default(), &tx, 1, SIGHASH_ALL, 0, 0, None),
+            Err(SighashError::InputIndexOutOfRange(1))
+        );
+    }
+
+    #[test]
+    fn legacy_ignores_codeseparators() {
+        let tx = tx_with(1, 1);
+        let plain = Script::new(vec![0x76, 0xac]);
+        let separated = Script::new(vec![0xab, 0x76, 0xab, 0xac]);
+        assert_eq!(
+            signature_hash(&plain, &tx, 0, SIGHASH_ALL, 0, 0, None),
+            signature_hash(&separated, &tx, 0, SIGHASH_ALL, 0, 0, None)
+        );
+    }
+
+    #[test]
+    fn zip243_commits_to_amount_and_branch_id() {
+        let tx = sapling(tx_with(1, 1));
+        let script = Script::new(vec![0xac]);
+        let base = signature_hash(&script, &tx, 0, SIGHASH_ALL, 5_000, SAPLING_BRANCH_ID, None).unwrap();
+        let other_amount =
+            signature_hash(&script, &tx, 0, SIGHASH_ALL, 5_001, SAPLING_BRANCH_ID, None).unwrap();
+        let other_branch = signature_hash(&script, &tx, 0, SIGHASH_ALL, 5_000, 0x5ba81b19, None).unwrap();
+        assert_ne!(base, other_amount);
+        assert_ne!(base, other_branch);
+    }
+
+    #[test]
+    fn zip243_cache_matches_uncached() {
+        let tx = sapling(tx_with(3, 2));
+        let cache = PrecomputedTransactionData::new(&tx);
+        let script = Script::new(vec![0xac]);
+        for hash_type in [SIGHASH_ALL, SIGHASH_NONE, SIGHASH_SINGLE, SIGHASH_ALL | SIGHASH_ANYONECANPAY] {
+            for n_in in 0..3 {
+                assert_eq!(
+                    signature_hash(&script, &tx, n_in, hash_type, 7, SAPLING_BRANCH_ID, None),
+                    signature_hash(&script, &tx, n_in, hash_type, 7, SAPLING_BRANCH_ID, Some(&cache))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn anyonecanpay_ignores_other_inputs() {
+        let tx = sapling(tx_with(2, 2));
+        let mut other = tx.clone();
+        other.inputs[1].prev_out.index = 99;
+        let script = Script::new(vec![0xac]);
+        let hash_type = SIGHASH_ALL | SIGHASH_ANYONECANPAY;
+        assert_eq!(
+            signature_hash(&script, &tx, 0, hash_type, 1, SAPLING_BRANCH_ID, None),
+            signature_hash(&script, &other, 0, hash_type, 1, SAPLING_BRANCH_ID, None)
+        );
+        assert_ne!(
+            signature_hash(&script, &tx, 0, SIGHASH_ALL, 1, SAPLING_BRANCH_ID, None),
+            signature_hash(&script, &other, 0, SIGHASH_ALL, 1, SAPLING_BRANCH_ID, None)
+        );
+    }
+}
@@ -1,59 +1,560 @@
-use crate::utxo::{OutPoint, Utxo};
-use std::collections::HashMap;
-use thiserror::Error;
+use crate::blockstore::BlockFileInfo;
+use crate::chain::DiskBlockIndex;
+use crate::coins::{
+    Coin, CoinsBatch, CoinsStats, CoinsView, CoinsViewError, ShieldedType, SAPLING_EMPTY_ROOT,
+};
+use crate::dbwrapper::DBWrapper;
+use crate::hash::HashWriter;
+use crate::incremental_merkle_tree::{SaplingMerkleTree, SproutMerkleTree};
+use crate::primitives::transaction::OutPoint;
+use crate::serialize::{Deserializable, Serializable, VarInt};
+use sled::{Batch, IVec};
+use std::io::Write;
+use std::path::Path;
 
-/// Custom errors for the transaction database
-#[derive(Debug, Error)]
-pub enum TxDbError {
-    #[error("Transaction not found")]
-    TxNotFound,
-    #[error("Database operation failed")]
-    DbError,
+const DB_COIN: u8 = b'C';
+const DB_SPROUT_ANCHOR: u8 = b'A';
+const DB_SAPLING_ANCHOR: u8 = b'Z';
+const DB_NULLIFIER: u8 = b's';
+const DB_SAPLING_NULLIFIER: u8 = b'S';
+const DB_BEST_BLOCK: u8 = b'B';
+const DB_BEST_SPROUT_ANCHOR: u8 = b'a';
+const DB_BEST_SAPLING_ANCHOR: u8 = b'z';
+
+const DB_BLOCK_INDEX: u8 = b'b';
+const DB_BLOCK_FILES: u8 = b'f';
+const DB_LAST_BLOCK: u8 = b'l';
+
+/// Default `-dbcache` size, in MiB
+pub const DEFAULT_DB_CACHE: i64 = 450;
+/// Smallest `-dbcache` size, in MiB
+pub const MIN_DB_CACHE: i64 = 4;
+/// Largest `-dbcache` size, in MiB
+pub const MAX_DB_CACHE: i64 = 16384;
+/// Most of `-dbcache` given to the block index database, in MiB
+pub const MAX_BLOCK_DB_CACHE: i64 = 2;
+
+/// Splits a `-dbcache` size in MiB between the databases and returns the
+/// bytes left for the in-memory coins cache.
+pub fn coins_cache_budget(db_cache: i64) -> usize {
+    let total = (db_cache.clamp(MIN_DB_CACHE, MAX_DB_CACHE) as usize) << 20;
+    let block_tree = (total / 8).min((MAX_BLOCK_DB_CACHE as usize) << 20);
+    let remaining = total - block_tree;
+    let coins_db = (remaining / 2).min(remaining / 4 + (1 << 23));
+    remaining - coins_db
+}
+
+/// Coins are keyed by `'C' || txid || VARINT(index)`, so that the outputs of
+/// a transaction are next to each other
+fn coin_key(outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = vec![DB_COIN];
+    key.extend_from_slice(&outpoint.txid);
+    VarInt(outpoint.index as u64).serialize(&mut key).expect("writing to a Vec can't fail");
+    key
+}
+
+fn parse_coin_key(key: &[u8]) -> OutPoint {
+    let mut reader = &key[1..];
+    let txid = <[u8; 32]>::deserialize(&mut reader).expect("corrupted chainstate database key");
+    let index = VarInt::deserialize(&mut reader)
+        .ok()
+        .and_then(|index| u32::try_from(index.0).ok())
+        .expect("corrupted chainstate database key");
+    OutPoint { txid, index }
+}
+
+fn hash_key(prefix: u8, hash: &[u8; 32]) -> Vec<u8> {
+    let mut key = vec![prefix];
+    key.extend_from_slice(hash);
+    key
+}
+
+fn nullifier_prefix(pool: ShieldedType) -> u8 {
+    match pool {
+        ShieldedType::Sprout => DB_NULLIFIER,
+        ShieldedType::Sapling => DB_SAPLING_NULLIFIER,
+    }
 }
 
-/// Persistent transaction database
-pub struct TxDb {
-    utxo_set: HashMap<OutPoint, Utxo>,
+fn serialize<T: Serializable>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    value.serialize(&mut bytes).expect("writing to a Vec can't fail");
+    bytes
 }
 
-impl TxDb {
-    /// Creates a new empty TxDb
-    pub fn new() -> Self {
-        TxDb {
-            utxo_set: HashMap::new(),
+fn deserialize<T: Deserializable>(bytes: &[u8]) -> T {
+    T::deserialize(&mut &bytes[..]).expect("corrupted chainstate database entry")
+}
+
+/// Ends the hashed record of a transaction's coins with a zero
+fn finish_tx_record(hasher: &mut HashWriter, record: &mut Vec<u8>) {
+    VarInt(0).serialize(record).expect("writing to a Vec can't fail");
+    hasher.write_all(record).expect("hashing can't fail");
+    record.clear();
+}
+
+/// The UTXO set and shielded state on disk, the base of the chainstate's
+/// coins cache. A flush writes the cache's changes together with the new
+/// best block in one atomic batch, so the database always matches a block.
+/// Clones share the same database.
+#[derive(Clone)]
+pub struct CoinsViewDb {
+    db: DBWrapper,
+}
+
+impl CoinsViewDb {
+    /// Opens or creates the chainstate database at `path`.
+    pub fn new(path: &Path) -> Result<Self, CoinsViewError> {
+        Ok(CoinsViewDb {
+            db: DBWrapper::new(path)?,
+        })
+    }
+
+    // Without the chainstate the node can't go on, so read errors are fatal
+    fn read(&self, key: &[u8]) -> Option<IVec> {
+        self.db
+            .read_raw(key)
+            .unwrap_or_else(|error| panic!("chainstate database read failed: {}", error))
+    }
+
+    fn read_hash(&self, key: &[u8]) -> Option<[u8; 32]> {
+        self.read(key).map(|bytes| deserialize(&bytes))
+    }
+
+    /// Iterates over the unspent coins on disk.
+    pub fn coins(&self) -> impl Iterator<Item = (OutPoint, Coin)> + '_ {
+        self.db.iter_prefix(&[DB_COIN]).map(|entry| {
+            let (key, value) = entry.expect("chainstate database read failed");
+            (parse_coin_key(&key), deserialize(&value))
+        })
+    }
+
+    /// Iterates over the nullifiers of `pool` revealed on chain.
+    pub fn nullifiers(&self, pool: ShieldedType) -> impl Iterator<Item = [u8; 32]> + '_ {
+        self.db.iter_prefix(&[nullifier_prefix(pool)]).map(|entry| {
+            let (key, _) = entry.expect("chainstate database read failed");
+            deserialize(&key[1..])
+        })
+    }
+
+    /// Iterates over the known Sprout anchors and their trees.
+    pub fn sprout_anchors(&self) -> impl Iterator<Item = ([u8; 32], SproutMerkleTree)> + '_ {
+        self.db.iter_prefix(&[DB_SPROUT_ANCHOR]).map(|entry| {
+            let (key, value) = entry.expect("chainstate database read failed");
+            (deserialize(&key[1..]), deserialize(&value))
+        })
+    }
+
+    /// Iterates over the known Sapling anchors and their trees.
+    pub fn sapling_anchors(&self) -> impl Iterator<Item = ([u8; 32], SaplingMerkleTree)> + '_ {
+        self.db.iter_prefix(&[DB_SAPLING_ANCHOR]).map(|entry| {
+            let (key, value) = entry.expect("chainstate database read failed");
+            (deserialize(&key[1..]), deserialize(&value))
+        })
+    }
+
+    /// Walks the database to compute the statistics reported by
+    /// `gettxoutsetinfo`. The database doesn't know block heights, so the
+    /// height is left zero.
+    pub fn get_stats(&self) -> Result<CoinsStats, CoinsViewError> {
+        let mut stats = CoinsStats {
+            best_block: self.best_block(),
+            ..Default::default()
+        };
+        let mut hasher = HashWriter::new();
+        hasher.write_all(&stats.best_block).expect("hashing can't fail");
+
+        // Coins are hashed per transaction, as upstream: the txid and the
+        // height and coinbase flag, then each output after its index plus
+        // one, then a zero
+        let mut record = Vec::new();
+        let mut txid = None;
+        for entry in self.db.iter_prefix(&[DB_COIN]) {
+            let (key, value) = entry?;
+            let outpoint = parse_coin_key(&key);
+            let coin: Coin = deserialize(&value);
+            if txid != Some(outpoint.txid) {
+                if txid.is_some() {
+                    finish_tx_record(&mut hasher, &mut record);
+                }
+                txid = Some(outpoint.txid);
+                stats.transactions += 1;
+                record.extend_from_slice(&outpoint.txid);
+                let code = ((coin.height as u64) << 1) | coin.is_coinbase as u64;
+                VarInt(code).serialize(&mut record).expect("writing to a Vec can't fail");
+            }
+            VarInt(outpoint.index as u64 + 1)
+                .serialize(&mut record)
+                .expect("writing to a Vec can't fail");
+            coin.output.script_pubkey.serialize(&mut record).expect("writing to a Vec can't fail");
+            VarInt(coin.output.value as u64)
+                .serialize(&mut record)
+                .expect("writing to a Vec can't fail");
+            stats.transaction_outputs += 1;
+            stats.serialized_size += (key.len() + value.len()) as u64;
+            stats.total_amount += coin.output.value;
+        }
+        if txid.is_some() {
+            finish_tx_record(&mut hasher, &mut record);
+        }
+
+        // The shielded state is hashed as stored, each entry after its key
+        for pool in [ShieldedType::Sprout, ShieldedType::Sapling] {
+            hasher.write_all(&self.get_best_anchor(pool)).expect("hashing can't fail");
         }
+        let prefixes = [DB_NULLIFIER, DB_SAPLING_NULLIFIER, DB_SPROUT_ANCHOR, DB_SAPLING_ANCHOR];
+        for prefix in prefixes {
+            for entry in self.db.iter_prefix(&[prefix]) {
+                let (key, value) = entry?;
+                hasher.write_all(&key).expect("hashing can't fail");
+                hasher.write_all(&value).expect("hashing can't fail");
+            }
+        }
+        stats.hash_serialized = hasher.finalize();
+        Ok(stats)
+    }
+
+    /// Deletes everything in the database.
+    pub fn clear(&self) -> Result<(), CoinsViewError> {
+        Ok(self.db.clear()?)
     }
 
-    /// Adds a UTXO to the database
-    pub fn add_utxo(&mut self, utxo: Utxo) {
-        self.utxo_set.insert(utxo.outpoint.clone(), utxo);
+    /// Flushes the database to disk.
+    pub fn flush(&self) -> Result<(), CoinsViewError> {
+        Ok(self.db.flush()?)
+    }
+}
+
+/// The block index and the statistics of the blk files, in `blocks/index`.
+/// Entries are keyed by `'b' || hash` and file statistics by
+/// `'f' || file number`. Clones share the same database.
+#[derive(Clone)]
+pub struct BlockTreeDb {
+    db: DBWrapper,
+}
+
+impl BlockTreeDb {
+    /// Opens or creates the block index database at `path`.
+    pub fn new(path: &Path) -> Result<Self, CoinsViewError> {
+        Ok(BlockTreeDb {
+            db: DBWrapper::new(path)?,
+        })
     }
 
-    /// Removes a UTXO from the database (marks it as spent)
-    pub fn remove_utxo(&mut self, outpoint: &OutPoint) -> Result<(), TxDbError> {
-        if self.utxo_set.remove(outpoint).is_none() {
-            return Err(TxDbError::TxNotFound);
+    /// Reads the statistics of every blk file and the number of the file
+    /// blocks are appended to.
+    pub fn read_block_files(&self) -> Result<(Vec<BlockFileInfo>, u32), CoinsViewError> {
+        let mut files = Vec::new();
+        for entry in self.db.iter_prefix(&[DB_BLOCK_FILES]) {
+            let (key, value) = entry?;
+            let file = u32::from_be_bytes(key[1..].try_into().expect("corrupted block file key"));
+            let info = BlockFileInfo::deserialize(&mut &value[..])
+                .expect("corrupted block file statistics");
+            if files.len() <= file as usize {
+                files.resize(file as usize + 1, BlockFileInfo::default());
+            }
+            files[file as usize] = info;
         }
+        let last_file = match self.db.read_raw(&[DB_LAST_BLOCK])? {
+            Some(bytes) => u32::from_le_bytes(bytes[..].try_into().expect("corrupted last file")),
+            None => 0,
+        };
+        Ok((files, last_file))
+    }
+
+    /// Reads every saved block index entry, in no particular order.
+    pub fn read_block_index(&self) -> Result<Vec<DiskBlockIndex>, CoinsViewError> {
+        self.db
+            .iter_prefix(&[DB_BLOCK_INDEX])
+            .map(|entry| {
+                let (_, value) = entry?;
+                Ok(DiskBlockIndex::deserialize(&mut &value[..])
+                    .expect("corrupted block index entry"))
+            })
+            .collect()
+    }
+
+    /// Saves changed file statistics and block index entries in one atomic
+    /// batch.
+    pub fn write(
+        &self,
+        files: &[(u32, BlockFileInfo)],
+        last_file: u32,
+        entries: &[DiskBlockIndex],
+    ) -> Result<(), CoinsViewError> {
+        let mut writes = Batch::default();
+        for (file, info) in files {
+            let mut key = vec![DB_BLOCK_FILES];
+            key.extend_from_slice(&file.to_be_bytes());
+            writes.insert(key, serialize(info));
+        }
+        writes.insert(&[DB_LAST_BLOCK][..], &last_file.to_le_bytes()[..]);
+        for entry in entries {
+            writes.insert(hash_key(DB_BLOCK_INDEX, &entry.header.hash()), serialize(entry));
+        }
+        self.db.write_batch(writes)?;
         Ok(())
     }
 
-    /// Retrieves a UTXO from the database
-    pub fn get_utxo(&self, outpoint: &OutPoint) -> Option<&Utxo> {
-        self.utxo_set.get(outpoint)
+    /// Deletes everything in the database.
+    pub fn clear(&self) -> Result<(), CoinsViewError> {
+        Ok(self.db.clear()?)
+    }
+
+    /// Flushes the database to disk.
+    pub fn flush(&self) -> Result<(), CoinsViewError> {
+        Ok(self.db.flush()?)
+    }
+}
+
+impl CoinsView for CoinsViewDb {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.read(&coin_key(outpoint)).map(|bytes| deserialize(&bytes))
+    }
+
+    fn best_block(&self) -> [u8; 32] {
+        self.read_hash(&[DB_BEST_BLOCK]).unwrap_or([0; 32])
+    }
+
+    fn get_nullifier(&self, nullifier: &[u8; 32], pool: ShieldedType) -> bool {
+        self.read(&hash_key(nullifier_prefix(pool), nullifier)).is_some()
+    }
+
+    fn get_sprout_anchor_at(&self, anchor: &[u8; 32]) -> Option<SproutMerkleTree> {
+        if *anchor == SproutMerkleTree::empty_root() {
+            return Some(SproutMerkleTree::new());
+        }
+        self.read(&hash_key(DB_SPROUT_ANCHOR, anchor)).map(|bytes| deserialize(&bytes))
     }
 
-    /// Validates and updates the UTXO set for a block
-    pub fn update_utxo_set(&mut self, block_utxos: Vec<Utxo>, spent_outpoints: Vec<OutPoint>) -> Result<(), TxDbError> {
-        // Add new UTXOs
-        for utxo in block_utxos {
-            self.add_utxo(utxo);
+    fn get_sapling_anchor_at(&self, anchor: &[u8; 32]) -> Option<SaplingMerkleTree> {
+        if *anchor == SAPLING_EMPTY_ROOT {
+            return Some(SaplingMerkleTree::new());
         }
+        self.read(&hash_key(DB_SAPLING_ANCHOR, anchor)).map(|bytes| deserialize(&bytes))
+    }
 
-        // Remove spent outputs
-        for outpoint in spent_outpoints {
-            self.remove_utxo(&outpoint)?;
+    fn get_best_anchor(&self, pool: ShieldedType) -> [u8; 32] {
+        match pool {
+            ShieldedType::Sprout => self
+                .read_hash(&[DB_BEST_SPROUT_ANCHOR])
+                .unwrap_or_else(SproutMerkleTree::empty_root),
+            ShieldedType::Sapling => {
+                self.read_hash(&[DB_BEST_SAPLING_ANCHOR]).unwrap_or(SAPLING_EMPTY_ROOT)
+            }
         }
+    }
 
+    fn batch_write(&mut self, batch: CoinsBatch) -> Result<(), CoinsViewError> {
+        let mut writes = Batch::default();
+        for (outpoint, entry) in batch.coins.iter().filter(|(_, entry)| entry.is_dirty()) {
+            match &entry.value {
+                Some(coin) => writes.insert(coin_key(outpoint), serialize(coin)),
+                None => writes.remove(coin_key(outpoint)),
+            }
+        }
+        let nullifiers = [
+            (&batch.sprout_nullifiers, ShieldedType::Sprout),
+            (&batch.sapling_nullifiers, ShieldedType::Sapling),
+        ];
+        for (entries, pool) in nullifiers {
+            for (nullifier, entry) in entries.iter().filter(|(_, entry)| entry.is_dirty()) {
+                let key = hash_key(nullifier_prefix(pool), nullifier);
+                if entry.value {
+                    writes.insert(key, &[][..]);
+                } else {
+                    writes.remove(key);
+                }
+            }
+        }
+        for (root, entry) in batch.sprout_anchors.iter().filter(|(_, e)| e.is_dirty()) {
+            match &entry.value {
+                Some(tree) => writes.insert(hash_key(DB_SPROUT_ANCHOR, root), serialize(tree)),
+                None => writes.remove(hash_key(DB_SPROUT_ANCHOR, root)),
+            }
+        }
+        for (root, entry) in batch.sapling_anchors.iter().filter(|(_, e)| e.is_dirty()) {
+            match &entry.value {
+                Some(tree) => writes.insert(hash_key(DB_SAPLING_ANCHOR, root), serialize(tree)),
+                None => writes.remove(hash_key(DB_SAPLING_ANCHOR, root)),
+            }
+        }
+        writes.insert(&[DB_BEST_SPROUT_ANCHOR][..], &batch.best_sprout_anchor[..]);
+        writes.insert(&[DB_BEST_SAPLING_ANCHOR][..], &batch.best_sapling_anchor[..]);
+        writes.insert(&[DB_BEST_BLOCK][..], &batch.best_block[..]);
+        self.db.write_batch(writes)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coins::CoinsViewCache;
+    use crate::primitives::transaction::TxOutput;
+    use crate::script::Script;
+    use std::env;
+
+    #[test]
+    fn test_chainstate_round_trip() {
+        let path = env::temp_dir().join("txdb_test");
+        let _ = std::fs::remove_dir_all(&path);
+        let outpoint = OutPoint {
+            txid: [1; 32],
+            index: 3,
+        };
+        let output = TxOutput {
+            value: 5000,
+            script_pubkey: Script::new(vec![0x51]),
+        };
+        let coin = Coin::new(output, 7, true);
+        let mut tree = SproutMerkleTree::new();
+        tree.append([2; 32]).unwrap();
+        let mut sapling_tree = SaplingMerkleTree::new();
+        sapling_tree.append([6; 32]).unwrap();
+
+        // sled may hold its lock for a moment after a drop, so keep one handle
+        let mut db = CoinsViewDb::new(&path).unwrap();
+        {
+            let mut cache = CoinsViewCache::new(&mut db);
+            cache.add_coin(outpoint.clone(), coin.clone(), false);
+            cache.set_nullifier([3; 32], ShieldedType::Sapling, true);
+            cache.push_sprout_anchor(tree.clone());
+            cache.push_sapling_anchor(sapling_tree.clone());
+            cache.set_best_block([4; 32]);
+            cache.flush().unwrap();
+        }
+
+        assert_eq!(db.get_coin(&outpoint), Some(coin.clone()));
+        assert_eq!(db.coins().collect::<Vec<_>>(), vec![(outpoint.clone(), coin)]);
+        assert!(db.get_nullifier(&[3; 32], ShieldedType::Sapling));
+        assert!(!db.get_nullifier(&[3; 32], ShieldedType::Sprout));
+        assert_eq!(db.get_best_anchor(ShieldedType::Sprout), tree.root());
+        assert_eq!(db.get_sprout_anchor_at(&tree.root()), Some(tree));
+        assert_eq!(db.get_best_anchor(ShieldedType::Sapling), sapling_tree.root());
+        assert_eq!(db.get_sapling_anchor_at(&sapling_tree.root()), Some(sapling_tree));
+        assert_eq!(db.best_block(), [4; 32]);
+
+        let mut cache = CoinsViewCache::new(&mut db);
+        assert!(cache.spend_coin(&outpoint).is_some());
+        cache.set_best_block([5; 32]);
+        cache.flush().unwrap();
+        drop(cache);
+        assert_eq!(db.get_coin(&outpoint), None);
+        assert_eq!(db.best_block(), [5; 32]);
+
+        drop(db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_block_tree_round_trip() {
+        use crate::chain::{BLOCK_HAVE_DATA, BLOCK_VALID_SCRIPTS};
+        use crate::primitives::block::BlockHeader;
+
+        let path = env::temp_dir().join("txdb_block_tree_test");
+        let _ = std::fs::remove_dir_all(&path);
+        let entry = DiskBlockIndex {
+            header: BlockHeader {
+                version: 4,
+                bits: 0x207fffff,
+                ..Default::default()
+            },
+            height: 0,
+            status: BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA,
+            file: 1,
+            data_pos: 8,
+            undo_pos: 0,
+            tx_count: 1,
+        };
+        let mut info = BlockFileInfo::default();
+        info.add_block(0, 1_500_000_000);
+
+        {
+            let db = BlockTreeDb::new(&path).unwrap();
+            assert_eq!(db.read_block_files().unwrap(), (Vec::new(), 0));
+            db.write(&[(1, info.clone())], 1, std::slice::from_ref(&entry)).unwrap();
+            db.flush().unwrap();
+        }
+
+        let db = BlockTreeDb::new(&path).unwrap();
+        assert_eq!(db.read_block_files().unwrap(), (vec![BlockFileInfo::default(), info], 1));
+        assert_eq!(db.read_block_index().unwrap(), vec![entry]);
+        db.clear().unwrap();
+        assert!(db.read_block_index().unwrap().is_empty());
+
+        drop(db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_stats() {
+        let path = env::temp_dir().join("txdb_stats_test");
+        let _ = std::fs::remove_dir_all(&path);
+        let coin = |value, height| {
+            let output = TxOutput {
+                value,
+                script_pubkey: Script::new(vec![0x51]),
+            };
+            Coin::new(output, height, false)
+        };
+        let mut db = CoinsViewDb::new(&path).unwrap();
+        let empty = db.get_stats().unwrap();
+        assert_eq!(empty.transactions, 0);
+
+        let mut cache = CoinsViewCache::new(&mut db);
+        for index in 0..2 {
+            let outpoint = OutPoint {
+                txid: [1; 32],
+                index,
+            };
+            cache.add_coin(outpoint, coin(5000, 7), false);
+        }
+        let outpoint = OutPoint {
+            txid: [2; 32],
+            index: 0,
+        };
+        cache.add_coin(outpoint, coin(100, 8), false);
+        cache.set_best_block([4; 32]);
+        cache.flush().unwrap();
+        drop(cache);
+
+        let stats = db.get_stats().unwrap();
+        assert_eq!((stats.transactions, stats.transaction_outputs), (2, 3));
+        assert_eq!(stats.total_amount, 10_100);
+        assert_eq!(stats.best_block, [4; 32]);
+        assert!(stats.serialized_size > 0);
+        assert_eq!(db.get_stats().unwrap(), stats);
+        assert_ne!(stats.hash_serialized, empty.hash_serialized);
+
+        // The shielded state is covered by the hash too
+        let mut cache = CoinsViewCache::new(&mut db);
+        cache.set_nullifier([3; 32], ShieldedType::Sapling, true);
+        cache.flush().unwrap();
+        drop(cache);
+        assert_eq!(db.nullifiers(ShieldedType::Sapling).collect::<Vec<_>>(), vec![[3; 32]]);
+        assert_ne!(db.get_stats().unwrap().hash_serialized, stats.hash_serialized);
+
+        drop(db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_coin_keys() {
+        let outpoint = OutPoint {
+            txid: [9; 32],
+            index: 0x1234,
+        };
+        let key = coin_key(&outpoint);
+        assert_eq!(key[0], DB_COIN);
+        assert_eq!(key[33..], [0xa3, 0x34]);
+        assert_eq!(parse_coin_key(&key), outpoint);
+    }
+
+    #[test]
+    fn test_coins_cache_budget() {
+        assert_eq!(coins_cache_budget(0), coins_cache_budget(MIN_DB_CACHE));
+        let budget = coins_cache_budget(DEFAULT_DB_CACHE);
+        assert!(budget > 0 && budget < (DEFAULT_DB_CACHE as usize) << 20);
+    }
+}
@@ -1,5 +1,9 @@
-use crate::transaction::Transaction;
-use crate::utxo::OutPoint;
+use crate::coins::{CoinsView, CoinsViewCache};
+use crate::amount::Amount;
+use crate::consensus::validation::{check_shielded_requirements, TxValidationError};
+use crate::policy::fees::FeePolicy;
+use crate::policy::policy::Policy;
+use crate::primitives::transaction::{Transaction, TxOutput};
 use std::collections::{HashMap, BinaryHeap};
 use std::cmp::Reverse;
 use thiserror::Error;
@@ -11,6 +15,10 @@ pub enum MempoolError {
     TxNotFound,
     #[error("Transaction is invalid")]
     InvalidTransaction,
+    #[error("Transaction inputs are missing or spent")]
+    MissingInputs,
+    #[error("Transaction rejected: {0}")]
+    Rejected(#[from] TxValidationError),
 }
 
 /// Represents a mempool transaction with its fee rate for prioritization
@@ -59,7 +67,7 @@ impl Mempool {
     pub fn add_transaction(&mut self, transaction: Transaction, fee_rate: u64) -> Result<(), MempoolError> {
         let txid = transaction.txid();
         if self.transactions.contains_key(&txid) {
-            return Err(MempoolError::InvalidTransaction);
+            return Err(TxValidationError::AlreadyInMempool.into());
         }
 
         let mempool_tx = MempoolTransaction {
@@ -72,6 +80,69 @@ impl Mempool {
         Ok(())
     }
 
+    /// Adds a transaction after applying the relay policy. Its inputs and
+    /// shielded spends are checked against `view`, normally the coins cache
+    /// of the chainstate.
+    pub fn accept_transaction(
+        &mut self,
+        transaction: Transaction,
+        view: &CoinsViewCache,
+        fee_rate: u64,
+        require_standard: bool,
+    ) -> Result<(), MempoolError> {
+        if transaction.is_coinbase() {
+            return Err(MempoolError::InvalidTransaction);
+        }
+
+        let result = Self::check_against_view(&transaction, view, require_standard);
+        if result.is_err() {
+            // Don't let rejected transactions fill the cache
+            for input in &transaction.inputs {
+                view.uncache(&input.prev_out);
+            }
+        }
+        result?;
+
+        self.add_transaction(transaction, fee_rate)
+    }
+
+    fn check_against_view(
+        transaction: &Transaction,
+        view: &CoinsViewCache,
+        require_standard: bool,
+    ) -> Result<(), MempoolError> {
+        let spent_outputs = transaction
+            .inputs
+            .iter()
+            .map(|input| view.get_coin(&input.prev_out).map(|coin| coin.output))
+            .collect::<Option<Vec<TxOutput>>>()
+            .ok_or(MempoolError::MissingInputs)?;
+        check_shielded_requirements(transaction, view)?;
+
+        if require_standard {
+            Policy::is_standard_tx(transaction).map_err(TxValidationError::NonStandard)?;
+            if !Policy::are_inputs_standard(transaction, &spent_outputs) {
+                let reason = "bad-txns-nonstandard-inputs";
+                return Err(TxValidationError::NonStandard(reason).into());
+            }
+        }
+
+        // Value moved between the pools counts towards the fee
+        let value_in: Amount = spent_outputs.iter().map(|output| output.value).sum::<Amount>()
+            + transaction.value_balance
+            + transaction
+                .join_splits
+                .iter()
+                .map(|join_split| join_split.vpub_new - join_split.vpub_old)
+                .sum::<Amount>();
+        let value_out: Amount = transaction.outputs.iter().map(|output| output.value).sum();
+        let fee = u64::try_from(value_in - value_out).unwrap_or(0);
+        if !FeePolicy::validate_fee(transaction, fee) {
+            return Err(TxValidationError::InsufficientFee.into());
+        }
+        Ok(())
+    }
+
     /// Retrieves a transaction by its ID
     pub fn get_transaction(&self, txid: &str) -> Option<&Transaction> {
         self.transactions.get(txid).map(|mempool_tx| &mempool_tx.transaction)
@@ -1,6 +1,11 @@
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 
+/// Event for messages shown while the node starts up
+pub const INIT_MESSAGE: &str = "InitMessage";
+/// Event for the progress of a long operation, as "<title> <percent>%"
+pub const SHOW_PROGRESS: &str = "ShowProgress";
+
 /// Type alias for UI event callbacks
 type UiCallback = Box<dyn Fn(&str) + Send + Sync>;
 
@@ -43,4 +48,14 @@ impl UiInterface {
             }
         }
     }
+
+    /// Shows a message while the node starts up
+    pub fn init_message(&self, message: &str) {
+        self.trigger_event(INIT_MESSAGE, message);
+    }
+
+    /// Reports that a long operation is `percent` done
+    pub fn show_progress(&self, title: &str, percent: u32) {
+        self.trigger_event(SHOW_PROGRESS, &format!("{} {}%", title, percent));
+    }
 }
@@ -1,67 +1,101 @@
-use crate::transaction::TxOutput;
-use crate::utxo::OutPoint;
-use std::collections::HashMap;
+use crate::coins::Coin;
+use crate::compressor::TxOutCompressor;
+use crate::serialize::{
+    CompactSize, Deserializable, Serializable, SerializationError, SerializeHelper, VarInt,
+};
+use std::io::{Read, Write};
 
-/// Represents undo data for a single transaction input
-#[derive(Debug, Clone)]
-pub struct TxInUndo {
-    pub is_coinbase: bool,
-    pub height: u32,
-    pub amount: u64,
-    pub script_pubkey: Vec<u8>,
-}
-
-impl TxInUndo {
-    /// Creates a new TxInUndo
-    pub fn new(is_coinbase: bool, height: u32, amount: u64, script_pubkey: Vec<u8>) -> Self {
-        TxInUndo {
-            is_coinbase,
-            height,
-            amount,
-            script_pubkey,
-        }
-    }
-}
-
-/// Represents undo data for an entire transaction
-#[derive(Debug, Clone)]
+/// Undo data for a transaction: the coins spent by its inputs, in input
+/// order
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct TxUndo {
-    pub inputs: Vec<TxInUndo>, // Undo data for each transaction input
+    pub prev_coins: Vec<Coin>,
 }
 
 impl TxUndo {
     /// Creates a new TxUndo
     pub fn new() -> Self {
-        TxUndo { inputs: Vec::new() }
+        Self::default()
     }
 
-    /// Adds undo data for a single input
-    pub fn add_input(&mut self, undo: TxInUndo) {
-        self.inputs.push(undo);
+    /// Adds the coin spent by the next input
+    pub fn add_input(&mut self, coin: Coin) {
+        self.prev_coins.push(coin);
     }
 }
 
-/// Represents undo data for a block
-#[derive(Debug, Clone)]
+/// Undo data for a block: one `TxUndo` per transaction after the coinbase,
+/// in block order, plus the Sprout anchor to restore
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct BlockUndo {
-    pub tx_undos: HashMap<OutPoint, TxUndo>, // Undo data for transactions in the block
+    pub tx_undos: Vec<TxUndo>,
+    /// Best Sprout anchor before the block was connected
+    pub old_sprout_tree_root: [u8; 32],
 }
 
 impl BlockUndo {
     /// Creates a new BlockUndo
-    pub fn new() -> Self {
+    pub fn new(old_sprout_tree_root: [u8; 32]) -> Self {
         BlockUndo {
-            tx_undos: HashMap::new(),
+            tx_undos: Vec::new(),
+            old_sprout_tree_root,
         }
     }
 
-    /// Adds undo data for a transaction
-    pub fn add_tx_undo(&mut self, outpoint: OutPoint, tx_undo: TxUndo) {
-        self.tx_undos.insert(outpoint, tx_undo);
+    /// Adds undo data for the next transaction
+    pub fn add_tx_undo(&mut self, tx_undo: TxUndo) {
+        self.tx_undos.push(tx_undo);
     }
+}
+
+/// Spent coins are stored like chainstate coins, except that coins not
+/// created at height 0 are followed by a zero that older versions used for
+/// the transaction version.
+impl Serializable for TxUndo {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        CompactSize(self.prev_coins.len() as u64).serialize(writer)?;
+        for coin in &self.prev_coins {
+            VarInt(((coin.height as u64) << 1) | coin.is_coinbase as u64).serialize(writer)?;
+            if coin.height > 0 {
+                VarInt(0).serialize(writer)?;
+            }
+            TxOutCompressor(coin.output.clone()).serialize(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deserializable for TxUndo {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        let count = CompactSize::deserialize(reader)?.0 as usize;
+        let mut prev_coins = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let code = VarInt::deserialize(reader)?.0;
+            let height = u32::try_from(code >> 1).map_err(|_| SerializationError::InvalidData)?;
+            if height > 0 {
+                VarInt::deserialize(reader)?;
+            }
+            let output = TxOutCompressor::deserialize(reader)?.0;
+            prev_coins.push(Coin::new(output, height, code & 1 == 1));
+        }
+        Ok(TxUndo { prev_coins })
+    }
+}
+
+impl Serializable for BlockUndo {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        SerializeHelper::write_vec(writer, &self.tx_undos)?;
+        self.old_sprout_tree_root.serialize(writer)
+    }
+}
 
-    /// Retrieves undo data for a transaction
-    pub fn get_tx_undo(&self, outpoint: &OutPoint) -> Option<&TxUndo> {
-        self.tx_undos.get(outpoint)
+impl Deserializable for BlockUndo {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        let tx_undos = SerializeHelper::read_vec(reader)?;
+        let old_sprout_tree_root = <[u8; 32]>::deserialize(reader)?;
+        Ok(BlockUndo {
+            tx_undos,
+            old_sprout_tree_root,
+        })
     }
 }
@@ -0,0 +1,200 @@
+use crate::coins::{
+    CacheEntry, Coin, CoinsBatch, CoinsView, CoinsViewError, ShieldedType, DIRTY,
+    SAPLING_EMPTY_ROOT,
+};
+use crate::incremental_merkle_tree::{SaplingMerkleTree, SproutMerkleTree};
+use crate::primitives::transaction::OutPoint;
+use crate::serialize::{CompactSize, Deserializable, Serializable, SerializationError};
+use crate::txdb::CoinsViewDb;
+use std::io::{self, Read, Write};
+use std::mem;
+use thiserror::Error;
+
+/// Bytes every UTXO snapshot starts with
+pub const SNAPSHOT_MAGIC: [u8; 5] = *b"utxo\xff";
+/// Version of the snapshot format written by `write_snapshot`
+pub const SNAPSHOT_VERSION: u16 = 1;
+/// Coins written to the database per batch while loading a snapshot
+const LOAD_BATCH_SIZE: usize = 100_000;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed snapshot: {0}")]
+    Serialization(#[from] SerializationError),
+    #[error("{0}")]
+    Coins(#[from] CoinsViewError),
+    #[error("the snapshot is for another network")]
+    WrongNetwork,
+    #[error("no snapshot at block {0} is allowed by the chain parameters")]
+    NotAllowed(String),
+    #[error("snapshot hash {actual} doesn't match the expected {expected}")]
+    HashMismatch { expected: String, actual: String },
+    #[error("the snapshot has {actual} coins instead of {expected}")]
+    CoinsCount { expected: u64, actual: u64 },
+    #[error("a snapshot can only be loaded into an empty chainstate")]
+    NotEmpty,
+}
+
+/// Hex of a hash in the byte-reversed order it is displayed in
+pub fn display_hash(hash: &[u8; 32]) -> String {
+    let mut reversed = *hash;
+    reversed.reverse();
+    hex::encode(reversed)
+}
+
+/// Header of a snapshot: the format, the network and the block the UTXO set
+/// is at. The coins follow, then the shielded state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotMetadata {
+    pub network_magic: [u8; 4],
+    pub base_block: [u8; 32],
+    pub coins_count: u64,
+}
+
+impl Serializable for SnapshotMetadata {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), SerializationError> {
+        SNAPSHOT_MAGIC.serialize(writer)?;
+        SNAPSHOT_VERSION.to_le_bytes().serialize(writer)?;
+        self.network_magic.serialize(writer)?;
+        self.base_block.serialize(writer)?;
+        self.coins_count.to_le_bytes().serialize(writer)
+    }
+}
+
+impl Deserializable for SnapshotMetadata {
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self, SerializationError> {
+        if <[u8; 5]>::deserialize(reader)? != SNAPSHOT_MAGIC {
+            return Err(SerializationError::ParseError("not a UTXO snapshot"));
+        }
+        if u16::from_le_bytes(Deserializable::deserialize(reader)?) != SNAPSHOT_VERSION {
+            return Err(SerializationError::ParseError("unsupported snapshot version"));
+        }
+        Ok(SnapshotMetadata {
+            network_magic: Deserializable::deserialize(reader)?,
+            base_block: Deserializable::deserialize(reader)?,
+            coins_count: u64::from_le_bytes(Deserializable::deserialize(reader)?),
+        })
+    }
+}
+
+/// Writes the UTXO set and shielded state of `db` as a snapshot: the
+/// metadata, each coin after its outpoint, the best anchors, then the
+/// nullifiers and the anchors with their trees, each list after its length.
+pub fn write_snapshot<W: Write>(
+    db: &CoinsViewDb,
+    network_magic: [u8; 4],
+    writer: &mut W,
+) -> Result<SnapshotMetadata, SnapshotError> {
+    let metadata = SnapshotMetadata {
+        network_magic,
+        base_block: db.best_block(),
+        coins_count: db.coins().count() as u64,
+    };
+    metadata.serialize(writer)?;
+    for (outpoint, coin) in db.coins() {
+        outpoint.serialize(writer)?;
+        coin.serialize(writer)?;
+    }
+
+    let pools = [ShieldedType::Sprout, ShieldedType::Sapling];
+    for pool in pools {
+        db.get_best_anchor(pool).serialize(writer)?;
+    }
+    for pool in pools {
+        CompactSize(db.nullifiers(pool).count() as u64).serialize(writer)?;
+        for nullifier in db.nullifiers(pool) {
+            nullifier.serialize(writer)?;
+        }
+    }
+    CompactSize(db.sprout_anchors().count() as u64).serialize(writer)?;
+    for (root, tree) in db.sprout_anchors() {
+        root.serialize(writer)?;
+        tree.serialize(writer)?;
+    }
+    CompactSize(db.sapling_anchors().count() as u64).serialize(writer)?;
+    for (root, tree) in db.sapling_anchors() {
+        root.serialize(writer)?;
+        tree.serialize(writer)?;
+    }
+    writer.flush()?;
+    Ok(metadata)
+}
+
+/// Reads the rest of a snapshot after its `metadata` into `db`. The coins
+/// are written in chunks without a best block, which is only set with the
+/// shielded state in the last batch.
+pub fn read_snapshot<R: Read>(
+    reader: &mut R,
+    metadata: &SnapshotMetadata,
+    db: &mut CoinsViewDb,
+) -> Result<(), SnapshotError> {
+    let empty_batch = || CoinsBatch {
+        best_sprout_anchor: SproutMerkleTree::empty_root(),
+        best_sapling_anchor: SAPLING_EMPTY_ROOT,
+        ..Default::default()
+    };
+    let mut batch = empty_batch();
+    for _ in 0..metadata.coins_count {
+        let outpoint = OutPoint::deserialize(reader)?;
+        let coin = Coin::deserialize(reader)?;
+        batch.coins.insert(outpoint, CacheEntry::new(Some(coin), DIRTY));
+        if batch.coins.len() == LOAD_BATCH_SIZE {
+            db.batch_write(mem::replace(&mut batch, empty_batch()))?;
+        }
+    }
+
+    batch.best_sprout_anchor = Deserializable::deserialize(reader)?;
+    batch.best_sapling_anchor = Deserializable::deserialize(reader)?;
+    for pool in [ShieldedType::Sprout, ShieldedType::Sapling] {
+        let nullifiers = match pool {
+            ShieldedType::Sprout => &mut batch.sprout_nullifiers,
+            ShieldedType::Sapling => &mut batch.sapling_nullifiers,
+        };
+        for _ in 0..CompactSize::deserialize(reader)?.0 {
+            nullifiers.insert(Deserializable::deserialize(reader)?, CacheEntry::new(true, DIRTY));
+        }
+    }
+    for _ in 0..CompactSize::deserialize(reader)?.0 {
+        let root = Deserializable::deserialize(reader)?;
+        let tree = SproutMerkleTree::deserialize(reader)?;
+        batch.sprout_anchors.insert(root, CacheEntry::new(Some(tree), DIRTY));
+    }
+    for _ in 0..CompactSize::deserialize(reader)?.0 {
+        let root = Deserializable::deserialize(reader)?;
+        let tree = SaplingMerkleTree::deserialize(reader)?;
+        batch.sapling_anchors.insert(root, CacheEntry::new(Some(tree), DIRTY));
+    }
+    if reader.read(&mut [0])? != 0 {
+        return Err(SerializationError::ParseError("data after the end of the snapshot").into());
+    }
+
+    batch.best_block = metadata.base_block;
+    db.batch_write(batch)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let metadata = SnapshotMetadata {
+            network_magic: [0x24, 0xe9, 0x27, 0x64],
+            base_block: [7; 32],
+            coins_count: 12345,
+        };
+        let mut bytes = Vec::new();
+        metadata.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 5 + 2 + 4 + 32 + 8);
+        assert_eq!(SnapshotMetadata::deserialize(&mut &bytes[..]).unwrap(), metadata);
+
+        let mut newer = bytes.clone();
+        newer[5] = 2;
+        assert!(SnapshotMetadata::deserialize(&mut &newer[..]).is_err());
+        bytes[0] = b'x';
+        assert!(SnapshotMetadata::deserialize(&mut &bytes[..]).is_err());
+    }
+}
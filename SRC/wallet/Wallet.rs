@@ -1,7 +1,10 @@
 use std::collections::HashMap;
+use bitcoinz::hash::hash160;
+use bitcoinz::primitives::transaction::{OutPoint, Transaction};
+use bitcoinz::script::standard::{solver, TxnOutType};
+use bitcoinz::script::Script;
 use crate::keys::KeyPair;
 use crate::utxo::UtxoSet;
-use crate::transactions::Transaction;
 
 pub struct Wallet {
     pub address: String,
@@ -28,17 +31,39 @@ impl Wallet {
         self.utxos.calculate_balance()
     }
 
-    /// Add a UTXO to the wallet
-    pub fn add_utxo(&mut self, txid: String, amount: u64) {
-        self.utxos.add(txid, amount);
+    /// Add a UTXO paying to the wallet's key
+    pub fn add_utxo(&mut self, outpoint: OutPoint, amount: u64) {
+        self.utxos.add(outpoint, amount, self.key_pair.get_script_pubkey());
         self.balance = self.get_balance();
     }
 
-    /// Send funds to another address
-    pub fn create_transaction(&self, to_address: &str, amount: u64, fee: u64) -> Option<Transaction> {
+    /// Send funds to another address, signing for `consensus_branch_id`
+    pub fn create_transaction(
+        &self,
+        to_address: &str,
+        amount: u64,
+        fee: u64,
+        consensus_branch_id: u32,
+    ) -> Option<Transaction> {
         if amount + fee > self.balance {
             return None; // Insufficient funds
         }
-        self.utxos.create_transaction(&self.key_pair, to_address, amount, fee)
+        self.utxos.create_transaction(&self.key_pair, to_address, amount, fee, consensus_branch_id)
+    }
+
+    /// Check whether an output script pays to this wallet's key
+    pub fn is_mine(&self, script_pubkey: &Script) -> bool {
+        let public_key = self.key_pair.get_public_key();
+        let (out_type, solutions) = solver(script_pubkey);
+        match out_type {
+            TxnOutType::PubKey => solutions[0] == public_key,
+            TxnOutType::PubKeyHash => solutions[0] == hash160(&public_key),
+            // Only spendable if every key belongs to us
+            TxnOutType::MultiSig => solutions[1..solutions.len() - 1]
+                .iter()
+                .all(|key| *key == public_key),
+            // No redeem scripts are stored, so P2SH outputs can't be ours
+            TxnOutType::ScriptHash | TxnOutType::NullData | TxnOutType::NonStandard => false,
+        }
     }
 }
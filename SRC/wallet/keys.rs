@@ -1,5 +1,8 @@
-use secp256k1::{Secp256k1, SecretKey, PublicKey};
-use bitcoin_hashes::{sha256, Hash};
+use secp256k1::{Message, Secp256k1, SecretKey, PublicKey};
+use bitcoinz::hash::hash160;
+use bitcoinz::key_io::encode_destination;
+use bitcoinz::script::standard::{get_script_for_destination, TxDestination};
+use bitcoinz::script::Script;
 
 pub struct KeyPair {
     private_key: SecretKey,
@@ -17,14 +20,37 @@ impl KeyPair {
         }
     }
 
-    /// Derive the wallet address from the public key
+    /// Destination of the compressed public key's P2PKH outputs
+    pub fn get_destination(&self) -> TxDestination {
+        let mut key_id = [0u8; 20];
+        key_id.copy_from_slice(&hash160(&self.public_key.serialize()));
+        TxDestination::KeyId(key_id)
+    }
+
+    /// Derive the transparent "t1" address from the public key
     pub fn get_address(&self) -> String {
-        let pubkey_hash = sha256::Hash::hash(&self.public_key.serialize());
-        format!("BTCZ{}", hex::encode(pubkey_hash))
+        encode_destination(&self.get_destination())
+    }
+
+    /// Get the P2PKH scriptPubKey paying to this key
+    pub fn get_script_pubkey(&self) -> Script {
+        get_script_for_destination(&self.get_destination())
+    }
+
+    /// Get the compressed public key
+    pub fn get_public_key(&self) -> Vec<u8> {
+        self.public_key.serialize().to_vec()
     }
 
     /// Get the private key
     pub fn get_private_key(&self) -> String {
         hex::encode(self.private_key.secret_bytes())
     }
+
+    /// Sign a signature hash, returning the DER-encoded ECDSA signature
+    pub fn sign(&self, hash: &[u8; 32]) -> Vec<u8> {
+        let secp = Secp256k1::signing_only();
+        let message = Message::from_slice(hash).expect("32-byte hash");
+        secp.sign_ecdsa(&message, &self.private_key).serialize_der().to_vec()
+    }
 }
@@ -5,12 +5,14 @@ mod transactions;
 mod backup;
 mod rpc;
 
+use bitcoinz::primitives::transaction::OutPoint;
 use wallet::Wallet;
 
 fn main() {
     let mut wallet = Wallet::new();
-    wallet.add_utxo("txid1".to_string(), 50000);
+    wallet.add_utxo(OutPoint { txid: [1; 32], index: 0 }, 50000);
 
+    let recipient = Wallet::new();
     println!("Balance: {}", rpc::get_balance(&wallet));
-    rpc::send_to_address(&mut wallet, "BTCZAddress123", 10000, 500);
+    rpc::send_to_address(&mut wallet, &recipient.address, 10000, 500);
 }
@@ -1,3 +1,5 @@
+use bitcoinz::consensus::upgrades::NetworkUpgrade;
+use bitcoinz::serialize::Serializable;
 use crate::wallet::Wallet;
 
 pub fn get_balance(wallet: &Wallet) -> u64 {
@@ -5,8 +7,11 @@ pub fn get_balance(wallet: &Wallet) -> u64 {
 }
 
 pub fn send_to_address(wallet: &mut Wallet, to_address: &str, amount: u64, fee: u64) -> bool {
-    if let Some(tx) = wallet.create_transaction(to_address, amount, fee) {
-        println!("Transaction sent: {:?}", tx);
+    let branch_id = NetworkUpgrade::Sapling.branch_id();
+    if let Some(tx) = wallet.create_transaction(to_address, amount, fee, branch_id) {
+        let mut bytes = Vec::new();
+        tx.serialize(&mut bytes).expect("Transaction serialization failed");
+        println!("Transaction sent: {}", hex::encode(bytes));
         true
     } else {
         false
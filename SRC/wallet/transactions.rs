@@ -1,32 +1,77 @@
+use bitcoinz::key_io::decode_destination;
+use bitcoinz::primitives::transaction::{
+    Transaction, TxInput, TxOutput, SAPLING_TX_VERSION, SAPLING_VERSION_GROUP_ID,
+};
+use bitcoinz::script::standard::get_script_for_destination;
+use bitcoinz::script::Script;
+use bitcoinz::sighash::{signature_hash, PrecomputedTransactionData, SIGHASH_ALL};
 use crate::keys::KeyPair;
+use crate::utxo::Utxo;
 
-pub struct Transaction {
-    pub inputs: Vec<String>,
-    pub outputs: Vec<(String, u64)>,
-    pub signature: String,
-}
+/// Builds a Sapling-format transaction spending `inputs` to `to_address`,
+/// returning any change to the wallet, and signs every input for
+/// `consensus_branch_id`. Returns None for an invalid address or when the
+/// inputs don't cover `amount + fee`.
+pub fn create_signed_transaction(
+    inputs: &[&Utxo],
+    to_address: &str,
+    amount: u64,
+    fee: u64,
+    key_pair: &KeyPair,
+    consensus_branch_id: u32,
+) -> Option<Transaction> {
+    let destination = decode_destination(to_address)?;
+    let total_input: u64 = inputs.iter().map(|utxo| utxo.amount).sum();
+    let change = total_input.checked_sub(amount + fee)?;
+
+    let mut outputs = vec![TxOutput {
+        value: amount as i64,
+        script_pubkey: get_script_for_destination(&destination),
+    }];
+    if change > 0 {
+        outputs.push(TxOutput {
+            value: change as i64,
+            script_pubkey: key_pair.get_script_pubkey(),
+        });
+    }
 
-impl Transaction {
-    pub fn new(
-        inputs: Vec<String>,
-        to_address: String,
-        amount: u64,
-        fee: u64,
-        key_pair: &KeyPair,
-    ) -> Self {
-        let mut outputs = vec![(to_address, amount)];
-        let change = inputs.iter().map(|_| fee).sum::<u64>() - amount - fee;
-        if change > 0 {
-            outputs.push((key_pair.get_address(), change));
-        }
+    let mut tx = Transaction {
+        overwintered: true,
+        version: SAPLING_TX_VERSION,
+        version_group_id: SAPLING_VERSION_GROUP_ID,
+        inputs: inputs
+            .iter()
+            .map(|utxo| TxInput {
+                prev_out: utxo.outpoint.clone(),
+                script_sig: Script::default(),
+                sequence: u32::MAX,
+            })
+            .collect(),
+        outputs,
+        ..Default::default()
+    };
 
-        let tx_data = format!("{:?}{:?}", inputs, outputs);
-        let signature = key_pair.sign(&tx_data);
+    let cache = PrecomputedTransactionData::new(&tx);
+    let public_key = key_pair.get_public_key();
+    let mut script_sigs = Vec::with_capacity(inputs.len());
+    for (n_in, utxo) in inputs.iter().enumerate() {
+        let hash = signature_hash(
+            &utxo.script_pubkey,
+            &tx,
+            n_in,
+            SIGHASH_ALL,
+            utxo.amount as i64,
+            consensus_branch_id,
+            Some(&cache),
+        )
+        .ok()?;
+        let mut signature = key_pair.sign(&hash);
+        signature.push(SIGHASH_ALL as u8);
+        script_sigs.push(Script::default().push_slice(&signature).push_slice(&public_key));
+    }
 
-        Transaction {
-            inputs,
-            outputs,
-            signature,
-        }
+    for (input, script_sig) in tx.inputs.iter_mut().zip(script_sigs) {
+        input.script_sig = script_sig;
     }
+    Some(tx)
 }
@@ -1,14 +1,17 @@
 use std::collections::HashMap;
-use crate::transactions::Transaction;
+use bitcoinz::primitives::transaction::{OutPoint, Transaction};
+use bitcoinz::script::Script;
+use crate::transactions::create_signed_transaction;
 use crate::keys::KeyPair;
 
 pub struct Utxo {
-    pub txid: String,
+    pub outpoint: OutPoint,
     pub amount: u64,
+    pub script_pubkey: Script,
 }
 
 pub struct UtxoSet {
-    utxos: HashMap<String, Utxo>,
+    utxos: HashMap<OutPoint, Utxo>,
 }
 
 impl UtxoSet {
@@ -18,8 +21,8 @@ impl UtxoSet {
         }
     }
 
-    pub fn add(&mut self, txid: String, amount: u64) {
-        self.utxos.insert(txid.clone(), Utxo { txid, amount });
+    pub fn add(&mut self, outpoint: OutPoint, amount: u64, script_pubkey: Script) {
+        self.utxos.insert(outpoint.clone(), Utxo { outpoint, amount, script_pubkey });
     }
 
     pub fn calculate_balance(&self) -> u64 {
@@ -32,13 +35,14 @@ impl UtxoSet {
         to_address: &str,
         amount: u64,
         fee: u64,
+        consensus_branch_id: u32,
     ) -> Option<Transaction> {
         let mut total_input = 0;
         let mut inputs = vec![];
 
-        for (txid, utxo) in &self.utxos {
+        for utxo in self.utxos.values() {
             total_input += utxo.amount;
-            inputs.push(txid.clone());
+            inputs.push(utxo);
             if total_input >= amount + fee {
                 break;
             }
@@ -48,12 +52,6 @@ impl UtxoSet {
             return None; // Insufficient funds
         }
 
-        Some(Transaction::new(
-            inputs,
-            to_address.to_string(),
-            amount,
-            fee,
-            key_pair,
-        ))
+        create_signed_transaction(&inputs, to_address, amount, fee, key_pair, consensus_branch_id)
     }
 }